@@ -0,0 +1,619 @@
+pub mod typed;
+pub mod tx;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{Duration, Instant};
+use lsm_protocol::{seal, unseal, Format, Request, Response};
+use crate::tx::Transaction;
+
+// 协议的常量、请求和响应的编解码在 lsm-protocol 中，服务端使用同一份
+pub use lsm_protocol::{ClusterInfo, Meta, HANDSHAKE_NUM, HELLO_NUM, PROTOCOL_VERSION};
+pub use lsm_protocol::{FEATURE_CRC, FEATURE_LONG_LEN, FEATURE_LZ4, FEATURE_PING, FEATURE_REQUEST_ID};
+pub use lsm_protocol::{OP_GET, OP_SET, OP_EXISTS, OP_SCAN, OP_PREFIX, OP_MGET, OP_MSET, OP_SETEX, OP_CAS, OP_INCR, OP_APPEND, OP_DBSIZE, OP_GETSET, OP_FLUSHALL, OP_PING, OP_PONG, OP_STATS, OP_MULTI, OP_EXEC, OP_AUTH, OP_SELECT, OP_KEYS, OP_GETV, OP_SETV, OP_MERGE, OP_META, OP_SNAPSHOT, OP_RELEASE, OP_COMPACT, OP_SCRUB, OP_INGEST, OP_DELETE_RANGE, OP_LPM, OP_AUTH_USER, OP_CLUSTER};
+pub use lsm_protocol::{RES_GET, RES_SET, RES_EXISTS, RES_SCAN, RES_SCAN_END, RES_MGET, RES_MSET, RES_CAS, RES_INCR, RES_APPEND, RES_DBSIZE, RES_GETSET, RES_FLUSHALL, RES_PONG, RES_PING, RES_STATS, RES_MULTI, RES_EXEC, RES_AUTH, RES_SELECT, RES_GETV, RES_SETV, RES_MERGE, RES_META, RES_SNAPSHOT, RES_RELEASE, RES_COMPACT, RES_SCRUB, RES_ERROR, RES_INGEST, RES_DELETE_RANGE, RES_LPM, RES_MOVED, RES_CLUSTER};
+pub use lsm_protocol::{ERR_KEY_TOO_LARGE, ERR_VALUE_TOO_LARGE, ERR_BUSY, ERR_UNSORTED, ERR_CROSS_SHARD, ERR_DENIED, ERR_READ_ONLY, ERR_UNCOMMITTED, ERR_TIMEOUT, ERR_RESULT_TOO_LARGE};
+// 协商 FEATURE_LONG_LEN 后长度字段为 4 bit，全 1 表示 None
+pub use lsm_protocol::NONE_LONG_LEN as NONE_LEN;
+
+// 服务端必须支持的特性
+const REQUIRED_FEATURES: u32 = FEATURE_REQUEST_ID | FEATURE_LONG_LEN;
+const CLIENT_FEATURES: u32 = REQUIRED_FEATURES | FEATURE_PING | FEATURE_CRC | FEATURE_LZ4;
+
+// 服务端统计信息，旧服务端没有的统计项为 0
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub keys: u64,
+    // 内存表中 key 和 value 的总字节数
+    pub memtable_bytes: u64,
+    // 所有 WAL 文件的总大小
+    pub wal_bytes: u64,
+    pub clients: u64,
+    // 上一秒处理的请求数
+    pub ops_per_sec: u64,
+    // SST block 缓存的命中和未命中次数
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    // 内存表占用的堆内存，包括等待落盘的内存表
+    pub memtable_memory: u64,
+    // 因为用户权限不足被拒绝的请求数
+    pub denied: u64,
+    // 超过写限流速率被延迟的写入次数和累计等待的毫秒数
+    pub write_throttled: u64,
+    pub write_throttled_ms: u64,
+}
+
+// 集群模式中 key 不由连接的节点负责，请求没有执行，需要发给 addr
+#[derive(Debug)]
+pub struct Moved {
+    pub addr: String,
+}
+
+impl std::fmt::Display for Moved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Moved to {}", self.addr)
+    }
+}
+
+impl std::error::Error for Moved {}
+
+// 错误是 RES_MOVED 时返回负责的节点地址
+pub fn moved_to(err: &Error) -> Option<&str> {
+    err.get_ref()?.downcast_ref::<Moved>().map(|moved| moved.addr.as_str())
+}
+
+type Pending = Arc<StdMutex<HashMap<u32, oneshot::Sender<Response>>>>;
+
+// 每个请求带编号，服务端在响应中原样返回，按编号匹配请求与响应
+// 同一连接上可以同时有多个未完成的请求
+pub struct Client {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    pending: Pending,
+    next_req: AtomicU32,
+    version: u8,
+    features: u32,
+}
+
+fn closed() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "Connection closed by server")
+}
+
+fn unexpected(res: Response) -> Error {
+    match res {
+        Response::ERROR(ERR_KEY_TOO_LARGE) => Error::new(ErrorKind::InvalidInput, "Key too large"),
+        Response::ERROR(ERR_VALUE_TOO_LARGE) => Error::new(ErrorKind::InvalidInput, "Value too large"),
+        // 服务端落盘跟不上写入，稍后可以重试
+        Response::ERROR(ERR_BUSY) => Error::new(ErrorKind::WouldBlock, "Server busy"),
+        Response::ERROR(ERR_UNSORTED) => Error::new(ErrorKind::InvalidInput, "Keys not sorted"),
+        // 服务端分片时，MSET 和事务中的 key 需要在同一个分片
+        Response::ERROR(ERR_CROSS_SHARD) => Error::new(ErrorKind::InvalidInput, "Keys span shards"),
+        Response::ERROR(ERR_DENIED) => Error::new(ErrorKind::PermissionDenied, "Permission denied"),
+        // 从节点只读，写入需要发给主节点
+        Response::ERROR(ERR_READ_ONLY) => Error::new(ErrorKind::PermissionDenied, "Read-only follower"),
+        // Raft 主节点在写入提交前失去主节点身份，写入可能没有生效
+        Response::ERROR(ERR_UNCOMMITTED) => Error::new(ErrorKind::Interrupted, "Write may not be committed"),
+        // 请求超过服务端配置的时限，已经在执行的写入可能生效了
+        Response::ERROR(ERR_TIMEOUT) => Error::new(ErrorKind::TimedOut, "Request timed out, write may have been applied"),
+        Response::ERROR(ERR_RESULT_TOO_LARGE) => Error::new(ErrorKind::InvalidData, "Result too large for negotiated length"),
+        Response::MOVED(addr) => Error::other(Moved { addr }),
+        res => Error::new(ErrorKind::InvalidData, format!("Unexpected response {:?}", res)),
+    }
+}
+
+// 带请求编号的一帧请求，协商 FEATURE_CRC 或 FEATURE_LZ4 后包一层
+fn encode(req: u32, request: &Request, features: u32) -> Vec<u8> {
+    let format = Format::new(features);
+    let mut frame = Vec::new();
+    request.encode(Some(req), format, &mut frame);
+    if !format.sealed {
+        return frame;
+    }
+    let mut buf = Vec::with_capacity(frame.len() + 8);
+    seal(&frame, features, &mut buf);
+    buf
+}
+
+// 以 prefix 开头的 key 的上界：去掉末尾的 0xff 后最后一个字节加一，全是 0xff 时为空，表示没有上界
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    end
+}
+
+impl Client {
+    pub async fn connect(addr: &str) -> Result<Client, Error> {
+        let mut socket = TcpStream::connect(addr).await?;
+        let hello = socket.read_u8().await?;
+        if hello != HELLO_NUM {
+            return Err(Error::new(ErrorKind::InvalidData, "Hello fail"));
+        }
+        // 1 bit handshake num
+        // 1 bit protocol version
+        // 4 bit features
+        info!("Write handshake to server");
+        let mut handshake = vec![HANDSHAKE_NUM, PROTOCOL_VERSION];
+        handshake.extend_from_slice(&CLIENT_FEATURES.to_be_bytes());
+        socket.write_all(&handshake).await?;
+        // 1 bit server protocol version
+        // 4 bit features, both sides support
+        let version = socket.read_u8().await?;
+        let features = socket.read_u32().await?;
+        info!("Server protocol version {} features {:#x}", version, features);
+        if features & REQUIRED_FEATURES != REQUIRED_FEATURES {
+            return Err(Error::new(ErrorKind::Unsupported, format!("Server does not support features {:#x}", REQUIRED_FEATURES & !features)));
+        }
+
+        let format = Format::new(features);
+        let (mut read_socket, write_socket) = socket.into_split();
+        let writer = Arc::new(Mutex::new(write_socket));
+        let read_writer = writer.clone();
+        let pending: Pending = Arc::new(StdMutex::new(HashMap::new()));
+        let read_pending = pending.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let mut b = Vec::new();
+            // 协商 FEATURE_CRC 或 FEATURE_LZ4 后未拆开的数据
+            let mut raw = Vec::new();
+            let mut entries: HashMap<u32, Vec<_>> = HashMap::new();
+            info!("Start read event loop");
+            loop {
+                loop {
+                    match Response::decode(&b, format) {
+                        Ok(Some((req, res, used))) => {
+                            b.drain(..used);
+                            // 只有 RES_PING 没有请求编号
+                            let req = req.unwrap_or(0);
+                            match res {
+                                Response::ENTRY(key, value) => entries.entry(req).or_default().push((key, value)),
+                                Response::PING(payload) => {
+                                    // 请求编号服务端不使用
+                                    let frame = encode(0, &Request::PONG { payload }, features);
+                                    if let Err(e) = read_writer.lock().await.write_all(&frame).await {
+                                        warn!("Write pong fail, err = {:?}", e);
+                                    }
+                                }
+                                res => {
+                                    let res = match res {
+                                        Response::SCAN(_) => Response::SCAN(entries.remove(&req).unwrap_or_default()),
+                                        res => res,
+                                    };
+                                    let sender = read_pending.lock().expect("Lock pending fail").remove(&req);
+                                    match sender {
+                                        Some(sender) => {
+                                            let _ = sender.send(res);
+                                        }
+                                        None => warn!("Receive response without request {:?}", res),
+                                    }
+                                }
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("Read from server fail, err = {:?}", e);
+                            read_pending.lock().expect("Lock pending fail").clear();
+                            return;
+                        }
+                    }
+                }
+                match read_socket.read(&mut buf).await {
+                    Ok(n) if n > 0 && !format.sealed => b.extend_from_slice(&buf[0..n]),
+                    Ok(n) if n > 0 => {
+                        raw.extend_from_slice(&buf[0..n]);
+                        loop {
+                            // 服务端的响应不限制长度
+                            match unseal(&raw, features, usize::MAX) {
+                                Ok(Some((frame, used))) => {
+                                    b.extend_from_slice(&frame);
+                                    raw.drain(..used);
+                                }
+                                Ok(None) => break,
+                                Err(e) => {
+                                    warn!("Read from server fail, err = {:?}", e);
+                                    read_pending.lock().expect("Lock pending fail").clear();
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    res => {
+                        info!("Close by server {:?}", res);
+                        read_pending.lock().expect("Lock pending fail").clear();
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Client {
+            writer,
+            pending,
+            next_req: AtomicU32::new(0),
+            version,
+            features,
+        })
+    }
+
+    // 服务端协议版本
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    // 双方都支持的特性位
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    // 先登记再发送，避免响应先于登记到达
+    async fn request(&self, request: Request) -> Result<Response, Error> {
+        let req = self.next_req.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().expect("Lock pending fail").insert(req, tx);
+        if let Err(e) = self.writer.lock().await.write_all(&encode(req, &request, self.features)).await {
+            self.pending.lock().expect("Lock pending fail").remove(&req);
+            return Err(e);
+        }
+        rx.await.map_err(|_| closed())
+    }
+
+    // 多个请求一次写出，中间不会插入同一连接上的其他请求；结果按请求顺序返回
+    async fn request_all(&self, requests: Vec<Request>) -> Result<Vec<Response>, Error> {
+        let mut buf = Vec::new();
+        let mut reqs = Vec::with_capacity(requests.len());
+        let mut rxs = Vec::with_capacity(requests.len());
+        for request in requests {
+            let req = self.next_req.fetch_add(1, Ordering::Relaxed);
+            buf.extend(encode(req, &request, self.features));
+            let (tx, rx) = oneshot::channel();
+            self.pending.lock().expect("Lock pending fail").insert(req, tx);
+            reqs.push(req);
+            rxs.push(rx);
+        }
+        if let Err(e) = self.writer.lock().await.write_all(&buf).await {
+            let mut pending = self.pending.lock().expect("Lock pending fail");
+            for req in reqs {
+                pending.remove(&req);
+            }
+            return Err(e);
+        }
+        let mut res = Vec::with_capacity(rxs.len());
+        for rx in rxs {
+            res.push(rx.await.map_err(|_| closed())?);
+        }
+        Ok(res)
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match self.request(Request::GET { key: key.to_vec() }).await? {
+            Response::GET(value) => Ok(value),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 一次往返读取多个 key，结果与 keys 一一对应
+    pub async fn mget(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        match self.request(Request::MGET { keys: keys.iter().map(|key| key.to_vec()).collect() }).await? {
+            Response::MGET(values) => Ok(values),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 原子写入多个 key，其他客户端不会读到一半的数据
+    pub async fn mset(&self, entries: &[(&[u8], &[u8])]) -> Result<(), Error> {
+        match self.request(Request::MSET { entries: entries.iter().map(|(key, value)| (key.to_vec(), Some(value.to_vec()))).collect() }).await? {
+            Response::MSET => Ok(()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    pub async fn exists(&self, key: &[u8]) -> Result<bool, Error> {
+        match self.request(Request::EXISTS { key: key.to_vec() }).await? {
+            Response::EXISTS(exists) => Ok(exists),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // [start, end) 范围内的数据；end 为空表示没有上界
+    pub async fn scan(&self, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        match self.request(Request::SCAN { start: start.to_vec(), end: end.to_vec() }).await? {
+            Response::SCAN(entries) => Ok(entries),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    async fn do_prefix(&self, prefix: &[u8], with_values: bool) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        match self.request(Request::PREFIX { prefix: prefix.to_vec(), with_values }).await? {
+            Response::SCAN(entries) => Ok(entries),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 以 prefix 开头的 key
+    pub async fn prefix_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(self.do_prefix(prefix, false).await?.into_iter().map(|(key, _)| key).collect())
+    }
+
+    // 以 prefix 开头的 key 和 value
+    pub async fn prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        self.do_prefix(prefix, true).await
+    }
+
+    // 匹配 glob 模式的 key，按 key 排序
+    // * 任意个字节，? 一个字节，[abc] [a-z] [^abc] 其中一个字节，\x 字节 x 本身
+    pub async fn keys(&self, pattern: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        match self.request(Request::KEYS { pattern: pattern.to_vec() }).await? {
+            Response::SCAN(entries) => Ok(entries.into_iter().map(|(key, _)| key).collect()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    async fn do_set(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), Error> {
+        match self.request(Request::SET { key: key.to_vec(), value: value.map(<[u8]>::to_vec) }).await? {
+            Response::SET => Ok(()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    pub async fn set(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.do_set(key, Some(value)).await
+    }
+
+    // ttl 秒后过期
+    pub async fn setex(&self, key: &[u8], ttl: u32, value: &[u8]) -> Result<(), Error> {
+        match self.request(Request::SETEX { key: key.to_vec(), value: value.to_vec(), ttl }).await? {
+            Response::SET => Ok(()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 当前值等于 expected 时写入 value，返回是否写入
+    // expected 为 None 表示 key 不存在，value 为 None 表示删除
+    pub async fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: Option<&[u8]>) -> Result<bool, Error> {
+        match self.request(Request::CAS { key: key.to_vec(), expected: expected.map(<[u8]>::to_vec), value: value.map(<[u8]>::to_vec) }).await? {
+            Response::CAS(swapped) => Ok(swapped),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // value 和版本号，不存在时为 (None, 0)
+    // 每次写入 key 都会得到更大的版本号，配合 setv 实现乐观并发的读改写
+    pub async fn getv(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, u64), Error> {
+        match self.request(Request::GETV { key: key.to_vec() }).await? {
+            Response::GETV(value, version) => Ok((value, version)),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // key 的前缀中存在的最长的一个和它的 value，key 本身也算前缀；都不存在时为 None
+    pub async fn longest_prefix(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        match self.request(Request::LPM { key: key.to_vec() }).await? {
+            Response::LPM(entry) => Ok(entry),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // value 长度、版本号和剩余存活时间，不传输 value；不存在时为 None
+    pub async fn meta(&self, key: &[u8]) -> Result<Option<Meta>, Error> {
+        match self.request(Request::META { key: key.to_vec() }).await? {
+            Response::META(meta) => Ok(meta),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 当前版本号等于 version 时写入 value，version 为 0 表示 key 不存在，value 为 None 表示删除
+    // 返回是否写入；写入时同时返回新的版本号（删除后为 0），否则返回当前的版本号
+    pub async fn setv(&self, key: &[u8], version: u64, value: Option<&[u8]>) -> Result<(bool, u64), Error> {
+        match self.request(Request::SETV { key: key.to_vec(), version, value: value.map(<[u8]>::to_vec) }).await? {
+            Response::SETV(ok, version) => Ok((ok, version)),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 用服务端名为 name 的合并函数把 operand 合并到原来的 value 上，返回合并后的 value
+    // 内置 append、add、max、min（十进制 i64）和 union（逗号分隔的集合）
+    pub async fn merge(&self, name: &str, key: &[u8], operand: &[u8]) -> Result<Vec<u8>, Error> {
+        let request = Request::MERGE { name: name.as_bytes().to_vec(), key: key.to_vec(), operand: operand.to_vec() };
+        match self.request(request).await? {
+            Response::MERGE(Some(value)) => Ok(value),
+            Response::MERGE(None) => Err(Error::new(ErrorKind::InvalidData, "Unknown merge operator or merge fail")),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 原子地给整数 value 加上 delta，返回新值；key 不存在时视为 0
+    pub async fn incr_by(&self, key: &[u8], delta: i64) -> Result<i64, Error> {
+        match self.request(Request::INCR { key: key.to_vec(), delta }).await? {
+            Response::INCR(Some(value)) => Ok(value),
+            Response::INCR(None) => Err(Error::new(ErrorKind::InvalidData, "Value is not an integer or overflow")),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    pub async fn incr(&self, key: &[u8]) -> Result<i64, Error> {
+        self.incr_by(key, 1).await
+    }
+
+    pub async fn decr(&self, key: &[u8]) -> Result<i64, Error> {
+        self.incr_by(key, -1).await
+    }
+
+    // 追加到 value 后，不存在时创建；返回新长度
+    pub async fn append(&self, key: &[u8], suffix: &[u8]) -> Result<usize, Error> {
+        match self.request(Request::APPEND { key: key.to_vec(), suffix: suffix.to_vec() }).await? {
+            Response::APPEND(Some(len)) => Ok(len),
+            Response::APPEND(None) => Err(Error::new(ErrorKind::InvalidData, "Value too long")),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 写入 value 并返回旧值，不存在时为 None
+    pub async fn getset(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match self.request(Request::GETSET { key: key.to_vec(), value: value.to_vec() }).await? {
+            Response::GETSET(value) => Ok(value),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // key 的数量，已过期但未清理的 key 也计入
+    pub async fn dbsize(&self) -> Result<u64, Error> {
+        match self.request(Request::DBSIZE).await? {
+            Response::DBSIZE(size) => Ok(size),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 清空所有数据，服务端未开启 enable_admin_ops 时会断开连接
+    pub async fn flushall(&self) -> Result<(), Error> {
+        match self.request(Request::FLUSHALL).await? {
+            Response::FLUSHALL => Ok(()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 落盘并立即合并与 [start, end) 重叠的 SST 文件，end 为空表示没有上界；返回合并的文件数
+    // 服务端未开启 enable_admin_ops 时会断开连接
+    pub async fn compact(&self, start: &[u8], end: &[u8]) -> Result<u32, Error> {
+        match self.request(Request::COMPACT { start: start.to_vec(), end: end.to_vec() }).await? {
+            Response::COMPACT(files) => Ok(files),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 按 key 严格递增的数据直接写成服务端当前命名空间的 SST 文件，不经过 WAL，用于大批量初始导入
+    // 返回其中之前不存在的 key 的数量；服务端未开启 enable_admin_ops 时会断开连接
+    pub async fn ingest(&self, entries: &[(&[u8], &[u8])]) -> Result<u32, Error> {
+        let entries = entries.iter().map(|(key, value)| (key.to_vec(), value.to_vec())).collect();
+        match self.request(Request::INGEST { entries }).await? {
+            Response::INGEST(keys) => Ok(keys),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 重新读取服务端当前命名空间的 SST 文件和已写完的 WAL 段并校验，返回校验过的文件数和损坏的文件数
+    // 损坏的文件名只写在服务端日志中；服务端未开启 enable_admin_ops 时会断开连接
+    pub async fn scrub(&self) -> Result<(u32, u32), Error> {
+        match self.request(Request::SCRUB).await? {
+            Response::SCRUB(files, corrupt) => Ok((files, corrupt)),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 一次经过服务端事件循环的往返，返回往返时间
+    pub async fn ping(&self) -> Result<Duration, Error> {
+        let start = Instant::now();
+        let payload = self.next_req.load(Ordering::Relaxed) as u64;
+        match self.request(Request::PING { payload }).await? {
+            Response::PONG(p) if p == payload => Ok(start.elapsed()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 服务端配置了密码时，连接后先 AUTH 才能执行 PING 以外的命令
+    // 密码错误返回 false，连续错误多次服务端会断开连接
+    pub async fn auth(&self, password: &[u8]) -> Result<bool, Error> {
+        match self.request(Request::AUTH { password: password.to_vec() }).await? {
+            Response::AUTH(ok) => Ok(ok),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 按服务端配置的用户认证，之后只能执行用户权限内的命令，访问允许的前缀内的 key，否则返回 PermissionDenied
+    // 用户不存在或密码错误返回 false，和 auth 一样连续错误多次服务端会断开连接
+    pub async fn auth_user(&self, user: &str, password: &[u8]) -> Result<bool, Error> {
+        match self.request(Request::AUTH_USER { user: user.as_bytes().to_vec(), password: password.to_vec() }).await? {
+            Response::AUTH(ok) => Ok(ok),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 之后该连接的命令都在 namespace 中执行，空字符串为默认命名空间
+    // 名字最长 64 字节，只能包含字母、数字、'_' 和 '-'；名字不合法或命名空间过多时返回 false
+    pub async fn select(&self, namespace: &str) -> Result<bool, Error> {
+        match self.request(Request::SELECT { name: namespace.as_bytes().to_vec() }).await? {
+            Response::SELECT(ok) => Ok(ok),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 在当前命名空间打开快照，返回快照中已分配的最大版本号；已打开的快照先释放
+    // 之后该连接在这个命名空间的 get、exists、mget、meta、getv、scan、prefix、keys 都读取快照，看不到之后的写入，写入不受影响
+    pub async fn snapshot(&self) -> Result<u64, Error> {
+        match self.request(Request::SNAPSHOT).await? {
+            Response::SNAPSHOT(version) => Ok(version),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 释放快照，之后恢复读取最新的数据；没有打开的快照时返回 false
+    pub async fn release(&self) -> Result<bool, Error> {
+        match self.request(Request::RELEASE).await? {
+            Response::RELEASE(ok) => Ok(ok),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 开始一个事务，op 先在本地缓存，exec 时一起发送并原子执行
+    pub fn multi(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    // 集群模式的节点和哈希环，按 ClusterInfo::owner 把请求直接发给负责的节点，不用等 RES_MOVED
+    pub async fn cluster_info(&self) -> Result<ClusterInfo, Error> {
+        match self.request(Request::CLUSTER).await? {
+            Response::CLUSTER(info) => Ok(info),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 服务端统计信息
+    pub async fn stats(&self) -> Result<Stats, Error> {
+        match self.request(Request::STATS).await? {
+            Response::STATS(stats) => {
+                // 按服务端的顺序，新服务端多出的统计项忽略
+                let stat = |i: usize| stats.get(i).copied().unwrap_or(0);
+                Ok(Stats {
+                    keys: stat(0),
+                    memtable_bytes: stat(1),
+                    wal_bytes: stat(2),
+                    clients: stat(3),
+                    ops_per_sec: stat(4),
+                    block_cache_hits: stat(5),
+                    block_cache_misses: stat(6),
+                    memtable_memory: stat(7),
+                    denied: stat(8),
+                    write_throttled: stat(9),
+                    write_throttled_ms: stat(10),
+                })
+            }
+            res => Err(unexpected(res)),
+        }
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        self.do_set(key, None).await
+    }
+
+    // 删除 [start, end) 中所有的 key，end 为空表示没有上界；服务端只写一条范围删除记录，返回删除的 key 的数量
+    pub async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<u64, Error> {
+        match self.request(Request::DELETE_RANGE { start: start.to_vec(), end: end.to_vec() }).await? {
+            Response::DELETE_RANGE(keys) => Ok(keys),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // 删除以 prefix 开头的所有 key，返回删除的 key 的数量
+    pub async fn delete_prefix(&self, prefix: &[u8]) -> Result<u64, Error> {
+        self.delete_range(prefix, &prefix_end(prefix)).await
+    }
+}
@@ -1,30 +1,25 @@
 use std::collections::HashMap;
 use std::env;
-use std::io::BufRead;
+use client::Client;
+use client::tx::Transaction;
 use log::{error, info};
 use serde_derive::Deserialize;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{stdin, AsyncBufReadExt, AsyncReadExt, BufReader};
 
 const SUB: &str = "-";
-
-const HELLO_NUM: u8 = 77;
-
-pub const OP_GET: u8 = 0xc1;
-pub const OP_SET: u8 = 0xc2;
-
-pub const RES_GET: u8 = 0x81;
-pub const RES_SET: u8 = 0x82;
-
-pub const LEN_MASK: u16 = 0x7fff;
-pub const NONE_VALUE_LEN: u16 = 0xffff;
+// ingest 每次请求发送的条数，每次请求在服务端生成一个 SST 文件
+const INGEST_BATCH: usize = 10000;
 
 // 文件配置参数
 #[derive(Deserialize)]
 struct FileConfig {
     ip: String,
     port: u32,
+    // 服务端配置了密码时，连接后先 AUTH
+    password: Option<String>,
+    // 配置了用户时按用户认证，password 为该用户的密码
+    user: Option<String>,
 }
 
 // 命令行参数
@@ -56,7 +51,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let env_config = EnvConfig {
-        config_file_path: String::from(get_config_file_path(&args_map, &String::from("./client_config.toml"))),
+        config_file_path: get_config_file_path(&args_map, &String::from("./client_config.toml")),
     };
 
     info!("LSM client start with config");
@@ -83,94 +78,275 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("LSM client connect to ip {} port {}", file_config.ip, file_config.port);
 
-    let socket = match TcpStream::connect(format!("{}:{}", file_config.ip, file_config.port)).await {
-        Ok(socket) => {
-            socket
+    let client = match Client::connect(&format!("{}:{}", file_config.ip, file_config.port)).await {
+        Ok(client) => {
+            client
         }
         Err(e) => {
             panic!("Error conect to server, err = {:?}", e);
         }
     };
 
-    let (mut read_socket, mut write_socket) = socket.into_split();
-
-    let join_read = tokio::spawn(async move {
-        let hello = read_socket.read_u8().await.expect("Read hello from server error");
-        if hello != HELLO_NUM {
-            panic!("Hello fail");
+    if let Some(password) = &file_config.password {
+        let ok = match &file_config.user {
+            Some(user) => client.auth_user(user, password.as_bytes()).await,
+            None => client.auth(password.as_bytes()).await,
+        };
+        if !ok.expect("Auth err") {
+            panic!("Auth fail, wrong password");
         }
+    }
 
-        info!("Alloc buffer");
-        let mut buf = [0; 1024];
-        let mut b = Vec::new();
-
-        info!("Start read event loop");
-        loop {
-            let n = read_socket.read(&mut buf).await.expect("Read from server error");
-            if n == 0 {
-                panic!("Close by server");
-            }
-            b.extend_from_slice(&buf[0..n]);
-            info!("Read from server {:?}", b);
-            if b.len() >= 1 {
-                let op_res = b[0];
-                match op_res {
-                    RES_GET => {
-                        // 1 bit op res
-                        // 2 bit value len
-                        // n bit value
-                        let value_len = b[1] as usize * 0x100 + b[2] as usize;
-                        if value_len == NONE_VALUE_LEN as usize {
-                            println!("None");
-                        } else {
-                            let value_len = value_len & LEN_MASK as usize;
-                            if b.len() >= 1 + 2 + value_len {
-                                let next = b.split_off(1 + 2 + value_len);
-                                let value = b.split_off(1 + 2);
-                                b = next;
-                                println!("{}", String::from_utf8(value).unwrap_or(String::from("Decoder fail")));
+    info!("Start read stdio loop");
+    let mut lines = BufReader::new(stdin()).lines();
+    // multi 之后的命令先缓存，exec 时一起执行
+    let mut tx: Option<Transaction> = None;
+    while let Some(line) = lines.next_line().await.expect("Read from stdin err") {
+        info!("Read from stdio {}", line);
+        let line_split: Vec<&str> = line.split(' ').collect();
+        if let Some(t) = tx.as_mut() {
+            if line_split[0] == "exec" {
+                match tx.take().expect("No transaction").exec().await {
+                    Ok(replies) => replies.iter().for_each(|reply| println!("{:?}", reply)),
+                    Err(e) => error!("Exec err = {:?}", e),
+                }
+                continue;
+            } else if line_split[0] == "get" && line_split.len() >= 2 {
+                t.get(line_split[1].as_bytes());
+            } else if line_split[0] == "set" && line_split.len() >= 3 {
+                t.set(line_split[1].as_bytes(), line_split[2].as_bytes());
+            } else if line_split[0] == "del" && line_split.len() >= 2 {
+                t.delete(line_split[1].as_bytes());
+            } else if line_split[0] == "incr" && line_split.len() >= 2 {
+                match line_split.get(2).unwrap_or(&"1").parse::<i64>() {
+                    Ok(delta) => {
+                        t.incr_by(line_split[1].as_bytes(), delta);
+                    }
+                    Err(e) => {
+                        error!("Invalid delta {}, err = {:?}", line_split[2], e);
+                        continue;
+                    }
+                }
+            } else if line_split[0] == "append" && line_split.len() >= 3 {
+                t.append(line_split[1].as_bytes(), line_split[2].as_bytes());
+            } else if line_split[0] == "getset" && line_split.len() >= 3 {
+                t.getset(line_split[1].as_bytes(), line_split[2].as_bytes());
+            } else {
+                error!("Unknown op in transaction {}", line);
+                continue;
+            }
+            println!("QUEUED");
+            continue;
+        }
+        if line_split[0] == "multi" {
+            tx = Some(client.multi());
+        } else if line_split[0] == "get" && line_split.len() >= 2 {
+            match client.get(line_split[1].as_bytes()).await.expect("Get err") {
+                Some(value) => println!("{}", String::from_utf8(value).unwrap_or(String::from("Decoder fail"))),
+                None => println!("None"),
+            }
+        } else if line_split[0] == "set" && line_split.len() >= 3 {
+            client.set(line_split[1].as_bytes(), line_split[2].as_bytes()).await.expect("Set err");
+        } else if line_split[0] == "setex" && line_split.len() >= 4 {
+            match line_split[2].parse::<u32>() {
+                Ok(ttl) => client.setex(line_split[1].as_bytes(), ttl, line_split[3].as_bytes()).await.expect("Setex err"),
+                Err(e) => error!("Invalid ttl {}, err = {:?}", line_split[2], e),
+            }
+        } else if line_split[0] == "mget" && line_split.len() >= 2 {
+            let keys: Vec<&[u8]> = line_split[1..].iter().map(|k| k.as_bytes()).collect();
+            for value in client.mget(&keys).await.expect("Mget err") {
+                match value {
+                    Some(value) => println!("{}", String::from_utf8(value).unwrap_or(String::from("Decoder fail"))),
+                    None => println!("None"),
+                }
+            }
+        } else if line_split[0] == "mset" && line_split.len() >= 3 && line_split.len() % 2 == 1 {
+            let entries: Vec<(&[u8], &[u8])> = line_split[1..].chunks(2).map(|kv| (kv[0].as_bytes(), kv[1].as_bytes())).collect();
+            client.mset(&entries).await.expect("Mset err");
+        } else if line_split[0] == "cas" && line_split.len() >= 4 {
+            // "-" 表示不存在 / 删除
+            let expected = Some(line_split[2]).filter(|v| *v != "-").map(|v| v.as_bytes());
+            let value = Some(line_split[3]).filter(|v| *v != "-").map(|v| v.as_bytes());
+            println!("{}", client.cas(line_split[1].as_bytes(), expected, value).await.expect("Cas err"));
+        } else if (line_split[0] == "incr" || line_split[0] == "decr") && line_split.len() >= 2 {
+            match line_split.get(2).unwrap_or(&"1").parse::<i64>() {
+                Ok(delta) => {
+                    let delta = if line_split[0] == "incr" { delta } else { delta.saturating_neg() };
+                    match client.incr_by(line_split[1].as_bytes(), delta).await {
+                        Ok(value) => println!("{}", value),
+                        Err(e) => error!("Incr err = {:?}", e),
+                    }
+                }
+                Err(e) => error!("Invalid delta {}, err = {:?}", line_split[2], e),
+            }
+        } else if line_split[0] == "append" && line_split.len() >= 3 {
+            match client.append(line_split[1].as_bytes(), line_split[2].as_bytes()).await {
+                Ok(len) => println!("{}", len),
+                Err(e) => error!("Append err = {:?}", e),
+            }
+        } else if line_split[0] == "getset" && line_split.len() >= 3 {
+            match client.getset(line_split[1].as_bytes(), line_split[2].as_bytes()).await.expect("Getset err") {
+                Some(value) => println!("{}", String::from_utf8(value).unwrap_or(String::from("Decoder fail"))),
+                None => println!("None"),
+            }
+        } else if line_split[0] == "flushall" {
+            match client.flushall().await {
+                Ok(()) => println!("OK"),
+                Err(e) => error!("Flushall err = {:?}", e),
+            }
+        } else if line_split[0] == "auth" && line_split.len() >= 3 {
+            match client.auth_user(line_split[1], line_split[2].as_bytes()).await {
+                Ok(true) => println!("OK"),
+                Ok(false) => println!("Wrong user or password"),
+                Err(e) => error!("Auth err = {:?}", e),
+            }
+        } else if line_split[0] == "auth" && line_split.len() >= 2 {
+            match client.auth(line_split[1].as_bytes()).await {
+                Ok(true) => println!("OK"),
+                Ok(false) => println!("Wrong password"),
+                Err(e) => error!("Auth err = {:?}", e),
+            }
+        } else if line_split[0] == "getv" && line_split.len() >= 2 {
+            match client.getv(line_split[1].as_bytes()).await.expect("Getv err") {
+                (Some(value), version) => println!("{} (version {})", String::from_utf8(value).unwrap_or(String::from("Decoder fail")), version),
+                (None, version) => println!("None (version {})", version),
+            }
+        } else if line_split[0] == "lpm" && line_split.len() >= 2 {
+            match client.longest_prefix(line_split[1].as_bytes()).await.expect("Lpm err") {
+                Some((key, value)) => println!("{} {}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value)),
+                None => println!("None"),
+            }
+        } else if line_split[0] == "meta" && line_split.len() >= 2 {
+            match client.meta(line_split[1].as_bytes()).await.expect("Meta err") {
+                Some(meta) => match meta.ttl {
+                    Some(ttl) => println!("len {}, version {}, ttl {}ms", meta.len, meta.version, ttl.as_millis()),
+                    None => println!("len {}, version {}, no ttl", meta.len, meta.version),
+                },
+                None => println!("None"),
+            }
+        } else if line_split[0] == "setv" && line_split.len() >= 4 {
+            match line_split[2].parse::<u64>() {
+                Ok(version) => match client.setv(line_split[1].as_bytes(), version, Some(line_split[3].as_bytes())).await.expect("Setv err") {
+                    (true, version) => println!("OK (version {})", version),
+                    (false, version) => println!("Version mismatch (version {})", version),
+                },
+                Err(e) => error!("Invalid version {}, err = {:?}", line_split[2], e),
+            }
+        } else if line_split[0] == "merge" && line_split.len() >= 4 {
+            match client.merge(line_split[1], line_split[2].as_bytes(), line_split[3].as_bytes()).await {
+                Ok(value) => println!("{}", String::from_utf8_lossy(&value)),
+                Err(e) => error!("Merge err = {:?}", e),
+            }
+        } else if line_split[0] == "select" {
+            match client.select(line_split.get(1).unwrap_or(&"")).await {
+                Ok(true) => println!("OK"),
+                Ok(false) => println!("Select fail"),
+                Err(e) => error!("Select err = {:?}", e),
+            }
+        } else if line_split[0] == "compact" {
+            let start = line_split.get(1).unwrap_or(&"");
+            let end = line_split.get(2).unwrap_or(&"");
+            match client.compact(start.as_bytes(), end.as_bytes()).await {
+                Ok(files) => println!("Compacted {} files", files),
+                Err(e) => error!("Compact err = {:?}", e),
+            }
+        } else if line_split[0] == "delrange" && line_split.len() >= 2 {
+            let end = line_split.get(2).unwrap_or(&"");
+            match client.delete_range(line_split[1].as_bytes(), end.as_bytes()).await {
+                Ok(keys) => println!("Deleted {} keys", keys),
+                Err(e) => error!("Delete range err = {:?}", e),
+            }
+        } else if line_split[0] == "delprefix" && line_split.len() >= 2 {
+            match client.delete_prefix(line_split[1].as_bytes()).await {
+                Ok(keys) => println!("Deleted {} keys", keys),
+                Err(e) => error!("Delete prefix err = {:?}", e),
+            }
+        } else if line_split[0] == "scrub" {
+            match client.scrub().await {
+                Ok((files, corrupt)) => println!("Checked {} files, {} corrupted", files, corrupt),
+                Err(e) => error!("Scrub err = {:?}", e),
+            }
+        } else if line_split[0] == "ingest" && line_split.len() >= 2 {
+            // 文件每行一条 "key value"，key 严格递增
+            match tokio::fs::read_to_string(line_split[1]).await {
+                Ok(content) => {
+                    let entries: Vec<(&[u8], &[u8])> = content.lines()
+                        .filter(|line| !line.is_empty())
+                        .map(|line| line.split_once(' ').unwrap_or((line, "")))
+                        .map(|(key, value)| (key.as_bytes(), value.as_bytes()))
+                        .collect();
+                    let mut keys = 0;
+                    let mut result = Ok(());
+                    for batch in entries.chunks(INGEST_BATCH) {
+                        match client.ingest(batch).await {
+                            Ok(n) => keys += n,
+                            Err(e) => {
+                                result = Err(e);
+                                break;
                             }
                         }
                     }
-                    RES_SET => {
-                        // 1 bit op res
-                        b = b.split_off(1);
-                    }
-                    n => {
-                        panic!("Unknown OP res, op = {}", n);
+                    match result {
+                        Ok(()) => println!("Ingested {} entries, {} new keys", entries.len(), keys),
+                        Err(e) => error!("Ingest err = {:?}", e),
                     }
                 }
+                Err(e) => error!("Read file {} err = {:?}", line_split[1], e),
             }
-        }
-    });
-
-    let join_write = tokio::spawn(async move {
-        info!("Write hello to server");
-        write_socket.write_u8(HELLO_NUM).await.expect("Write hello err");
-        info!("Start write event loop");
-        loop {
-            let mut line = String::new();
-            std::io::stdin().lock().read_line(&mut line).expect("Read from stdin err");
-            line = line.replace("\n", "");
-            info!("Read from stdio {}", line);
-            let line_split: Vec<&str> = line.split(" ").collect();
-            if line_split[0] == "get" && line_split.len() >= 2 {
-                write_socket.write_u8(OP_GET).await.expect("Write op get err");
-                write_socket.write_u16(line_split[1].len() as u16 & LEN_MASK).await.expect("Write get key len err");
-                write_socket.write_all(line_split[1].as_bytes()).await.expect("Write get key err");
-            } else if line_split[0] == "set" && line_split.len() >= 3 {
-                write_socket.write_u8(OP_SET).await.expect("Write op set err");
-                write_socket.write_u16(line_split[1].len() as u16 & LEN_MASK).await.expect("Write set key len err");
-                write_socket.write_all(line_split[1].as_bytes()).await.expect("Write set key err");
-                write_socket.write_u16(line_split[2].len() as u16 & LEN_MASK).await.expect("Write set value len err");
-                write_socket.write_all(line_split[2].as_bytes()).await.expect("Write set value err");
+        } else if line_split[0] == "snapshot" {
+            match client.snapshot().await {
+                Ok(version) => println!("Snapshot at version {}", version),
+                Err(e) => error!("Snapshot err = {:?}", e),
+            }
+        } else if line_split[0] == "release" {
+            match client.release().await {
+                Ok(true) => println!("OK"),
+                Ok(false) => println!("No snapshot"),
+                Err(e) => error!("Release err = {:?}", e),
+            }
+        } else if line_split[0] == "ping" {
+            match client.ping().await {
+                Ok(rtt) => println!("PONG {:?}", rtt),
+                Err(e) => error!("Ping err = {:?}", e),
+            }
+        } else if line_split[0] == "stats" {
+            println!("{:?}", client.stats().await.expect("Stats err"));
+        } else if line_split[0] == "cluster" {
+            let info = client.cluster_info().await.expect("Cluster err");
+            for (index, node) in info.nodes.iter().enumerate() {
+                let points = info.points.iter().filter(|(_, owner)| *owner == index).count();
+                let local = if index == info.local { " (local)" } else { "" };
+                println!("{} {} points{}", node, points, local);
+            }
+            if let Some(key) = line_split.get(1) {
+                println!("{} -> {:?}", key, info.owner(key.as_bytes()));
+            }
+        } else if line_split[0] == "dbsize" {
+            println!("{}", client.dbsize().await.expect("Dbsize err"));
+        } else if line_split[0] == "exists" && line_split.len() >= 2 {
+            println!("{}", client.exists(line_split[1].as_bytes()).await.expect("Exists err"));
+        } else if line_split[0] == "scan" && line_split.len() >= 2 {
+            let end = line_split.get(2).unwrap_or(&"");
+            for (key, value) in client.scan(line_split[1].as_bytes(), end.as_bytes()).await.expect("Scan err") {
+                println!("{} {}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+            }
+        } else if line_split[0] == "prefix" && line_split.len() >= 2 {
+            if line_split.get(2) == Some(&"values") {
+                for (key, value) in client.prefix(line_split[1].as_bytes()).await.expect("Prefix err") {
+                    println!("{} {}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+                }
             } else {
-                error!("Unknown op {}",line);
+                for key in client.prefix_keys(line_split[1].as_bytes()).await.expect("Prefix err") {
+                    println!("{}", String::from_utf8_lossy(&key));
+                }
             }
+        } else if line_split[0] == "keys" && line_split.len() >= 2 {
+            for key in client.keys(line_split[1].as_bytes()).await.expect("Keys err") {
+                println!("{}", String::from_utf8_lossy(&key));
+            }
+        } else {
+            error!("Unknown op {}", line);
         }
-    });
-
-    join_read.await.expect("Wait read error");
-    join_write.await.expect("Wait write error");
+    }
     Ok(())
 }
@@ -0,0 +1,119 @@
+use std::io::{Error, ErrorKind};
+use lsm_protocol::{Request, Response};
+use crate::{unexpected, Client};
+
+// 事务中各 op 的结果，与加入事务的顺序一一对应
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum TxReply {
+    // GET 和 GETSET 的结果
+    GET(Option<Vec<u8>>),
+    EXISTS(bool),
+    MGET(Vec<Option<Vec<u8>>>),
+    // SET、DELETE 和 MSET 的结果
+    SET,
+    CAS(bool),
+    // value 不是整数或溢出时为 None，不写入
+    INCR(Option<i64>),
+    // 追加后超过长度上限时为 None，不写入
+    APPEND(Option<usize>),
+}
+
+// 服务端在 EXEC 时依次执行，所有写合成一条批量记录，中间不会插入其他客户端的读写
+// 事务中写入的 key 不保留过期时间
+pub struct Transaction<'a> {
+    client: &'a Client,
+    requests: Vec<Request>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        Self {
+            client,
+            requests: Vec::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> &mut Self {
+        self.requests.push(Request::GET { key: key.to_vec() });
+        self
+    }
+
+    pub fn exists(&mut self, key: &[u8]) -> &mut Self {
+        self.requests.push(Request::EXISTS { key: key.to_vec() });
+        self
+    }
+
+    pub fn mget(&mut self, keys: &[&[u8]]) -> &mut Self {
+        self.requests.push(Request::MGET { keys: keys.iter().map(|key| key.to_vec()).collect() });
+        self
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.requests.push(Request::SET { key: key.to_vec(), value: Some(value.to_vec()) });
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.requests.push(Request::SET { key: key.to_vec(), value: None });
+        self
+    }
+
+    pub fn mset(&mut self, entries: &[(&[u8], &[u8])]) -> &mut Self {
+        self.requests.push(Request::MSET { entries: entries.iter().map(|(key, value)| (key.to_vec(), Some(value.to_vec()))).collect() });
+        self
+    }
+
+    pub fn cas(&mut self, key: &[u8], expected: Option<&[u8]>, value: Option<&[u8]>) -> &mut Self {
+        self.requests.push(Request::CAS { key: key.to_vec(), expected: expected.map(<[u8]>::to_vec), value: value.map(<[u8]>::to_vec) });
+        self
+    }
+
+    pub fn incr_by(&mut self, key: &[u8], delta: i64) -> &mut Self {
+        self.requests.push(Request::INCR { key: key.to_vec(), delta });
+        self
+    }
+
+    pub fn append(&mut self, key: &[u8], suffix: &[u8]) -> &mut Self {
+        self.requests.push(Request::APPEND { key: key.to_vec(), suffix: suffix.to_vec() });
+        self
+    }
+
+    pub fn getset(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.requests.push(Request::GETSET { key: key.to_vec(), value: value.to_vec() });
+        self
+    }
+
+    // MULTI、缓存的 op 和 EXEC 一次写出
+    pub async fn exec(self) -> Result<Vec<TxReply>, Error> {
+        let count = self.requests.len();
+        let mut requests = Vec::with_capacity(count + 2);
+        requests.push(Request::MULTI);
+        requests.extend(self.requests);
+        requests.push(Request::EXEC);
+        let mut res = self.client.request_all(requests).await?.into_iter();
+        match res.next() {
+            Some(Response::MULTI) => {}
+            Some(res) => return Err(unexpected(res)),
+            None => return Err(Error::new(ErrorKind::InvalidData, "Missing multi response")),
+        }
+        let mut replies = Vec::with_capacity(count);
+        for res in res.by_ref().take(count) {
+            replies.push(match res {
+                Response::GET(value) | Response::GETSET(value) => TxReply::GET(value),
+                Response::EXISTS(exists) => TxReply::EXISTS(exists),
+                Response::MGET(values) => TxReply::MGET(values),
+                Response::SET | Response::MSET => TxReply::SET,
+                Response::CAS(swapped) => TxReply::CAS(swapped),
+                Response::INCR(value) => TxReply::INCR(value),
+                Response::APPEND(len) => TxReply::APPEND(len),
+                res => return Err(unexpected(res)),
+            });
+        }
+        match res.next() {
+            Some(Response::EXEC) => Ok(replies),
+            Some(res) => Err(unexpected(res)),
+            None => Err(Error::new(ErrorKind::InvalidData, "Missing exec response")),
+        }
+    }
+}
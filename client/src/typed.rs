@@ -0,0 +1,181 @@
+use std::io::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::Client;
+
+// 值的序列化格式，由 feature 开启
+pub trait Format {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Error>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error>;
+}
+
+#[cfg(any(feature = "json", feature = "bincode", feature = "msgpack"))]
+fn invalid_data<E: std::fmt::Display>(e: E) -> Error {
+    Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Format for Json {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(value).map_err(invalid_data)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(bytes).map_err(invalid_data)
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Format for Bincode {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+        bincode::serialize(value).map_err(invalid_data)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        bincode::deserialize(bytes).map_err(invalid_data)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Format for MsgPack {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+        rmp_serde::to_vec(value).map_err(invalid_data)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        rmp_serde::from_slice(bytes).map_err(invalid_data)
+    }
+}
+
+impl Client {
+    // client.get_as::<User, Json>(b"user:1")
+    pub async fn get_as<T: DeserializeOwned, F: Format>(&self, key: &[u8]) -> Result<Option<T>, Error> {
+        match self.get(key).await? {
+            Some(bytes) => Ok(Some(F::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    // client.set_as::<User, Json>(b"user:1", &user)
+    pub async fn set_as<T: Serialize, F: Format>(&self, key: &[u8], value: &T) -> Result<(), Error> {
+        self.set(key, &F::encode(value)?).await
+    }
+}
+
+#[cfg(all(test, any(feature = "json", feature = "bincode", feature = "msgpack")))]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::ErrorKind;
+    use serde_derive::{Deserialize, Serialize};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use lsm_protocol::{Request, Response, HANDSHAKE_NUM, HELLO_NUM, PROTOCOL_VERSION};
+    use super::*;
+    use crate::REQUIRED_FEATURES;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+    }
+
+    fn user() -> User {
+        User { id: 7, name: String::from("alice"), tags: vec![String::from("admin"), String::from("ops")] }
+    }
+
+    // 只支持 GET 和 SET 的服务端，值保存在内存中，只协商必需的特性
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_u8(HELLO_NUM).await.unwrap();
+            let mut handshake = [0; 6];
+            socket.read_exact(&mut handshake).await.unwrap();
+            assert_eq!(&handshake[..2], &[HANDSHAKE_NUM, PROTOCOL_VERSION]);
+            socket.write_u8(PROTOCOL_VERSION).await.unwrap();
+            socket.write_u32(REQUIRED_FEATURES).await.unwrap();
+            let format = lsm_protocol::Format::new(REQUIRED_FEATURES);
+            let mut data: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+            let mut b = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                while let Some((req, request, used)) = Request::decode(&b, format).unwrap() {
+                    b.drain(..used);
+                    let res = match request {
+                        Request::GET { key } => Response::GET(data.get(&key).cloned()),
+                        Request::SET { key, value } => {
+                            match value {
+                                Some(value) => data.insert(key, value),
+                                None => data.remove(&key),
+                            };
+                            Response::SET
+                        }
+                        request => panic!("Unexpected request {:?}", request),
+                    };
+                    let mut frame = Vec::new();
+                    res.encode(req, format, &mut frame);
+                    socket.write_all(&frame).await.unwrap();
+                }
+                match socket.read(&mut buf).await {
+                    Ok(n) if n > 0 => b.extend_from_slice(&buf[..n]),
+                    _ => return,
+                }
+            }
+        });
+        addr
+    }
+
+    // set_as 写入的是 F::encode 的结果，get_as 读回原值，不存在的 key 为 None
+    async fn round_trip<F: Format>() {
+        let client = Client::connect(&serve().await).await.unwrap();
+        client.set_as::<User, F>(b"user:1", &user()).await.unwrap();
+        assert_eq!(client.get(b"user:1").await.unwrap(), Some(F::encode(&user()).unwrap()));
+        assert_eq!(client.get_as::<User, F>(b"user:1").await.unwrap(), Some(user()));
+        assert_eq!(client.get_as::<User, F>(b"user:2").await.unwrap(), None);
+    }
+
+    // 存的字节不是 F 的格式时 get_as 返回 InvalidData，连接仍然可用
+    async fn decode_error<F: Format>(bytes: &[u8]) {
+        let client = Client::connect(&serve().await).await.unwrap();
+        client.set(b"user:1", bytes).await.unwrap();
+        let err = client.get_as::<User, F>(b"user:1").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(client.get(b"user:1").await.unwrap(), Some(bytes.to_vec()));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json_round_trip() {
+        round_trip::<Json>().await;
+        decode_error::<Json>(b"{\"id\": \"seven\"}").await;
+        decode_error::<Json>(b"not json").await;
+    }
+
+    #[cfg(feature = "bincode")]
+    #[tokio::test]
+    async fn bincode_round_trip() {
+        round_trip::<Bincode>().await;
+        let mut truncated = Bincode::encode(&user()).unwrap();
+        truncated.pop();
+        decode_error::<Bincode>(&truncated).await;
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn msgpack_round_trip() {
+        round_trip::<MsgPack>().await;
+        decode_error::<MsgPack>(&[0xc1]).await;
+        decode_error::<MsgPack>(&rmp_serde::to_vec(&(1, 2)).unwrap()).await;
+    }
+}
@@ -0,0 +1,1418 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+// 服务端和客户端共用的协议：握手、op 和响应码、长度字段、外层帧，以及请求和响应的编解码
+
+// 握手数字
+pub const HELLO_NUM: u8 = 77;
+// 带版本和特性的握手，不发送的旧客户端只回复 HELLO_NUM
+pub const HANDSHAKE_NUM: u8 = 78;
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// 特性位，握手时协商
+// 请求和响应带 4 bit 请求编号
+pub const FEATURE_REQUEST_ID: u32 = 1;
+// 长度字段为 4 bit
+pub const FEATURE_LONG_LEN: u32 = 1 << 1;
+// 服务端定时发送 RES_PING，客户端回复 OP_PONG
+pub const FEATURE_PING: u32 = 1 << 2;
+// 每帧前加 4 bit 长度，后加 4 bit crc32
+pub const FEATURE_CRC: u32 = 1 << 3;
+// 每帧前加 4 bit 长度，超过 COMPRESS_THRESHOLD 的帧用 lz4 压缩
+pub const FEATURE_LZ4: u32 = 1 << 4;
+// 帧达到该长度才尝试压缩，压缩后不更短则原样发送
+const COMPRESS_THRESHOLD: usize = 1024;
+// 外层长度的最高位表示帧经过压缩
+const COMPRESSED_FLAG: u32 = 1 << 31;
+// lz4 最大压缩比约 255，原长度超过该倍数的帧视为非法，避免按伪造的长度分配内存
+const MAX_COMPRESS_RATIO: usize = 255;
+
+pub const OP_GET: u8 = 0xc1;
+pub const OP_SET: u8 = 0xc2;
+pub const OP_EXISTS: u8 = 0xc3;
+pub const OP_SCAN: u8 = 0xc4;
+pub const OP_PREFIX: u8 = 0xc5;
+pub const OP_MGET: u8 = 0xc6;
+pub const OP_MSET: u8 = 0xc7;
+pub const OP_SETEX: u8 = 0xc8;
+pub const OP_CAS: u8 = 0xc9;
+pub const OP_INCR: u8 = 0xca;
+pub const OP_APPEND: u8 = 0xcb;
+pub const OP_DBSIZE: u8 = 0xcc;
+pub const OP_GETSET: u8 = 0xcd;
+pub const OP_FLUSHALL: u8 = 0xce;
+pub const OP_PING: u8 = 0xcf;
+// 回复服务端的 RES_PING
+pub const OP_PONG: u8 = 0xd0;
+pub const OP_STATS: u8 = 0xd1;
+pub const OP_MULTI: u8 = 0xd2;
+pub const OP_EXEC: u8 = 0xd3;
+pub const OP_AUTH: u8 = 0xd4;
+pub const OP_SELECT: u8 = 0xd5;
+pub const OP_KEYS: u8 = 0xd6;
+pub const OP_GETV: u8 = 0xd7;
+pub const OP_SETV: u8 = 0xd8;
+pub const OP_MERGE: u8 = 0xd9;
+pub const OP_META: u8 = 0xda;
+pub const OP_SNAPSHOT: u8 = 0xdb;
+pub const OP_RELEASE: u8 = 0xdc;
+pub const OP_COMPACT: u8 = 0xdd;
+pub const OP_SCRUB: u8 = 0xde;
+pub const OP_INGEST: u8 = 0xdf;
+pub const OP_DELETE_RANGE: u8 = 0xe0;
+pub const OP_LPM: u8 = 0xe1;
+// 按配置中的用户认证，结果同 OP_AUTH
+pub const OP_AUTH_USER: u8 = 0xe2;
+// 集群模式的节点和哈希环
+pub const OP_CLUSTER: u8 = 0xe3;
+
+pub const RES_GET: u8 = 0x81;
+pub const RES_SET: u8 = 0x82;
+pub const RES_EXISTS: u8 = 0x83;
+pub const RES_SCAN: u8 = 0x84;
+pub const RES_SCAN_END: u8 = 0x85;
+pub const RES_MGET: u8 = 0x86;
+pub const RES_MSET: u8 = 0x87;
+pub const RES_CAS: u8 = 0x88;
+pub const RES_INCR: u8 = 0x89;
+pub const RES_APPEND: u8 = 0x8a;
+pub const RES_DBSIZE: u8 = 0x8b;
+pub const RES_GETSET: u8 = 0x8c;
+pub const RES_FLUSHALL: u8 = 0x8d;
+pub const RES_PONG: u8 = 0x8e;
+// 服务端主动发送，不带请求编号
+pub const RES_PING: u8 = 0x8f;
+pub const RES_STATS: u8 = 0x90;
+pub const RES_MULTI: u8 = 0x91;
+pub const RES_EXEC: u8 = 0x92;
+pub const RES_AUTH: u8 = 0x93;
+pub const RES_SELECT: u8 = 0x94;
+pub const RES_GETV: u8 = 0x95;
+pub const RES_SETV: u8 = 0x96;
+pub const RES_MERGE: u8 = 0x97;
+pub const RES_META: u8 = 0x98;
+pub const RES_SNAPSHOT: u8 = 0x99;
+pub const RES_RELEASE: u8 = 0x9a;
+pub const RES_COMPACT: u8 = 0x9b;
+pub const RES_SCRUB: u8 = 0x9c;
+pub const RES_ERROR: u8 = 0x9d;
+pub const RES_INGEST: u8 = 0x9e;
+pub const RES_DELETE_RANGE: u8 = 0x9f;
+pub const RES_LPM: u8 = 0xa0;
+// key 不由本节点负责，带负责的节点地址
+pub const RES_MOVED: u8 = 0xa1;
+pub const RES_CLUSTER: u8 = 0xa2;
+
+// RES_ERROR 的错误码
+pub const ERR_KEY_TOO_LARGE: u8 = 1;
+pub const ERR_VALUE_TOO_LARGE: u8 = 2;
+// 落盘或合并跟不上写入，稍后重试
+pub const ERR_BUSY: u8 = 3;
+// INGEST 的 key 不是严格递增
+pub const ERR_UNSORTED: u8 = 4;
+// 多个分片时，MSET 或事务中的 key 不在同一个分片
+pub const ERR_CROSS_SHARD: u8 = 5;
+// 认证的用户没有执行该请求的权限，或访问了允许的前缀之外的 key
+pub const ERR_DENIED: u8 = 6;
+// 从节点只读，写入需要发给主节点
+pub const ERR_READ_ONLY: u8 = 7;
+// Raft 主节点在写入提交前失去主节点身份，写入可能没有生效
+pub const ERR_UNCOMMITTED: u8 = 8;
+// 请求超过服务端的 request_timeout_ms 没有完成，写入可能已经生效
+pub const ERR_TIMEOUT: u8 = 9;
+// 结果超过协商的长度字段能表示的长度，没有协商 FEATURE_LONG_LEN 时出现；请求已经执行
+pub const ERR_RESULT_TOO_LARGE: u8 = 10;
+
+pub const LEN_MASK: u16 = 0x7fff;
+pub const NONE_VALUE_LEN: u16 = 0xffff;
+pub const NONE_LONG_LEN: u32 = 0xffffffff;
+
+// 长度字段的字节数，协商 FEATURE_LONG_LEN 后为 LONG_LEN_SIZE
+pub const SHORT_LEN_SIZE: usize = 2;
+pub const LONG_LEN_SIZE: usize = 4;
+
+// value 的最大长度
+pub const MAX_LEN: usize = NONE_LONG_LEN as usize - 1;
+
+// ls bit len
+pub fn push_len(buf: &mut Vec<u8>, len: usize, ls: usize) {
+    if ls == LONG_LEN_SIZE {
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        let len = len as u16 & LEN_MASK;
+        buf.push((len >> 8) as u8);
+        buf.push(len as u8);
+    }
+}
+
+// ls bit NONE
+pub fn push_none(buf: &mut Vec<u8>, ls: usize) {
+    if ls == LONG_LEN_SIZE {
+        buf.extend_from_slice(&NONE_LONG_LEN.to_be_bytes());
+    } else {
+        buf.extend_from_slice(&NONE_VALUE_LEN.to_be_bytes());
+    }
+}
+
+// ls bit len; if NONE value None
+// n bit value
+pub fn push_value(buf: &mut Vec<u8>, value: Option<&[u8]>, ls: usize) {
+    match value {
+        Some(v) => {
+            push_len(buf, v.len(), ls);
+            buf.extend_from_slice(v);
+        }
+        None => push_none(buf, ls),
+    }
+}
+
+// 读取 index 处 ls bit 的长度，NONE 返回 None；调用方保证数据足够
+pub fn read_len(b: &[u8], index: usize, ls: usize) -> Option<usize> {
+    if ls == LONG_LEN_SIZE {
+        let len = u32::from_be_bytes([b[index], b[index + 1], b[index + 2], b[index + 3]]);
+        if len == NONE_LONG_LEN { None } else { Some(len as usize) }
+    } else {
+        let len = b[index] as u16 * 0x100 + b[index + 1] as u16;
+        if len == NONE_VALUE_LEN { None } else { Some(len as usize) }
+    }
+}
+
+// 1 bit op res
+// 4 bit request id, 未协商请求编号时没有
+pub fn res_head(op: u8, req: Option<u32>) -> Vec<u8> {
+    let mut buf = vec![op];
+    if let Some(req) = req {
+        buf.extend_from_slice(&req.to_be_bytes());
+    }
+    buf
+}
+
+// 握手协商的特性决定的帧格式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    // 请求和响应带请求编号，RES_PING 除外
+    pub request_id: bool,
+    // 长度字段的字节数
+    pub ls: usize,
+    // 每帧包一层，见 seal
+    pub sealed: bool,
+}
+
+impl Format {
+    pub fn new(features: u32) -> Self {
+        Self {
+            request_id: features & FEATURE_REQUEST_ID != 0,
+            ls: if features & FEATURE_LONG_LEN != 0 { LONG_LEN_SIZE } else { SHORT_LEN_SIZE },
+            sealed: features & (FEATURE_CRC | FEATURE_LZ4) != 0,
+        }
+    }
+}
+
+// 协商 FEATURE_CRC 或 FEATURE_LZ4 后每帧包一层
+// 4 bit frame len, 最高位为 COMPRESSED_FLAG
+// n bit frame, lz4 压缩时带 4 bit 原长度
+// 4 bit crc32 of frame, if FEATURE_CRC，按发送的字节计算
+pub fn seal(frame: &[u8], features: u32, buf: &mut Vec<u8>) {
+    let compressed = if features & FEATURE_LZ4 != 0 && frame.len() >= COMPRESS_THRESHOLD {
+        Some(lz4_flex::compress_prepend_size(frame)).filter(|c| c.len() < frame.len())
+    } else {
+        None
+    };
+    let (flag, frame) = match &compressed {
+        Some(c) => (COMPRESSED_FLAG, c.as_slice()),
+        None => (0, frame),
+    };
+    buf.reserve(4 + frame.len() + 4);
+    buf.extend_from_slice(&(frame.len() as u32 | flag).to_be_bytes());
+    buf.extend_from_slice(frame);
+    if features & FEATURE_CRC != 0 {
+        buf.extend_from_slice(&crc32fast::hash(frame).to_be_bytes());
+    }
+}
+
+// 取出 raw 开头的一个完整的帧，校验、解压后返回帧和用掉的字节数；数据不足时为 None
+// 帧和解压后的长度超过 max_len 时读到外层长度即返回错误，不等数据到齐
+pub fn unseal(raw: &[u8], features: u32, max_len: usize) -> Result<Option<(Vec<u8>, usize)>, Error> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let cs = if features & FEATURE_CRC != 0 { 4 } else { 0 };
+    if raw.len() < 4 {
+        return Ok(None);
+    }
+    let head = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]);
+    let frame_len = (head & !COMPRESSED_FLAG) as usize;
+    if frame_len > max_len {
+        return Err(invalid("Frame too large"));
+    }
+    if raw.len() < 4 + frame_len + cs {
+        return Ok(None);
+    }
+    let frame = &raw[4..4 + frame_len];
+    if cs != 0 && crc32fast::hash(frame).to_be_bytes() != raw[4 + frame_len..4 + frame_len + cs] {
+        return Err(invalid("Frame crc mismatch"));
+    }
+    let frame = if head & COMPRESSED_FLAG == 0 {
+        frame.to_vec()
+    } else if features & FEATURE_LZ4 == 0 {
+        return Err(invalid("Compressed frame without FEATURE_LZ4"));
+    } else if frame.len() < 4 || u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize > (frame.len() * MAX_COMPRESS_RATIO).min(max_len) {
+        return Err(invalid("Compressed frame too large"));
+    } else {
+        lz4_flex::decompress_size_prepended(frame).map_err(|_| invalid("Frame decompress fail"))?
+    };
+    Ok(Some((frame, 4 + frame_len + cs)))
+}
+
+// 解析时数据不完整，或者数据非法
+enum Malformed {
+    Incomplete,
+    Invalid(String),
+}
+
+impl From<Malformed> for Option<Error> {
+    fn from(e: Malformed) -> Self {
+        match e {
+            Malformed::Incomplete => None,
+            Malformed::Invalid(msg) => Some(Error::new(ErrorKind::InvalidData, msg)),
+        }
+    }
+}
+
+// 按顺序读取帧中的字段
+struct Cursor<'a> {
+    buf: &'a [u8],
+    index: usize,
+    ls: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Malformed> {
+        let end = self.index.checked_add(len).ok_or(Malformed::Incomplete)?;
+        let bytes = self.buf.get(self.index..end).ok_or(Malformed::Incomplete)?;
+        self.index = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Malformed> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Malformed> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().expect("2 bytes")))
+    }
+
+    fn u32(&mut self) -> Result<u32, Malformed> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64, Malformed> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().expect("8 bytes")))
+    }
+
+    // ls bit len, NONE 为 None
+    fn len(&mut self) -> Result<Option<usize>, Malformed> {
+        self.bytes(self.ls)?;
+        Ok(read_len(self.buf, self.index - self.ls, self.ls))
+    }
+
+    // ls bit len, n bit bytes；长度为 NONE 时视为空
+    fn field(&mut self) -> Result<Vec<u8>, Malformed> {
+        let len = self.len()?.unwrap_or(0);
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    // ls bit len; if NONE None, n bit bytes
+    fn optional(&mut self) -> Result<Option<Vec<u8>>, Malformed> {
+        match self.len()? {
+            Some(len) => Ok(Some(self.bytes(len)?.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Malformed> {
+        Ok(String::from_utf8_lossy(&self.field()?).to_string())
+    }
+}
+
+// 客户端的一个请求
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Request {
+    GET { key: Vec<u8> },
+    EXISTS { key: Vec<u8> },
+    // value 为 None 表示删除
+    SET { key: Vec<u8>, value: Option<Vec<u8>> },
+    // end 为空表示没有上界
+    SCAN { start: Vec<u8>, end: Vec<u8> },
+    PREFIX { prefix: Vec<u8>, with_values: bool },
+    KEYS { pattern: Vec<u8> },
+    MGET { keys: Vec<Vec<u8>> },
+    MSET { entries: Vec<(Vec<u8>, Option<Vec<u8>>)> },
+    SETEX { key: Vec<u8>, value: Vec<u8>, ttl: u32 },
+    CAS { key: Vec<u8>, expected: Option<Vec<u8>>, value: Option<Vec<u8>> },
+    META { key: Vec<u8> },
+    LPM { key: Vec<u8> },
+    GETV { key: Vec<u8> },
+    SETV { key: Vec<u8>, version: u64, value: Option<Vec<u8>> },
+    MERGE { name: Vec<u8>, key: Vec<u8>, operand: Vec<u8> },
+    INCR { key: Vec<u8>, delta: i64 },
+    APPEND { key: Vec<u8>, suffix: Vec<u8> },
+    GETSET { key: Vec<u8>, value: Vec<u8> },
+    FLUSHALL,
+    COMPACT { start: Vec<u8>, end: Vec<u8> },
+    #[allow(non_camel_case_types)]
+    DELETE_RANGE { start: Vec<u8>, end: Vec<u8> },
+    SCRUB,
+    INGEST { entries: Vec<(Vec<u8>, Vec<u8>)> },
+    PING { payload: u64 },
+    // 回复服务端的 RES_PING，payload 为发送时的 unix 毫秒
+    PONG { payload: u64 },
+    STATS,
+    MULTI,
+    EXEC,
+    AUTH { password: Vec<u8> },
+    #[allow(non_camel_case_types)]
+    AUTH_USER { user: Vec<u8>, password: Vec<u8> },
+    SELECT { name: Vec<u8> },
+    SNAPSHOT,
+    RELEASE,
+    CLUSTER,
+    DBSIZE,
+}
+
+impl Request {
+    pub fn op(&self) -> u8 {
+        match self {
+            Request::GET { .. } => OP_GET,
+            Request::EXISTS { .. } => OP_EXISTS,
+            Request::SET { .. } => OP_SET,
+            Request::SCAN { .. } => OP_SCAN,
+            Request::PREFIX { .. } => OP_PREFIX,
+            Request::KEYS { .. } => OP_KEYS,
+            Request::MGET { .. } => OP_MGET,
+            Request::MSET { .. } => OP_MSET,
+            Request::SETEX { .. } => OP_SETEX,
+            Request::CAS { .. } => OP_CAS,
+            Request::META { .. } => OP_META,
+            Request::LPM { .. } => OP_LPM,
+            Request::GETV { .. } => OP_GETV,
+            Request::SETV { .. } => OP_SETV,
+            Request::MERGE { .. } => OP_MERGE,
+            Request::INCR { .. } => OP_INCR,
+            Request::APPEND { .. } => OP_APPEND,
+            Request::GETSET { .. } => OP_GETSET,
+            Request::FLUSHALL => OP_FLUSHALL,
+            Request::COMPACT { .. } => OP_COMPACT,
+            Request::DELETE_RANGE { .. } => OP_DELETE_RANGE,
+            Request::SCRUB => OP_SCRUB,
+            Request::INGEST { .. } => OP_INGEST,
+            Request::PING { .. } => OP_PING,
+            Request::PONG { .. } => OP_PONG,
+            Request::STATS => OP_STATS,
+            Request::MULTI => OP_MULTI,
+            Request::EXEC => OP_EXEC,
+            Request::AUTH { .. } => OP_AUTH,
+            Request::AUTH_USER { .. } => OP_AUTH_USER,
+            Request::SELECT { .. } => OP_SELECT,
+            Request::SNAPSHOT => OP_SNAPSHOT,
+            Request::RELEASE => OP_RELEASE,
+            Request::CLUSTER => OP_CLUSTER,
+            Request::DBSIZE => OP_DBSIZE,
+        }
+    }
+
+    // 1 bit op
+    // 4 bit request id, if FEATURE_REQUEST_ID
+    // 之后按各 op 的格式，长度字段为 ls bit，NONE 为全 1
+    pub fn encode(&self, req: Option<u32>, format: Format, buf: &mut Vec<u8>) {
+        let ls = format.ls;
+        buf.push(self.op());
+        if let Some(req) = req {
+            buf.extend_from_slice(&req.to_be_bytes());
+        }
+        match self {
+            // ls bit key len
+            // n bit key
+            Request::GET { key } | Request::EXISTS { key } | Request::META { key } | Request::LPM { key } | Request::GETV { key } => {
+                push_value(buf, Some(key), ls);
+            }
+            // ls bit key len
+            // n bit key
+            // ls bit value len; if NONE value None
+            // n bit value
+            Request::SET { key, value } => {
+                push_value(buf, Some(key), ls);
+                push_value(buf, value.as_deref(), ls);
+            }
+            // ls bit start len
+            // n bit start
+            // ls bit end len; end empty means no upper bound
+            // n bit end
+            Request::SCAN { start, end } | Request::COMPACT { start, end } | Request::DELETE_RANGE { start, end } => {
+                push_value(buf, Some(start), ls);
+                push_value(buf, Some(end), ls);
+            }
+            // 1 bit with values
+            // ls bit prefix len
+            // n bit prefix
+            Request::PREFIX { prefix, with_values } => {
+                buf.push(*with_values as u8);
+                push_value(buf, Some(prefix), ls);
+            }
+            // ls bit pattern len
+            // n bit pattern
+            Request::KEYS { pattern } => push_value(buf, Some(pattern), ls),
+            // 2 bit key count
+            // n * (ls bit key len, n bit key)
+            Request::MGET { keys } => {
+                buf.extend_from_slice(&(keys.len() as u16).to_be_bytes());
+                for key in keys {
+                    push_value(buf, Some(key), ls);
+                }
+            }
+            // 2 bit pair count
+            // n * (ls bit key len, n bit key, ls bit value len; if NONE value None, n bit value)
+            Request::MSET { entries } => {
+                buf.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+                for (key, value) in entries {
+                    push_value(buf, Some(key), ls);
+                    push_value(buf, value.as_deref(), ls);
+                }
+            }
+            // ls bit key len
+            // n bit key
+            // 4 bit ttl seconds
+            // ls bit value len
+            // n bit value
+            Request::SETEX { key, value, ttl } => {
+                push_value(buf, Some(key), ls);
+                buf.extend_from_slice(&ttl.to_be_bytes());
+                push_value(buf, Some(value), ls);
+            }
+            // ls bit key len
+            // n bit key
+            // ls bit expected len; if NONE key must not exist
+            // n bit expected
+            // ls bit value len; if NONE delete key
+            // n bit value
+            Request::CAS { key, expected, value } => {
+                push_value(buf, Some(key), ls);
+                push_value(buf, expected.as_deref(), ls);
+                push_value(buf, value.as_deref(), ls);
+            }
+            // ls bit key len
+            // n bit key
+            // 8 bit version, 0 表示 key 不存在
+            // ls bit value len; if NONE delete key
+            // n bit value
+            Request::SETV { key, version, value } => {
+                push_value(buf, Some(key), ls);
+                buf.extend_from_slice(&version.to_be_bytes());
+                push_value(buf, value.as_deref(), ls);
+            }
+            // ls bit name len
+            // n bit name
+            // ls bit key len
+            // n bit key
+            // ls bit operand len
+            // n bit operand
+            Request::MERGE { name, key, operand } => {
+                push_value(buf, Some(name), ls);
+                push_value(buf, Some(key), ls);
+                push_value(buf, Some(operand), ls);
+            }
+            // ls bit key len
+            // n bit key
+            // 8 bit delta, i64
+            Request::INCR { key, delta } => {
+                push_value(buf, Some(key), ls);
+                buf.extend_from_slice(&delta.to_be_bytes());
+            }
+            // ls bit key len
+            // n bit key
+            // ls bit suffix len
+            // n bit suffix
+            Request::APPEND { key, suffix: value } | Request::GETSET { key, value } => {
+                push_value(buf, Some(key), ls);
+                push_value(buf, Some(value), ls);
+            }
+            // 4 bit pair count
+            // n * (ls bit key len, n bit key, ls bit value len, n bit value)
+            Request::INGEST { entries } => {
+                buf.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (key, value) in entries {
+                    push_value(buf, Some(key), ls);
+                    push_value(buf, Some(value), ls);
+                }
+            }
+            // 8 bit payload
+            Request::PING { payload } | Request::PONG { payload } => buf.extend_from_slice(&payload.to_be_bytes()),
+            // ls bit password len
+            // n bit password
+            Request::AUTH { password } => push_value(buf, Some(password), ls),
+            // ls bit user len
+            // n bit user
+            // ls bit password len
+            // n bit password
+            Request::AUTH_USER { user, password } => {
+                push_value(buf, Some(user), ls);
+                push_value(buf, Some(password), ls);
+            }
+            // ls bit name len
+            // n bit name, 空名字为默认命名空间
+            Request::SELECT { name } => push_value(buf, Some(name), ls),
+            // 只有 op
+            Request::FLUSHALL | Request::SCRUB | Request::STATS | Request::MULTI | Request::EXEC |
+            Request::SNAPSHOT | Request::RELEASE | Request::CLUSTER | Request::DBSIZE => {}
+        }
+    }
+
+    // 解析 buf 开头的一个请求，返回请求编号、请求和用掉的字节数；数据不足时为 None，op 未知或长度非法时返回错误
+    pub fn decode(buf: &[u8], format: Format) -> Result<Option<(Option<u32>, Request, usize)>, Error> {
+        RequestDecoder::new().decode(buf, format)
+    }
+}
+
+// 请求体中的一项
+#[derive(Debug, Clone, Copy)]
+enum Item {
+    // ls bit len, n bit bytes；NONE 在必填的字段视为空，在可选的字段为 None
+    Field,
+    // 定长的整数
+    Fixed(usize),
+    // 2 bit 或 4 bit 数量，之后的各项重复这么多次
+    Count(usize, &'static [Item]),
+}
+
+use Item::{Count, Field, Fixed};
+
+// 一种 op 的请求体的格式，和由读出的各项组成请求的方法
+struct Layout {
+    op: u8,
+    items: &'static [Item],
+    build: fn(&mut Values) -> Request,
+}
+
+// build 是函数指针，只打印格式
+impl std::fmt::Debug for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Layout").field("op", &self.op).field("items", &self.items).finish()
+    }
+}
+
+// 各 op 请求体的格式，与 Request::encode 一致；解码只按这张表进行
+const LAYOUTS: &[Layout] = &[
+    Layout { op: OP_GET, items: &[Field], build: |v| Request::GET { key: v.field() } },
+    Layout { op: OP_EXISTS, items: &[Field], build: |v| Request::EXISTS { key: v.field() } },
+    Layout { op: OP_META, items: &[Field], build: |v| Request::META { key: v.field() } },
+    Layout { op: OP_LPM, items: &[Field], build: |v| Request::LPM { key: v.field() } },
+    Layout { op: OP_GETV, items: &[Field], build: |v| Request::GETV { key: v.field() } },
+    Layout { op: OP_SET, items: &[Field, Field], build: |v| Request::SET { key: v.field(), value: v.optional() } },
+    Layout { op: OP_SCAN, items: &[Field, Field], build: |v| Request::SCAN { start: v.field(), end: v.field() } },
+    Layout { op: OP_COMPACT, items: &[Field, Field], build: |v| Request::COMPACT { start: v.field(), end: v.field() } },
+    Layout { op: OP_DELETE_RANGE, items: &[Field, Field], build: |v| Request::DELETE_RANGE { start: v.field(), end: v.field() } },
+    Layout { op: OP_PREFIX, items: &[Fixed(1), Field], build: |v| Request::PREFIX { with_values: v.int() != 0, prefix: v.field() } },
+    Layout { op: OP_KEYS, items: &[Field], build: |v| Request::KEYS { pattern: v.field() } },
+    Layout { op: OP_MGET, items: &[Count(2, &[Field])], build: |v| Request::MGET { keys: v.rest(Values::field) } },
+    Layout { op: OP_MSET, items: &[Count(2, &[Field, Field])], build: |v| Request::MSET { entries: v.rest(|v| (v.field(), v.optional())) } },
+    Layout { op: OP_SETEX, items: &[Field, Fixed(4), Field], build: |v| Request::SETEX { key: v.field(), ttl: v.int() as u32, value: v.field() } },
+    Layout { op: OP_CAS, items: &[Field, Field, Field], build: |v| Request::CAS { key: v.field(), expected: v.optional(), value: v.optional() } },
+    Layout { op: OP_SETV, items: &[Field, Fixed(8), Field], build: |v| Request::SETV { key: v.field(), version: v.int(), value: v.optional() } },
+    Layout { op: OP_MERGE, items: &[Field, Field, Field], build: |v| Request::MERGE { name: v.field(), key: v.field(), operand: v.field() } },
+    Layout { op: OP_INCR, items: &[Field, Fixed(8)], build: |v| Request::INCR { key: v.field(), delta: v.int() as i64 } },
+    Layout { op: OP_APPEND, items: &[Field, Field], build: |v| Request::APPEND { key: v.field(), suffix: v.field() } },
+    Layout { op: OP_GETSET, items: &[Field, Field], build: |v| Request::GETSET { key: v.field(), value: v.field() } },
+    Layout { op: OP_INGEST, items: &[Count(4, &[Field, Field])], build: |v| Request::INGEST { entries: v.rest(|v| (v.field(), v.field())) } },
+    Layout { op: OP_PING, items: &[Fixed(8)], build: |v| Request::PING { payload: v.int() } },
+    Layout { op: OP_PONG, items: &[Fixed(8)], build: |v| Request::PONG { payload: v.int() } },
+    Layout { op: OP_AUTH, items: &[Field], build: |v| Request::AUTH { password: v.field() } },
+    Layout { op: OP_AUTH_USER, items: &[Field, Field], build: |v| Request::AUTH_USER { user: v.field(), password: v.field() } },
+    Layout { op: OP_SELECT, items: &[Field], build: |v| Request::SELECT { name: v.field() } },
+    Layout { op: OP_FLUSHALL, items: &[], build: |_| Request::FLUSHALL },
+    Layout { op: OP_SCRUB, items: &[], build: |_| Request::SCRUB },
+    Layout { op: OP_STATS, items: &[], build: |_| Request::STATS },
+    Layout { op: OP_MULTI, items: &[], build: |_| Request::MULTI },
+    Layout { op: OP_EXEC, items: &[], build: |_| Request::EXEC },
+    Layout { op: OP_SNAPSHOT, items: &[], build: |_| Request::SNAPSHOT },
+    Layout { op: OP_RELEASE, items: &[], build: |_| Request::RELEASE },
+    Layout { op: OP_CLUSTER, items: &[], build: |_| Request::CLUSTER },
+    Layout { op: OP_DBSIZE, items: &[], build: |_| Request::DBSIZE },
+];
+
+// 未知的 op 为 None
+fn layout(op: u8) -> Option<&'static Layout> {
+    LAYOUTS.iter().find(|layout| layout.op == op)
+}
+
+// 读出的一项：字段为 None 表示 NONE，整数按大端读出
+#[derive(Debug)]
+enum Value {
+    Bytes(Option<Vec<u8>>),
+    Int(u64),
+}
+
+// 按格式读出的各项，按顺序取出组成请求；种类由格式保证，不会取错
+struct Values(std::vec::IntoIter<Value>);
+
+impl Values {
+    fn optional(&mut self) -> Option<Vec<u8>> {
+        match self.0.next() {
+            Some(Value::Bytes(bytes)) => bytes,
+            _ => None,
+        }
+    }
+
+    fn field(&mut self) -> Vec<u8> {
+        self.optional().unwrap_or_default()
+    }
+
+    fn int(&mut self) -> u64 {
+        match self.0.next() {
+            Some(Value::Int(n)) => n,
+            _ => 0,
+        }
+    }
+
+    // 重复部分，取到没有剩下的项为止
+    fn rest<T>(&mut self, mut f: impl FnMut(&mut Self) -> T) -> Vec<T> {
+        let mut res = Vec::new();
+        while self.0.len() > 0 {
+            res.push(f(self));
+        }
+        res
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    // 等待下一个请求的 op
+    Op,
+    // 已读到 op，等待请求编号
+    RequestId { layout: &'static Layout },
+    // 按格式逐项读取，index 为已经读完的字节数
+    Body { layout: &'static Layout, req: Option<u32>, step: usize, group: Option<Group>, index: usize },
+    // 数据非法，连接应当断开
+    Failed,
+}
+
+// 正在读取的重复部分
+#[derive(Debug, Clone, Copy)]
+struct Group {
+    items: &'static [Item],
+    step: usize,
+    remaining: u32,
+}
+
+// 解码请求时的长度上限，读到长度字段即检查，超过时返回错误，不等数据到齐
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    // 一个字段的最大字节数
+    pub max_field: usize,
+    // 一个请求的最大字节数，包括 MSET、INGEST 的所有项；包一层时也是外层帧解压前后的最大字节数
+    pub max_request: usize,
+}
+
+impl Limits {
+    pub const UNLIMITED: Self = Self { max_field: usize::MAX, max_request: usize::MAX };
+}
+
+// 请求的增量解析
+// 数据分多次到达时记住解析到哪一项，已经读完的项不再检查；op 未知、长度非法时立即返回错误，不等数据完整
+// 每项读完即取出，请求完整时直接组成请求，每个请求只读一遍
+#[derive(Debug)]
+pub struct RequestDecoder {
+    state: State,
+    values: Vec<Value>,
+    limits: Limits,
+}
+
+impl Default for RequestDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestDecoder {
+    pub fn new() -> Self {
+        Self::with_limits(Limits::UNLIMITED)
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Self { state: State::Op, values: Vec::new(), limits }
+    }
+
+    // buf 从请求的 op 开始，每次调用时只能在末尾追加数据；返回值同 Request::decode
+    // 请求完整后从下一个请求重新开始，调用方应当丢弃用掉的字节；出错之后不再解析
+    pub fn decode(&mut self, buf: &[u8], format: Format) -> Result<Option<(Option<u32>, Request, usize)>, Error> {
+        let decoded = self.step(buf, format);
+        if decoded.is_err() {
+            self.state = State::Failed;
+            self.values.clear();
+        }
+        decoded
+    }
+
+    fn step(&mut self, buf: &[u8], format: Format) -> Result<Option<(Option<u32>, Request, usize)>, Error> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        loop {
+            match &mut self.state {
+                State::Op => {
+                    let Some(&op) = buf.first() else {
+                        return Ok(None);
+                    };
+                    let layout = layout(op).ok_or_else(|| invalid(format!("Unknown op {}", op)))?;
+                    self.state = if format.request_id {
+                        State::RequestId { layout }
+                    } else {
+                        State::Body { layout, req: None, step: 0, group: None, index: 1 }
+                    };
+                }
+                State::RequestId { layout } => {
+                    let Some(req) = buf.get(1..1 + 4) else {
+                        return Ok(None);
+                    };
+                    let req = Some(u32::from_be_bytes(req.try_into().expect("4 bytes")));
+                    self.state = State::Body { layout, req, step: 0, group: None, index: 1 + 4 };
+                }
+                State::Body { layout, req, step, group, index } => {
+                    // 下一项，重复部分读完一遍后从头开始，次数用完后回到外层
+                    let item = match group {
+                        Some(g) if g.step < g.items.len() => g.items[g.step],
+                        Some(g) if g.remaining > 1 => {
+                            *g = Group { step: 0, remaining: g.remaining - 1, ..*g };
+                            continue;
+                        }
+                        Some(_) => {
+                            *group = None;
+                            continue;
+                        }
+                        None if *step < layout.items.len() => layout.items[*step],
+                        None => {
+                            let mut values = Values(std::mem::take(&mut self.values).into_iter());
+                            let decoded = (*req, (layout.build)(&mut values), *index);
+                            self.state = State::Op;
+                            return Ok(Some(decoded));
+                        }
+                    };
+                    let rest = &buf[*index..];
+                    let limits = self.limits;
+                    let over = |end: usize| index.saturating_add(end) > limits.max_request;
+                    let len = match item {
+                        Field => {
+                            let ls = format.ls;
+                            if rest.len() < ls {
+                                return Ok(None);
+                            }
+                            // 2 bit 的长度只用低 15 bit，最高位只能出现在 NONE 中
+                            let len = read_len(rest, 0, ls);
+                            if let Some(len) = len.filter(|len| ls != LONG_LEN_SIZE && *len > LEN_MASK as usize) {
+                                return Err(invalid(format!("Invalid length {:#x}", len)));
+                            }
+                            if let Some(len) = len.filter(|len| *len > limits.max_field) {
+                                return Err(invalid(format!("Field length {} over limit {}", len, limits.max_field)));
+                            }
+                            let end = ls + len.unwrap_or(0);
+                            if over(end) {
+                                return Err(invalid(format!("Request over limit {}", limits.max_request)));
+                            }
+                            let Some(bytes) = rest.get(ls..end) else {
+                                return Ok(None);
+                            };
+                            self.values.push(Value::Bytes(len.map(|_| bytes.to_vec())));
+                            end
+                        }
+                        Fixed(size) | Count(size, _) => {
+                            if over(size) {
+                                return Err(invalid(format!("Request over limit {}", limits.max_request)));
+                            }
+                            let Some(bytes) = rest.get(..size) else {
+                                return Ok(None);
+                            };
+                            let n = bytes.iter().fold(0, |n, &b| n << 8 | b as u64);
+                            match item {
+                                // 数量来自客户端，不按它预先分配
+                                Count(_, repeated) if n > 0 && !repeated.is_empty() => {
+                                    *group = Some(Group { items: repeated, step: 0, remaining: n as u32 });
+                                }
+                                Count(..) => {}
+                                _ => self.values.push(Value::Int(n)),
+                            }
+                            size
+                        }
+                    };
+                    match group {
+                        Some(g) if !matches!(item, Count(..)) => g.step += 1,
+                        _ => *step += 1,
+                    }
+                    *index += len;
+                }
+                State::Failed => return Err(invalid(String::from("Decoder failed before"))),
+            }
+        }
+    }
+}
+
+// key 的元数据，不包含 value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta {
+    pub len: u64,
+    pub version: u64,
+    // 剩余存活时间，None 表示不过期
+    pub ttl: Option<Duration>,
+}
+
+// 集群模式的节点和哈希环，key 的 crc32 顺时针遇到的第一个位置属于哪个节点，key 就由哪个节点负责
+// 服务端不是集群模式时 nodes 为空
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClusterInfo {
+    // 各节点的客户端地址
+    pub nodes: Vec<String>,
+    // 连接的节点在 nodes 中的位置
+    pub local: usize,
+    // (位置, 节点)，按位置排序
+    pub points: Vec<(u32, usize)>,
+}
+
+impl ClusterInfo {
+    // 负责 key 的节点地址，不是集群模式时为 None
+    pub fn owner(&self, key: &[u8]) -> Option<&str> {
+        let hash = crc32fast::hash(key);
+        let index = self.points.partition_point(|(point, _)| *point < hash);
+        let (_, node) = self.points.get(index).or(self.points.first())?;
+        self.nodes.get(*node).map(String::as_str)
+    }
+}
+
+// 服务端的一个响应
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Response {
+    GET(Option<Vec<u8>>),
+    SET,
+    EXISTS(bool),
+    // SCAN、PREFIX、KEYS 的一条结果
+    ENTRY(Vec<u8>, Vec<u8>),
+    // 结果结束；编码时先把 entries 逐条写成 ENTRY，解码得到的 entries 总是为空，由接收方攒起之前的 ENTRY
+    SCAN(Vec<(Vec<u8>, Vec<u8>)>),
+    MGET(Vec<Option<Vec<u8>>>),
+    MSET,
+    CAS(bool),
+    // value 不是整数或溢出时为 None
+    INCR(Option<i64>),
+    // 追加后超过长度上限时为 None
+    APPEND(Option<usize>),
+    DBSIZE(u64),
+    // 写入前的旧值
+    GETSET(Option<Vec<u8>>),
+    FLUSHALL,
+    PONG(u64),
+    // 服务端主动发送，payload 为发送时的 unix 毫秒
+    PING(u64),
+    STATS(Vec<u64>),
+    MULTI,
+    EXEC,
+    AUTH(bool),
+    SELECT(bool),
+    // value, version
+    GETV(Option<Vec<u8>>, u64),
+    // ok, version
+    SETV(bool, u64),
+    // 合并失败时为 None
+    MERGE(Option<Vec<u8>>),
+    META(Option<Meta>),
+    // 快照中已分配的最大版本号
+    SNAPSHOT(u64),
+    RELEASE(bool),
+    // 合并的 SST 文件数
+    COMPACT(u32),
+    // 校验过的文件数和其中损坏的文件数
+    SCRUB(u32, u32),
+    // 服务端拒绝执行请求，ERR_* 错误码
+    ERROR(u8),
+    // 导入的 key 中之前不存在的数量
+    INGEST(u32),
+    // 删除的 key 的数量
+    #[allow(non_camel_case_types)]
+    DELETE_RANGE(u64),
+    // 匹配的前缀和它的 value
+    LPM(Option<(Vec<u8>, Vec<u8>)>),
+    // key 由这个地址上的节点负责
+    MOVED(String),
+    CLUSTER(ClusterInfo),
+}
+
+impl Response {
+    pub fn res(&self) -> u8 {
+        match self {
+            Response::GET(_) => RES_GET,
+            Response::SET => RES_SET,
+            Response::EXISTS(_) => RES_EXISTS,
+            Response::ENTRY(..) => RES_SCAN,
+            Response::SCAN(_) => RES_SCAN_END,
+            Response::MGET(_) => RES_MGET,
+            Response::MSET => RES_MSET,
+            Response::CAS(_) => RES_CAS,
+            Response::INCR(_) => RES_INCR,
+            Response::APPEND(_) => RES_APPEND,
+            Response::DBSIZE(_) => RES_DBSIZE,
+            Response::GETSET(_) => RES_GETSET,
+            Response::FLUSHALL => RES_FLUSHALL,
+            Response::PONG(_) => RES_PONG,
+            Response::PING(_) => RES_PING,
+            Response::STATS(_) => RES_STATS,
+            Response::MULTI => RES_MULTI,
+            Response::EXEC => RES_EXEC,
+            Response::AUTH(_) => RES_AUTH,
+            Response::SELECT(_) => RES_SELECT,
+            Response::GETV(..) => RES_GETV,
+            Response::SETV(..) => RES_SETV,
+            Response::MERGE(_) => RES_MERGE,
+            Response::META(_) => RES_META,
+            Response::SNAPSHOT(_) => RES_SNAPSHOT,
+            Response::RELEASE(_) => RES_RELEASE,
+            Response::COMPACT(_) => RES_COMPACT,
+            Response::SCRUB(..) => RES_SCRUB,
+            Response::ERROR(_) => RES_ERROR,
+            Response::INGEST(_) => RES_INGEST,
+            Response::DELETE_RANGE(_) => RES_DELETE_RANGE,
+            Response::LPM(_) => RES_LPM,
+            Response::MOVED(_) => RES_MOVED,
+            Response::CLUSTER(_) => RES_CLUSTER,
+        }
+    }
+
+    // 结果中最长的一个长度字段，未协商 FEATURE_LONG_LEN 时不能超过 LEN_MASK
+    pub fn max_len(&self) -> usize {
+        let len = |value: &Option<Vec<u8>>| value.as_ref().map_or(0, Vec::len);
+        match self {
+            Response::GET(value) | Response::GETSET(value) | Response::MERGE(value) | Response::GETV(value, _) => len(value),
+            Response::ENTRY(key, value) | Response::LPM(Some((key, value))) => key.len().max(value.len()),
+            Response::SCAN(entries) => entries.iter().map(|(key, value)| key.len().max(value.len())).max().unwrap_or(0),
+            Response::MGET(values) => values.iter().map(len).max().unwrap_or(0),
+            Response::APPEND(value) => value.unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    // 1 bit op res
+    // 4 bit request id, if FEATURE_REQUEST_ID; RES_PING 没有
+    // 之后按各响应的格式
+    pub fn encode(&self, req: Option<u32>, format: Format, buf: &mut Vec<u8>) {
+        let ls = format.ls;
+        if let Response::SCAN(entries) = self {
+            for (key, value) in entries {
+                Response::ENTRY(key.clone(), value.clone()).encode(req, format, buf);
+            }
+        }
+        buf.push(self.res());
+        if let Some(req) = req.filter(|_| !matches!(self, Response::PING(_))) {
+            buf.extend_from_slice(&req.to_be_bytes());
+        }
+        match self {
+            // ls bit value len; if NONE value None
+            // n bit value
+            Response::GET(value) | Response::GETSET(value) | Response::MERGE(value) => push_value(buf, value.as_deref(), ls),
+            // ls bit key len
+            // n bit key
+            // ls bit value len
+            // n bit value
+            Response::ENTRY(key, value) => {
+                push_value(buf, Some(key), ls);
+                push_value(buf, Some(value), ls);
+            }
+            // 1 bit flag
+            Response::EXISTS(flag) | Response::CAS(flag) | Response::AUTH(flag) | Response::SELECT(flag) | Response::RELEASE(flag) => buf.push(*flag as u8),
+            // 2 bit value count
+            // n * (ls bit value len; if NONE value None, n bit value)
+            Response::MGET(values) => {
+                buf.extend_from_slice(&(values.len() as u16).to_be_bytes());
+                for value in values {
+                    push_value(buf, value.as_deref(), ls);
+                }
+            }
+            // 1 bit ok; 0 when value is not an integer
+            // 8 bit value, i64
+            Response::INCR(value) => {
+                buf.push(value.is_some() as u8);
+                buf.extend_from_slice(&value.unwrap_or(0).to_be_bytes());
+            }
+            // ls bit new len; if NONE value too long
+            Response::APPEND(len) => match len {
+                Some(len) => push_len(buf, *len, ls),
+                None => push_none(buf, ls),
+            },
+            // 8 bit number
+            Response::DBSIZE(n) | Response::PONG(n) | Response::PING(n) | Response::SNAPSHOT(n) | Response::DELETE_RANGE(n) => buf.extend_from_slice(&n.to_be_bytes()),
+            // 2 bit stat count
+            // n * 8 bit stat
+            Response::STATS(stats) => {
+                buf.extend_from_slice(&(stats.len() as u16).to_be_bytes());
+                for stat in stats {
+                    buf.extend_from_slice(&stat.to_be_bytes());
+                }
+            }
+            // 8 bit version
+            // ls bit value len; if NONE value None
+            // n bit value
+            Response::GETV(value, version) => {
+                buf.extend_from_slice(&version.to_be_bytes());
+                push_value(buf, value.as_deref(), ls);
+            }
+            // 1 bit ok
+            // 8 bit version
+            Response::SETV(ok, version) => {
+                buf.push(*ok as u8);
+                buf.extend_from_slice(&version.to_be_bytes());
+            }
+            // 1 bit exists
+            // 8 bit value len
+            // 8 bit version
+            // 8 bit ttl millis; 全 1 表示不过期
+            // key 不存在时后面的字段都为 0
+            Response::META(meta) => {
+                buf.push(meta.is_some() as u8);
+                let (len, version, ttl) = match meta {
+                    Some(meta) => (meta.len, meta.version, meta.ttl.map_or(u64::MAX, |ttl| ttl.as_millis() as u64)),
+                    None => (0, 0, 0),
+                };
+                buf.extend_from_slice(&len.to_be_bytes());
+                buf.extend_from_slice(&version.to_be_bytes());
+                buf.extend_from_slice(&ttl.to_be_bytes());
+            }
+            // 4 bit number
+            Response::COMPACT(n) | Response::INGEST(n) => buf.extend_from_slice(&n.to_be_bytes()),
+            // 4 bit checked file count
+            // 4 bit corrupted file count
+            Response::SCRUB(files, corrupt) => {
+                buf.extend_from_slice(&files.to_be_bytes());
+                buf.extend_from_slice(&corrupt.to_be_bytes());
+            }
+            // 1 bit error code
+            Response::ERROR(code) => buf.push(*code),
+            // ls bit key len; if NONE no match and nothing follows
+            // n bit key
+            // ls bit value len
+            // n bit value
+            Response::LPM(entry) => match entry {
+                Some((key, value)) => {
+                    push_value(buf, Some(key), ls);
+                    push_value(buf, Some(value), ls);
+                }
+                None => push_none(buf, ls),
+            },
+            // ls bit addr len
+            // n bit addr
+            Response::MOVED(addr) => push_value(buf, Some(addr.as_bytes()), ls),
+            // 2 bit node count, 0 表示不是集群模式，之后没有数据
+            // n * (ls bit addr len, n bit addr)
+            // 2 bit local node
+            // 4 bit point count
+            // n * (4 bit point, 2 bit node)
+            Response::CLUSTER(info) => {
+                buf.extend_from_slice(&(info.nodes.len() as u16).to_be_bytes());
+                if info.nodes.is_empty() {
+                    return;
+                }
+                for node in info.nodes.iter() {
+                    push_value(buf, Some(node.as_bytes()), ls);
+                }
+                buf.extend_from_slice(&(info.local as u16).to_be_bytes());
+                buf.extend_from_slice(&(info.points.len() as u32).to_be_bytes());
+                for (point, node) in info.points.iter() {
+                    buf.extend_from_slice(&point.to_be_bytes());
+                    buf.extend_from_slice(&(*node as u16).to_be_bytes());
+                }
+            }
+            // 只有 op res
+            Response::SET | Response::SCAN(_) | Response::MSET | Response::FLUSHALL | Response::MULTI | Response::EXEC => {}
+        }
+    }
+
+    // 解析 buf 开头的一个响应，返回请求编号、响应和用掉的字节数；数据不足时为 None，响应码未知时返回错误
+    pub fn decode(buf: &[u8], format: Format) -> Result<Option<(Option<u32>, Response, usize)>, Error> {
+        let Some(&op) = buf.first() else {
+            return Ok(None);
+        };
+        let mut c = Cursor { buf, index: 1, ls: format.ls };
+        let parsed = match format.request_id && op != RES_PING {
+            true => c.u32().map(Some),
+            false => Ok(None),
+        }.and_then(|req| Ok((req, Self::read(op, &mut c)?)));
+        match parsed {
+            Ok((req, res)) => Ok(Some((req, res, c.index))),
+            Err(e) => Option::<Error>::from(e).map_or(Ok(None), Err),
+        }
+    }
+
+    fn read(op: u8, c: &mut Cursor) -> Result<Response, Malformed> {
+        let res = match op {
+            RES_GET => Response::GET(c.optional()?),
+            RES_GETSET => Response::GETSET(c.optional()?),
+            RES_MERGE => Response::MERGE(c.optional()?),
+            RES_SET => Response::SET,
+            RES_EXISTS => Response::EXISTS(c.u8()? != 0),
+            RES_SCAN => Response::ENTRY(c.field()?, c.field()?),
+            RES_SCAN_END => Response::SCAN(Vec::new()),
+            RES_MGET => {
+                let count = c.u16()? as usize;
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    values.push(c.optional()?);
+                }
+                Response::MGET(values)
+            }
+            RES_MSET => Response::MSET,
+            RES_CAS => Response::CAS(c.u8()? != 0),
+            RES_INCR => {
+                let ok = c.u8()? != 0;
+                let value = c.u64()? as i64;
+                Response::INCR(ok.then_some(value))
+            }
+            RES_APPEND => Response::APPEND(c.len()?),
+            RES_DBSIZE => Response::DBSIZE(c.u64()?),
+            RES_FLUSHALL => Response::FLUSHALL,
+            RES_PONG => Response::PONG(c.u64()?),
+            RES_PING => Response::PING(c.u64()?),
+            RES_STATS => {
+                let count = c.u16()? as usize;
+                let mut stats = Vec::with_capacity(count);
+                for _ in 0..count {
+                    stats.push(c.u64()?);
+                }
+                Response::STATS(stats)
+            }
+            RES_MULTI => Response::MULTI,
+            RES_EXEC => Response::EXEC,
+            RES_AUTH => Response::AUTH(c.u8()? != 0),
+            RES_SELECT => Response::SELECT(c.u8()? != 0),
+            RES_GETV => {
+                let version = c.u64()?;
+                Response::GETV(c.optional()?, version)
+            }
+            RES_SETV => Response::SETV(c.u8()? != 0, c.u64()?),
+            RES_META => {
+                let exists = c.u8()? != 0;
+                let (len, version, ttl) = (c.u64()?, c.u64()?, c.u64()?);
+                Response::META(exists.then(|| Meta {
+                    len,
+                    version,
+                    ttl: Some(ttl).filter(|ttl| *ttl != u64::MAX).map(Duration::from_millis),
+                }))
+            }
+            RES_SNAPSHOT => Response::SNAPSHOT(c.u64()?),
+            RES_RELEASE => Response::RELEASE(c.u8()? != 0),
+            RES_COMPACT => Response::COMPACT(c.u32()?),
+            RES_SCRUB => Response::SCRUB(c.u32()?, c.u32()?),
+            RES_ERROR => Response::ERROR(c.u8()?),
+            RES_INGEST => Response::INGEST(c.u32()?),
+            RES_DELETE_RANGE => Response::DELETE_RANGE(c.u64()?),
+            RES_LPM => match c.len()? {
+                Some(len) => {
+                    let key = c.bytes(len)?.to_vec();
+                    Response::LPM(Some((key, c.field()?)))
+                }
+                None => Response::LPM(None),
+            },
+            RES_MOVED => Response::MOVED(c.string()?),
+            RES_CLUSTER => {
+                let count = c.u16()? as usize;
+                if count == 0 {
+                    return Ok(Response::CLUSTER(ClusterInfo::default()));
+                }
+                let mut nodes = Vec::with_capacity(count);
+                for _ in 0..count {
+                    nodes.push(c.string()?);
+                }
+                let local = c.u16()? as usize;
+                let count = c.u32()? as usize;
+                let mut points = Vec::new();
+                for _ in 0..count {
+                    points.push((c.u32()?, c.u16()? as usize));
+                }
+                Response::CLUSTER(ClusterInfo { nodes, local, points })
+            }
+            n => return Err(Malformed::Invalid(format!("Unknown OP res, op = {}", n))),
+        };
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHORT: Format = Format { request_id: false, ls: SHORT_LEN_SIZE, sealed: false };
+    const LONG: Format = Format { request_id: true, ls: LONG_LEN_SIZE, sealed: false };
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::GET { key: b"k".to_vec() },
+            Request::SET { key: b"k".to_vec(), value: None },
+            Request::SET { key: b"k".to_vec(), value: Some(b"v".to_vec()) },
+            Request::PREFIX { prefix: b"p".to_vec(), with_values: true },
+            Request::MSET { entries: vec![(b"a".to_vec(), Some(b"1".to_vec())), (b"b".to_vec(), None)] },
+            Request::SETEX { key: b"k".to_vec(), value: b"v".to_vec(), ttl: 10 },
+            Request::CAS { key: b"k".to_vec(), expected: None, value: Some(Vec::new()) },
+            Request::SETV { key: b"k".to_vec(), version: 7, value: None },
+            Request::MERGE { name: b"add".to_vec(), key: b"k".to_vec(), operand: b"1".to_vec() },
+            Request::INCR { key: b"k".to_vec(), delta: -3 },
+            Request::INGEST { entries: vec![(b"a".to_vec(), b"1".to_vec())] },
+            Request::AUTH_USER { user: b"u".to_vec(), password: b"p".to_vec() },
+            Request::PONG { payload: 42 },
+            Request::DBSIZE,
+        ]
+    }
+
+    #[test]
+    fn request_round_trip() {
+        for format in [SHORT, LONG] {
+            let mut buf = Vec::new();
+            for (i, request) in requests().iter().enumerate() {
+                request.encode(format.request_id.then_some(i as u32), format, &mut buf);
+            }
+            let mut index = 0;
+            for (i, request) in requests().into_iter().enumerate() {
+                let (req, decoded, used) = Request::decode(&buf[index..], format).unwrap().unwrap();
+                assert_eq!((req, decoded), (format.request_id.then_some(i as u32), request));
+                index += used;
+            }
+            assert_eq!(index, buf.len());
+        }
+    }
+
+    #[test]
+    fn incomplete_and_unknown() {
+        let mut buf = Vec::new();
+        Request::SET { key: b"key".to_vec(), value: Some(b"value".to_vec()) }.encode(Some(1), LONG, &mut buf);
+        for end in 0..buf.len() {
+            assert!(Request::decode(&buf[..end], LONG).unwrap().is_none());
+        }
+        assert_eq!(Request::decode(&[0x01], SHORT).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(Response::decode(&[0x01], SHORT).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    // 编码用到的每个 op 都能按表解码，表中没有重复的 op
+    #[test]
+    fn every_op_has_layout() {
+        for request in requests() {
+            assert_eq!(layout(request.op()).map(|layout| layout.op), Some(request.op()), "{:?}", request);
+        }
+        let mut ops: Vec<u8> = LAYOUTS.iter().map(|layout| layout.op).collect();
+        ops.sort_unstable();
+        ops.dedup();
+        assert_eq!(ops.len(), LAYOUTS.len());
+    }
+
+    // 数据逐字节到达时从上次读到的位置继续，结果与整段解码相同；长度非法时不等数据到达
+    #[test]
+    fn decoder_resumes() {
+        for format in [SHORT, LONG] {
+            for (i, request) in requests().into_iter().enumerate() {
+                let mut buf = Vec::new();
+                request.encode(format.request_id.then_some(i as u32), format, &mut buf);
+                let mut decoder = RequestDecoder::new();
+                for end in 0..buf.len() {
+                    assert!(decoder.decode(&buf[..end], format).unwrap().is_none(), "{:?}", request);
+                }
+                let decoded = decoder.decode(&buf, format).unwrap();
+                assert_eq!(decoded, Some((format.request_id.then_some(i as u32), request, buf.len())));
+            }
+        }
+        let mut decoder = RequestDecoder::new();
+        assert_eq!(decoder.decode(&[OP_GET, 0x80, 0x00], SHORT).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(decoder.decode(&[OP_DBSIZE], SHORT).is_err());
+    }
+
+    // 长度字段超过上限时只凭头部就返回错误，不等数据到达
+    #[test]
+    fn decoder_limits() {
+        let limits = Limits { max_field: 8, max_request: 32 };
+        let mut key = vec![OP_GET];
+        key.extend(0u32.to_be_bytes());
+        key.extend(0xfffffffeu32.to_be_bytes());
+        let err = RequestDecoder::with_limits(limits).decode(&key, LONG).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("over limit 8"));
+
+        // 每个字段都不超过上限，合起来超过请求的上限
+        let mut buf = Vec::new();
+        Request::MSET { entries: vec![(b"k1".to_vec(), Some(b"12345678".to_vec())); 3] }.encode(Some(0), LONG, &mut buf);
+        let mut decoder = RequestDecoder::with_limits(limits);
+        assert!(decoder.decode(&buf[..35], LONG).is_err());
+
+        // 上限之内的请求正常解码
+        let mut buf = Vec::new();
+        let request = Request::SET { key: b"k".to_vec(), value: Some(b"12345678".to_vec()) };
+        request.encode(Some(1), LONG, &mut buf);
+        assert_eq!(RequestDecoder::with_limits(limits).decode(&buf, LONG).unwrap(), Some((Some(1), request, buf.len())));
+    }
+
+    // 外层帧的长度超过上限时只凭 4 bit 长度就返回错误
+    #[test]
+    fn unseal_limit() {
+        let err = unseal(&0x7fff_fff0u32.to_be_bytes(), FEATURE_CRC, 1024).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let mut buf = Vec::new();
+        seal(&[OP_DBSIZE; 100], FEATURE_CRC, &mut buf);
+        assert!(unseal(&buf, FEATURE_CRC, 64).is_err());
+        assert_eq!(unseal(&buf, FEATURE_CRC, 100).unwrap(), Some((vec![OP_DBSIZE; 100], buf.len())));
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let responses = vec![
+            Response::GET(None),
+            Response::GETSET(Some(b"old".to_vec())),
+            Response::MGET(vec![Some(b"1".to_vec()), None]),
+            Response::INCR(None),
+            Response::APPEND(Some(3)),
+            Response::META(Some(Meta { len: 1, version: 2, ttl: None })),
+            Response::META(None),
+            Response::GETV(Some(b"v".to_vec()), 9),
+            Response::LPM(None),
+            Response::LPM(Some((b"k".to_vec(), b"v".to_vec()))),
+            Response::MOVED(String::from("127.0.0.1:8080")),
+            Response::CLUSTER(ClusterInfo::default()),
+            Response::CLUSTER(ClusterInfo { nodes: vec![String::from("a"), String::from("b")], local: 1, points: vec![(1, 0), (2, 1)] }),
+            Response::PING(5),
+            Response::ERROR(ERR_BUSY),
+        ];
+        let mut buf = Vec::new();
+        for response in responses.iter() {
+            response.encode(Some(3), LONG, &mut buf);
+        }
+        let mut index = 0;
+        for response in responses {
+            let (req, decoded, used) = Response::decode(&buf[index..], LONG).unwrap().unwrap();
+            // RES_PING 不带请求编号
+            assert_eq!(req, (!matches!(response, Response::PING(_))).then_some(3));
+            assert_eq!(decoded, response);
+            index += used;
+        }
+        assert_eq!(index, buf.len());
+    }
+
+    #[test]
+    fn scan_encodes_entries() {
+        let mut buf = Vec::new();
+        Response::SCAN(vec![(b"a".to_vec(), b"1".to_vec())]).encode(None, SHORT, &mut buf);
+        let (_, entry, used) = Response::decode(&buf, SHORT).unwrap().unwrap();
+        assert_eq!(entry, Response::ENTRY(b"a".to_vec(), b"1".to_vec()));
+        let (_, end, _) = Response::decode(&buf[used..], SHORT).unwrap().unwrap();
+        assert_eq!(end, Response::SCAN(Vec::new()));
+    }
+
+    #[test]
+    fn sealed_frames() {
+        let features = FEATURE_CRC | FEATURE_LZ4;
+        let frame = vec![b'v'; 4096];
+        let mut buf = Vec::new();
+        seal(&frame, features, &mut buf);
+        assert!(buf.len() < frame.len());
+        assert!(unseal(&buf[..buf.len() - 1], features, usize::MAX).unwrap().is_none());
+        assert_eq!(unseal(&buf, features, usize::MAX).unwrap(), Some((frame, buf.len())));
+
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert_eq!(unseal(&buf, features, usize::MAX).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
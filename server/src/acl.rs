@@ -0,0 +1,97 @@
+use crate::event::Event;
+use crate::glob::Pattern;
+
+// 用户的权限，后一级包含前一级
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    // 只能读取
+    Read,
+    // 读取和写入
+    ReadWrite,
+    // 另外可以执行 FLUSHALL、COMPACT、SCRUB、INGEST，数据端口上仍需要 enable_admin_ops
+    Admin,
+}
+
+impl Permission {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "read-only" => Some(Permission::Read),
+            "read-write" => Some(Permission::ReadWrite),
+            "admin" => Some(Permission::Admin),
+            _ => None,
+        }
+    }
+}
+
+// 事件访问的 key
+enum Scope<'a> {
+    // 不访问数据
+    Nothing,
+    Keys(Vec<&'a [u8]>),
+    // [start, end)，end 为空表示没有上界
+    Range(&'a [u8], &'a [u8]),
+    // 以 prefix 开头的所有 key
+    Prefix(Vec<u8>),
+    // 可能访问任意 key，有前缀限制时不允许
+    Everything,
+}
+
+// 通过 AUTH 认证的用户可以执行的操作，由事件循环在执行前检查
+// prefixes 不为空时只能访问以其中之一开头的 key，所有命名空间相同
+#[derive(Debug)]
+pub struct Access {
+    pub user: String,
+    pub permission: Permission,
+    pub prefixes: Vec<Vec<u8>>,
+}
+
+impl Access {
+    pub fn allows(&self, event: &Event) -> bool {
+        let (permission, scope) = match event {
+            Event::GET { key, .. } | Event::EXISTS { key, .. } | Event::META { key, .. } |
+            Event::GETV { key, .. } => (Permission::Read, Scope::Keys(vec![key])),
+            Event::MGET { keys, .. } => (Permission::Read, Scope::Keys(keys.iter().map(Vec::as_slice).collect())),
+            Event::SCAN { start, end, .. } => (Permission::Read, Scope::Range(start, end)),
+            Event::PREFIX { prefix, .. } => (Permission::Read, Scope::Prefix(prefix.clone())),
+            Event::KEYS { pattern, .. } => (Permission::Read, Scope::Prefix(Pattern::parse(pattern).literal_prefix())),
+            // 计数和匹配的前缀都可能涉及范围外的 key
+            Event::DBSIZE { .. } | Event::LPM { .. } => (Permission::Read, Scope::Everything),
+            Event::PING { .. } | Event::STATS { .. } | Event::MULTI { .. } | Event::EXEC { .. } |
+            Event::AUTH { .. } | Event::SELECT { .. } | Event::SNAPSHOT { .. } |
+            Event::RELEASE { .. } | Event::CLUSTER { .. } => (Permission::Read, Scope::Nothing),
+            Event::SET { key, .. } | Event::SETEX { key, .. } | Event::CAS { key, .. } |
+            Event::INCR { key, .. } | Event::APPEND { key, .. } | Event::GETSET { key, .. } |
+            Event::SETV { key, .. } | Event::MERGE { key, .. } => (Permission::ReadWrite, Scope::Keys(vec![key])),
+            Event::SETM { entries, .. } => (Permission::ReadWrite, Scope::Keys(entries.iter().map(|(key, _)| key.as_slice()).collect())),
+            Event::DELETE_RANGE { start, end, .. } => (Permission::ReadWrite, Scope::Range(start, end)),
+            Event::INGEST { entries, .. } => (Permission::Admin, Scope::Keys(entries.iter().map(|(key, _)| key.as_slice()).collect())),
+            Event::FLUSHALL { .. } => (Permission::Admin, Scope::Everything),
+            Event::COMPACT { .. } | Event::SCRUB { .. } | Event::FLUSH { .. } |
+            Event::SHUTDOWN { .. } => (Permission::Admin, Scope::Nothing),
+        };
+        self.permission >= permission && (self.prefixes.is_empty() || self.covers(scope))
+    }
+
+    fn covers(&self, scope: Scope) -> bool {
+        match scope {
+            Scope::Nothing => true,
+            Scope::Keys(keys) => keys.iter().all(|key| self.prefixes.iter().any(|prefix| key.starts_with(prefix))),
+            Scope::Range(start, end) => self.prefixes.iter().any(|prefix| {
+                start >= prefix.as_slice() && match successor(prefix) {
+                    Some(limit) => !end.is_empty() && end <= limit.as_slice(),
+                    None => true,
+                }
+            }),
+            Scope::Prefix(key) => self.prefixes.iter().any(|prefix| key.starts_with(prefix)),
+            Scope::Everything => false,
+        }
+    }
+}
+
+// 比所有以 prefix 开头的 key 都大的最小 key，prefix 为空或全是 0xff 时没有
+fn successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let len = prefix.iter().rposition(|b| *b != 0xff)? + 1;
+    let mut limit = prefix[..len].to_vec();
+    limit[len - 1] += 1;
+    Some(limit)
+}
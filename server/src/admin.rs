@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::event::{Event, EventRes};
+use crate::gossip::Membership;
+use crate::shard::{is_last, EventSender, Shards};
+use crate::throttle::WriteThrottle;
+use crate::utils::get_id;
+
+// 管理端口只监听本机
+pub const ADMIN_IP: &str = "127.0.0.1";
+
+// STATS 各项的名字，与 EventRes::STATS 的顺序一致
+const STAT_NAMES: [&str; 11] = ["keys", "memtable_bytes", "wal_bytes", "clients", "ops_per_sec", "block_cache_hits", "block_cache_misses", "memtable_memory", "denied", "write_throttled", "write_throttled_ms"];
+
+// STATS 的各项加上写限流的速率，0 表示不限制；管理端口和管理页面共用
+pub fn named_stats(stats: Vec<u64>, throttle: &WriteThrottle) -> Vec<(&'static str, u64)> {
+    let (ops, bytes) = throttle.rates();
+    STAT_NAMES.into_iter().zip(stats)
+        .chain([("write_ops_per_sec", ops.unwrap_or(0)), ("write_bytes_per_sec", bytes.unwrap_or(0))])
+        .collect()
+}
+
+// 发送一个事件并取回它的所有响应：SCAN 和 PREFIX 逐条返回，以 is_last 的一条结束；事件循环已关闭时为空
+pub async fn call(event_tx: &mut EventSender, client_rx: &mut mpsc::Receiver<EventRes>, event: Event) -> Vec<EventRes> {
+    let mut responses = Vec::new();
+    if event_tx.send(event).await.is_err() {
+        return responses;
+    }
+    while let Some(res) = client_rx.recv().await {
+        event_tx.received(&res);
+        let last = is_last(&res);
+        responses.push(res);
+        if last {
+            break;
+        }
+    }
+    responses
+}
+
+// 管理端口，文本协议：每行一条命令，参数用空格分隔；每条回复若干行，以一个空行结束
+// stats                 统计项，每行 "名字 值"，最后是写限流的速率，0 表示不限制
+// flush                 所有内存表落盘，回复 OK
+// compact [start [end]] 落盘并合并与 [start, end) 重叠的 SST 文件，回复 "files 合并的文件数"
+// clients               每行一个连接 id
+// shutdown              sync 所有 WAL 后退出进程，回复 OK
+// throttle ops bytes    修改写限流的每秒 op 数和字节数，0 表示不限制，回复 OK；SIGHUP 重新读取配置文件时恢复为配置的值
+// members               gossip 发现的集群节点，每行 "客户端地址 gossip 地址 状态"，状态为 alive、suspect 或 dead；没有开启 gossip 时回复 ERR
+// 数据端口上的管理命令仍由 enable_admin_ops 控制，与这里无关
+pub async fn serve(listener: TcpListener, shards: Arc<Shards>, throttle: Arc<WriteThrottle>, membership: Option<Arc<Membership>>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let id = format!("admin-{}", get_id(&addr.ip().to_string(), addr.port()));
+                let span = info_span!("admin", client = %id);
+                tokio::spawn(session(id, socket, shards.clone(), throttle.clone(), membership.clone()).instrument(span));
+            }
+            Err(e) => {
+                error!("Fail to accept admin connection; err = {:?}", e);
+            }
+        }
+    }
+}
+
+async fn session(id: String, socket: TcpStream, shards: Arc<Shards>, throttle: Arc<WriteThrottle>, membership: Option<Arc<Membership>>) {
+    info!("Admin connection from [{}]", id);
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    let mut event_tx = shards.connect(&id, client_tx);
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Read from admin [{}] fail, err = {:?}", id, e);
+                break;
+            }
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(&command) = args.first() else {
+            continue;
+        };
+        info!("Admin [{}] command {}", id, line);
+        let event = match command {
+            "stats" => Some(Event::STATS { id: id.clone(), req: None }),
+            "flush" => Some(Event::FLUSH { id: id.clone(), req: None }),
+            "compact" => Some(Event::COMPACT {
+                id: id.clone(),
+                req: None,
+                start: args.get(1).map_or(Vec::new(), |start| start.as_bytes().to_vec()),
+                end: args.get(2).map_or(Vec::new(), |end| end.as_bytes().to_vec()),
+            }),
+            "shutdown" => Some(Event::SHUTDOWN { id: id.clone(), req: None }),
+            _ => None,
+        };
+        let reply = match event {
+            Some(event) => {
+                match call(&mut event_tx, &mut client_rx, event).await.pop() {
+                    Some(EventRes::STATS { stats, .. }) => named_stats(stats, &throttle).iter()
+                        .map(|(name, stat)| format!("{} {}\n", name, stat))
+                        .collect(),
+                    Some(EventRes::COMPACT { files, .. }) => format!("files {}\n", files),
+                    Some(EventRes::FLUSH { .. }) | Some(EventRes::SHUTDOWN { .. }) => String::from("OK\n"),
+                    Some(EventRes::ERROR { code, .. }) => format!("ERR {}\n", code),
+                    _ => String::from("ERR no response\n"),
+                }
+            }
+            None if command == "throttle" => match (args.get(1).map(|ops| ops.parse()), args.get(2).map(|bytes| bytes.parse())) {
+                (Some(Ok(ops)), Some(Ok(bytes))) if args.len() == 3 => {
+                    warn!("Admin [{}] set write throttle to {} ops/s, {} bytes/s", id, ops, bytes);
+                    throttle.set_rates(Some(ops), Some(bytes));
+                    String::from("OK\n")
+                }
+                _ => String::from("ERR usage: throttle <ops_per_sec> <bytes_per_sec>\n"),
+            },
+            None if command == "clients" => shards.clients().iter().map(|client| format!("{}\n", client)).collect(),
+            None if command == "members" => match membership.as_ref() {
+                Some(membership) => membership.members().iter()
+                    .map(|(addr, gossip, health)| format!("{} {} {}\n", addr, gossip, health.name()))
+                    .collect(),
+                None => String::from("ERR gossip not enabled\n"),
+            },
+            None => format!("ERR unknown command {}\n", command),
+        };
+        if let Err(e) = writer.write_all(format!("{}\n", reply).as_bytes()).await {
+            warn!("Write to admin [{}] fail, err = {:?}", id, e);
+            break;
+        }
+        if command == "shutdown" {
+            warn!("LSM server shutdown by admin [{}]", id);
+            std::process::exit(0);
+        }
+    }
+    shards.disconnect(&id);
+    info!("Admin [{}] disconnect", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::watch;
+    use crate::shard::EventLoop;
+    use crate::testing::{settings, spawn_loop};
+
+    // 读一条回复，去掉结尾的空行
+    async fn reply(lines: &mut tokio::io::Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>) -> Vec<String> {
+        let mut reply = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap().filter(|line| !line.is_empty()) {
+            reply.push(line);
+        }
+        reply
+    }
+
+    #[tokio::test]
+    async fn throttle_command_sets_rates() {
+        let (_settings_tx, settings_rx) = watch::channel(settings());
+        let (shards, mut loops) = Shards::new(1, false, settings_rx);
+        let EventLoop { receiver, client_map, .. } = loops.remove(0);
+        spawn_loop("admin-throttle", receiver, client_map, None, |_| {}).await;
+        let throttle = Arc::new(WriteThrottle::new(None, None));
+        let listener = TcpListener::bind((ADMIN_IP, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(shards), throttle.clone(), None));
+
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"throttle 100 2048\n").await.unwrap();
+        assert_eq!(reply(&mut lines).await, ["OK"]);
+        assert_eq!(throttle.rates(), (Some(100), Some(2048)));
+
+        writer.write_all(b"stats\n").await.unwrap();
+        let stats = reply(&mut lines).await;
+        assert_eq!(stats.len(), STAT_NAMES.len() + 2);
+        assert_eq!(&stats[STAT_NAMES.len()..], ["write_ops_per_sec 100", "write_bytes_per_sec 2048"]);
+
+        // 0 表示不限制
+        writer.write_all(b"throttle 0 0\n").await.unwrap();
+        assert_eq!(reply(&mut lines).await, ["OK"]);
+        assert_eq!(throttle.rates(), (None, None));
+
+        writer.write_all(b"throttle 100\n").await.unwrap();
+        assert!(reply(&mut lines).await[0].starts_with("ERR"));
+
+        // 没有开启 gossip
+        writer.write_all(b"members\n").await.unwrap();
+        assert!(reply(&mut lines).await[0].starts_with("ERR"));
+    }
+}
@@ -0,0 +1,91 @@
+use tracing::info;
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, rename};
+use tokio::io::AsyncWriteExt;
+use crate::utils::now_millis;
+
+pub const AUDIT_OP_SET: &str = "SET";
+pub const AUDIT_OP_DELETE: &str = "DELETE";
+// 值字段为追加部分的摘要
+pub const AUDIT_OP_APPEND: &str = "APPEND";
+// 清空所有数据，key 字段为空
+pub const AUDIT_OP_FLUSHALL: &str = "FLUSHALL";
+// key 字段为范围的起点，值字段为终点的摘要，终点为空表示没有上界
+pub const AUDIT_OP_DELETE_RANGE: &str = "DELETE_RANGE";
+// 批量导入只记一条，key 字段为第一个 key，值字段为 条数:最后一个 key(hex)
+pub const AUDIT_OP_INGEST: &str = "INGEST";
+
+// 空字段占位，如未认证的用户、删除操作的值
+const EMPTY_FIELD: &str = "-";
+
+// 审计日志默认大小 64M
+pub const DEFAULT_AUDIT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+pub struct AuditLog {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl AuditLog {
+    pub async fn open(path: String, max_size: u64) -> Self {
+        let file = Self::open_file(&path).await;
+        let size = file.metadata().await.expect("Read audit file meta fail").len();
+        info!("LSM open audit file {} size {}", &path, size);
+        Self {
+            path,
+            file,
+            size,
+            max_size,
+        }
+    }
+
+    async fn open_file(path: &String) -> File {
+        // 只追加，不截断
+        File::options().append(true).create(true).open(path).await.unwrap_or_else(|e| {
+            panic!("Open audit file err {:?}", e);
+        })
+    }
+
+    async fn rotate(&mut self) {
+        self.file.sync_all().await.expect("Flush audit file fail");
+        let rotated = format!("{}.{}", &self.path, now_millis());
+        info!("Rotate audit file {} to {}", &self.path, &rotated);
+        rename(&self.path, &rotated).await.expect("Rotate audit file fail");
+        self.file = Self::open_file(&self.path).await;
+        self.size = 0;
+    }
+
+    // 一行一条记录
+    // timestamp user client op key(hex) value_sha256(hex) crc32(hex)
+    // user 为按用户认证的用户名，没有时为 EMPTY_FIELD
+    pub async fn append(&mut self, user: Option<&str>, client: &str, op: &str, key: &[u8], value: Option<&[u8]>) {
+        let value_hash = match value {
+            Some(v) => to_hex(&Sha256::digest(v)),
+            None => String::from(EMPTY_FIELD),
+        };
+        self.write(user, client, op, key, &value_hash).await;
+    }
+
+    // 批量操作记一条：key 字段为第一个 key，值字段为 count:最后一个 key(hex)
+    pub async fn append_bulk(&mut self, user: Option<&str>, client: &str, op: &str, count: usize, first: &[u8], last: &[u8]) {
+        self.write(user, client, op, first, &format!("{}:{}", count, to_hex(last))).await;
+    }
+
+    async fn write(&mut self, user: Option<&str>, client: &str, op: &str, key: &[u8], value: &str) {
+        let record = format!("{}\t{}\t{}\t{}\t{}\t{}", now_millis(), user.unwrap_or(EMPTY_FIELD), client, op, to_hex(key), value);
+        let line = format!("{}\t{:08x}\n", &record, crc32fast::hash(record.as_bytes()));
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate().await;
+        }
+        self.file.write_all(line.as_bytes()).await.expect("Write audit file fail");
+        self.file.sync_data().await.expect("Flush audit file fail");
+        self.size += line.len() as u64;
+    }
+}
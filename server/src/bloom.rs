@@ -0,0 +1,71 @@
+// 布隆过滤器，每个 key 10 bit，7 个哈希函数，误判率约 1%
+const BITS_PER_KEY: usize = 10;
+const HASH_COUNT: u8 = 7;
+
+// FNV-1a 加上 murmur3 的 fmix64，写入文件后不能改变
+pub fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in key {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+// 1 bit hash count
+// n bit bits
+pub struct Bloom {
+    hash_count: u8,
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    // 由所有 key 的 hash 构建
+    pub fn build(hashes: &[u64]) -> Self {
+        let len = (hashes.len() * BITS_PER_KEY).div_ceil(8).max(8);
+        let mut bloom = Self {
+            hash_count: HASH_COUNT,
+            bits: vec![0; len],
+        };
+        for hash in hashes {
+            for bit in bloom.positions(*hash) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [hash_count, bits @ ..] if !bits.is_empty() => Some(Self {
+                hash_count: *hash_count,
+                bits: bits.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + self.bits.len());
+        buf.push(self.hash_count);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    // 双重哈希：第 i 个位置为 h1 + i * h2
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let n = self.bits.len() as u64 * 8;
+        let h1 = hash & 0xffffffff;
+        let h2 = (hash >> 32) | 1;
+        (0..self.hash_count as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % n) as usize)
+    }
+
+    // false 表示一定不存在
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(hash(key)).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::sstable::Entry;
+
+// SST 文件编号, block 偏移
+type BlockKey = (u64, u64);
+
+// 解码后的 block
+pub type Block = Arc<Vec<Entry>>;
+
+struct Lru {
+    // block, 计入的字节数, 最近一次使用的 tick
+    blocks: HashMap<BlockKey, (Block, usize, u64)>,
+    // tick 到 block，最小的是最久没有使用的
+    order: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    bytes: usize,
+}
+
+// SST block 的 LRU 缓存，所有 SST 文件共用，超过 capacity 字节时淘汰最久没有使用的 block
+// capacity 为 0 时不缓存
+pub struct BlockCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru {
+                blocks: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, table: u64, offset: u64) -> Option<Block> {
+        let mut lru = self.lru.lock().expect("Lock block cache fail");
+        lru.tick += 1;
+        let tick = lru.tick;
+        let Some((block, _, last)) = lru.blocks.get_mut(&(table, offset)) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let block = block.clone();
+        let last = std::mem::replace(last, tick);
+        lru.order.remove(&last);
+        lru.order.insert(tick, (table, offset));
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(block)
+    }
+
+    // charge 为 block 占用的字节数，超过 capacity 的 block 不缓存
+    pub fn insert(&self, table: u64, offset: u64, block: Block, charge: usize) {
+        if charge > self.capacity {
+            return;
+        }
+        let mut lru = self.lru.lock().expect("Lock block cache fail");
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some((_, old_charge, last)) = lru.blocks.insert((table, offset), (block, charge, tick)) {
+            lru.order.remove(&last);
+            lru.bytes -= old_charge;
+        }
+        lru.order.insert(tick, (table, offset));
+        lru.bytes += charge;
+        while lru.bytes > self.capacity {
+            let Some((_, key)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some((_, charge, _)) = lru.blocks.remove(&key) {
+                lru.bytes -= charge;
+            }
+        }
+    }
+
+    // SST 文件关闭后它的 block 不会再被读取
+    pub fn evict_table(&self, table: u64) {
+        let mut lru = self.lru.lock().expect("Lock block cache fail");
+        let Lru { blocks, order, bytes, .. } = &mut *lru;
+        blocks.retain(|(t, _), (_, charge, last)| {
+            if *t == table {
+                order.remove(last);
+                *bytes -= *charge;
+            }
+            *t != table
+        });
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
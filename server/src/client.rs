@@ -1,23 +1,51 @@
-use log::error;
+use std::sync::Arc;
+use dashmap::DashSet;
+use tracing::error;
 use tokio::sync::mpsc::Sender;
 use crate::event::EventRes;
 
+// 连接已经超时放弃的请求号，事件循环跳过这些请求，各分片共用
+pub type Cancelled = Arc<DashSet<u32>>;
+
 pub struct Client {
     id: String,
     sender: Sender<EventRes>,
+    // Raft 主节点上暂存的响应，写入提交后再发送
+    held: Option<Vec<EventRes>>,
+    cancelled: Cancelled,
 }
 
 impl Client {
-    pub fn new(id: String, sender: Sender<EventRes>) -> Client {
+    pub fn new(id: String, sender: Sender<EventRes>, cancelled: Cancelled) -> Client {
         Client {
             id,
             sender,
+            held: None,
+            cancelled,
         }
     }
 
+    // 没有请求号的请求无法取消
+    pub fn cancelled(&self, req: Option<u32>) -> bool {
+        req.is_some_and(|req| self.cancelled.contains(&req))
+    }
+
     pub async fn send_event_res(&mut self, event_res: EventRes) {
+        if let Some(held) = self.held.as_mut() {
+            held.push(event_res);
+            return;
+        }
         if let Err(e) =  self.sender.send(event_res).await {
             error!("Send event res fail, id = {}, err => {:?}", self.id, e);
         };
     }
-}
\ No newline at end of file
+
+    // 之后的响应先暂存，直到 release
+    pub fn hold(&mut self) {
+        self.held = Some(Vec::new());
+    }
+
+    pub fn release(&mut self) -> Vec<EventRes> {
+        self.held.take().unwrap_or_default()
+    }
+}
@@ -0,0 +1,68 @@
+use lsm_protocol::ClusterInfo;
+use crate::event::Event;
+
+// 每个节点在哈希环上的默认位置数，越多各节点负责的 key 越均匀
+pub const DEFAULT_CLUSTER_VNODES: usize = 64;
+
+// 集群模式的哈希环：每个节点按地址在环上有 vnodes 个位置，key 的 crc32 顺时针遇到的第一个位置属于哪个节点，key 就由哪个节点负责
+// 节点用客户端连接的地址标识，各节点配置相同的节点列表和 vnodes，算出的环相同
+// 不负责的 key 返回 RES_MOVED 和负责的节点地址，由客户端重新发送；节点之间不迁移数据，节点列表变了之后原来的 key 可能找不到
+// 节点列表来自 cluster_nodes 配置，或者由 gossip 得到，见 gossip.rs
+#[derive(Debug)]
+pub struct Ring {
+    nodes: Vec<String>,
+    // 本节点在 nodes 中的位置
+    local: usize,
+    // (位置, 节点)，按位置排序
+    points: Vec<(u32, usize)>,
+}
+
+impl Ring {
+    pub fn new(nodes: Vec<String>, local: &str, vnodes: usize) -> Self {
+        let local = nodes.iter().position(|node| node == local)
+            .unwrap_or_else(|| panic!("Cluster nodes {:?} do not contain cluster_addr {}", nodes, local));
+        let mut points: Vec<(u32, usize)> = nodes.iter().enumerate()
+            .flat_map(|(index, node)| (0..vnodes.max(1)).map(move |i| (crc32fast::hash(format!("{}#{}", node, i).as_bytes()), index)))
+            .collect();
+        // 位置相同时按节点顺序取第一个，各节点上结果一致
+        points.sort();
+        points.dedup_by_key(|(point, _)| *point);
+        Self { nodes, local, points }
+    }
+
+    // 各节点的客户端地址
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    // 负责 key 的节点
+    fn owner(&self, key: &[u8]) -> usize {
+        let hash = crc32fast::hash(key);
+        let index = self.points.partition_point(|(point, _)| *point < hash);
+        self.points.get(index).or(self.points.first()).map_or(self.local, |(_, node)| *node)
+    }
+
+    // 请求中有不由本节点负责的 key 时返回第一个这样的 key 所在节点的地址
+    // SCAN、KEYS、DBSIZE、LPM、DELETE_RANGE 等范围操作只在本节点的数据上执行，由客户端按需发给每个节点
+    pub fn moved(&self, event: &Event) -> Option<&str> {
+        let keys: Vec<&Vec<u8>> = match event {
+            Event::GET { key, .. } | Event::SET { key, .. } | Event::EXISTS { key, .. } |
+            Event::SETEX { key, .. } | Event::CAS { key, .. } | Event::META { key, .. } |
+            Event::GETV { key, .. } | Event::SETV { key, .. } | Event::INCR { key, .. } |
+            Event::MERGE { key, .. } | Event::APPEND { key, .. } | Event::GETSET { key, .. } => vec![key],
+            Event::MGET { keys, .. } => keys.iter().collect(),
+            Event::SETM { entries, .. } => entries.iter().map(|(key, _)| key).collect(),
+            Event::INGEST { entries, .. } => entries.iter().map(|(key, _)| key).collect(),
+            _ => return None,
+        };
+        keys.into_iter()
+            .map(|key| self.owner(key))
+            .find(|owner| *owner != self.local)
+            .map(|owner| self.nodes[owner].as_str())
+    }
+
+    // CLUSTER INFO 的结果，格式见 lsm-protocol
+    pub fn info(&self) -> ClusterInfo {
+        ClusterInfo { nodes: self.nodes.clone(), local: self.local, points: self.points.clone() }
+    }
+}
@@ -0,0 +1,393 @@
+use std::io::Error;
+use std::time::Duration;
+use bytes::BytesMut;
+use lsm_protocol::{seal, Format, Limits, Meta, Request, Response, ERR_RESULT_TOO_LARGE, SHORT_LEN_SIZE};
+use lsm_protocol::{FEATURE_CRC, FEATURE_LONG_LEN, FEATURE_LZ4, FEATURE_PING, FEATURE_REQUEST_ID};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::{debug, info, warn};
+use crate::event::{Event, EventRes, LEN_MASK};
+use crate::parser::Parser;
+
+// 客户端连接的编解码：握手之后的请求解码为 Request，EventRes 编码为 Response
+// 帧的格式见 lsm-protocol，协商 FEATURE_CRC 或 FEATURE_LZ4 后每帧包一层
+
+pub const SERVER_FEATURES: u32 = FEATURE_REQUEST_ID | FEATURE_LONG_LEN | FEATURE_PING | FEATURE_CRC | FEATURE_LZ4;
+
+// 需要 enable_admin_ops 的请求
+pub fn is_admin(request: &Request) -> bool {
+    matches!(request, Request::FLUSHALL | Request::COMPACT { .. } | Request::SCRUB | Request::INGEST { .. })
+}
+
+// 写入的字节数，用于写限流；不是写入时为 None，INGEST 不限流
+pub fn write_bytes(request: &Request) -> Option<usize> {
+    let len = |value: &Option<Vec<u8>>| value.as_ref().map_or(0, Vec::len);
+    match request {
+        Request::SET { key, value } | Request::CAS { key, value, .. } | Request::SETV { key, value, .. } => Some(key.len() + len(value)),
+        Request::MSET { entries } => Some(entries.iter().map(|(key, value)| key.len() + len(value)).sum()),
+        Request::SETEX { key, value, .. } | Request::GETSET { key, value } => Some(key.len() + value.len()),
+        Request::MERGE { key, operand, .. } => Some(key.len() + operand.len()),
+        Request::INCR { key, .. } => Some(key.len() + 8),
+        Request::APPEND { key, suffix } => Some(key.len() + suffix.len()),
+        _ => None,
+    }
+}
+
+// 转为发往事件循环的事件；PONG 和认证由连接自己处理，为 None
+pub fn into_event(request: Request, id: &str, req: Option<u32>) -> Option<Event> {
+    let id = id.to_string();
+    let event = match request {
+        Request::GET { key } => Event::GET { id, req, key },
+        Request::EXISTS { key } => Event::EXISTS { id, req, key },
+        Request::SET { key, value } => Event::SET { id, req, key, value },
+        Request::SCAN { start, end } => Event::SCAN { id, req, start, end },
+        Request::PREFIX { prefix, with_values } => Event::PREFIX { id, req, prefix, with_values },
+        Request::KEYS { pattern } => Event::KEYS { id, req, pattern },
+        Request::MGET { keys } => Event::MGET { id, req, keys },
+        Request::MSET { entries } => Event::SETM { id, req, entries },
+        Request::SETEX { key, value, ttl } => Event::SETEX { id, req, key, value, ttl },
+        Request::CAS { key, expected, value } => Event::CAS { id, req, key, expected, value },
+        Request::META { key } => Event::META { id, req, key },
+        Request::LPM { key } => Event::LPM { id, req, key },
+        Request::GETV { key } => Event::GETV { id, req, key },
+        Request::SETV { key, version, value } => Event::SETV { id, req, key, version, value },
+        Request::MERGE { name, key, operand } => Event::MERGE { id, req, name, key, operand },
+        Request::INCR { key, delta } => Event::INCR { id, req, key, delta },
+        Request::APPEND { key, suffix } => Event::APPEND { id, req, key, suffix },
+        Request::GETSET { key, value } => Event::GETSET { id, req, key, value },
+        Request::FLUSHALL => Event::FLUSHALL { id, req },
+        Request::COMPACT { start, end } => Event::COMPACT { id, req, start, end },
+        Request::DELETE_RANGE { start, end } => Event::DELETE_RANGE { id, req, start, end },
+        Request::SCRUB => Event::SCRUB { id, req },
+        Request::INGEST { entries } => Event::INGEST { id, req, entries },
+        Request::PING { payload } => Event::PING { id, req, payload },
+        Request::STATS => Event::STATS { id, req },
+        Request::MULTI => Event::MULTI { id, req },
+        Request::EXEC => Event::EXEC { id, req, abort: None },
+        Request::SELECT { name } => Event::SELECT { id, req, name },
+        Request::SNAPSHOT => Event::SNAPSHOT { id, req },
+        Request::RELEASE => Event::RELEASE { id, req },
+        Request::CLUSTER => Event::CLUSTER { id, req },
+        Request::DBSIZE => Event::DBSIZE { id, req },
+        Request::PONG { .. } | Request::AUTH { .. } | Request::AUTH_USER { .. } => return None,
+    };
+    Some(event)
+}
+
+// 握手协商的特性决定帧的格式，每个连接一个
+pub struct LsmCodec {
+    features: u32,
+    format: Format,
+    parser: Parser,
+    // 这个请求编号的 SCAN 有一条结果太长，已经回复了错误，丢弃它剩下的结果直到结束
+    dropping: Option<Option<u32>>,
+}
+
+impl LsmCodec {
+    // limits 为连接建立时配置的长度上限
+    pub fn new(features: u32, limits: Limits) -> Self {
+        Self { features, format: Format::new(features), parser: Parser::new(features, limits), dropping: None }
+    }
+
+    fn write(&self, req: Option<u32>, res: &Response, dst: &mut BytesMut) {
+        let mut frame = Vec::new();
+        res.encode(req, self.format, &mut frame);
+        if self.format.sealed {
+            let mut sealed = Vec::with_capacity(frame.len() + 8);
+            seal(&frame, self.features, &mut sealed);
+            frame = sealed;
+        }
+        dst.extend_from_slice(&frame);
+    }
+}
+
+impl Decoder for LsmCodec {
+    type Item = (Option<u32>, Request);
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
+        self.parser.parse(src)
+    }
+
+    // 连接关闭时不完整的请求直接丢弃
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Error> {
+        self.decode(src)
+    }
+}
+
+// 服务端主动发送的响应，例如 RES_PING
+impl Encoder<Response> for LsmCodec {
+    type Error = Error;
+
+    fn encode(&mut self, res: Response, dst: &mut BytesMut) -> Result<(), Error> {
+        self.write(None, &res, dst);
+        Ok(())
+    }
+}
+
+// 结果超过未协商 FEATURE_LONG_LEN 的客户端能收的长度时只对这个请求回复 ERR_RESULT_TOO_LARGE，连接继续使用
+// SCAN 的结果逐条发送，其中一条太长时回复错误代替整个 SCAN，之后的结果和结束都丢弃；响应按请求顺序发送，它们是连续的
+impl Encoder<EventRes> for LsmCodec {
+    type Error = Error;
+
+    fn encode(&mut self, res: EventRes, dst: &mut BytesMut) -> Result<(), Error> {
+        let Some((id, req, res)) = response(res) else {
+            return Ok(());
+        };
+        if self.dropping == Some(req) && matches!(res, Response::ENTRY(..) | Response::SCAN(_)) {
+            if matches!(res, Response::SCAN(_)) {
+                self.dropping = None;
+            }
+            return Ok(());
+        }
+        if self.format.ls == SHORT_LEN_SIZE && res.max_len() > LEN_MASK as usize {
+            warn!("Result too long for client [{}] without long len", id);
+            if matches!(res, Response::ENTRY(..)) {
+                self.dropping = Some(req);
+            }
+            self.write(req, &Response::ERROR(ERR_RESULT_TOO_LARGE), dst);
+            return Ok(());
+        }
+        self.write(req, &res, dst);
+        Ok(())
+    }
+}
+
+// 事件循环的结果转为响应，不发给客户端的结果为 None
+fn response(res: EventRes) -> Option<(String, Option<u32>, Response)> {
+    let res = match res {
+        EventRes::GET {id, req, value} => {
+            info!("Receive get event result, value = {:?}", &value);
+            (id, req, Response::GET(value))
+        }
+        EventRes::SET {id, req} => {
+            info!("Receive set event result for [{}]", id);
+            (id, req, Response::SET)
+        }
+        EventRes::SCAN {id, req, entry} => match entry {
+            Some((key, value)) => (id, req, Response::ENTRY(key, value)),
+            None => (id, req, Response::SCAN(Vec::new())),
+        },
+        EventRes::MGET {id, req, values} => {
+            info!("Receive mget event result, count = {}", values.len());
+            (id, req, Response::MGET(values))
+        }
+        EventRes::SETM {id, req} => {
+            info!("Receive mset event result for [{}]", id);
+            (id, req, Response::MSET)
+        }
+        EventRes::EXISTS {id, req, exists} => {
+            info!("Receive exists event result for [{}], exists = {}", id, exists);
+            (id, req, Response::EXISTS(exists))
+        }
+        EventRes::CAS {id, req, swapped} => {
+            info!("Receive cas event result for [{}], swapped = {}", id, swapped);
+            (id, req, Response::CAS(swapped))
+        }
+        EventRes::META {id, req, meta, ttl} => {
+            info!("Receive meta event result for [{}], exists = {}, ttl = {:?}", id, meta.is_some(), ttl);
+            let meta = meta.map(|meta| Meta { len: meta.len, version: meta.version, ttl: ttl.map(Duration::from_millis) });
+            (id, req, Response::META(meta))
+        }
+        EventRes::GETV {id, req, value, version} => {
+            info!("Receive getv event result, value = {:?}, version = {}", value, version);
+            (id, req, Response::GETV(value, version))
+        }
+        EventRes::LPM {id, req, entry} => {
+            info!("Receive lpm event result for [{}], entry = {:?}", id, entry);
+            (id, req, Response::LPM(entry))
+        }
+        // 只由管理端口发出
+        EventRes::FLUSH {id, req} | EventRes::SHUTDOWN {id, req} => {
+            warn!("Unexpected admin event result for [{}], req = {:?}", id, req);
+            return None;
+        }
+        EventRes::SETV {id, req, ok, version} => {
+            info!("Receive setv event result for [{}], ok = {}, version = {}", id, ok, version);
+            (id, req, Response::SETV(ok, version))
+        }
+        EventRes::MERGE {id, req, value} => {
+            info!("Receive merge event result, value = {:?}", value);
+            (id, req, Response::MERGE(value))
+        }
+        EventRes::INCR {id, req, value} => {
+            info!("Receive incr event result for [{}], value = {:?}", id, value);
+            (id, req, Response::INCR(value))
+        }
+        EventRes::APPEND {id, req, len} => {
+            info!("Receive append event result, len = {:?}", len);
+            (id, req, Response::APPEND(len))
+        }
+        EventRes::DBSIZE {id, req, size} => {
+            info!("Receive dbsize event result for [{}], size = {}", id, size);
+            (id, req, Response::DBSIZE(size))
+        }
+        EventRes::GETSET {id, req, value} => {
+            info!("Receive getset event result, value = {:?}", value);
+            (id, req, Response::GETSET(value))
+        }
+        EventRes::FLUSHALL {id, req} => {
+            info!("Receive flushall event result for [{}]", id);
+            (id, req, Response::FLUSHALL)
+        }
+        EventRes::PONG {id, req, payload} => {
+            debug!("Receive ping event result for [{}], payload = {}", id, payload);
+            (id, req, Response::PONG(payload))
+        }
+        EventRes::STATS {id, req, stats} => {
+            info!("Receive stats event result for [{}], stats = {:?}", id, stats);
+            (id, req, Response::STATS(stats))
+        }
+        EventRes::MULTI {id, req} => {
+            info!("Receive multi event result for [{}]", id);
+            (id, req, Response::MULTI)
+        }
+        EventRes::EXEC {id, req} => {
+            info!("Receive exec event result for [{}]", id);
+            (id, req, Response::EXEC)
+        }
+        EventRes::SELECT {id, req, ok} => {
+            info!("Receive select event result for [{}], ok = {}", id, ok);
+            (id, req, Response::SELECT(ok))
+        }
+        EventRes::SNAPSHOT {id, req, version} => {
+            info!("Receive snapshot event result for [{}], version = {}", id, version);
+            (id, req, Response::SNAPSHOT(version))
+        }
+        EventRes::RELEASE {id, req, ok} => {
+            info!("Receive release event result for [{}], ok = {}", id, ok);
+            (id, req, Response::RELEASE(ok))
+        }
+        EventRes::COMPACT {id, req, files} => {
+            info!("Receive compact event result for [{}], files = {}", id, files);
+            (id, req, Response::COMPACT(files))
+        }
+        EventRes::SCRUB {id, req, files, corrupt} => {
+            info!("Receive scrub event result for [{}], files = {}, corrupt = {}", id, files, corrupt);
+            (id, req, Response::SCRUB(files, corrupt))
+        }
+        EventRes::INGEST {id, req, keys} => {
+            info!("Receive ingest event result for [{}], keys = {}", id, keys);
+            (id, req, Response::INGEST(keys))
+        }
+        EventRes::DELETE_RANGE {id, req, keys} => {
+            info!("Receive delete range event result for [{}], keys = {}", id, keys);
+            (id, req, Response::DELETE_RANGE(keys))
+        }
+        EventRes::ERROR {id, req, code} => {
+            info!("Receive error event result for [{}], code = {}", id, code);
+            (id, req, Response::ERROR(code))
+        }
+        EventRes::CLUSTER {id, req, ring} => {
+            // 不是集群模式时没有节点
+            info!("Receive cluster event result for [{}]", id);
+            (id, req, Response::CLUSTER(ring.map(|ring| ring.info()).unwrap_or_default()))
+        }
+        EventRes::MOVED {id, req, addr} => {
+            info!("Receive moved event result for [{}], addr = {}", id, addr);
+            (id, req, Response::MOVED(addr))
+        }
+        EventRes::AUTH {id, req, ok} => {
+            info!("Receive auth event result for [{}], ok = {}", id, ok);
+            (id, req, Response::AUTH(ok))
+        }
+    };
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use lsm_protocol::{OP_DBSIZE, RES_ERROR, RES_GET};
+
+    fn set(key: &[u8], value: &[u8], req: Option<u32>, features: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Request::SET { key: key.to_vec(), value: Some(value.to_vec()) }.encode(req, Format::new(features), &mut buf);
+        buf
+    }
+
+    #[test]
+    fn decode_partial_frames() {
+        let mut codec = LsmCodec::new(0, Limits::UNLIMITED);
+        let frame = set(b"key", b"value", None, 0);
+        let mut src = BytesMut::new();
+        // 逐字节到达，最后一个字节之前都不完整
+        for byte in &frame[..frame.len() - 1] {
+            src.extend_from_slice(&[*byte]);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+        }
+        src.extend_from_slice(&frame[frame.len() - 1..]);
+        src.extend_from_slice(&[OP_DBSIZE]);
+        match codec.decode(&mut src).unwrap() {
+            Some((None, Request::SET { key, value })) => assert_eq!((key, value), (b"key".to_vec(), Some(b"value".to_vec()))),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some((None, Request::DBSIZE))));
+        assert!(src.is_empty());
+
+        // 未知的 op 不用等数据完整
+        src.extend_from_slice(&[0x01]);
+        assert_eq!(codec.decode(&mut src).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_request_id_and_long_len() {
+        let features = FEATURE_REQUEST_ID | FEATURE_LONG_LEN;
+        let mut codec = LsmCodec::new(features, Limits::UNLIMITED);
+        let mut src = BytesMut::from(set(b"k", b"v", Some(7), features).as_slice());
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some((Some(7), Request::SET { .. }))));
+    }
+
+    #[test]
+    fn sealed_frames_round_trip() {
+        let features = FEATURE_CRC | FEATURE_LZ4;
+        let mut codec = LsmCodec::new(features, Limits::UNLIMITED);
+        // 长的请求经过压缩
+        let frame = set(b"key", &[b'v'; 4096], None, features);
+        let mut sealed = Vec::new();
+        seal(&frame, features, &mut sealed);
+        assert!(sealed.len() < frame.len());
+        let mut src = BytesMut::from(sealed.as_slice());
+        match codec.decode(&mut src).unwrap() {
+            Some((None, Request::SET { value, .. })) => assert_eq!(value.unwrap().len(), 4096),
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        // crc 不匹配
+        let mut sealed = Vec::new();
+        seal(&[OP_DBSIZE], features, &mut sealed);
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        let mut src = BytesMut::from(sealed.as_slice());
+        assert_eq!(codec.decode(&mut src).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    // 太长的结果只对这个请求回复错误，之后的响应照常发送
+    #[test]
+    fn too_long_result_rejected() {
+        let mut codec = LsmCodec::new(0, Limits::UNLIMITED);
+        let mut dst = BytesMut::new();
+        let res = EventRes::GET { id: String::from("c"), req: None, value: Some(vec![0; LEN_MASK as usize + 1]) };
+        codec.encode(res, &mut dst).unwrap();
+        codec.encode(EventRes::GET { id: String::from("c"), req: None, value: None }, &mut dst).unwrap();
+        assert_eq!(&dst[..], &[RES_ERROR, ERR_RESULT_TOO_LARGE, RES_GET, 0xff, 0xff]);
+    }
+
+    // SCAN 中一条结果太长时整个 SCAN 回复一个错误，剩下的结果和结束都不发送
+    #[test]
+    fn too_long_scan_entry() {
+        let mut codec = LsmCodec::new(FEATURE_REQUEST_ID, Limits::UNLIMITED);
+        let mut dst = BytesMut::new();
+        let scan = |req, entry| EventRes::SCAN { id: String::from("c"), req: Some(req), entry };
+        let long = (b"k".to_vec(), vec![0; LEN_MASK as usize + 1]);
+        for res in [scan(1, Some(long)), scan(1, Some((b"a".to_vec(), b"1".to_vec()))), scan(1, None), scan(2, None)] {
+            codec.encode(res, &mut dst).unwrap();
+        }
+        let format = Format::new(FEATURE_REQUEST_ID);
+        let (req, res, used) = Response::decode(&dst, format).unwrap().unwrap();
+        assert_eq!((req, res), (Some(1), Response::ERROR(ERR_RESULT_TOO_LARGE)));
+        let (req, res, rest) = Response::decode(&dst[used..], format).unwrap().unwrap();
+        assert_eq!((req, res), (Some(2), Response::SCAN(Vec::new())));
+        assert_eq!(used + rest, dst.len());
+    }
+}
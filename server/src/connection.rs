@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use futures_util::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, Duration, Instant, Interval};
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, warn};
+use lsm_protocol::{Request, Response, FEATURE_PING, HANDSHAKE_NUM, HELLO_NUM, PROTOCOL_VERSION};
+use crate::acl::Access;
+use crate::codec::{self, LsmCodec, SERVER_FEATURES};
+use crate::event::{self, Event, EventRes, ERR_TIMEOUT};
+use crate::pending::Pending;
+use crate::shard::Shards;
+use crate::throttle::WriteThrottle;
+use crate::utils::now_millis;
+
+// 客户端连接：握手之后读取请求发往事件循环，按请求顺序写回响应
+
+// AUTH 连续失败达到该次数断开连接
+const MAX_AUTH_FAILURES: u32 = 3;
+
+// 用户名到密码摘要和权限
+pub type Users = HashMap<String, (Vec<u8>, Arc<Access>)>;
+
+// 所有连接共用的配置
+pub struct Context {
+    pub shards: Arc<Shards>,
+    pub throttle: Arc<WriteThrottle>,
+    // AUTH 密码的摘要
+    pub passwords: Arc<Vec<Vec<u8>>>,
+    pub users: Arc<Users>,
+    // 数据端口上是否允许管理命令
+    pub admin_ops: bool,
+    // 服务端 PING 间隔
+    pub ping_interval: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    // 一个连接最多的未完成请求数
+    pub max_inflight: usize,
+}
+
+// tcp close func
+async fn shutdown(id: &String, shards: &Shards, mut socket: TcpStream) {
+    // 断开的原因在调用处记录，健康检查的连接只记 debug
+    debug!("Client [{}] disconnect", id);
+    shards.disconnect(id);
+    socket.shutdown().await.unwrap_or_else(|e| {
+        info!("Fail close client [{}]; err = {:?} ", id, e);
+    });
+}
+
+// 没有配置 PING 时永远不会完成
+async fn tick(ping: &mut Option<Interval>) {
+    match ping {
+        Some(ping) => {
+            ping.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+pub async fn serve(mut socket: TcpStream, id: String, context: Arc<Context>) {
+    // create client mpsc
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    // 负载均衡的健康检查连接后立即关闭或只发送一个 PING，连接时的日志都是 debug，确定是客户端后再记 info
+    debug!("Receive connection from [{}]", id);
+    // create client，事件发往 event_tx，按 key 分到各事件循环
+    let mut event_tx = context.shards.connect(&id, client_tx);
+
+    // write hello
+    debug!("Hello to client {}", id);
+    if let Err(e) = socket.write_u8(HELLO_NUM).await {
+        warn!("Failed to write hello to [{}]; err = {:?}", id, e);
+        shutdown(&id, &context.shards, socket).await;
+        return;
+    }
+
+    // wait hello
+    let features = match socket.read_u8().await {
+        Ok(HELLO_NUM) => {
+            debug!("Client [{}] hello without handshake, no features", id);
+            0
+        }
+        Ok(HANDSHAKE_NUM) => {
+            // 1 bit handshake num
+            // 1 bit protocol version
+            // 4 bit features
+            let mut head = [0; 1 + 4];
+            if let Err(e) = socket.read_exact(&mut head).await {
+                warn!("Failed to read handshake from [{}]; err = {:?}", id, e);
+                shutdown(&id, &context.shards, socket).await;
+                return;
+            }
+            let features = u32::from_be_bytes([head[1], head[2], head[3], head[4]]) & SERVER_FEATURES;
+            debug!("Client [{}] handshake version {} features {:#x}", id, head[0], features);
+            // 1 bit protocol version
+            // 4 bit features, both sides support
+            let mut res = vec![PROTOCOL_VERSION];
+            res.extend_from_slice(&features.to_be_bytes());
+            if let Err(e) = socket.write_all(&res).await {
+                warn!("Failed to write handshake to [{}]; err = {:?}", id, e);
+                shutdown(&id, &context.shards, socket).await;
+                return;
+            }
+            features
+        }
+        Ok(_) => {
+            warn!("Client [{}] verify hello fail", id);
+            shutdown(&id, &context.shards, socket).await;
+            return;
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset) => {
+            debug!("Client [{}] closed before hello", id);
+            shutdown(&id, &context.shards, socket).await;
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to read hello from [{}]; err = {:?}", id, e);
+            shutdown(&id, &context.shards, socket).await;
+            return;
+        }
+    };
+    // 服务端 PING，第一次在一个间隔之后
+    let mut ping = context.ping_interval
+        .filter(|_| features & FEATURE_PING != 0)
+        .map(|period| interval_at(Instant::now() + period, period));
+
+    // 握手之后的请求和响应都经过编解码
+    let mut framed = Framed::new(socket, LsmCodec::new(features, context.shards.limits().decode_limits()));
+    debug!("Alloc codec for client [{}]", id);
+
+    // 是否在 MULTI 和 EXEC 之间
+    let mut multi = false;
+
+    // 还没有发送 PING 之外的请求，可能是健康检查
+    let mut probe = true;
+
+    // 未完成的请求，响应按请求顺序发送
+    let mut pending = Pending::new(context.request_timeout, event_tx.cancelled());
+
+    // 未认证时只能 AUTH 和 PING
+    let mut authed = context.passwords.is_empty() && context.users.is_empty();
+    let mut auth_failures = 0;
+
+    loop {
+        select! {
+            request = framed.next(), if pending.inflight() < context.max_inflight => {
+                let (req, request) = match request {
+                    Some(Ok(request)) => request,
+                    Some(Err(e)) => {
+                        if e.kind() == ErrorKind::InvalidData {
+                            warn!("{} from client [{}]", e, id);
+                        } else {
+                            warn!("Failed to read from [{}]; err = {:?}", id, e);
+                        }
+                        shutdown(&id, &context.shards, framed.into_inner()).await;
+                        return;
+                    }
+                    None => {
+                        if probe {
+                            debug!("Probe [{}] closed", id);
+                        } else {
+                            warn!("Client [{}] read fail", id);
+                        }
+                        shutdown(&id, &context.shards, framed.into_inner()).await;
+                        return;
+                    }
+                };
+                let op = request.op();
+                if probe && !matches!(request, Request::PING { .. } | Request::PONG { .. }) {
+                    probe = false;
+                    info!("New client from id [{}], features {:#x}", id, features);
+                }
+                if multi && !event::TX_OPS.contains(&op) {
+                    warn!("Op {} not allowed in transaction from client [{}]", op, id);
+                    shutdown(&id, &context.shards, framed.into_inner()).await;
+                    return;
+                }
+                // 缓存的 op 也占着上限，达到上限时 EXEC 就读不到了
+                if multi && op != event::OP_EXEC && pending.queued() + 1 >= context.max_inflight {
+                    warn!("Transaction from client [{}] exceeds {} ops", id, context.max_inflight - 1);
+                    shutdown(&id, &context.shards, framed.into_inner()).await;
+                    return;
+                }
+                if !authed && !matches!(op, event::OP_AUTH | event::OP_AUTH_USER | event::OP_PING | event::OP_PONG) {
+                    warn!("Op {} from unauthenticated client [{}]", op, id);
+                    shutdown(&id, &context.shards, framed.into_inner()).await;
+                    return;
+                }
+                if codec::is_admin(&request) && !context.admin_ops {
+                    warn!("Admin op {} from client [{}] not allowed", op, id);
+                    shutdown(&id, &context.shards, framed.into_inner()).await;
+                    return;
+                }
+                if let Some(bytes) = codec::write_bytes(&request) {
+                    context.throttle.acquire(bytes).await;
+                }
+                let event = match request {
+                    Request::MULTI | Request::EXEC => {
+                        if multi == (op == event::OP_MULTI) {
+                            warn!("Unexpected op {} from client [{}], in transaction {}", op, id, multi);
+                            shutdown(&id, &context.shards, framed.into_inner()).await;
+                            return;
+                        }
+                        multi = op == event::OP_MULTI;
+                        info!("Receive multi from [{}] op {}", id, op);
+                        codec::into_event(request, &id, req)
+                    }
+                    Request::PONG { payload } => {
+                        let rtt = now_millis().saturating_sub(payload);
+                        info!("Client [{}] rtt {} ms", id, rtt);
+                        None
+                    }
+                    Request::AUTH { password } => {
+                        let digest = Sha256::digest(&password).to_vec();
+                        let ok = context.passwords.contains(&digest);
+                        if ok {
+                            info!("Client [{}] auth success", id);
+                            authed = true;
+                            auth_failures = 0;
+                        } else {
+                            auth_failures += 1;
+                            warn!("Client [{}] auth fail {} times", id, auth_failures);
+                            if auth_failures >= MAX_AUTH_FAILURES {
+                                shutdown(&id, &context.shards, framed.into_inner()).await;
+                                return;
+                            }
+                        }
+                        Some(Event::AUTH {
+                            id: id.clone(),
+                            req,
+                            ok,
+                            access: None,
+                        })
+                    }
+                    Request::AUTH_USER { user, password } => {
+                        let user = String::from_utf8_lossy(&user).to_string();
+                        let digest = Sha256::digest(&password).to_vec();
+                        let access = context.users.get(&user).filter(|(expected, _)| *expected == digest).map(|(_, access)| access.clone());
+                        let ok = access.is_some();
+                        if ok {
+                            info!("Client [{}] auth success as user {}", id, user);
+                            authed = true;
+                            auth_failures = 0;
+                        } else {
+                            auth_failures += 1;
+                            warn!("Client [{}] auth as user {} fail {} times", id, user, auth_failures);
+                            if auth_failures >= MAX_AUTH_FAILURES {
+                                shutdown(&id, &context.shards, framed.into_inner()).await;
+                                return;
+                            }
+                        }
+                        Some(Event::AUTH {
+                            id: id.clone(),
+                            req,
+                            ok,
+                            access,
+                        })
+                    }
+                    request => {
+                        if codec::is_admin(&request) {
+                            warn!("Receive {:?} from [{}]", request, id);
+                        } else if probe {
+                            debug!("Receive {:?} from [{}]", request, id);
+                        } else {
+                            info!("Receive {:?} from [{}]", request, id);
+                        }
+                        codec::into_event(request, &id, req)
+                    }
+                };
+                if let Some(event) = event {
+                    // MULTI 之后到 EXEC 之前的 op 等待 EXEC
+                    if multi && op != event::OP_MULTI {
+                        pending.queue(req);
+                    } else {
+                        pending.start(req);
+                    }
+                    event_tx.send(event).await.unwrap_or_else(|e| {
+                        error!("Client {} send event error; {:?}", id, e);
+                    });
+                }
+            }
+            _ = tick(&mut ping) => {
+                if let Err(e) = framed.send(Response::PING(now_millis())).await {
+                    warn!("Failed to write ping to [{}]; err = {:?}", id, e);
+                    shutdown(&id, &context.shards, framed.into_inner()).await;
+                    return;
+                };
+            }
+            req = pending.expired() => {
+                warn!("Request {:?} from client [{}] timed out", req, id);
+                if let Err(e) = framed.send(EventRes::ERROR { id: id.clone(), req, code: ERR_TIMEOUT }).await {
+                    warn!("Failed to write timeout to [{}]; err = {:?}", id, e);
+                    shutdown(&id, &context.shards, framed.into_inner()).await;
+                    return;
+                };
+            }
+            message = client_rx.recv() => {
+                match message {
+                    Some(event_res) => {
+                        event_tx.received(&event_res);
+                        for event_res in pending.finish(event_res) {
+                            if let Err(e) = framed.send(event_res).await {
+                                warn!("Failed to write result to [{}]; err = {:?}", id, e);
+                                shutdown(&id, &context.shards, framed.into_inner()).await;
+                                return;
+                            };
+                        }
+                    }
+                    None => {
+                       warn!("Client [{}] receive event result none", id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsm_protocol::{Format, FEATURE_REQUEST_ID};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::Receiver;
+    use tokio::sync::watch;
+    use tokio::time::timeout;
+    use crate::shard::{ClientMap, EventLoop};
+    use crate::testing::{settings, CLIENT};
+
+    const MAX_INFLIGHT: usize = 2;
+    // 这段时间内没有收到事件视为连接已暂停读取
+    const IDLE: Duration = Duration::from_millis(100);
+
+    // 启动一个连接任务，握手协商请求编号；事件不经过事件循环，由测试取走并响应
+    async fn connect() -> (TcpStream, Receiver<Event>, ClientMap) {
+        let (shards, mut loops) = Shards::new(1, false, watch::channel(settings()).1);
+        let EventLoop { receiver, client_map, .. } = loops.remove(0);
+        let context = Arc::new(Context {
+            shards: Arc::new(shards),
+            throttle: Arc::new(WriteThrottle::new(None, None)),
+            passwords: Arc::default(),
+            users: Arc::default(),
+            admin_ops: false,
+            ping_interval: None,
+            request_timeout: None,
+            max_inflight: MAX_INFLIGHT,
+        });
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let mut socket = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        tokio::spawn(serve(server, CLIENT.to_string(), context));
+        assert_eq!(socket.read_u8().await.unwrap(), HELLO_NUM);
+        socket.write_all(&[HANDSHAKE_NUM, PROTOCOL_VERSION]).await.unwrap();
+        socket.write_u32(FEATURE_REQUEST_ID).await.unwrap();
+        let mut res = [0; 5];
+        socket.read_exact(&mut res).await.unwrap();
+        (socket, receiver, client_map)
+    }
+
+    async fn send(socket: &mut TcpStream, req: u32, request: Request) {
+        let mut frame = Vec::new();
+        request.encode(Some(req), Format::new(FEATURE_REQUEST_ID), &mut frame);
+        socket.write_all(&frame).await.unwrap();
+    }
+
+    fn set(key: &[u8]) -> Request {
+        Request::SET { key: key.to_vec(), value: Some(b"v".to_vec()) }
+    }
+
+    async fn respond(client_map: &ClientMap, res: EventRes) {
+        client_map.get_mut(CLIENT).unwrap().send_event_res(res).await;
+    }
+
+    // 未完成的请求达到上限后不再读取，有请求完成后继续
+    #[tokio::test]
+    async fn pause_reading_at_limit() {
+        let (mut socket, mut events, client_map) = connect().await;
+        for req in 1..=4 {
+            send(&mut socket, req, set(b"k")).await;
+        }
+        for req in 1..=MAX_INFLIGHT as u32 {
+            assert!(matches!(events.recv().await, Some(Event::SET { req: Some(r), .. }) if r == req));
+        }
+        assert!(timeout(IDLE, events.recv()).await.is_err());
+
+        respond(&client_map, EventRes::SET { id: CLIENT.to_string(), req: Some(1) }).await;
+        assert!(matches!(events.recv().await, Some(Event::SET { req: Some(3), .. })));
+        assert!(timeout(IDLE, events.recv()).await.is_err());
+        respond(&client_map, EventRes::SET { id: CLIENT.to_string(), req: Some(2) }).await;
+        assert!(matches!(events.recv().await, Some(Event::SET { req: Some(4), .. })));
+    }
+
+    // 事务中缓存的 op 计入上限，最多缓存 MAX_INFLIGHT - 1 个，仍能读到 EXEC
+    #[tokio::test]
+    async fn exec_at_limit() {
+        let (mut socket, mut events, client_map) = connect().await;
+        send(&mut socket, 1, Request::MULTI).await;
+        assert!(matches!(events.recv().await, Some(Event::MULTI { .. })));
+        respond(&client_map, EventRes::MULTI { id: CLIENT.to_string(), req: Some(1) }).await;
+        send(&mut socket, 2, set(b"k")).await;
+        send(&mut socket, 3, Request::EXEC).await;
+        assert!(matches!(events.recv().await, Some(Event::SET { .. })));
+        assert!(matches!(events.recv().await, Some(Event::EXEC { .. })));
+    }
+
+    // 事务中的 op 超过上限时断开连接，不会无限缓存
+    #[tokio::test]
+    async fn reject_long_transaction() {
+        let (mut socket, mut events, client_map) = connect().await;
+        send(&mut socket, 1, Request::MULTI).await;
+        assert!(matches!(events.recv().await, Some(Event::MULTI { .. })));
+        respond(&client_map, EventRes::MULTI { id: CLIENT.to_string(), req: Some(1) }).await;
+        for req in 2..=3 {
+            send(&mut socket, req, set(b"k")).await;
+        }
+        assert!(matches!(events.recv().await, Some(Event::SET { req: Some(2), .. })));
+        let mut rest = Vec::new();
+        let _ = timeout(Duration::from_secs(5), socket.read_to_end(&mut rest)).await.expect("Connection not closed");
+        assert!(!client_map.contains_key(CLIENT));
+        assert!(events.try_recv().is_err());
+    }
+}
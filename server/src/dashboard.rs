@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error, info, warn};
+use crate::admin::{call, named_stats};
+use crate::dump::json_string;
+use crate::event::{Event, EventRes};
+use crate::shard::Shards;
+use crate::throttle::WriteThrottle;
+use crate::utils::get_id;
+
+// 管理页面，和管理端口一样只监听本机；每个 HTTP 请求一个连接，回复后关闭
+// GET  /                          页面，每秒请求下面的接口刷新
+// GET  /api/stats                 统计项和写限流的速率，同管理端口的 stats
+// GET  /api/clients               连接 id
+// GET  /api/keys?prefix=p         以 p 开头的 key，最多 DASHBOARD_KEYS 个，以及总数；不是 UTF-8 的字节显示为替换字符
+// POST /api/flush                 所有内存表落盘
+// POST /api/compact?start=&end=   落盘并合并与 [start, end) 重叠的 SST 文件，返回合并的文件数
+// POST 必须带 DASHBOARD_HEADER，其他网站的页面跨域发送时浏览器先预检，这里不回复 CORS，请求不会发出
+// 没有延迟统计和备份命令，页面不提供
+
+const PAGE: &str = include_str!("dashboard.html");
+const DASHBOARD_HEADER: &str = "x-lsm-dashboard:";
+// 请求头的最大长度和等待时间
+const MAX_REQUEST_HEAD: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// 一次返回的 key 数
+const DASHBOARD_KEYS: usize = 100;
+
+pub async fn serve(listener: TcpListener, shards: Arc<Shards>, throttle: Arc<WriteThrottle>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let id = format!("dashboard-{}", get_id(&addr.ip().to_string(), addr.port()));
+                let shards = shards.clone();
+                let throttle = throttle.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = request(&id, socket, &shards, &throttle).await {
+                        debug!("Dashboard request from [{}] fail, err = {:?}", id, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Fail to accept dashboard connection; err = {:?}", e);
+            }
+        }
+    }
+}
+
+async fn request(id: &str, mut socket: TcpStream, shards: &Shards, throttle: &WriteThrottle) -> std::io::Result<()> {
+    let Some(head) = read_head(&mut socket).await? else {
+        return respond(&mut socket, "400 Bad Request", "text/plain", "bad request\n").await;
+    };
+    let mut lines = head.lines();
+    let mut parts = lines.next().unwrap_or("").split(' ');
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = parse_query(query);
+    debug!("Dashboard [{}] {} {}", id, method, target);
+    let event = match (method, path) {
+        ("GET", "/") => return respond(&mut socket, "200 OK", "text/html; charset=utf-8", PAGE).await,
+        ("GET", "/api/clients") => {
+            let clients: Vec<String> = shards.clients().iter().map(|client| json_string(client)).collect();
+            return respond(&mut socket, "200 OK", "application/json", &format!("[{}]", clients.join(","))).await;
+        }
+        ("GET", "/api/stats") => Event::STATS { id: id.to_string(), req: None },
+        ("GET", "/api/keys") => Event::PREFIX { id: id.to_string(), req: None, prefix: query.get("prefix").cloned().unwrap_or_default(), with_values: false },
+        ("POST", "/api/flush") => Event::FLUSH { id: id.to_string(), req: None },
+        ("POST", "/api/compact") => Event::COMPACT {
+            id: id.to_string(),
+            req: None,
+            start: query.get("start").cloned().unwrap_or_default(),
+            end: query.get("end").cloned().unwrap_or_default(),
+        },
+        _ => return respond(&mut socket, "404 Not Found", "text/plain", "not found\n").await,
+    };
+    if method == "POST" {
+        if !lines.any(|line| line.to_ascii_lowercase().starts_with(DASHBOARD_HEADER)) {
+            return respond(&mut socket, "403 Forbidden", "text/plain", "missing dashboard header\n").await;
+        }
+        info!("Dashboard [{}] {} {}", id, method, target);
+    }
+    // 和管理端口的连接一样注册为客户端，回复后注销
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    let mut event_tx = shards.connect(id, client_tx);
+    let mut responses = call(&mut event_tx, &mut client_rx, event).await;
+    shards.disconnect(id);
+    let body = match responses.pop() {
+        Some(EventRes::STATS { stats, .. }) => {
+            let stats: Vec<String> = named_stats(stats, throttle).iter().map(|(name, stat)| format!("\"{}\":{}", name, stat)).collect();
+            format!("{{{}}}", stats.join(","))
+        }
+        // 最后一条是结束标记
+        Some(EventRes::SCAN { .. }) => {
+            let keys: Vec<String> = responses.iter().take(DASHBOARD_KEYS).filter_map(|res| match res {
+                EventRes::SCAN { entry: Some((key, _)), .. } => Some(json_string(&String::from_utf8_lossy(key))),
+                _ => None,
+            }).collect();
+            format!("{{\"total\":{},\"keys\":[{}]}}", responses.len(), keys.join(","))
+        }
+        Some(EventRes::FLUSH { .. }) => String::from("{\"ok\":true}"),
+        Some(EventRes::COMPACT { files, .. }) => format!("{{\"files\":{}}}", files),
+        Some(EventRes::ERROR { code, .. }) => {
+            warn!("Dashboard [{}] {} error {}", id, target, code);
+            return respond(&mut socket, "500 Internal Server Error", "application/json", &format!("{{\"error\":{}}}", code)).await;
+        }
+        _ => return respond(&mut socket, "503 Service Unavailable", "application/json", "{\"error\":\"no response\"}").await,
+    };
+    respond(&mut socket, "200 OK", "application/json", &body).await
+}
+
+// 读到请求头结束，返回请求行和各个头；超时、过长或不是 UTF-8 时为 None
+async fn read_head(socket: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = match timeout(REQUEST_TIMEOUT, socket.read(&mut buf)).await {
+            Ok(read) => read?,
+            Err(_) => return Ok(None),
+        };
+        if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8(head).ok())
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    let response = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body);
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+// a=1&b=%2F 解码为字节，+ 为空格
+fn parse_query(query: &str) -> HashMap<String, Vec<u8>> {
+    query.split('&').filter(|pair| !pair.is_empty()).map(|pair| {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (String::from_utf8_lossy(&percent_decode(name)).into_owned(), percent_decode(value))
+    }).collect()
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => out.push(b' '),
+            (byte, _) => out.push(byte),
+        }
+        i += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::watch;
+    use crate::admin::ADMIN_IP;
+    use crate::shard::EventLoop;
+    use crate::testing::{settings, spawn_loop};
+
+    async fn http(addr: std::net::SocketAddr, method: &str, target: &str) -> (String, String) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(format!("{} {} HTTP/1.1\r\nHost: localhost\r\nX-LSM-Dashboard: 1\r\n\r\n", method, target).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    #[test]
+    fn query() {
+        let query = parse_query("prefix=user%3A1+a&start=&x=%zz&&end");
+        assert_eq!(query["prefix"], b"user:1 a");
+        assert_eq!(query["start"], b"");
+        assert_eq!(query["x"], b"%zz");
+        assert_eq!(query["end"], b"");
+    }
+
+    #[tokio::test]
+    async fn api() {
+        let (_settings_tx, settings_rx) = watch::channel(settings());
+        let (shards, mut loops) = Shards::new(1, false, settings_rx);
+        let EventLoop { receiver, client_map, .. } = loops.remove(0);
+        spawn_loop("dashboard-api", receiver, client_map, None, |_| {}).await;
+        let shards = Arc::new(shards);
+        let listener = TcpListener::bind((ADMIN_IP, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, shards.clone(), Arc::new(WriteThrottle::new(Some(10), None))));
+
+        // 另一个连接写入数据，连接还在时出现在 clients 中
+        let (client_tx, mut client_rx) = mpsc::channel(16);
+        let mut event_tx = shards.connect("writer", client_tx);
+        for i in 0..120 {
+            let set = Event::SET { id: String::from("writer"), req: None, key: format!("user:{:03}", i).into_bytes(), value: Some(b"v".to_vec()) };
+            call(&mut event_tx, &mut client_rx, set).await;
+        }
+        call(&mut event_tx, &mut client_rx, Event::SET { id: String::from("writer"), req: None, key: b"other".to_vec(), value: Some(b"v".to_vec()) }).await;
+
+        let (status, body) = http(addr, "GET", "/").await;
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        assert!(body.contains("<canvas"));
+        let (_, body) = http(addr, "GET", "/api/stats").await;
+        assert!(body.starts_with("{\"keys\":121,") && body.ends_with("\"write_ops_per_sec\":10,\"write_bytes_per_sec\":0}"), "{}", body);
+        assert_eq!(http(addr, "GET", "/api/clients").await.1, "[\"writer\"]");
+
+        let (_, body) = http(addr, "GET", "/api/keys?prefix=user%3A").await;
+        assert!(body.starts_with("{\"total\":120,\"keys\":[\"user:000\",") && body.ends_with("\"user:099\"]}"), "{}", body);
+        assert_eq!(http(addr, "GET", "/api/keys?prefix=o").await.1, "{\"total\":1,\"keys\":[\"other\"]}");
+
+        assert_eq!(http(addr, "POST", "/api/flush").await.1, "{\"ok\":true}");
+        assert_eq!(http(addr, "POST", "/api/compact").await.1, "{\"files\":1}");
+        assert_eq!(http(addr, "GET", "/api/flush").await.0, "HTTP/1.0 404 Not Found");
+        // 没有 DASHBOARD_HEADER 的 POST 拒绝
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"POST /api/flush HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.0 403"), "{}", response);
+        // 页面的请求注销后不留在连接表中
+        assert_eq!(shards.clients(), ["writer"]);
+    }
+}
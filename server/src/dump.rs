@@ -0,0 +1,458 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+use tracing::{info, warn};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use crate::event::SizeLimits;
+use crate::storage::{LsmExt, LsmStorage, StorageEngine};
+use crate::utils::now_millis;
+
+// key 和 value 都是 UTF-8 时原样写入；否则两者都写成小写十六进制，encoding 为 HEX_ENCODING
+const HEX_ENCODING: &str = "hex";
+
+// 导出文件的格式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    // 每行一个 JSON 对象：{"key":"..","value":"..","expire_at":..,"encoding":"hex"}
+    // expire_at 为 unix 毫秒，没有过期时间时省略；encoding 只在十六进制时出现
+    JsonLines,
+    // 第一行是表头 key,value,expire_at,encoding，没有的字段为空；含逗号、引号或换行的字段加引号，引号写两次
+    Csv,
+}
+
+impl DumpFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "jsonl" | "json" => Some(DumpFormat::JsonLines),
+            "csv" => Some(DumpFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        String::from(s)
+    }
+}
+
+// 一条记录编码为一行，不含换行
+fn encode(key: &[u8], value: &[u8], expire_at: Option<u64>, format: DumpFormat) -> String {
+    let (key, value, encoding) = match (std::str::from_utf8(key), std::str::from_utf8(value)) {
+        (Ok(key), Ok(value)) => (String::from(key), String::from(value), None),
+        _ => (hex(key), hex(value), Some(HEX_ENCODING)),
+    };
+    match format {
+        DumpFormat::JsonLines => {
+            let mut line = format!("{{\"key\":{},\"value\":{}", json_string(&key), json_string(&value));
+            if let Some(expire_at) = expire_at {
+                line.push_str(&format!(",\"expire_at\":{}", expire_at));
+            }
+            if let Some(encoding) = encoding {
+                line.push_str(&format!(",\"encoding\":{}", json_string(encoding)));
+            }
+            line.push('}');
+            line
+        }
+        DumpFormat::Csv => format!("{},{},{},{}", csv_field(&key), csv_field(&value), expire_at.map(|e| e.to_string()).unwrap_or_default(), encoding.unwrap_or_default()),
+    }
+}
+
+// 把所有未删除、未过期的 key 按顺序写入 path，返回导出的 key 数量
+pub fn dump_file(storage: &LsmStorage, path: &str, format: DumpFormat) -> usize {
+    let file = File::create(path).unwrap_or_else(|e| panic!("Create dump file {} fail, err = {:?}", path, e));
+    let mut writer = BufWriter::new(file);
+    let err_message = "Write dump file fail";
+    if format == DumpFormat::Csv {
+        writeln!(writer, "key,value,expire_at,encoding").expect(err_message);
+    }
+    let mut keys = 0;
+    storage.for_each_live(now_millis(), &mut |key, value, expire_at| {
+        writeln!(writer, "{}", encode(key, value, expire_at, format)).expect(err_message);
+        keys += 1;
+    });
+    writer.flush().expect(err_message);
+    info!("Dump {} keys to {}", keys, path);
+    keys
+}
+
+// 导入时每处理这么多条记录打印一次进度
+const IMPORT_PROGRESS_INTERVAL: usize = 100000;
+// 存储引擎暂停写入时等待的间隔
+const IMPORT_STALL_WAIT: Duration = Duration::from_millis(10);
+
+// 导入的一条记录
+struct Record {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    expire_at: Option<u64>,
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("invalid hex {:?}", s));
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("invalid hex {:?}", s))).collect()
+}
+
+fn record(key: String, value: String, expire_at: Option<u64>, encoding: Option<&str>) -> Result<Record, String> {
+    match encoding {
+        None | Some("") => Ok(Record { key: key.into_bytes(), value: value.into_bytes(), expire_at }),
+        Some(HEX_ENCODING) => Ok(Record { key: unhex(&key)?, value: unhex(&value)?, expire_at }),
+        Some(other) => Err(format!("unknown encoding {:?}", other)),
+    }
+}
+
+enum JsonValue {
+    Str(String),
+    Int(u64),
+    Null,
+}
+
+// 只解析一层的 JSON 对象，字段值为字符串、非负整数或 null
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            c => Err(format!("expect {:?}, found {:?}", expected, c)),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| format!("invalid \\u escape {:?}", digits))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next().ok_or("unterminated string")? {
+                '"' => return Ok(out),
+                '\\' => match self.chars.next().ok_or("unterminated string")? {
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    '/' => out.push('/'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        // 代理对
+                        if (0xd800..0xdc00).contains(&code) {
+                            if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+                                return Err(String::from("unpaired surrogate"));
+                            }
+                            let low = self.hex4()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return Err(String::from("unpaired surrogate"));
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        out.push(char::from_u32(code).ok_or("invalid \\u escape")?);
+                    }
+                    c => return Err(format!("invalid escape \\{}", c)),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<JsonValue, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('"') => Ok(JsonValue::Str(self.string()?)),
+            Some('n') => {
+                let word: String = (0..4).filter_map(|_| self.chars.next()).collect();
+                if word == "null" { Ok(JsonValue::Null) } else { Err(format!("invalid value {:?}", word)) }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut digits = String::new();
+                while let Some(c) = self.chars.next_if(char::is_ascii_digit) {
+                    digits.push(c);
+                }
+                digits.parse().map(JsonValue::Int).map_err(|_| format!("invalid number {}", digits))
+            }
+            c => Err(format!("unsupported value starting with {:?}", c)),
+        }
+    }
+
+    // {"key":"..","value":"..","expire_at":..,"encoding":".."}，其他字段忽略
+    fn record(line: &str) -> Result<Record, String> {
+        let mut parser = JsonParser { chars: line.chars().peekable() };
+        let (mut key, mut value, mut expire_at, mut encoding) = (None, None, None, None);
+        parser.expect('{')?;
+        parser.skip_whitespace();
+        if parser.chars.next_if_eq(&'}').is_none() {
+            loop {
+                let name = parser.string()?;
+                parser.expect(':')?;
+                match (name.as_str(), parser.value()?) {
+                    ("key", JsonValue::Str(s)) => key = Some(s),
+                    ("value", JsonValue::Str(s)) => value = Some(s),
+                    ("expire_at", JsonValue::Int(n)) => expire_at = Some(n),
+                    ("encoding", JsonValue::Str(s)) => encoding = Some(s),
+                    ("expire_at" | "encoding", JsonValue::Null) => {}
+                    ("key" | "value" | "expire_at" | "encoding", _) => return Err(format!("invalid type of field {}", name)),
+                    _ => {}
+                }
+                parser.skip_whitespace();
+                match parser.chars.next() {
+                    Some(',') => continue,
+                    Some('}') => break,
+                    c => return Err(format!("expect ',' or '}}', found {:?}", c)),
+                }
+            }
+        }
+        parser.skip_whitespace();
+        if let Some(c) = parser.chars.next() {
+            return Err(format!("unexpected {:?} after object", c));
+        }
+        record(key.ok_or("missing key")?, value.ok_or("missing value")?, expire_at, encoding.as_deref())
+    }
+}
+
+// 一行 CSV 拆成字段；引号内的换行已拼接在 line 中
+fn csv_fields(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next().ok_or("unterminated quote")? {
+                    '"' if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',')) {
+                return Err(String::from("unexpected character after quote"));
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                field.push(c);
+            }
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+// 表头中 key,value,expire_at,encoding 各自的列，key 和 value 必须有
+struct CsvColumns {
+    key: usize,
+    value: usize,
+    expire_at: Option<usize>,
+    encoding: Option<usize>,
+}
+
+impl CsvColumns {
+    fn parse(header: &str) -> Result<Self, String> {
+        let names = csv_fields(header)?;
+        let column = |name: &str| names.iter().position(|n| n == name);
+        Ok(Self {
+            key: column("key").ok_or("missing key column")?,
+            value: column("value").ok_or("missing value column")?,
+            expire_at: column("expire_at"),
+            encoding: column("encoding"),
+        })
+    }
+
+    fn record(&self, line: &str) -> Result<Record, String> {
+        let mut fields = csv_fields(line)?;
+        let mut take = |index: usize| fields.get_mut(index).map(std::mem::take);
+        let key = take(self.key).ok_or("missing key field")?;
+        let value = take(self.value).ok_or("missing value field")?;
+        let expire_at = match self.expire_at.and_then(&mut take).filter(|s| !s.is_empty()) {
+            Some(s) => Some(s.parse().map_err(|_| format!("invalid expire_at {:?}", s))?),
+            None => None,
+        };
+        let encoding = self.encoding.and_then(take);
+        record(key, value, expire_at, encoding.as_deref())
+    }
+}
+
+// 读取 dump_file 格式的文件，通过正常的写入路径（WAL 和内存表）写入；storage 为 None 时只检查不写入
+// 出错时写入模式直接停止，只检查时记录并继续，最后汇总
+pub async fn import_file(mut storage: Option<&mut LsmStorage>, path: &str, format: DumpFormat, limits: &SizeLimits) {
+    let file = tokio::fs::File::open(path).await.unwrap_or_else(|e| panic!("Open import file {} fail, err = {:?}", path, e));
+    let mut lines = BufReader::new(file).lines();
+    let dry_run = storage.is_none();
+    let mut columns = None;
+    let mut line_number = 0;
+    let (mut records, mut expired, mut invalid): (usize, usize, usize) = (0, 0, 0);
+    let now = now_millis();
+    loop {
+        let Some(mut line) = lines.next_line().await.unwrap_or_else(|e| panic!("Read import file {} fail, err = {:?}", path, e)) else {
+            break;
+        };
+        line_number += 1;
+        let start_line = line_number;
+        // CSV 引号内的换行，引号个数为奇数时还没结束
+        while format == DumpFormat::Csv && line.matches('"').count() % 2 == 1 {
+            match lines.next_line().await.unwrap_or_else(|e| panic!("Read import file {} fail, err = {:?}", path, e)) {
+                Some(next) => {
+                    line_number += 1;
+                    line.push('\n');
+                    line.push_str(&next);
+                }
+                None => break,
+            }
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = match (format, &columns) {
+            (DumpFormat::JsonLines, _) => JsonParser::record(&line),
+            (DumpFormat::Csv, Some(columns)) => CsvColumns::record(columns, &line),
+            (DumpFormat::Csv, None) => {
+                columns = Some(CsvColumns::parse(&line).unwrap_or_else(|e| panic!("Import file {} line {}, invalid header: {}", path, start_line, e)));
+                continue;
+            }
+        };
+        let parsed = parsed.and_then(|record| {
+            if record.key.len() > limits.max_key || record.value.len() > limits.max_value {
+                Err(String::from("key or value too large"))
+            } else {
+                Ok(record)
+            }
+        });
+        let record = match parsed {
+            Ok(record) => record,
+            Err(e) if dry_run => {
+                warn!("Import file {} line {}, {}", path, start_line, e);
+                invalid += 1;
+                continue;
+            }
+            Err(e) => panic!("Import file {} line {}, {}", path, start_line, e),
+        };
+        records += 1;
+        // 已过期的记录不写入
+        if record.expire_at.is_some_and(|expire_at| expire_at <= now) {
+            expired += 1;
+        } else if let Some(storage) = storage.as_mut() {
+            // 落盘跟不上时等待，和服务端拒绝写入的条件相同
+            while storage.write_stalled().await {
+                tokio::time::sleep(IMPORT_STALL_WAIT).await;
+            }
+            match record.expire_at {
+                Some(expire_at) => storage.put_expire(record.key, record.value, expire_at).await,
+                None => storage.put(record.key, record.value).await,
+            }
+        }
+        if records.is_multiple_of(IMPORT_PROGRESS_INTERVAL) {
+            info!("Import file {}, {} records", path, records);
+            // 没有事件循环，顺便删除合并留下的旧文件
+            if let Some(storage) = storage.as_mut() {
+                storage.collect_garbage().await;
+            }
+        }
+    }
+    if let Some(storage) = storage {
+        storage.sync_wal().await;
+        storage.wait_background().await;
+    }
+    if dry_run {
+        info!("Dry run import file {} done, {} records, {} expired, {} invalid", path, records, expired, invalid);
+    } else {
+        info!("Import file {} done, {} records, {} expired and skipped", path, records, expired);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{open, settings, temp_path};
+
+    type Entries = Vec<(Vec<u8>, Vec<u8>, Option<u64>)>;
+
+    fn live(storage: &LsmStorage) -> Entries {
+        let mut entries = Vec::new();
+        storage.for_each_live(now_millis(), &mut |key, value, expire_at| entries.push((key.to_vec(), value.to_vec(), expire_at)));
+        entries
+    }
+
+    // 文本和二进制的 key、已删除的 key、带过期时间和已过期的 key，部分在 SST 文件中
+    async fn source(name: &str) -> LsmStorage {
+        let mut storage = open(name).await;
+        let expire_at = now_millis() + 3_600_000;
+        storage.put(b"deleted".to_vec(), b"old".to_vec()).await;
+        storage.put(vec![0x00, 0x9f, 0xff], vec![0xc3, 0x28, 0x00]).await;
+        storage.put(b"text".to_vec(), b"a, \"quoted\"\nline".to_vec()).await;
+        storage.flush().await;
+        storage.wait_background().await;
+        storage.delete(b"deleted".to_vec()).await;
+        storage.put(b"deleted-in-memtable".to_vec(), b"v".to_vec()).await;
+        storage.delete(b"deleted-in-memtable".to_vec()).await;
+        storage.put_expire(b"ttl".to_vec(), b"v".to_vec(), expire_at).await;
+        storage.put_expire(b"expired".to_vec(), b"v".to_vec(), now_millis() - 1).await;
+        storage
+    }
+
+    async fn round_trip(name: &str, format: DumpFormat) {
+        let storage = source(&format!("{}-source", name)).await;
+        let entries = live(&storage);
+        let keys: Vec<&[u8]> = entries.iter().map(|(key, _, _)| key.as_slice()).collect();
+        assert_eq!(keys, [&[0x00, 0x9f, 0xff][..], b"text", b"ttl"]);
+        assert!(entries[2].2.is_some());
+
+        let path = format!("{}/dump", temp_path(&format!("{}-file", name)));
+        assert_eq!(dump_file(&storage, &path, format), 3);
+        let mut imported = open(&format!("{}-target", name)).await;
+        import_file(Some(&mut imported), &path, format, &settings().limits).await;
+        assert_eq!(live(&imported), entries);
+
+        // 再导出一次，文件相同
+        let again = format!("{}.again", path);
+        dump_file(&imported, &again, format);
+        assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&again).unwrap());
+    }
+
+    #[tokio::test]
+    async fn json_lines_round_trip() {
+        round_trip("dump-jsonl", DumpFormat::JsonLines).await;
+    }
+
+    #[tokio::test]
+    async fn csv_round_trip() {
+        round_trip("dump-csv", DumpFormat::Csv).await;
+    }
+}
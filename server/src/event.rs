@@ -1,263 +1,2289 @@
-use std::fs::create_dir_all;
-use std::io::SeekFrom;
+use std::collections::{HashMap, VecDeque};
+use std::iter::once;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
 use dashmap::DashMap;
-use log::{info, warn};
-use tokio::fs::{File, try_exists};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tokio::select;
 use tokio::sync::mpsc::Receiver;
-use tokio::sync::Mutex;
+use tokio::sync::watch;
+use tokio::time::{interval, Duration, Interval};
+use crate::acl::Access;
+use crate::audit::{AUDIT_OP_APPEND, AUDIT_OP_DELETE, AUDIT_OP_DELETE_RANGE, AUDIT_OP_FLUSHALL, AUDIT_OP_INGEST, AUDIT_OP_SET, AuditLog};
 use crate::client::Client;
-use crate::trie::Trie;
+use crate::cluster::Ring;
+use crate::health::Readiness;
+use crate::hook::HookDispatcher;
+use crate::merge::MergeRegistry;
+use crate::raft::{RaftCommand, RaftFeed, RaftSnapshot};
+use crate::replication::{ReplicaFeed, ReplicaSnapshot, Replicated, SnapshotFeed, SnapshotRequest};
+use crate::storage::{Durability, KeyMeta, LsmExt, ReadView, StorageOptions, Tunables};
+use crate::throttle::WriteThrottle;
+use crate::utils::{now_millis, OpsCounter};
 
-pub const OP_GET: u8 = 0xc1;
-pub const OP_SET: u8 = 0xc2;
+// 协议的常量和长度字段的编码在 lsm-protocol 中，存储格式也使用同样的长度字段
+pub use lsm_protocol::{OP_APPEND, OP_AUTH, OP_AUTH_USER, OP_CAS, OP_EXEC, OP_EXISTS, OP_GET, OP_GETSET, OP_INCR, OP_MGET, OP_MSET, OP_MULTI, OP_PING, OP_PONG, OP_SET};
+pub use lsm_protocol::{ERR_BUSY, ERR_CROSS_SHARD, ERR_DENIED, ERR_KEY_TOO_LARGE, ERR_READ_ONLY, ERR_TIMEOUT, ERR_UNCOMMITTED, ERR_UNSORTED, ERR_VALUE_TOO_LARGE};
+pub use lsm_protocol::{push_value, read_len, Limits, LEN_MASK, LONG_LEN_SIZE, MAX_LEN, SHORT_LEN_SIZE};
 
-pub const RES_GET: u8 = 0x81;
-pub const RES_SET: u8 = 0x82;
+// MULTI 和 EXEC 之间允许的 op
+pub const TX_OPS: [u8; 10] = [OP_GET, OP_EXISTS, OP_MGET, OP_SET, OP_MSET, OP_CAS, OP_INCR, OP_APPEND, OP_GETSET, OP_EXEC];
 
-pub const LEN_MASK: u16 = 0x7fff;
-pub const NONE_VALUE_LEN: u16 = 0xffff;
+// 每秒清理一次过期的 key，每次最多清理 EXPIRE_SWEEP_LIMIT 个，避免长时间阻塞事件循环
+const EXPIRE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const EXPIRE_SWEEP_LIMIT: usize = 1000;
 
-const WAL_FILE_PREFIX: &str = "WAL_FILE_";
-const LOG_FILE_PREFIX: &str = "LOG_FILE_";
-const INDEX_FILE: &str = "INDEX";
+// 命名空间的数据在 data_path/NAMESPACE_DIR/name 下，默认命名空间直接在 data_path 下
+const NAMESPACE_DIR: &str = "ns";
+// 命名空间名字最长 64 字节，只能包含字母、数字、'_' 和 '-'
+const MAX_NAMESPACE_LEN: usize = 64;
+// 默认最多打开的命名空间数，包括默认命名空间
+pub const DEFAULT_MAX_NAMESPACES: usize = 16;
 
-const FILE_BATCH: usize = 2;
+// 默认的 key 和 value 长度上限
+pub const DEFAULT_MAX_KEY_SIZE: usize = 64 * 1024;
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+// 默认的一个请求的长度上限
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 256 * 1024 * 1024;
 
+// 请求中 key 和 value 的长度上限，超过时返回 RES_ERROR，不执行
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeLimits {
+    pub max_key: usize,
+    // 不超过 MAX_LEN；APPEND 和 MERGE 的结果也不能超过
+    pub max_value: usize,
+    // 一个请求的最大字节数，连接解码时检查，超过时断开；不小于一个 key 和 value 的长度之和
+    pub max_request: usize,
+}
+
+impl SizeLimits {
+    // 连接解码请求时的上限：任一字段超过 key 和 value 中较大的上限时不等数据到齐就断开
+    // 在两个上限之间的 key 解码后由 check_size 返回 ERR_KEY_TOO_LARGE
+    pub fn decode_limits(&self) -> Limits {
+        Limits { max_field: self.max_key.max(self.max_value), max_request: self.max_request }
+    }
+}
+
+// 运行中可以修改的配置，SIGHUP 重新读取配置文件后通过 watch 通道发给事件循环和连接
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub limits: SizeLimits,
+    pub tunables: Tunables,
+}
+
+// id 为客户端连接，req 为客户端的请求编号，响应中原样返回；未协商请求编号时为 None
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Event {
     GET {
         id: String,
+        req: Option<u32>,
         key: Vec<u8>,
     },
     SET {
         id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    },
+    EXISTS {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+    },
+    SCAN {
+        id: String,
+        req: Option<u32>,
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    PREFIX {
+        id: String,
+        req: Option<u32>,
+        prefix: Vec<u8>,
+        with_values: bool,
+    },
+    MGET {
+        id: String,
+        req: Option<u32>,
+        keys: Vec<Vec<u8>>,
+    },
+    // ttl 秒后过期，返回 EventRes::SET
+    SETEX {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    },
+    // 批量写，value 为 None 表示删除
+    SETM {
+        id: String,
+        req: Option<u32>,
+        entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    },
+    // 当前值等于 expected 时写入 value；None 表示不存在 / 删除
+    CAS {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Option<Vec<u8>>,
+    },
+    // 不返回 value 的元数据
+    META {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+    },
+    // GET 同时返回版本号
+    GETV {
+        id: String,
+        req: Option<u32>,
         key: Vec<u8>,
+    },
+    // 当前版本号等于 version 时才写入，version 为 0 表示 key 不存在；value 为 None 时删除
+    SETV {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+        version: u64,
         value: Option<Vec<u8>>,
     },
+    // value 按十进制整数解析，不存在视为 0；delta 为负数即 DECR
+    INCR {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+        delta: i64,
+    },
+    // 用名为 name 的合并函数把 operand 合并到原来的 value 上
+    MERGE {
+        id: String,
+        req: Option<u32>,
+        name: Vec<u8>,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+    APPEND {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+    },
+    DBSIZE {
+        id: String,
+        req: Option<u32>,
+    },
+    // 写入 value 并返回旧值
+    GETSET {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // 管理命令，清空所有数据
+    FLUSHALL {
+        id: String,
+        req: Option<u32>,
+    },
+    // 经过事件循环，PONG 说明事件循环也在工作
+    PING {
+        id: String,
+        req: Option<u32>,
+        payload: u64,
+    },
+    STATS {
+        id: String,
+        req: Option<u32>,
+    },
+    // 开始事务，之后的 op 缓存到 EXEC 时一起执行
+    MULTI {
+        id: String,
+        req: Option<u32>,
+    },
+    // 多个分片时，转发任务发现事务跨分片后 abort 为 ERR_CROSS_SHARD，各分片整个拒绝事务
+    EXEC {
+        id: String,
+        req: Option<u32>,
+        abort: Option<u8>,
+    },
+    // 密码在连接上校验，经过事件循环保证结果和之前请求的结果顺序一致
+    // 成功时 access 为认证的用户，之后由事件循环按它检查权限；None 表示用密码认证，不限制
+    AUTH {
+        id: String,
+        req: Option<u32>,
+        ok: bool,
+        access: Option<Arc<Access>>,
+    },
+    // 匹配 glob 模式的 key，按 SCAN 的方式逐条返回，value 为空
+    KEYS {
+        id: String,
+        req: Option<u32>,
+        pattern: Vec<u8>,
+    },
+    // 之后该连接的命令都在 name 命名空间中执行，空名字为默认命名空间
+    SELECT {
+        id: String,
+        req: Option<u32>,
+        name: Vec<u8>,
+    },
+    // 在当前命名空间打开快照，之后该连接在这个命名空间的读取都从快照进行，已打开的快照先释放
+    SNAPSHOT {
+        id: String,
+        req: Option<u32>,
+    },
+    RELEASE {
+        id: String,
+        req: Option<u32>,
+    },
+    // 在当前命名空间落盘并合并与 [start, end) 重叠的 SST 文件，end 为空表示没有上界
+    COMPACT {
+        id: String,
+        req: Option<u32>,
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    // 校验当前命名空间的 SST 和 WAL 文件
+    SCRUB {
+        id: String,
+        req: Option<u32>,
+    },
+    // 按 key 严格递增的数据直接写成当前命名空间的 SST 文件，不经过 WAL
+    INGEST {
+        id: String,
+        req: Option<u32>,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    // 删除 [start, end) 中所有的 key，end 为空表示没有上界
+    #[allow(non_camel_case_types)]
+    DELETE_RANGE {
+        id: String,
+        req: Option<u32>,
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    // key 的前缀中存在的最长的一个
+    LPM {
+        id: String,
+        req: Option<u32>,
+        key: Vec<u8>,
+    },
+    // 集群模式的节点和哈希环，不是集群模式时没有节点
+    CLUSTER {
+        id: String,
+        req: Option<u32>,
+    },
+    // 管理端口：所有已打开命名空间的内存表落盘
+    FLUSH {
+        id: String,
+        req: Option<u32>,
+    },
+    // 管理端口：sync 所有已打开命名空间的 WAL，响应后进程退出
+    SHUTDOWN {
+        id: String,
+        req: Option<u32>,
+    },
+}
+
+impl Event {
+    fn id(&self) -> &String {
+        match self {
+            Event::GET { id, .. } | Event::SET { id, .. } | Event::EXISTS { id, .. } |
+            Event::SCAN { id, .. } | Event::PREFIX { id, .. } | Event::MGET { id, .. } |
+            Event::SETEX { id, .. } | Event::SETM { id, .. } | Event::CAS { id, .. } |
+            Event::INCR { id, .. } | Event::APPEND { id, .. } | Event::DBSIZE { id, .. } |
+            Event::GETSET { id, .. } | Event::FLUSHALL { id, .. } | Event::PING { id, .. } |
+            Event::STATS { id, .. } | Event::MULTI { id, .. } | Event::EXEC { id, .. } |
+            Event::AUTH { id, .. } | Event::SELECT { id, .. } | Event::KEYS { id, .. } |
+            Event::GETV { id, .. } | Event::SETV { id, .. } | Event::MERGE { id, .. } |
+            Event::META { id, .. } | Event::SNAPSHOT { id, .. } | Event::RELEASE { id, .. } |
+            Event::COMPACT { id, .. } | Event::SCRUB { id, .. } | Event::INGEST { id, .. } |
+            Event::DELETE_RANGE { id, .. } | Event::LPM { id, .. } | Event::FLUSH { id, .. } |
+            Event::SHUTDOWN { id, .. } | Event::CLUSTER { id, .. } => id,
+        }
+    }
+
+    fn req(&self) -> Option<u32> {
+        match self {
+            Event::GET { req, .. } | Event::SET { req, .. } | Event::EXISTS { req, .. } |
+            Event::SCAN { req, .. } | Event::PREFIX { req, .. } | Event::MGET { req, .. } |
+            Event::SETEX { req, .. } | Event::SETM { req, .. } | Event::CAS { req, .. } |
+            Event::INCR { req, .. } | Event::APPEND { req, .. } | Event::DBSIZE { req, .. } |
+            Event::GETSET { req, .. } | Event::FLUSHALL { req, .. } | Event::PING { req, .. } |
+            Event::STATS { req, .. } | Event::MULTI { req, .. } | Event::EXEC { req, .. } |
+            Event::AUTH { req, .. } | Event::SELECT { req, .. } | Event::KEYS { req, .. } |
+            Event::GETV { req, .. } | Event::SETV { req, .. } | Event::MERGE { req, .. } |
+            Event::META { req, .. } | Event::SNAPSHOT { req, .. } | Event::RELEASE { req, .. } |
+            Event::COMPACT { req, .. } | Event::SCRUB { req, .. } | Event::INGEST { req, .. } |
+            Event::DELETE_RANGE { req, .. } | Event::LPM { req, .. } | Event::FLUSH { req, .. } |
+            Event::SHUTDOWN { req, .. } | Event::CLUSTER { req, .. } => *req,
+        }
+    }
+
+    // op 名字，用于日志
+    fn name(&self) -> &'static str {
+        match self {
+            Event::GET { .. } => "GET",
+            Event::SET { .. } => "SET",
+            Event::EXISTS { .. } => "EXISTS",
+            Event::SCAN { .. } => "SCAN",
+            Event::PREFIX { .. } => "PREFIX",
+            Event::MGET { .. } => "MGET",
+            Event::SETEX { .. } => "SETEX",
+            Event::SETM { .. } => "SETM",
+            Event::CAS { .. } => "CAS",
+            Event::META { .. } => "META",
+            Event::GETV { .. } => "GETV",
+            Event::SETV { .. } => "SETV",
+            Event::INCR { .. } => "INCR",
+            Event::MERGE { .. } => "MERGE",
+            Event::APPEND { .. } => "APPEND",
+            Event::DBSIZE { .. } => "DBSIZE",
+            Event::GETSET { .. } => "GETSET",
+            Event::FLUSHALL { .. } => "FLUSHALL",
+            Event::PING { .. } => "PING",
+            Event::STATS { .. } => "STATS",
+            Event::MULTI { .. } => "MULTI",
+            Event::EXEC { .. } => "EXEC",
+            Event::AUTH { .. } => "AUTH",
+            Event::KEYS { .. } => "KEYS",
+            Event::SELECT { .. } => "SELECT",
+            Event::SNAPSHOT { .. } => "SNAPSHOT",
+            Event::RELEASE { .. } => "RELEASE",
+            Event::COMPACT { .. } => "COMPACT",
+            Event::SCRUB { .. } => "SCRUB",
+            Event::INGEST { .. } => "INGEST",
+            Event::DELETE_RANGE { .. } => "DELETE_RANGE",
+            Event::LPM { .. } => "LPM",
+            Event::CLUSTER { .. } => "CLUSTER",
+            Event::FLUSH { .. } => "FLUSH",
+            Event::SHUTDOWN { .. } => "SHUTDOWN",
+        }
+    }
+
+    // 请求中最长的 key 和最长的 value，没有时为 0
+    fn sizes(&self) -> (usize, usize) {
+        let len = |value: &Option<Vec<u8>>| value.as_ref().map_or(0, Vec::len);
+        match self {
+            Event::GET { key, .. } | Event::EXISTS { key, .. } | Event::META { key, .. } |
+            Event::GETV { key, .. } | Event::INCR { key, .. } | Event::LPM { key, .. } => (key.len(), 0),
+            Event::SET { key, value, .. } | Event::SETV { key, value, .. } => (key.len(), len(value)),
+            Event::SETEX { key, value, .. } | Event::GETSET { key, value, .. } => (key.len(), value.len()),
+            Event::APPEND { key, suffix, .. } => (key.len(), suffix.len()),
+            Event::MERGE { key, operand, .. } => (key.len(), operand.len()),
+            Event::CAS { key, expected, value, .. } => (key.len(), len(expected).max(len(value))),
+            Event::SCAN { start, end, .. } | Event::COMPACT { start, end, .. } |
+            Event::DELETE_RANGE { start, end, .. } => (start.len().max(end.len()), 0),
+            Event::PREFIX { prefix, .. } => (prefix.len(), 0),
+            Event::KEYS { pattern, .. } => (pattern.len(), 0),
+            Event::MGET { keys, .. } => (keys.iter().map(Vec::len).max().unwrap_or(0), 0),
+            Event::SETM { entries, .. } => (
+                entries.iter().map(|(key, _)| key.len()).max().unwrap_or(0),
+                entries.iter().map(|(_, value)| len(value)).max().unwrap_or(0),
+            ),
+            Event::INGEST { entries, .. } => (
+                entries.iter().map(|(key, _)| key.len()).max().unwrap_or(0),
+                entries.iter().map(|(_, value)| value.len()).max().unwrap_or(0),
+            ),
+            _ => (0, 0),
+        }
+    }
+
+    // 超过长度上限时返回错误响应
+    pub fn check_size(&self, limits: &SizeLimits) -> Option<EventRes> {
+        let (key, value) = self.sizes();
+        let code = if key > limits.max_key {
+            ERR_KEY_TOO_LARGE
+        } else if value > limits.max_value {
+            ERR_VALUE_TOO_LARGE
+        } else {
+            return None;
+        };
+        warn!("Reject request from client {}, key len = {}, value len = {}", self.id(), key, value);
+        Some(EventRes::ERROR { id: self.id().clone(), req: self.req(), code })
+    }
+
+    // 会写入存储的事件，暂停写入时拒绝
+    fn is_write(&self) -> bool {
+        matches!(self,
+            Event::SET { .. } | Event::SETEX { .. } | Event::SETM { .. } | Event::CAS { .. } |
+            Event::INCR { .. } | Event::APPEND { .. } | Event::GETSET { .. } | Event::SETV { .. } |
+            Event::MERGE { .. } | Event::EXEC { .. } | Event::INGEST { .. } | Event::DELETE_RANGE { .. })
+    }
+
+    // 可以放在事务中的 op 返回客户端 id
+    pub fn tx_id(&self) -> Option<&String> {
+        match self {
+            Event::GET { id, .. } | Event::EXISTS { id, .. } | Event::MGET { id, .. } |
+            Event::SET { id, .. } | Event::SETM { id, .. } | Event::CAS { id, .. } |
+            Event::INCR { id, .. } | Event::APPEND { id, .. } | Event::GETSET { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+}
+
+// 按用户认证的客户端的用户名，记入审计日志；用密码认证或不需要认证的客户端为 None
+fn user<'a>(access: &'a HashMap<String, Arc<Access>>, id: &str) -> Option<&'a str> {
+    access.get(id).map(|access| access.user.as_str())
+}
+
+// value 按十进制整数解析加上 delta，不存在视为 0；不是整数或溢出时为 None
+fn incr_value(value: Option<Vec<u8>>, delta: i64) -> Option<i64> {
+    match value {
+        None => Some(delta),
+        Some(v) => std::str::from_utf8(&v).ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .and_then(|v| v.checked_add(delta)),
+    }
+}
+
+// 空名字为默认命名空间
+fn valid_namespace(name: &str) -> bool {
+    name.len() <= MAX_NAMESPACE_LEN && name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+}
+
+// 事务中的写先记在 overlay 中，后面的 op 能读到
+fn stage(overlay: &mut HashMap<Vec<u8>, Option<Vec<u8>>>, writes: &mut Vec<(Vec<u8>, Option<Vec<u8>>)>, key: Vec<u8>, value: Option<Vec<u8>>) {
+    overlay.insert(key.clone(), value.clone());
+    writes.push((key, value));
 }
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum EventRes {
     GET {
         id: String,
+        req: Option<u32>,
         value: Option<Vec<u8>>,
     },
     SET {
         id: String,
+        req: Option<u32>,
+    },
+    EXISTS {
+        id: String,
+        req: Option<u32>,
+        exists: bool,
+    },
+    // SCAN 和 PREFIX 都逐条返回，None 表示结束
+    SCAN {
+        id: String,
+        req: Option<u32>,
+        entry: Option<(Vec<u8>, Vec<u8>)>,
+    },
+    // 与 keys 一一对应
+    MGET {
+        id: String,
+        req: Option<u32>,
+        values: Vec<Option<Vec<u8>>>,
+    },
+    SETM {
+        id: String,
+        req: Option<u32>,
+    },
+    CAS {
+        id: String,
+        req: Option<u32>,
+        swapped: bool,
+    },
+    // 不存在时为 None；ttl 为剩余毫秒数，不过期时为 None
+    META {
+        id: String,
+        req: Option<u32>,
+        meta: Option<KeyMeta>,
+        ttl: Option<u64>,
+    },
+    // 不存在时 value 为 None，version 为 0
+    GETV {
+        id: String,
+        req: Option<u32>,
+        value: Option<Vec<u8>>,
+        version: u64,
+    },
+    // 写入时 version 为新的版本号，删除后为 0；没有写入时为当前的版本号
+    SETV {
+        id: String,
+        req: Option<u32>,
+        ok: bool,
+        version: u64,
+    },
+    // 合并后的 value；合并函数不存在、无法合并或结果过长时为 None，不写入
+    MERGE {
+        id: String,
+        req: Option<u32>,
+        value: Option<Vec<u8>>,
+    },
+    // value 不是整数或溢出时为 None
+    INCR {
+        id: String,
+        req: Option<u32>,
+        value: Option<i64>,
+    },
+    // 追加后的长度，超过 MAX_LEN 时不写入，为 None
+    APPEND {
+        id: String,
+        req: Option<u32>,
+        len: Option<usize>,
+    },
+    DBSIZE {
+        id: String,
+        req: Option<u32>,
+        size: u64,
+    },
+    // 写入前的旧值
+    GETSET {
+        id: String,
+        req: Option<u32>,
+        value: Option<Vec<u8>>,
+    },
+    FLUSHALL {
+        id: String,
+        req: Option<u32>,
+    },
+    // 原样返回 PING 的 payload
+    PONG {
+        id: String,
+        req: Option<u32>,
+        payload: u64,
+    },
+    // key 数, 内存表字节数, WAL 字节数, 连接数, 每秒事件数, block 缓存命中数, 未命中数, 内存表内存, 权限不足拒绝的请求数；新的统计项追加在后面
+    STATS {
+        id: String,
+        req: Option<u32>,
+        stats: Vec<u64>,
+    },
+    MULTI {
+        id: String,
+        req: Option<u32>,
+    },
+    // 在事务中各 op 的结果之后返回
+    EXEC {
+        id: String,
+        req: Option<u32>,
+    },
+    AUTH {
+        id: String,
+        req: Option<u32>,
+        ok: bool,
+    },
+    SELECT {
+        id: String,
+        req: Option<u32>,
+        ok: bool,
+    },
+    // 快照中已分配的最大版本号
+    SNAPSHOT {
+        id: String,
+        req: Option<u32>,
+        version: u64,
     },
+    // 没有打开的快照时 ok 为 false
+    RELEASE {
+        id: String,
+        req: Option<u32>,
+        ok: bool,
+    },
+    // 合并的 SST 文件数，没有需要合并的文件时为 0
+    COMPACT {
+        id: String,
+        req: Option<u32>,
+        files: u32,
+    },
+    // 校验过的文件数和其中损坏的文件数，损坏的文件名写在服务端日志中
+    SCRUB {
+        id: String,
+        req: Option<u32>,
+        files: u32,
+        corrupt: u32,
+    },
+    // 导入的 key 中之前不存在的数量
+    INGEST {
+        id: String,
+        req: Option<u32>,
+        keys: u32,
+    },
+    // 删除的 key 的数量
+    #[allow(non_camel_case_types)]
+    DELETE_RANGE {
+        id: String,
+        req: Option<u32>,
+        keys: u64,
+    },
+    // 匹配的前缀和它的 value，没有匹配时为 None
+    LPM {
+        id: String,
+        req: Option<u32>,
+        entry: Option<(Vec<u8>, Vec<u8>)>,
+    },
+    FLUSH {
+        id: String,
+        req: Option<u32>,
+    },
+    SHUTDOWN {
+        id: String,
+        req: Option<u32>,
+    },
+    // 不是集群模式时 ring 为 None
+    CLUSTER {
+        id: String,
+        req: Option<u32>,
+        ring: Option<Arc<Ring>>,
+    },
+    // 请求中的 key 由 addr 上的节点负责，请求不执行
+    MOVED {
+        id: String,
+        req: Option<u32>,
+        addr: String,
+    },
+    // 请求不执行，code 为 ERR_*
+    ERROR {
+        id: String,
+        req: Option<u32>,
+        code: u8,
+    },
+}
+
+impl EventRes {
+    pub fn req(&self) -> Option<u32> {
+        match self {
+            EventRes::GET { req, .. } | EventRes::SET { req, .. } | EventRes::EXISTS { req, .. } |
+            EventRes::SCAN { req, .. } | EventRes::MGET { req, .. } | EventRes::SETM { req, .. } |
+            EventRes::CAS { req, .. } | EventRes::META { req, .. } | EventRes::GETV { req, .. } |
+            EventRes::SETV { req, .. } | EventRes::MERGE { req, .. } | EventRes::INCR { req, .. } |
+            EventRes::APPEND { req, .. } | EventRes::DBSIZE { req, .. } | EventRes::GETSET { req, .. } |
+            EventRes::FLUSHALL { req, .. } | EventRes::PONG { req, .. } | EventRes::STATS { req, .. } |
+            EventRes::MULTI { req, .. } | EventRes::EXEC { req, .. } | EventRes::AUTH { req, .. } |
+            EventRes::SELECT { req, .. } | EventRes::SNAPSHOT { req, .. } | EventRes::RELEASE { req, .. } |
+            EventRes::COMPACT { req, .. } | EventRes::SCRUB { req, .. } | EventRes::INGEST { req, .. } |
+            EventRes::DELETE_RANGE { req, .. } | EventRes::LPM { req, .. } | EventRes::FLUSH { req, .. } |
+            EventRes::SHUTDOWN { req, .. } | EventRes::CLUSTER { req, .. } | EventRes::MOVED { req, .. } |
+            EventRes::ERROR { req, .. } => *req,
+        }
+    }
 }
 
-pub struct EventHandler {
+pub struct EventHandler<S: LsmExt> {
     receiver: Receiver<Event>,
-    trie: Trie,
+    // 当前事件所在命名空间的存储
+    storage: S,
+    namespace: String,
+    // 其他已打开的命名空间，打开后不再关闭
+    storages: HashMap<String, S>,
+    data_path: String,
+    max_namespaces: usize,
+    // 客户端选择的命名空间，没有选择的在默认命名空间
+    namespaces: HashMap<String, String>,
     client_map: Arc<DashMap<String, Client>>,
-    wal_files: Vec<File>,
-    log_files: Vec<Arc<Mutex<File>>>,
-    index_file: File,
+    audit: Option<AuditLog>,
+    hooks: Option<HookDispatcher>,
+    // 处理的事件数
+    ops: OpsCounter,
+    // MULTI 之后缓存的事件，按客户端 id
+    multi: HashMap<String, Vec<Event>>,
+    merges: MergeRegistry,
+    // 客户端打开的快照和打开时所在的命名空间
+    snapshots: HashMap<String, (String, S::Snapshot)>,
+    limits: SizeLimits,
+    // 默认命名空间的配置，打开其他命名空间时以它为准
+    options: StorageOptions,
+    // 重新读取配置文件后的新配置
+    settings: watch::Receiver<Settings>,
+    // 按用户认证的客户端的权限，不在这里的客户端不限制
+    access: HashMap<String, Arc<Access>>,
+    // 因为权限不足拒绝的请求数，事务中的 op 逐个计入
+    denied: u64,
+    // 从节点收到的主节点复制记录，主节点和单机时为 None
+    replica: Option<ReplicaFeed>,
+    // 主节点开启复制端口时为数据目录为空的从节点生成快照
+    snapshot_feed: Option<SnapshotFeed>,
+    // Raft 集群模式，只有主节点接受写入
+    raft: Option<RaftFeed>,
+    leading: bool,
+    // Raft 主节点上已提交的复制日志序号，和等待提交的响应
+    committed: u64,
+    held: VecDeque<Held>,
+    // 集群模式的哈希环，不由本节点负责的 key 返回 RES_MOVED；gossip 发现节点变化时更新
+    ring: Option<watch::Receiver<Arc<Ring>>>,
+    // 开启健康检查端口时报告写入是否暂停，和本分片的编号
+    readiness: Option<(Arc<Readiness>, usize)>,
+    // 全局写限流，STATS 报告被延迟的写入次数和等待时间
+    throttle: Option<Arc<WriteThrottle>>,
 }
 
-impl EventHandler {
-    pub async fn new(receiver: Receiver<Event>, trie: Trie, client_map: Arc<DashMap<String, Client>>, data_path: String) -> Self {
-        // dir
-        if !try_exists(&data_path).await.unwrap_or_else(|e| { panic!("Try exists fail, err = {:?}", e); }) {
-            create_dir_all(&data_path).unwrap_or_else(|e| { panic!("Create data dir fail, err = {:?}", e) });
-        }
-        // file
-        let mut wal_files = Vec::new();
-        let mut log_files = Vec::new();
-
-        async fn open_file(file_name: String, append: bool) -> File {
-            File::options().append(append).read(true).write(true).create(true).open(file_name).await.unwrap_or_else(|e| {
-                panic!("Open file err {:?}", e);
-            })
-        }
-        let index_file_name = format!("{}/{}", &data_path, INDEX_FILE);
-        info!("LSM open index file {}", &index_file_name);
-        let index_file = open_file(index_file_name, false).await;
-        for i in 0..FILE_BATCH {
-            let wal_file_name = format!("{}/{}{}", &data_path, WAL_FILE_PREFIX, i);
-            let log_file_name = format!("{}/{}{}", &data_path, LOG_FILE_PREFIX, i);
-            info!("LSM open file {}", &wal_file_name);
-            let wal_file = open_file(wal_file_name, true).await;
-            info!("LSM open file {}", &log_file_name);
-            let log_file = open_file(log_file_name, true).await;
-
-            wal_files.push(wal_file);
-            log_files.push(Arc::new(Mutex::new(log_file)));
-        }
+// 一个事件的响应，复制日志序号不大于 seq 的写入都提交后发送
+struct Held {
+    seq: u64,
+    id: String,
+    req: Option<u32>,
+    responses: Vec<EventRes>,
+}
+
+impl<S: LsmExt> EventHandler<S> {
+    // storage 为 data_path 下的默认命名空间
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(receiver: Receiver<Event>, storage: S, data_path: String, max_namespaces: usize, mut settings: watch::Receiver<Settings>, client_map: Arc<DashMap<String, Client>>, audit: Option<AuditLog>, hooks: Option<HookDispatcher>) -> Self {
+        let options = storage.options().clone();
+        let limits = settings.borrow_and_update().limits;
         Self {
             receiver,
-            trie,
+            storage,
+            namespace: String::new(),
+            storages: HashMap::new(),
+            data_path,
+            max_namespaces,
+            namespaces: HashMap::new(),
             client_map,
-            wal_files,
-            log_files,
-            index_file,
+            audit,
+            hooks,
+            ops: OpsCounter::new(),
+            multi: HashMap::new(),
+            merges: MergeRegistry::new(),
+            snapshots: HashMap::new(),
+            limits,
+            options,
+            settings,
+            access: HashMap::new(),
+            denied: 0,
+            replica: None,
+            snapshot_feed: None,
+            raft: None,
+            leading: false,
+            committed: 0,
+            held: VecDeque::new(),
+            ring: None,
+            readiness: None,
+            throttle: None,
         }
     }
 
-    async fn refresh_index_file(&mut self, index: u8) {
-        self.index_file.seek(SeekFrom::Start(0)).await.expect("Seek index file fail ");
-        self.index_file.write_u8(index).await.expect("Write index file fail err");
-        self.index_file.sync_all().await.expect("Flush index file fail");
+    // 集群模式，只执行由本节点负责的 key
+    pub fn cluster(&mut self, ring: watch::Receiver<Arc<Ring>>) {
+        self.ring = Some(ring);
     }
 
-    async fn load(&mut self, buf: Vec<u8>) {
-        // 2 bit key length
-        // n bit key
-        // 2 bit value length
-        // n bit value
-        let mut index = 0;
-        let len = buf.len();
-        while index < len {
-            if index + 2 > len {
-                break;
+    // 开启健康检查端口，写入暂停时本分片没有就绪
+    pub fn report(&mut self, readiness: Arc<Readiness>, shard: usize) {
+        self.readiness = Some((readiness, shard));
+    }
+
+    // STATS 中带上写限流的计数
+    pub fn throttled(&mut self, throttle: Arc<WriteThrottle>) {
+        self.throttle = Some(throttle);
+    }
+
+    // 写入是否暂停，同时更新健康检查端口看到的状态
+    async fn write_stalled(&mut self) -> bool {
+        let stalled = self.storage.write_stalled().await;
+        if let Some((readiness, shard)) = self.readiness.as_ref() {
+            readiness.set_stalled(*shard, stalled);
+        }
+        stalled
+    }
+
+    // 加入 Raft 集群，成为主节点前拒绝写入；过期的 key 由主节点删除
+    pub fn join(&mut self, feed: RaftFeed) {
+        self.raft = Some(feed);
+    }
+
+    // 从节点和 Raft 集群中不是主节点时拒绝客户端的写入
+    fn read_only(&self) -> bool {
+        self.replica.is_some() || (self.raft.is_some() && !self.leading)
+    }
+
+    // 作为从节点回放主节点的写入；过期的 key 由主节点删除后复制过来，不再自己清理
+    pub fn follow(&mut self, feed: ReplicaFeed) {
+        self.replica = Some(feed);
+    }
+
+    // 开启复制端口的主节点，按请求生成快照发给数据目录为空的从节点
+    pub fn replicate(&mut self, feed: SnapshotFeed) {
+        self.snapshot_feed = Some(feed);
+    }
+
+    // 所有命名空间的数据和生成时复制日志的最新序号，包括还没有打开的命名空间；打开的命名空间达到上限时跳过其余的
+    async fn replica_snapshot(&mut self) -> ReplicaSnapshot {
+        let current = self.namespace.clone();
+        let mut names: Vec<String> = once(String::new()).chain(self.storages.keys().cloned()).collect();
+        if let Ok(dir) = std::fs::read_dir(format!("{}/{}", &self.data_path, NAMESPACE_DIR)) {
+            names.extend(dir.flatten().filter_map(|entry| entry.file_name().into_string().ok()).filter(|name| valid_namespace(name)));
+        }
+        names.sort();
+        names.dedup();
+        let mut namespaces = Vec::with_capacity(names.len());
+        for name in names {
+            if !self.switch(&name).await {
+                error!("Snapshot namespace {} fail, too many namespaces", &name);
+                continue;
             }
-            let key_len = (buf[index] as usize * 0x100 + buf[index + 1] as usize) & LEN_MASK as usize;
-            if index + 2 + key_len + 2 > len {
-                break;
+            namespaces.push((name, self.storage.last_version(), self.storage.export()));
+        }
+        self.switch(&current).await;
+        let seq = self.snapshot_feed.as_ref().map_or(0, |feed| feed.log.last_seq());
+        ReplicaSnapshot { seq, namespaces }
+    }
+
+    // 认证的用户没有权限时返回错误响应；所有按用户的权限检查都经过这里，拒绝时记日志并计入 STATS
+    fn check_access(&mut self, event: &Event) -> Option<EventRes> {
+        let access = self.access.get(event.id())?;
+        if access.allows(event) {
+            return None;
+        }
+        self.denied += 1;
+        warn!("Deny {} from client {}, user = {}, permission = {:?}, denied = {}", event.name(), event.id(), &access.user, access.permission, self.denied);
+        Some(EventRes::ERROR { id: event.id().clone(), req: event.req(), code: ERR_DENIED })
+    }
+
+    // 请求中有不由本节点负责的 key 时返回负责的节点
+    fn check_owner(&self, event: &Event) -> Option<EventRes> {
+        let ring = self.ring.as_ref()?.borrow().clone();
+        let addr = ring.moved(event)?;
+        info!("Move {} from client {} to {}", event.name(), event.id(), addr);
+        Some(EventRes::MOVED { id: event.id().clone(), req: event.req(), addr: addr.to_string() })
+    }
+
+    // 客户端在当前命名空间打开了快照时从快照读取，事务中的读取不经过这里
+    fn reader(&self, id: &str) -> &dyn ReadView {
+        match self.snapshots.get(id) {
+            Some((namespace, snapshot)) if *namespace == self.namespace => snapshot,
+            _ => &self.storage,
+        }
+    }
+
+    // 把 namespace 换到 storage，未打开时打开；已打开的命名空间达到 max_namespaces 时返回 false
+    async fn switch(&mut self, namespace: &str) -> bool {
+        if self.namespace == namespace {
+            return true;
+        }
+        let storage = match self.storages.remove(namespace) {
+            Some(storage) => storage,
+            None => {
+                if self.storages.len() + 1 >= self.max_namespaces {
+                    warn!("Open namespace {} fail, too many namespaces", namespace);
+                    return false;
+                }
+                let mut options = self.options.clone();
+                let path = if namespace.is_empty() {
+                    self.data_path.clone()
+                } else {
+                    // SST 文件放在各层目录下同名的子目录；连接只直接读取默认命名空间
+                    options.shared_view = None;
+                    options.replication = options.replication.map(|sink| sink.namespace(namespace));
+                    options.level_paths = options.level_paths.iter().map(|dir| format!("{}/{}/{}", dir, NAMESPACE_DIR, namespace)).collect();
+                    format!("{}/{}/{}", &self.data_path, NAMESPACE_DIR, namespace)
+                };
+                info!("Open namespace {} at {}", namespace, &path);
+                S::open(path, options).await
             }
-            let key = Vec::from(&buf[index + 2..index + 2 + key_len]);
-            let value_len = buf[index + 2 + key_len] as usize * 0x100 + buf[index + 2 + key_len + 1] as usize;
-            if value_len == NONE_VALUE_LEN as usize {
-                self.trie.set(key, None);
-                index += 2 + key_len + 2;
-            } else {
-                let value_len = value_len & LEN_MASK as usize;
-                if index + 2 + key_len + 2 + value_len > len {
-                    break;
+        };
+        let storage = std::mem::replace(&mut self.storage, storage);
+        let namespace = std::mem::replace(&mut self.namespace, namespace.to_string());
+        self.storages.insert(namespace, storage);
+        true
+    }
+
+    async fn send_entries(&mut self, id: String, req: Option<u32>, entries: Vec<(Vec<u8>, Vec<u8>)>) {
+        match self.client_map.get_mut(&id) {
+            None => {
+                info!("Don't have client id = {}", &id)
+            }
+            Some(mut client_entry) => {
+                for entry in entries {
+                    client_entry.value_mut().send_event_res(EventRes::SCAN {
+                        id: id.clone(),
+                        req,
+                        entry: Some(entry),
+                    }).await;
                 }
-                let value = Vec::from(&buf[index + 2 + key_len + 2..index + 2 + key_len + 2 + value_len]);
-                self.trie.set(key, Some(value));
-                index += 2 + key_len + 2 + value_len;
+                client_entry.value_mut().send_event_res(EventRes::SCAN {
+                    id: id.clone(),
+                    req,
+                    entry: None,
+                }).await;
             }
         }
     }
 
-    pub async fn start_event_loop(&mut self) {
-        // read index
-        let mut file_index = match self.index_file.read_u8().await {
-            Ok(n) if n == 1 || n == 0 => n,
-            Ok(n) => {
-                info!("Read index invalid {} default 0", n);
-                let i = 0;
-                self.refresh_index_file(i).await;
-                i
-            }
-            Err(e) => {
-                info!("Read index file err {:?}", e);
-                let i = 0;
-                self.refresh_index_file(i).await;
-                i
-            }
-        } as usize;
-
-        info!("File index is {}", file_index);
-
-        // read from LOG file and WAL file
-        let file_index_last = FILE_BATCH - 1 - file_index;
-        // last log
-        let mut log_file_last_content = Vec::new();
-        self.log_files[file_index_last].lock().await.read_to_end(&mut log_file_last_content).await.expect("Read last log file fail");
-        self.load(log_file_last_content).await;
-        // last wal
-        let mut wal_file_last_content = Vec::new();
-        self.wal_files[file_index_last].read_to_end(&mut wal_file_last_content).await.expect("Read last wal file fail");
-        self.load(wal_file_last_content).await;
-        // this log
-        let mut log_file_this_content = Vec::new();
-        self.log_files[file_index].lock().await.read_to_end(&mut log_file_this_content).await.expect("Read this log file fail");
-        self.load(log_file_this_content).await;
-        // this wal
-        let mut wal_file_this_content = Vec::new();
-        self.wal_files[file_index].read_to_end(&mut wal_file_this_content).await.expect("Read this wal file fail");
-        self.load(wal_file_this_content).await;
-
-        // is saving
-        let saving = Arc::new(AtomicBool::new(false));
+    // 事务中读到的值，先看事务中的写
+    fn tx_get(&self, overlay: &HashMap<Vec<u8>, Option<Vec<u8>>>, key: &[u8]) -> Option<Vec<u8>> {
+        match overlay.get(key) {
+            Some(value) => value.clone(),
+            None => self.storage.get(key),
+        }
+    }
 
-        // do
-        info!("LSM server start event loop");
-        loop {
-            match self.receiver.recv().await {
-                Some(event) => {
-                    match event {
-                        Event::GET { id, key } => {
-                            info!("Receive get event, id = {}, key = {:?}", &id, &key);
-                            let client_option = self.client_map.get_mut(&id);
-                            match client_option {
-                                None => {
-                                    info!("Don't have client id = {}", &id)
-                                }
-                                Some(mut client_entry) => {
-                                    client_entry.value_mut().send_event_res(EventRes::GET {
-                                        id: id.clone(),
-                                        value: self.trie.get(key),
-                                    }).await;
-                                }
-                            }
-                        }
-                        Event::SET { id, key, value } => {
-                            // WAL
-                            let mut buf = Vec::new();
-                            buf.push(((key.len() as u16 & LEN_MASK) >> 8) as u8);
-                            buf.push(key.len() as u8);
-                            buf.extend(key.iter());
-                            match &value {
-                                None => {
-                                    buf.push((NONE_VALUE_LEN >> 8) as u8);
-                                    buf.push(NONE_VALUE_LEN as u8);
-                                }
-                                Some(v) => {
-                                    buf.push(((v.len() as u16 & LEN_MASK) >> 8) as u8);
-                                    buf.push(v.len() as u8);
-                                    buf.extend(v.iter());
-                                }
-                            }
-                            self.wal_files[file_index].write_all(&buf).await.expect("Write wal file fail");
-
-                            // do set
-                            info!("Receive set event, id = {}, key = {:?}, value = {:?}", &id, &key, &value);
-                            match self.client_map.get_mut(&id) {
-                                None => {
-                                    info!("Don't have client id = {}", &id)
-                                }
-                                Some(mut client_entry) => {
-                                    self.trie.set(key, value);
-                                    client_entry.value_mut().send_event_res(EventRes::SET {
-                                        id: id.clone(),
-                                    }).await;
-                                }
-                            }
-                        }
+    // 在一个事件中依次执行事务中的 op，所有写合成一条批量记录，中间不会插入其他读写
+    // 事务中写入的 key 不保留过期时间
+    async fn exec(&mut self, id: String, req: Option<u32>, events: Vec<Event>) {
+        info!("Receive exec event, id = {}, count = {}", &id, events.len());
+        let mut overlay = HashMap::new();
+        let mut writes = Vec::new();
+        let mut results = Vec::with_capacity(events.len() + 1);
+        for event in events {
+            if let Some(res) = event.check_size(&self.limits).or_else(|| self.check_access(&event)) {
+                results.push(res);
+                continue;
+            }
+            let res = match event {
+                Event::GET { id, req, key } => {
+                    let value = self.tx_get(&overlay, &key);
+                    if let (None, Some(hooks)) = (&value, self.hooks.as_mut()) {
+                        hooks.on_get_miss(&key);
                     }
+                    EventRes::GET { id, req, value }
                 }
-                None => {
-                    warn!("Receive event none");
+                Event::EXISTS { id, req, key } => {
+                    let exists = self.tx_get(&overlay, &key).is_some();
+                    EventRes::EXISTS { id, req, exists }
+                }
+                Event::MGET { id, req, keys } => {
+                    let values = keys.iter().map(|key| self.tx_get(&overlay, key)).collect();
+                    EventRes::MGET { id, req, values }
+                }
+                Event::SET { id, req, key, value } => {
+                    stage(&mut overlay, &mut writes, key, value);
+                    EventRes::SET { id, req }
+                }
+                Event::SETM { id, req, entries } => {
+                    for (key, value) in entries {
+                        stage(&mut overlay, &mut writes, key, value);
+                    }
+                    EventRes::SETM { id, req }
+                }
+                Event::CAS { id, req, key, expected, value } => {
+                    let swapped = self.tx_get(&overlay, &key) == expected;
+                    if swapped {
+                        stage(&mut overlay, &mut writes, key, value);
+                    }
+                    EventRes::CAS { id, req, swapped }
+                }
+                Event::INCR { id, req, key, delta } => {
+                    let value = incr_value(self.tx_get(&overlay, &key), delta);
+                    match value {
+                        Some(v) => stage(&mut overlay, &mut writes, key, Some(v.to_string().into_bytes())),
+                        None => warn!("Incr key {:?} fail, value is not an integer or overflow", &key),
+                    }
+                    EventRes::INCR { id, req, value }
+                }
+                Event::APPEND { id, req, key, suffix } => {
+                    let mut value = self.tx_get(&overlay, &key).unwrap_or_default();
+                    let len = if value.len() + suffix.len() > self.limits.max_value {
+                        warn!("Append key {:?} fail, value too long", &key);
+                        None
+                    } else {
+                        value.extend(suffix);
+                        let len = value.len();
+                        stage(&mut overlay, &mut writes, key, Some(value));
+                        Some(len)
+                    };
+                    EventRes::APPEND { id, req, len }
+                }
+                Event::GETSET { id, req, key, value } => {
+                    let old = self.tx_get(&overlay, &key);
+                    stage(&mut overlay, &mut writes, key, Some(value));
+                    EventRes::GETSET { id, req, value: old }
+                }
+                _ => {
+                    warn!("Event not allowed in transaction, id = {}", &id);
+                    continue;
+                }
+            };
+            results.push(res);
+        }
+        for (key, value) in writes.iter() {
+            if let Some(audit) = self.audit.as_mut() {
+                let op = if value.is_some() { AUDIT_OP_SET } else { AUDIT_OP_DELETE };
+                audit.append(user(&self.access, &id), &id, op, key, value.as_deref()).await;
+            }
+            if let Some(hooks) = self.hooks.as_mut() {
+                match value {
+                    Some(v) => hooks.on_set(key, v),
+                    None => hooks.on_delete(key),
                 }
             }
-            // check wal file size:10M
-            if self.wal_files[file_index].metadata().await.expect("Read wal file meta fail").len() > 1 * 1024 * 1024 * 10 && !saving.load(Ordering::Relaxed) {
-                // change file index
-                file_index = FILE_BATCH - 1 - file_index;
-                self.refresh_index_file(file_index as u8).await;
-                // clear wal file
-                self.wal_files[file_index].set_len(0).await.expect("Set this wal len zero err");
-
-                // save the log file
-                let clone_trie = self.trie.clone();
-                let file = self.log_files[file_index].clone();
-                let clone_saving = saving.clone();
-                tokio::spawn(async move {
-                    info!("Save to log file");
-                    clone_saving.store(true, Ordering::Relaxed);
-                    file.lock().await.set_len(0).await.expect("Set this log file len zero err");
-                    clone_trie.save(&file);
-                    clone_saving.store(false, Ordering::Relaxed);
-                    info!("Save to log file done");
-                });
+        }
+        if !writes.is_empty() {
+            self.storage.write_batch(writes).await;
+        }
+        results.push(EventRes::EXEC { id: id.clone(), req });
+        match self.client_map.get_mut(&id) {
+            None => {
+                info!("Don't have client id = {}", &id)
+            }
+            Some(mut client_entry) => {
+                for res in results {
+                    client_entry.value_mut().send_event_res(res).await;
+                }
             }
         }
     }
+
+    // 删除已过期的 key，读时的过期判断之外再主动清理
+    async fn sweep_expired(&mut self) {
+        let now = now_millis();
+        let mut keys = self.storage.remove_expired(now, EXPIRE_SWEEP_LIMIT).await;
+        for storage in self.storages.values_mut() {
+            keys.extend(storage.remove_expired(now, EXPIRE_SWEEP_LIMIT).await);
+        }
+        if keys.is_empty() {
+            return;
+        }
+        info!("Sweep {} expired keys", keys.len());
+        if let Some(hooks) = self.hooks.as_mut() {
+            for key in keys.iter() {
+                hooks.on_delete(key);
+            }
+        }
+    }
+
+    // 所有已打开命名空间中存在太久的内存表
+    async fn flush_aged(&mut self) {
+        self.storage.flush_aged().await;
+        for storage in self.storages.values_mut() {
+            storage.flush_aged().await;
+        }
+    }
+
+    // 所有已打开命名空间中等待删除的旧文件
+    async fn collect_garbage(&mut self) {
+        self.storage.collect_garbage().await;
+        for storage in self.storages.values_mut() {
+            storage.collect_garbage().await;
+        }
+    }
+
+    // 所有已打开命名空间的 WAL
+    async fn sync_wal(&mut self) {
+        self.storage.sync_wal().await;
+        for storage in self.storages.values_mut() {
+            storage.sync_wal().await;
+        }
+    }
+
+    // 请求或事务中缓存的 op 已被连接取消
+    fn timed_out(&self, event: &Event) -> bool {
+        let Some(client) = self.client_map.get(event.id()) else {
+            return false;
+        };
+        match event {
+            Event::EXEC { id, req, .. } => client.cancelled(*req) || self.multi.get(id).is_some_and(|queue| queue.iter().any(|event| client.cancelled(event.req()))),
+            event => client.cancelled(event.req()),
+        }
+    }
+
+    // 修改数据的事件，只包含读取的事务不算
+    fn modifies(&self, event: &Event) -> bool {
+        match event {
+            Event::EXEC { id, .. } => self.multi.get(id).is_some_and(|queue| queue.iter().any(Event::is_write)),
+            Event::FLUSHALL { .. } => true,
+            event => event.is_write(),
+        }
+    }
+
+    // 在记录所在的命名空间回放，完成后通知复制任务确认；快照中除最后一条外序号为 0，不确认
+    async fn replay(&mut self, seq: u64, record: Replicated) {
+        if !self.apply(record).await || seq == 0 {
+            return;
+        }
+        if let Some(replica) = self.replica.as_ref() {
+            replica.applied.send_replace(seq);
+        }
+    }
+
+    async fn apply(&mut self, record: Replicated) -> bool {
+        let namespace = record.namespace().to_string();
+        if !self.switch(&namespace).await {
+            error!("Replay record fail, can't open namespace {}", &namespace);
+            return false;
+        }
+        self.storage.replay(record).await;
+        true
+    }
+
+    // Raft 主节点上事件的响应等它之前的写入都提交后再发送，没有写入的事件也排在前面的响应之后
+    async fn handle_leading(&mut self, event: Event) {
+        let (id, req) = (event.id().clone(), event.req());
+        if let Some(mut client_entry) = self.client_map.get_mut(&id) {
+            client_entry.value_mut().hold();
+        }
+        self.handle(event).await;
+        let responses = self.client_map.get_mut(&id).map(|mut client_entry| client_entry.value_mut().release()).unwrap_or_default();
+        let seq = self.raft.as_ref().map_or(0, |raft| raft.proposals.last_seq());
+        self.held.push_back(Held { seq, id, req, responses });
+        self.release_committed().await;
+    }
+
+    async fn release_committed(&mut self) {
+        while let Some(held) = self.held.pop_front() {
+            if held.seq > self.committed {
+                self.held.push_front(held);
+                break;
+            }
+            if let Some(mut client_entry) = self.client_map.get_mut(&held.id) {
+                for res in held.responses {
+                    client_entry.value_mut().send_event_res(res).await;
+                }
+            }
+        }
+    }
+
+    async fn raft_command(&mut self, command: RaftCommand) {
+        match command {
+            RaftCommand::Apply(record) => {
+                self.apply(record).await;
+            }
+            RaftCommand::Commit(seq) => {
+                self.committed = seq;
+                self.release_committed().await;
+            }
+            RaftCommand::Lead { records, reply } => {
+                for record in records {
+                    self.apply(record).await;
+                }
+                let seq = self.raft.as_ref().map_or(0, |raft| raft.proposals.last_seq());
+                self.leading = true;
+                self.committed = seq;
+                info!("Raft leader accept writes from seq {}", seq);
+                let _ = reply.send(seq);
+            }
+            RaftCommand::StepDown(reply) => {
+                self.leading = false;
+                self.release_committed().await;
+                // 还没提交的写入可能不会生效，返回错误
+                for held in std::mem::take(&mut self.held) {
+                    if let Some(mut client_entry) = self.client_map.get_mut(&held.id) {
+                        client_entry.value_mut().send_event_res(EventRes::ERROR { id: held.id.clone(), req: held.req, code: ERR_UNCOMMITTED }).await;
+                    }
+                }
+                let _ = reply.send(self.raft.as_ref().map_or(0, |raft| raft.proposals.last_seq()));
+            }
+            RaftCommand::Snapshot(reply) => {
+                self.switch("").await;
+                let seq = self.raft.as_ref().map_or(0, |raft| raft.proposals.last_seq());
+                let _ = reply.send(RaftSnapshot { seq, version: self.storage.last_version(), records: self.storage.export() });
+            }
+            RaftCommand::Install { first, records, version, reply } => {
+                self.switch("").await;
+                if first {
+                    self.storage.clear().await;
+                }
+                // 和全量同步一样按记录中的版本号写入，GETV 和 SETV 在主从节点上看到相同的版本号
+                for payload in records {
+                    self.storage.replay(Replicated::Load { namespace: String::new(), payload }).await;
+                }
+                if let Some(version) = version {
+                    self.storage.replay(Replicated::Version { namespace: String::new(), version }).await;
+                    self.storage.sync_wal().await;
+                }
+                let _ = reply.send(());
+            }
+            RaftCommand::Sync(reply) => {
+                self.sync_wal().await;
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    // 新配置应用到所有已打开的命名空间，之后打开的命名空间也沿用
+    fn tune(&mut self) {
+        let settings = *self.settings.borrow_and_update();
+        info!("Apply reloaded settings {:?}", settings);
+        self.limits = settings.limits;
+        self.options.tune(&settings.tunables);
+        self.storage.tune(&settings.tunables);
+        for storage in self.storages.values_mut() {
+            storage.tune(&settings.tunables);
+        }
+    }
+
+    pub async fn start_event_loop(&mut self) {
+        // do
+        info!("LSM server start event loop");
+        let mut sweep = interval(EXPIRE_SWEEP_INTERVAL);
+        let sync_interval = |durability| match durability {
+            Durability::Interval(period) => Some(interval(period)),
+            _ => None,
+        };
+        let mut sync = sync_interval(self.options.durability);
+        // 不按间隔 sync 时永远不会完成
+        async fn tick(sync: &mut Option<Interval>) {
+            match sync {
+                Some(sync) => {
+                    sync.tick().await;
+                }
+                None => std::future::pending().await,
+            }
+        }
+        // 不是从节点时永远不会完成
+        async fn replicated(replica: &mut Option<ReplicaFeed>) -> Option<(u64, Replicated)> {
+            match replica {
+                Some(replica) => replica.receiver.recv().await,
+                None => std::future::pending().await,
+            }
+        }
+        async fn raft_command(raft: &mut Option<RaftFeed>) -> Option<RaftCommand> {
+            match raft {
+                Some(raft) => raft.commands.recv().await,
+                None => std::future::pending().await,
+            }
+        }
+        async fn snapshot_request(feed: &mut Option<SnapshotFeed>) -> Option<SnapshotRequest> {
+            match feed {
+                Some(feed) => feed.requests.recv().await,
+                None => std::future::pending().await,
+            }
+        }
+        loop {
+            let event = select! {
+                event = self.receiver.recv() => event,
+                Some(command) = raft_command(&mut self.raft) => {
+                    self.raft_command(command).await;
+                    continue;
+                }
+                Some((seq, record)) = replicated(&mut self.replica) => {
+                    self.replay(seq, record).await;
+                    continue;
+                }
+                Some(reply) = snapshot_request(&mut self.snapshot_feed) => {
+                    let snapshot = self.replica_snapshot().await;
+                    info!("Snapshot for follower at {}, {} namespaces", snapshot.seq, snapshot.namespaces.len());
+                    let _ = reply.send(snapshot);
+                    continue;
+                }
+                _ = tick(&mut sync) => {
+                    self.sync_wal().await;
+                    continue;
+                }
+                Ok(()) = self.settings.changed() => {
+                    let durability = self.options.durability;
+                    self.tune();
+                    if self.options.durability != durability {
+                        // 之前按间隔写入的 WAL 先 sync，再按新的策略
+                        self.sync_wal().await;
+                        sync = sync_interval(self.options.durability);
+                    }
+                    continue;
+                }
+                _ = sweep.tick() => {
+                    if !self.read_only() {
+                        self.sweep_expired().await;
+                    }
+                    self.flush_aged().await;
+                    self.collect_garbage().await;
+                    // 没有写入时暂停的状态也要结束
+                    if self.readiness.as_ref().is_some_and(|(readiness, shard)| readiness.stalled(*shard)) {
+                        self.write_stalled().await;
+                    }
+                    // 断开的客户端未执行的事务和选择的命名空间
+                    let client_map = self.client_map.clone();
+                    self.multi.retain(|id, _| client_map.contains_key(id));
+                    self.namespaces.retain(|id, _| client_map.contains_key(id));
+                    self.snapshots.retain(|id, _| client_map.contains_key(id));
+                    self.access.retain(|id, _| client_map.contains_key(id));
+                    continue;
+                }
+            };
+            // 事务中的事件先缓存
+            let event = match event {
+                Some(event) => match event.tx_id().and_then(|id| self.multi.get_mut(id)) {
+                    Some(queue) => {
+                        queue.push(event);
+                        continue;
+                    }
+                    None => Some(event),
+                },
+                None => None,
+            };
+            match event {
+                Some(event) => {
+                    // 每个事件一个 span，日志带上客户端 id、op 和 key 的长度
+                    let span = info_span!("event", client = %event.id(), op = event.name(), key_len = event.sizes().0);
+                    if self.leading {
+                        self.handle_leading(event).instrument(span).await;
+                    } else {
+                        self.handle(event).instrument(span).await;
+                    }
+                }
+                None => {
+                    warn!("Receive event none");
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, event: Event) {
+        self.ops.incr();
+        // 超过长度上限、没有权限或不由本节点负责的请求直接返回错误，不访问存储
+        if let Some(res) = event.check_size(&self.limits).or_else(|| self.check_access(&event)).or_else(|| self.check_owner(&event)) {
+            if let Some(mut client_entry) = self.client_map.get_mut(event.id()) {
+                client_entry.value_mut().send_event_res(res).await;
+            }
+            return;
+        }
+        // 在客户端选择的命名空间中执行，打开时已检查过数量
+        let namespace = self.namespaces.get(event.id()).cloned().unwrap_or_default();
+        self.switch(&namespace).await;
+        // 连接已超时放弃的请求不再执行；从节点拒绝客户端的写入；落盘或合并跟不上时拒绝写入，避免内存和第 0 层文件无限增长
+        // 被拒绝的事务整个丢弃，事务中缓存的 op 也各返回一个错误，响应与请求一一对应
+        let code = if let Event::EXEC { abort: Some(code), .. } = event {
+            Some(code)
+        } else if self.timed_out(&event) {
+            Some(ERR_TIMEOUT)
+        } else if self.read_only() && self.modifies(&event) {
+            Some(ERR_READ_ONLY)
+        } else if event.is_write() && self.write_stalled().await {
+            Some(ERR_BUSY)
+        } else {
+            None
+        };
+        if let Some(code) = code {
+            let queued = match &event {
+                Event::EXEC { id, .. } => self.multi.remove(id).unwrap_or_default(),
+                _ => Vec::new(),
+            };
+            if let Some(mut client_entry) = self.client_map.get_mut(event.id()) {
+                for event in queued.iter().chain(once(&event)) {
+                    let res = EventRes::ERROR { id: event.id().clone(), req: event.req(), code };
+                    client_entry.value_mut().send_event_res(res).await;
+                }
+            }
+            return;
+        }
+        match event {
+            Event::GET { id, req, key } => {
+                info!("Receive get event, id = {}, key = {:?}", &id, &key);
+                let value = self.reader(&id).get(&key);
+                if let (None, Some(hooks)) = (&value, self.hooks.as_mut()) {
+                    hooks.on_get_miss(&key);
+                }
+                let client_option = self.client_map.get_mut(&id);
+                match client_option {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::GET {
+                            id: id.clone(),
+                            req,
+                            value,
+                        }).await;
+                    }
+                }
+            }
+            Event::SET { id, req, key, value } => {
+                // audit
+                if let Some(audit) = self.audit.as_mut() {
+                    let op = if value.is_some() { AUDIT_OP_SET } else { AUDIT_OP_DELETE };
+                    audit.append(user(&self.access, &id), &id, op, &key, value.as_deref()).await;
+                }
+
+                // do set
+                info!("Receive set event, id = {}, key = {:?}, value = {:?}", &id, &key, &value);
+                if let Some(hooks) = self.hooks.as_mut() {
+                    match &value {
+                        Some(v) => hooks.on_set(&key, v),
+                        None => hooks.on_delete(&key),
+                    }
+                }
+                match value {
+                    Some(v) => self.storage.put(key, v).await,
+                    None => self.storage.delete(key).await,
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SET {
+                            id: id.clone(),
+                            req,
+                        }).await;
+                    }
+                }
+            }
+            Event::EXISTS { id, req, key } => {
+                info!("Receive exists event, id = {}, key = {:?}", &id, &key);
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::EXISTS {
+                            id: id.clone(),
+                            req,
+                            exists: self.reader(&id).contains(&key),
+                        }).await;
+                    }
+                }
+            }
+            Event::SCAN { id, req, start, end } => {
+                info!("Receive scan event, id = {}, start = {:?}, end = {:?}", &id, &start, &end);
+                let entries = self.reader(&id).scan(&start, &end);
+                self.send_entries(id, req, entries).await;
+            }
+            Event::PREFIX { id, req, prefix, with_values } => {
+                info!("Receive prefix event, id = {}, prefix = {:?}, with values = {}", &id, &prefix, with_values);
+                let entries = self.reader(&id).prefix(&prefix, with_values);
+                self.send_entries(id, req, entries).await;
+            }
+            Event::KEYS { id, req, pattern } => {
+                info!("Receive keys event, id = {}, pattern = {:?}", &id, &pattern);
+                let entries = self.reader(&id).keys(&pattern).into_iter().map(|key| (key, Vec::new())).collect();
+                self.send_entries(id, req, entries).await;
+            }
+            Event::MGET { id, req, keys } => {
+                info!("Receive mget event, id = {}, keys = {:?}", &id, &keys);
+                let mut values = Vec::with_capacity(keys.len());
+                for key in keys.iter() {
+                    let value = self.reader(&id).get(key);
+                    if let (None, Some(hooks)) = (&value, self.hooks.as_mut()) {
+                        hooks.on_get_miss(key);
+                    }
+                    values.push(value);
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::MGET {
+                            id: id.clone(),
+                            req,
+                            values,
+                        }).await;
+                    }
+                }
+            }
+            Event::SETEX { id, req, key, value, ttl } => {
+                info!("Receive setex event, id = {}, key = {:?}, ttl = {}, value = {:?}", &id, &key, ttl, &value);
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.append(user(&self.access, &id), &id, AUDIT_OP_SET, &key, Some(&value)).await;
+                }
+                if let Some(hooks) = self.hooks.as_mut() {
+                    hooks.on_set(&key, &value);
+                }
+                let expire_at = now_millis() + ttl as u64 * 1000;
+                self.storage.put_expire(key, value, expire_at).await;
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SET {
+                            id: id.clone(),
+                            req,
+                        }).await;
+                    }
+                }
+            }
+            Event::SETM { id, req, entries } => {
+                info!("Receive mset event, id = {}, count = {}", &id, entries.len());
+                for (key, value) in entries.iter() {
+                    if let Some(audit) = self.audit.as_mut() {
+                        let op = if value.is_some() { AUDIT_OP_SET } else { AUDIT_OP_DELETE };
+                        audit.append(user(&self.access, &id), &id, op, key, value.as_deref()).await;
+                    }
+                    if let Some(hooks) = self.hooks.as_mut() {
+                        match value {
+                            Some(v) => hooks.on_set(key, v),
+                            None => hooks.on_delete(key),
+                        }
+                    }
+                }
+                // 整批写入后才处理下一个事件，读不会看到一半的数据
+                self.storage.write_batch(entries).await;
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SETM {
+                            id: id.clone(),
+                            req,
+                        }).await;
+                    }
+                }
+            }
+            Event::CAS { id, req, key, expected, value } => {
+                info!("Receive cas event, id = {}, key = {:?}, expected = {:?}, value = {:?}", &id, &key, &expected, &value);
+                // 事件循环单线程执行，比较和写入之间不会插入其他写
+                let swapped = self.storage.get(&key) == expected;
+                if swapped {
+                    if let Some(audit) = self.audit.as_mut() {
+                        let op = if value.is_some() { AUDIT_OP_SET } else { AUDIT_OP_DELETE };
+                        audit.append(user(&self.access, &id), &id, op, &key, value.as_deref()).await;
+                    }
+                    if let Some(hooks) = self.hooks.as_mut() {
+                        match &value {
+                            Some(v) => hooks.on_set(&key, v),
+                            None => hooks.on_delete(&key),
+                        }
+                    }
+                    match value {
+                        Some(v) => self.storage.put(key, v).await,
+                        None => self.storage.delete(key).await,
+                    }
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::CAS {
+                            id: id.clone(),
+                            req,
+                            swapped,
+                        }).await;
+                    }
+                }
+            }
+            Event::META { id, req, key } => {
+                info!("Receive meta event, id = {}, key = {:?}", &id, &key);
+                let meta = self.reader(&id).meta(&key);
+                let ttl = meta.as_ref().and_then(|meta| meta.expire_at).map(|expire_at| expire_at.saturating_sub(now_millis()));
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::META {
+                            id: id.clone(),
+                            req,
+                            meta,
+                            ttl,
+                        }).await;
+                    }
+                }
+            }
+            Event::LPM { id, req, key } => {
+                info!("Receive lpm event, id = {}, key = {:?}", &id, &key);
+                let entry = self.reader(&id).longest_prefix(&key);
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::LPM {
+                            id: id.clone(),
+                            req,
+                            entry,
+                        }).await;
+                    }
+                }
+            }
+            Event::CLUSTER { id, req } => {
+                info!("Receive cluster event, id = {}", &id);
+                if let Some(mut client_entry) = self.client_map.get_mut(&id) {
+                    client_entry.value_mut().send_event_res(EventRes::CLUSTER { id: id.clone(), req, ring: self.ring.as_ref().map(|ring| ring.borrow().clone()) }).await;
+                }
+            }
+            Event::FLUSH { id, req } => {
+                warn!("Receive flush event, id = {}", &id);
+                self.storage.flush().await;
+                for storage in self.storages.values_mut() {
+                    storage.flush().await;
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::FLUSH {
+                            id: id.clone(),
+                            req,
+                        }).await;
+                    }
+                }
+            }
+            Event::SHUTDOWN { id, req } => {
+                warn!("Receive shutdown event, id = {}", &id);
+                self.sync_wal().await;
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SHUTDOWN {
+                            id: id.clone(),
+                            req,
+                        }).await;
+                    }
+                }
+            }
+            Event::GETV { id, req, key } => {
+                info!("Receive getv event, id = {}, key = {:?}", &id, &key);
+                let value = self.reader(&id).get(&key);
+                let version = self.reader(&id).version(&key);
+                if let (None, Some(hooks)) = (&value, self.hooks.as_mut()) {
+                    hooks.on_get_miss(&key);
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::GETV {
+                            id: id.clone(),
+                            req,
+                            value,
+                            version,
+                        }).await;
+                    }
+                }
+            }
+            Event::SETV { id, req, key, version, value } => {
+                info!("Receive setv event, id = {}, key = {:?}, version = {}, value = {:?}", &id, &key, version, &value);
+                let current = self.storage.version(&key);
+                let ok = current == version;
+                let version = if ok {
+                    if let Some(audit) = self.audit.as_mut() {
+                        let op = if value.is_some() { AUDIT_OP_SET } else { AUDIT_OP_DELETE };
+                        audit.append(user(&self.access, &id), &id, op, &key, value.as_deref()).await;
+                    }
+                    if let Some(hooks) = self.hooks.as_mut() {
+                        match &value {
+                            Some(v) => hooks.on_set(&key, v),
+                            None => hooks.on_delete(&key),
+                        }
+                    }
+                    match value {
+                        Some(v) => self.storage.put(key.clone(), v).await,
+                        None => self.storage.delete(key.clone()).await,
+                    }
+                    self.storage.version(&key)
+                } else {
+                    current
+                };
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SETV {
+                            id: id.clone(),
+                            req,
+                            ok,
+                            version,
+                        }).await;
+                    }
+                }
+            }
+            Event::MERGE { id, req, name, key, operand } => {
+                info!("Receive merge event, id = {}, name = {:?}, key = {:?}, operand = {:?}", &id, &name, &key, &operand);
+                let value = match self.merges.get(&name) {
+                    Some(operator) => operator.merge(self.storage.get(&key).as_deref(), &operand).filter(|v| v.len() <= self.limits.max_value),
+                    None => {
+                        warn!("Unknown merge operator {:?}", String::from_utf8_lossy(&name));
+                        None
+                    }
+                };
+                match &value {
+                    Some(v) => {
+                        if let Some(audit) = self.audit.as_mut() {
+                            audit.append(user(&self.access, &id), &id, AUDIT_OP_SET, &key, Some(v)).await;
+                        }
+                        if let Some(hooks) = self.hooks.as_mut() {
+                            hooks.on_set(&key, v);
+                        }
+                        self.storage.put(key, v.clone()).await;
+                    }
+                    None => warn!("Merge key {:?} fail", &key),
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::MERGE {
+                            id: id.clone(),
+                            req,
+                            value,
+                        }).await;
+                    }
+                }
+            }
+            Event::INCR { id, req, key, delta } => {
+                info!("Receive incr event, id = {}, key = {:?}, delta = {}", &id, &key, delta);
+                let value = incr_value(self.storage.get(&key), delta);
+                match value {
+                    Some(v) => {
+                        let v = v.to_string().into_bytes();
+                        if let Some(audit) = self.audit.as_mut() {
+                            audit.append(user(&self.access, &id), &id, AUDIT_OP_SET, &key, Some(&v)).await;
+                        }
+                        if let Some(hooks) = self.hooks.as_mut() {
+                            hooks.on_set(&key, &v);
+                        }
+                        self.storage.put(key, v).await;
+                    }
+                    None => {
+                        warn!("Incr key {:?} fail, value is not an integer or overflow", &key);
+                    }
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::INCR {
+                            id: id.clone(),
+                            req,
+                            value,
+                        }).await;
+                    }
+                }
+            }
+            Event::APPEND { id, req, key, suffix } => {
+                info!("Receive append event, id = {}, key = {:?}, suffix = {:?}", &id, &key, &suffix);
+                let old_len = self.storage.get(&key).map_or(0, |v| v.len());
+                let len = if old_len + suffix.len() > self.limits.max_value {
+                    warn!("Append key {:?} fail, value too long", &key);
+                    None
+                } else {
+                    if let Some(audit) = self.audit.as_mut() {
+                        audit.append(user(&self.access, &id), &id, AUDIT_OP_APPEND, &key, Some(&suffix)).await;
+                    }
+                    let hook_key = self.hooks.as_ref().map(|_| key.clone());
+                    let len = self.storage.append(key, suffix).await;
+                    if let (Some(hooks), Some(key)) = (self.hooks.as_mut(), hook_key) {
+                        if let Some(value) = self.storage.get(&key) {
+                            hooks.on_set(&key, &value);
+                        }
+                    }
+                    Some(len)
+                };
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::APPEND {
+                            id: id.clone(),
+                            req,
+                            len,
+                        }).await;
+                    }
+                }
+            }
+            Event::DBSIZE { id, req } => {
+                info!("Receive dbsize event, id = {}", &id);
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::DBSIZE {
+                            id: id.clone(),
+                            req,
+                            size: self.storage.size() as u64,
+                        }).await;
+                    }
+                }
+            }
+            Event::GETSET { id, req, key, value } => {
+                info!("Receive getset event, id = {}, key = {:?}, value = {:?}", &id, &key, &value);
+                // 读旧值和写新值在同一个事件里完成，中间不会插入其他写
+                let old = self.storage.get(&key);
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.append(user(&self.access, &id), &id, AUDIT_OP_SET, &key, Some(&value)).await;
+                }
+                if let Some(hooks) = self.hooks.as_mut() {
+                    hooks.on_set(&key, &value);
+                }
+                self.storage.put(key, value).await;
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::GETSET {
+                            id: id.clone(),
+                            req,
+                            value: old,
+                        }).await;
+                    }
+                }
+            }
+            Event::FLUSHALL { id, req } => {
+                warn!("Receive flushall event, id = {}", &id);
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.append(user(&self.access, &id), &id, AUDIT_OP_FLUSHALL, &[], None).await;
+                }
+                self.storage.clear().await;
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::FLUSHALL {
+                            id: id.clone(),
+                            req,
+                        }).await;
+                    }
+                }
+            }
+            Event::PING { id, req, payload } => {
+                // 健康检查也用 PING，不记 info
+                debug!("Receive ping event, id = {}, payload = {}", &id, payload);
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::PONG {
+                            id: id.clone(),
+                            req,
+                            payload,
+                        }).await;
+                    }
+                }
+            }
+            Event::STATS { id, req } => {
+                info!("Receive stats event, id = {}", &id);
+                let storage_stats = self.storage.stats().await;
+                let (delayed, delayed_ms) = self.throttle.as_ref().map_or((0, 0), |throttle| throttle.delayed());
+                let stats = vec![
+                    storage_stats.keys,
+                    storage_stats.memtable_bytes,
+                    storage_stats.wal_bytes,
+                    self.client_map.len() as u64,
+                    self.ops.per_sec(),
+                    storage_stats.block_cache_hits,
+                    storage_stats.block_cache_misses,
+                    storage_stats.memtable_memory,
+                    self.denied,
+                    delayed,
+                    delayed_ms,
+                ];
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::STATS {
+                            id: id.clone(),
+                            req,
+                            stats,
+                        }).await;
+                    }
+                }
+            }
+            Event::MULTI { id, req } => {
+                info!("Receive multi event, id = {}", &id);
+                self.multi.insert(id.clone(), Vec::new());
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::MULTI {
+                            id: id.clone(),
+                            req,
+                        }).await;
+                    }
+                }
+            }
+            Event::EXEC { id, req, .. } => {
+                let events = self.multi.remove(&id).unwrap_or_default();
+                self.exec(id, req, events).await;
+            }
+            Event::SELECT { id, req, name } => {
+                info!("Receive select event, id = {}, name = {:?}", &id, &name);
+                let ok = match String::from_utf8(name).ok().filter(|name| valid_namespace(name)) {
+                    Some(name) if self.switch(&name).await => {
+                        if name.is_empty() {
+                            self.namespaces.remove(&id);
+                        } else {
+                            self.namespaces.insert(id.clone(), name);
+                        }
+                        true
+                    }
+                    _ => false,
+                };
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SELECT {
+                            id: id.clone(),
+                            req,
+                            ok,
+                        }).await;
+                    }
+                }
+            }
+            Event::SNAPSHOT { id, req } => {
+                info!("Receive snapshot event, id = {}", &id);
+                let snapshot = self.storage.snapshot().await;
+                let version = snapshot.last_version();
+                self.snapshots.insert(id.clone(), (self.namespace.clone(), snapshot));
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SNAPSHOT {
+                            id: id.clone(),
+                            req,
+                            version,
+                        }).await;
+                    }
+                }
+            }
+            Event::RELEASE { id, req } => {
+                info!("Receive release event, id = {}", &id);
+                let ok = self.snapshots.remove(&id).is_some();
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::RELEASE {
+                            id: id.clone(),
+                            req,
+                            ok,
+                        }).await;
+                    }
+                }
+            }
+            Event::COMPACT { id, req, start, end } => {
+                warn!("Receive compact event, id = {}, start = {:?}, end = {:?}", &id, &start, &end);
+                let files = self.storage.compact(&start, &end).await as u32;
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::COMPACT {
+                            id: id.clone(),
+                            req,
+                            files,
+                        }).await;
+                    }
+                }
+            }
+            Event::SCRUB { id, req } => {
+                warn!("Receive scrub event, id = {}", &id);
+                let report = self.storage.scrub().await;
+                for path in report.corrupt.iter() {
+                    warn!("Scrub found corrupted file {}", path);
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::SCRUB {
+                            id: id.clone(),
+                            req,
+                            files: report.files,
+                            corrupt: report.corrupt.len() as u32,
+                        }).await;
+                    }
+                }
+            }
+            Event::INGEST { id, req, entries } => {
+                warn!("Receive ingest event, id = {}, count = {}", &id, entries.len());
+                // 有序才能直接写成 SST 文件，重复的 key 也拒绝
+                let res = if entries.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+                    // 整批记一条审计日志和一个钩子事件，不逐个 key 展开
+                    if let (Some((first, _)), Some((last, _))) = (entries.first(), entries.last()) {
+                        if let Some(audit) = self.audit.as_mut() {
+                            audit.append_bulk(user(&self.access, &id), &id, AUDIT_OP_INGEST, entries.len(), first, last).await;
+                        }
+                        if let Some(hooks) = self.hooks.as_mut() {
+                            hooks.on_ingest(entries.len(), first, last);
+                        }
+                    }
+                    let keys = self.storage.ingest(entries).await as u32;
+                    EventRes::INGEST { id: id.clone(), req, keys }
+                } else {
+                    warn!("Reject ingest from client {}, keys not sorted", &id);
+                    EventRes::ERROR { id: id.clone(), req, code: ERR_UNSORTED }
+                };
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(res).await;
+                    }
+                }
+            }
+            Event::DELETE_RANGE { id, req, start, end } => {
+                warn!("Receive delete range event, id = {}, start = {:?}, end = {:?}", &id, &start, &end);
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.append(user(&self.access, &id), &id, AUDIT_OP_DELETE_RANGE, &start, Some(&end)).await;
+                }
+                if let Some(hooks) = self.hooks.as_mut() {
+                    hooks.on_delete_range(&start, &end);
+                }
+                let keys = self.storage.delete_range(start, end).await as u64;
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::DELETE_RANGE {
+                            id: id.clone(),
+                            req,
+                            keys,
+                        }).await;
+                    }
+                }
+            }
+            Event::AUTH { id, req, ok, access } => {
+                info!("Receive auth event, id = {}, ok = {}", &id, ok);
+                // 失败时保持之前的认证
+                if ok {
+                    match access {
+                        Some(access) => self.access.insert(id.clone(), access),
+                        None => self.access.remove(&id),
+                    };
+                }
+                match self.client_map.get_mut(&id) {
+                    None => {
+                        info!("Don't have client id = {}", &id)
+                    }
+                    Some(mut client_entry) => {
+                        client_entry.value_mut().send_event_res(EventRes::AUTH {
+                            id: id.clone(),
+                            req,
+                            ok,
+                        }).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Permission;
+    use crate::audit::AuditLog;
+    use crate::hook::{Hook, HookRegistry};
+    use crate::replication::ReplicationLog;
+    use crate::testing::{temp_path, TestLoop, CLIENT};
+    use tokio::sync::{mpsc, oneshot};
+
+    fn set(key: &[u8]) -> Event {
+        Event::SET { id: CLIENT.to_string(), req: None, key: key.to_vec(), value: Some(b"v".to_vec()) }
+    }
+
+    // 审计日志的 user 列是按用户认证的用户名，其余客户端为 "-"
+    #[tokio::test]
+    async fn audit_records_user() {
+        let path = format!("{}/audit.log", temp_path("audit-user-log"));
+        let audit = AuditLog::open(path.clone(), u64::MAX).await;
+        let mut event_loop = TestLoop::with_audit("audit-user", Some(audit), |_| {}).await;
+        assert!(matches!(event_loop.call(set(b"anonymous")).await, EventRes::SET { .. }));
+        let access = Arc::new(Access { user: String::from("alice"), permission: Permission::ReadWrite, prefixes: Vec::new() });
+        let auth = Event::AUTH { id: CLIENT.to_string(), req: None, ok: true, access: Some(access) };
+        assert!(matches!(event_loop.call(auth).await, EventRes::AUTH { .. }));
+        assert!(matches!(event_loop.call(set(b"named")).await, EventRes::SET { .. }));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<Vec<&str>> = log.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(&records[0][1..5], ["-", CLIENT, "SET", "616e6f6e796d6f7573"]);
+        assert_eq!(&records[1][1..5], ["alice", CLIENT, "SET", "6e616d6564"]);
+    }
+
+    // 导入的 key 无序或重复时整批拒绝；成功时审计日志和钩子各记一条，给出条数和 key 的范围
+    #[tokio::test]
+    async fn ingest_audit_and_hooks() {
+        // 每次导入的条数和 key 的范围
+        type Ingested = Vec<(usize, Vec<u8>, Vec<u8>)>;
+        struct Recorder(Arc<std::sync::Mutex<Ingested>>);
+        impl Hook for Recorder {
+            fn name(&self) -> &str {
+                "recorder"
+            }
+
+            fn on_ingest(&self, count: usize, first: &[u8], last: &[u8]) {
+                self.0.lock().unwrap().push((count, first.to_vec(), last.to_vec()));
+            }
+        }
+        let ingested = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut registry = HookRegistry::new();
+        registry.register(Box::new(Recorder(ingested.clone())));
+        let hooks = registry.start(16);
+        let path = format!("{}/audit.log", temp_path("ingest-audit-log"));
+        let audit = AuditLog::open(path.clone(), u64::MAX).await;
+        let mut event_loop = TestLoop::with_audit("ingest-audit", Some(audit), |handler| handler.hooks = hooks).await;
+        let ingest = |keys: &[&[u8]]| Event::INGEST {
+            id: CLIENT.to_string(),
+            req: None,
+            entries: keys.iter().map(|key| (key.to_vec(), b"v".to_vec())).collect(),
+        };
+
+        for keys in [[b"a".as_slice(), b"c", b"b"], [b"a", b"b", b"b"]] {
+            assert!(matches!(event_loop.call(ingest(&keys)).await, EventRes::ERROR { code: ERR_UNSORTED, .. }));
+        }
+        let get = |key: &[u8]| Event::GET { id: CLIENT.to_string(), req: None, key: key.to_vec() };
+        assert!(matches!(event_loop.call(get(b"a")).await, EventRes::GET { value: None, .. }));
+
+        assert!(matches!(event_loop.call(ingest(&[b"a", b"b", b"c"])).await, EventRes::INGEST { keys: 3, .. }));
+        assert!(matches!(event_loop.call(get(b"b")).await, EventRes::GET { value: Some(_), .. }));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<Vec<&str>> = log.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(&records[0][1..6], ["-", CLIENT, "INGEST", "61", "3:63"]);
+        // 钩子在独立的线程中执行
+        for _ in 0..100 {
+            if !ingested.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*ingested.lock().unwrap(), [(3, b"a".to_vec(), b"c".to_vec())]);
+    }
+
+    // 只读用户的写入在执行前拒绝，事务中的也一样；拒绝的次数计入 STATS
+    #[tokio::test]
+    async fn deny_read_only() {
+        let mut event_loop = TestLoop::start("deny-read-only", |_| {}).await;
+        let access = Arc::new(Access { user: String::from("bob"), permission: Permission::Read, prefixes: Vec::new() });
+        let auth = Event::AUTH { id: CLIENT.to_string(), req: None, ok: true, access: Some(access) };
+        assert!(matches!(event_loop.call(auth).await, EventRes::AUTH { .. }));
+        assert!(matches!(event_loop.call(set(b"a")).await, EventRes::ERROR { code: ERR_DENIED, .. }));
+        let get = Event::GET { id: CLIENT.to_string(), req: None, key: b"a".to_vec() };
+        assert!(matches!(event_loop.call(get).await, EventRes::GET { value: None, .. }));
+
+        assert!(matches!(event_loop.call(Event::MULTI { id: CLIENT.to_string(), req: None }).await, EventRes::MULTI { .. }));
+        event_loop.send(set(b"b")).await;
+        event_loop.send(Event::EXEC { id: CLIENT.to_string(), req: None, abort: None }).await;
+        assert!(matches!(event_loop.recv().await, EventRes::ERROR { code: ERR_DENIED, .. }));
+        assert!(matches!(event_loop.recv().await, EventRes::EXEC { .. }));
+
+        match event_loop.call(Event::STATS { id: CLIENT.to_string(), req: None }).await {
+            EventRes::STATS { stats, .. } => assert_eq!(stats[8], 2),
+            _ => panic!("Expect STATS response"),
+        }
+    }
+
+    // 超过写限流速率的突发写入被延迟，延迟的次数和时间计入 STATS
+    #[tokio::test]
+    async fn throttle_stats() {
+        let throttle = Arc::new(WriteThrottle::new(Some(20), None));
+        let handler_throttle = throttle.clone();
+        let mut event_loop = TestLoop::start("throttle-stats", |handler| handler.throttled(handler_throttle)).await;
+        match event_loop.call(Event::STATS { id: CLIENT.to_string(), req: None }).await {
+            EventRes::STATS { stats, .. } => assert_eq!(stats[9..], [0, 0]),
+            _ => panic!("Expect STATS response"),
+        }
+
+        for _ in 0..25 {
+            throttle.acquire(0).await;
+        }
+        match event_loop.call(Event::STATS { id: CLIENT.to_string(), req: None }).await {
+            EventRes::STATS { stats, .. } => assert!(stats[9] > 0 && stats[10] > 0),
+            _ => panic!("Expect STATS response"),
+        }
+    }
+
+    // 内存存储引擎同样支持快照、范围删除和命名空间，落盘、合并和校验没有文件可处理
+    #[tokio::test]
+    async fn memory_engine() {
+        let mut event_loop = TestLoop::memory("memory-engine").await;
+        let set = |key: &[u8], value: &[u8]| Event::SET { id: CLIENT.to_string(), req: None, key: key.to_vec(), value: Some(value.to_vec()) };
+        let get = |key: &[u8]| Event::GET { id: CLIENT.to_string(), req: None, key: key.to_vec() };
+        for key in [b"a", b"b", b"c"] {
+            assert!(matches!(event_loop.call(set(key, b"1")).await, EventRes::SET { .. }));
+        }
+        assert!(matches!(event_loop.call(Event::SNAPSHOT { id: CLIENT.to_string(), req: None }).await, EventRes::SNAPSHOT { version: 3, .. }));
+        assert!(matches!(event_loop.call(set(b"a", b"2")).await, EventRes::SET { .. }));
+        assert!(matches!(event_loop.call(get(b"a")).await, EventRes::GET { value: Some(v), .. } if v == b"1"));
+        assert!(matches!(event_loop.call(Event::RELEASE { id: CLIENT.to_string(), req: None }).await, EventRes::RELEASE { ok: true, .. }));
+        assert!(matches!(event_loop.call(get(b"a")).await, EventRes::GET { value: Some(v), .. } if v == b"2"));
+
+        let delete_range = Event::DELETE_RANGE { id: CLIENT.to_string(), req: None, start: b"b".to_vec(), end: b"c".to_vec() };
+        assert!(matches!(event_loop.call(delete_range).await, EventRes::DELETE_RANGE { keys: 1, .. }));
+        event_loop.send(Event::SCAN { id: CLIENT.to_string(), req: None, start: Vec::new(), end: Vec::new() }).await;
+        let mut keys = Vec::new();
+        while let EventRes::SCAN { entry: Some((key, _)), .. } = event_loop.recv().await {
+            keys.push(key);
+        }
+        assert_eq!(keys, [b"a".to_vec(), b"c".to_vec()]);
+        let compact = Event::COMPACT { id: CLIENT.to_string(), req: None, start: Vec::new(), end: Vec::new() };
+        assert!(matches!(event_loop.call(compact).await, EventRes::COMPACT { files: 0, .. }));
+        match event_loop.call(Event::STATS { id: CLIENT.to_string(), req: None }).await {
+            EventRes::STATS { stats, .. } => assert_eq!(stats[..3], [2, 4, 0]),
+            _ => panic!("Expect STATS response"),
+        }
+
+        assert!(matches!(event_loop.call(Event::SELECT { id: CLIENT.to_string(), req: None, name: b"other".to_vec() }).await, EventRes::SELECT { ok: true, .. }));
+        assert!(matches!(event_loop.call(get(b"a")).await, EventRes::GET { value: None, .. }));
+    }
+
+    // 连接已超时放弃的请求不再执行，返回 ERR_TIMEOUT；事务中缓存的 op 被取消时整个事务丢弃
+    #[tokio::test]
+    async fn skip_cancelled() {
+        let mut event_loop = TestLoop::start("skip-cancelled", |_| {}).await;
+        event_loop.cancelled.insert(1);
+        let cancelled = Event::SET { id: CLIENT.to_string(), req: Some(1), key: b"a".to_vec(), value: Some(b"v".to_vec()) };
+        assert!(matches!(event_loop.call(cancelled).await, EventRes::ERROR { req: Some(1), code: ERR_TIMEOUT, .. }));
+
+        assert!(matches!(event_loop.call(Event::MULTI { id: CLIENT.to_string(), req: None }).await, EventRes::MULTI { .. }));
+        event_loop.send(Event::SET { id: CLIENT.to_string(), req: Some(1), key: b"b".to_vec(), value: Some(b"v".to_vec()) }).await;
+        event_loop.send(Event::EXEC { id: CLIENT.to_string(), req: Some(2), abort: None }).await;
+        assert!(matches!(event_loop.recv().await, EventRes::ERROR { req: Some(1), code: ERR_TIMEOUT, .. }));
+        assert!(matches!(event_loop.recv().await, EventRes::ERROR { req: Some(2), code: ERR_TIMEOUT, .. }));
+
+        for key in [b"a", b"b"] {
+            let get = Event::GET { id: CLIENT.to_string(), req: None, key: key.to_vec() };
+            assert!(matches!(event_loop.call(get).await, EventRes::GET { value: None, .. }));
+        }
+    }
+
+    // 加入 Raft 集群的事件循环，返回发送命令的一端
+    async fn raft_loop(name: &str) -> (TestLoop, mpsc::Sender<RaftCommand>) {
+        let (commands, receiver) = mpsc::channel(8);
+        let event_loop = TestLoop::start(name, |handler| handler.join(RaftFeed { commands: receiver, proposals: Arc::new(ReplicationLog::new(1 << 20)) })).await;
+        (event_loop, commands)
+    }
+
+    async fn install(commands: &mpsc::Sender<RaftCommand>, first: bool, records: Vec<Vec<u8>>, version: Option<u64>) {
+        let (reply, rx) = oneshot::channel();
+        commands.send(RaftCommand::Install { first, records, version, reply }).await.unwrap();
+        rx.await.unwrap();
+    }
+
+    async fn getv(event_loop: &mut TestLoop, key: &[u8]) -> (Option<Vec<u8>>, u64) {
+        match event_loop.call(Event::GETV { id: CLIENT.to_string(), req: None, key: key.to_vec() }).await {
+            EventRes::GETV { value, version, .. } => (value, version),
+            _ => panic!("Expect GETV response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn raft_install_keeps_versions() {
+        let (mut leader, commands) = raft_loop("raft-install-leader").await;
+        let (reply, rx) = oneshot::channel();
+        commands.send(RaftCommand::Lead { records: Vec::new(), reply }).await.unwrap();
+        rx.await.unwrap();
+        for key in [b"a", b"b", b"a"] {
+            assert!(matches!(leader.call(set(key)).await, EventRes::SET { .. }));
+        }
+        let (reply, rx) = oneshot::channel();
+        commands.send(RaftCommand::Snapshot(reply)).await.unwrap();
+        let snapshot = rx.await.unwrap();
+        assert_eq!(snapshot.records.len(), 2);
+        let expected = [getv(&mut leader, b"a").await, getv(&mut leader, b"b").await];
+        assert_ne!(expected[0].1, expected[1].1);
+
+        // 分两段安装，版本号和主节点相同
+        let (mut follower, commands) = raft_loop("raft-install-follower").await;
+        let mut records = snapshot.records.into_iter();
+        install(&commands, true, records.next().into_iter().collect(), None).await;
+        install(&commands, false, records.collect(), Some(snapshot.version)).await;
+        assert_eq!([getv(&mut follower, b"a").await, getv(&mut follower, b"b").await], expected);
+
+        // 第一段先清空之前的数据
+        install(&commands, true, Vec::new(), Some(snapshot.version)).await;
+        assert_eq!(getv(&mut follower, b"a").await, (None, 0));
+    }
 }
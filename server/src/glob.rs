@@ -0,0 +1,235 @@
+// glob 模式，按字节匹配
+// *      任意个字节
+// ?      一个字节
+// [abc]  其中一个字节，支持 a-z 范围，[^abc] 或 [!abc] 取反
+// \x     字节 x 本身
+enum Token {
+    Byte(u8),
+    Any,
+    Star,
+    Class {
+        negated: bool,
+        ranges: Vec<(u8, u8)>,
+    },
+}
+
+impl Token {
+    fn matches(&self, c: u8) -> bool {
+        match self {
+            Token::Byte(b) => *b == c,
+            Token::Any | Token::Star => true,
+            Token::Class { negated, ranges } => ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != *negated,
+        }
+    }
+}
+
+// 按 NFA 匹配：状态是模式中的位置，状态集为空时后面的字节不可能匹配，遍历 trie 时可以直接跳过子树
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &[u8]) -> Self {
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < pattern.len() {
+            match pattern[i] {
+                b'*' => {
+                    // 连续的 * 等价于一个
+                    if !matches!(tokens.last(), Some(Token::Star)) {
+                        tokens.push(Token::Star);
+                    }
+                }
+                b'?' => tokens.push(Token::Any),
+                b'\\' if i + 1 < pattern.len() => {
+                    i += 1;
+                    tokens.push(Token::Byte(pattern[i]));
+                }
+                b'[' => match Self::parse_class(pattern, i + 1) {
+                    Some((token, next)) => {
+                        tokens.push(token);
+                        i = next;
+                        continue;
+                    }
+                    // 没有闭合的 [ 按普通字节处理
+                    None => tokens.push(Token::Byte(b'[')),
+                },
+                c => tokens.push(Token::Byte(c)),
+            }
+            i += 1;
+        }
+        Pattern { tokens }
+    }
+
+    // 从 [ 之后开始解析，返回 token 和 ] 之后的位置
+    fn parse_class(pattern: &[u8], mut i: usize) -> Option<(Token, usize)> {
+        let negated = matches!(pattern.get(i), Some(b'^') | Some(b'!'));
+        if negated {
+            i += 1;
+        }
+        let mut ranges = Vec::new();
+        // 第一个 ] 作为普通字节
+        let mut first = true;
+        while i < pattern.len() {
+            let mut lo = pattern[i];
+            if lo == b']' && !first {
+                return Some((Token::Class { negated, ranges }, i + 1));
+            }
+            if lo == b'\\' && i + 1 < pattern.len() {
+                i += 1;
+                lo = pattern[i];
+            }
+            let hi = if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+                i += 2;
+                pattern[i]
+            } else {
+                lo
+            };
+            ranges.push((lo.min(hi), lo.max(hi)));
+            first = false;
+            i += 1;
+        }
+        None
+    }
+
+    // 模式开头不含通配符的部分，匹配的 key 都以它开头
+    pub fn literal_prefix(&self) -> Vec<u8> {
+        self.tokens.iter().map_while(|token| match token {
+            Token::Byte(b) => Some(*b),
+            _ => None,
+        }).collect()
+    }
+
+    // 跳过 literal_prefix 之后的初始状态
+    pub fn start(&self) -> Vec<usize> {
+        self.closure(vec![self.literal_prefix().len()])
+    }
+
+    // 读入字节 c 之后的状态
+    pub fn step(&self, states: &[usize], c: u8) -> Vec<usize> {
+        let mut next = Vec::new();
+        for &s in states {
+            match self.tokens.get(s) {
+                Some(Token::Star) => next.push(s),
+                Some(token) if token.matches(c) => next.push(s + 1),
+                _ => {}
+            }
+        }
+        self.closure(next)
+    }
+
+    pub fn is_match(&self, states: &[usize]) -> bool {
+        states.contains(&self.tokens.len())
+    }
+
+    // 完整的 key 是否匹配
+    pub fn matches(&self, key: &[u8]) -> bool {
+        let prefix = self.literal_prefix();
+        if !key.starts_with(&prefix) {
+            return false;
+        }
+        let mut states = self.start();
+        for c in &key[prefix.len()..] {
+            if states.is_empty() {
+                return false;
+            }
+            states = self.step(&states, *c);
+        }
+        self.is_match(&states)
+    }
+
+    // * 可以匹配空串，加上跳过 * 之后的状态
+    fn closure(&self, mut states: Vec<usize>) -> Vec<usize> {
+        let mut i = 0;
+        while i < states.len() {
+            if let Some(Token::Star) = self.tokens.get(states[i]) {
+                states.push(states[i] + 1);
+            }
+            i += 1;
+        }
+        states.sort_unstable();
+        states.dedup();
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        // 模式、key、是否匹配
+        let cases: [(&[u8], &[u8], bool); 34] = [
+            (b"*", b"", true),
+            (b"a*b**c", b"aXbYc", true),
+            (b"a*b**c", b"abc", true),
+            (b"a*b**c", b"acb", false),
+            (b"h?llo", b"hello", true),
+            (b"h?llo", b"hllo", false),
+            // 转义
+            (b"\\*", b"*", true),
+            (b"\\*", b"a", false),
+            (b"a\\?", b"a?", true),
+            (b"a\\?", b"ab", false),
+            (b"\\[a]", b"[a]", true),
+            (b"\\[a]", b"a", false),
+            (b"\\\\", b"\\", true),
+            // 末尾的 \ 按普通字节处理
+            (b"ab\\", b"ab\\", true),
+            (b"ab\\", b"ab", false),
+            // ] 前的 - 是普通字节
+            (b"[a-]", b"a", true),
+            (b"[a-]", b"-", true),
+            (b"[a-]", b"b", false),
+            (b"[a-c]", b"b", true),
+            (b"[c-a]", b"b", true),
+            (b"[a-c]", b"d", false),
+            // 取反
+            (b"[!abc]", b"d", true),
+            (b"[!abc]", b"b", false),
+            (b"[^a-c]x", b"dx", true),
+            (b"[^a-c]x", b"bx", false),
+            (b"[!]]", b"]", false),
+            // 第一个 ] 是普通字节，类中也可以转义
+            (b"[]a]", b"]", true),
+            (b"[]a]", b"a", true),
+            (b"[\\]x]", b"]", true),
+            (b"[\\]x]", b"\\", false),
+            // 没有闭合的 [ 按普通字节处理
+            (b"[ab", b"[ab", true),
+            (b"[ab", b"a", false),
+            (b"k[0-9][0-9]", b"k42", true),
+            (b"k[0-9][0-9]", b"k4x", false),
+        ];
+        for (pattern, key, expected) in cases {
+            assert_eq!(Pattern::parse(pattern).matches(key), expected, "{:?} {:?}", String::from_utf8_lossy(pattern), String::from_utf8_lossy(key));
+        }
+    }
+
+    // start 跳过 literal_prefix，开头的 * 可以匹配空串
+    #[test]
+    fn literal_prefix_and_start() {
+        // 模式、字面量前缀、初始状态
+        let cases: [(&[u8], &[u8], &[usize]); 7] = [
+            (b"", b"", &[0]),
+            (b"*", b"", &[0, 1]),
+            (b"abc", b"abc", &[3]),
+            (b"ab*c?", b"ab", &[2, 3]),
+            (b"a\\*b*", b"a*b", &[3, 4]),
+            (b"ab[c]", b"ab", &[2]),
+            (b"ab\\", b"ab\\", &[3]),
+        ];
+        for (pattern, prefix, start) in cases {
+            let glob = Pattern::parse(pattern);
+            assert_eq!(glob.literal_prefix(), prefix, "{:?}", String::from_utf8_lossy(pattern));
+            assert_eq!(glob.start(), start, "{:?}", String::from_utf8_lossy(pattern));
+        }
+        // 从 start 继续读入前缀之后的字节
+        let glob = Pattern::parse(b"ab*c?");
+        let states = glob.step(&glob.start(), b'c');
+        assert_eq!(states, [2, 3, 4]);
+        assert!(glob.is_match(&glob.step(&states, b'x')));
+        assert!(glob.step(&Pattern::parse(b"abc").start(), b'x').is_empty());
+    }
+}
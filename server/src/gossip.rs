@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::{interval, timeout, Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::cluster::Ring;
+use crate::replication::{put_field, Reader};
+use crate::utils::{get_id, now_millis};
+
+// 集群成员的 gossip：各节点每 GOSSIP_INTERVAL 轮流和一个已知节点交换成员表，不用在每个节点上维护完整的节点列表
+// 成员表中每个节点有自己递增的心跳，心跳超过 GOSSIP_SUSPECT 没有增加的节点疑似故障，超过 GOSSIP_DEAD 的视为故障
+// 哈希环由没有故障的节点组成，节点加入或故障时各节点算出新的环，发给事件循环；和 cluster_nodes 一样节点之间不迁移数据
+
+// 交换成员表
+// 发起方发送：
+// 8 bit GOSSIP_MAGIC
+// 4 bit len, 之后的字节数
+// 2 bit member count
+// n * (4 bit addr len, addr, 4 bit gossip addr len, gossip addr, 8 bit generation, 8 bit heartbeat)
+// 对方合并后回复自己的成员表，格式同 len 及之后的部分
+const GOSSIP_MAGIC: [u8; 8] = [0xff, b'L', b'S', b'M', b'G', b'O', b'S', 1];
+// 成员表的最大字节数，超过时视为非法
+const GOSSIP_MAX_LEN: usize = 1024 * 1024;
+// 交换成员表的间隔，也是本节点心跳增加的间隔
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+// 连接和读写的超时
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(3);
+const GOSSIP_SUSPECT: Duration = Duration::from_secs(5);
+const GOSSIP_DEAD: Duration = Duration::from_secs(15);
+
+// 节点的状态，由本节点最后一次看到它的心跳增加的时间判断
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Alive,
+    // 仍在哈希环中
+    Suspect,
+    // 不在哈希环中，也不再发给其他节点；心跳重新增加后恢复
+    Dead,
+}
+
+impl Health {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Health::Alive => "alive",
+            Health::Suspect => "suspect",
+            Health::Dead => "dead",
+        }
+    }
+}
+
+struct Member {
+    gossip: String,
+    // 节点每次启动时的 unix 毫秒，重启后心跳从 0 开始也比之前的新
+    generation: u64,
+    heartbeat: u64,
+    updated: Instant,
+}
+
+impl Member {
+    fn health(&self) -> Health {
+        match self.updated.elapsed() {
+            elapsed if elapsed >= GOSSIP_DEAD => Health::Dead,
+            elapsed if elapsed >= GOSSIP_SUSPECT => Health::Suspect,
+            _ => Health::Alive,
+        }
+    }
+}
+
+// 本节点看到的集群成员，按客户端地址；故障的节点一直保留，避免其他节点发来的旧心跳把它重新加入
+pub struct Membership {
+    local: String,
+    vnodes: usize,
+    members: Mutex<BTreeMap<String, Member>>,
+    // 下一次交换的对象在 targets 中的位置
+    next: Mutex<usize>,
+    ring: watch::Sender<Arc<Ring>>,
+}
+
+impl Membership {
+    // local 为本节点的客户端地址，gossip 为其他节点连接本节点 gossip 端口的地址
+    pub fn new(local: String, gossip: String, vnodes: usize) -> Self {
+        let member = Member { gossip, generation: now_millis(), heartbeat: 0, updated: Instant::now() };
+        let ring = Arc::new(Ring::new(vec![local.clone()], &local, vnodes));
+        Self {
+            local: local.clone(),
+            vnodes,
+            members: Mutex::new(BTreeMap::from([(local, member)])),
+            next: Mutex::new(0),
+            ring: watch::Sender::new(ring),
+        }
+    }
+
+    // 当前的哈希环，节点变化时唤醒
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Ring>> {
+        self.ring.subscribe()
+    }
+
+    // 各节点的客户端地址、gossip 地址和状态，按客户端地址排序
+    pub fn members(&self) -> Vec<(String, String, Health)> {
+        let members = self.members.lock().expect("Lock gossip members fail");
+        members.iter().map(|(addr, member)| (addr.clone(), member.gossip.clone(), self.health(addr, member))).collect()
+    }
+
+    fn health(&self, addr: &str, member: &Member) -> Health {
+        if addr == self.local { Health::Alive } else { member.health() }
+    }
+
+    // 本节点的心跳加一
+    fn beat(&self) {
+        let mut members = self.members.lock().expect("Lock gossip members fail");
+        if let Some(member) = members.get_mut(&self.local) {
+            member.heartbeat += 1;
+            member.updated = Instant::now();
+        }
+    }
+
+    // 没有故障的成员，故障的节点不发给其他节点
+    fn encode(&self) -> Vec<u8> {
+        let members = self.members.lock().expect("Lock gossip members fail");
+        let live: Vec<(&String, &Member)> = members.iter().filter(|(addr, member)| self.health(addr, member) != Health::Dead).collect();
+        let mut body = (live.len() as u16).to_be_bytes().to_vec();
+        for (addr, member) in live {
+            put_field(&mut body, addr.as_bytes());
+            put_field(&mut body, member.gossip.as_bytes());
+            body.extend(member.generation.to_be_bytes());
+            body.extend(member.heartbeat.to_be_bytes());
+        }
+        let mut buf = (body.len() as u32).to_be_bytes().to_vec();
+        buf.extend(body);
+        buf
+    }
+
+    // 合并 encode 的结果中 len 之后的部分，比本地新的心跳覆盖本地的；数据不完整时返回 None，已合并的部分保留
+    fn merge(&self, buf: &[u8]) -> Option<()> {
+        let mut reader = Reader::new(buf);
+        let count = u16::from_be_bytes(reader.bytes(2)?.try_into().ok()?) as usize;
+        let mut members = self.members.lock().expect("Lock gossip members fail");
+        for _ in 0..count {
+            let addr = String::from_utf8(reader.field()?.to_vec()).ok()?;
+            let gossip = String::from_utf8(reader.field()?.to_vec()).ok()?;
+            let (generation, heartbeat) = (reader.u64()?, reader.u64()?);
+            if addr == self.local {
+                continue;
+            }
+            match members.get_mut(&addr) {
+                Some(member) if (generation, heartbeat) <= (member.generation, member.heartbeat) => {}
+                Some(member) => {
+                    if member.health() == Health::Dead {
+                        warn!("Cluster node {} is back", &addr);
+                    }
+                    *member = Member { gossip, generation, heartbeat, updated: Instant::now() };
+                }
+                None => {
+                    info!("Cluster node {} joins, gossip {}", &addr, &gossip);
+                    members.insert(addr, Member { gossip, generation, heartbeat, updated: Instant::now() });
+                }
+            }
+        }
+        Some(())
+    }
+
+    // 没有故障的节点变化时发送新的哈希环
+    fn refresh(&self) {
+        let nodes: Vec<String> = self.members().into_iter()
+            .filter(|(_, _, health)| *health != Health::Dead)
+            .map(|(addr, _, _)| addr)
+            .collect();
+        if self.ring.borrow().nodes() != nodes.as_slice() {
+            warn!("Cluster nodes change to {:?}", &nodes);
+            self.ring.send_replace(Arc::new(Ring::new(nodes, &self.local, self.vnodes)));
+        }
+    }
+
+    // 下一次交换成员表的 gossip 地址：没有故障的其他节点和种子节点轮流
+    fn target(&self, seeds: &[String]) -> Option<String> {
+        let own = self.members.lock().expect("Lock gossip members fail").get(&self.local).map(|member| member.gossip.clone());
+        let mut targets: Vec<String> = self.members().into_iter()
+            .filter(|(addr, _, health)| *addr != self.local && *health != Health::Dead)
+            .map(|(_, gossip, _)| gossip)
+            .chain(seeds.iter().cloned())
+            .filter(|gossip| Some(gossip) != own.as_ref())
+            .collect();
+        targets.sort();
+        targets.dedup();
+        let mut next = self.next.lock().expect("Lock gossip target fail");
+        let target = targets.get(*next % targets.len().max(1)).cloned();
+        *next = next.wrapping_add(1);
+        target
+    }
+}
+
+// 接受其他节点的连接，合并它的成员表后回复自己的
+pub async fn serve(listener: TcpListener, membership: Arc<Membership>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let id = get_id(&addr.ip().to_string(), addr.port());
+                let membership = membership.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = timeout(GOSSIP_TIMEOUT, answer(socket, &membership)).await.unwrap_or(Err(String::from("timeout"))) {
+                        warn!("Gossip from [{}] fail, {}", id, e);
+                    }
+                }.instrument(info_span!("gossip", peer = %addr)));
+            }
+            Err(e) => {
+                error!("Fail to accept gossip connection; err = {:?}", e);
+            }
+        }
+    }
+}
+
+async fn answer(mut socket: TcpStream, membership: &Membership) -> Result<(), String> {
+    let mut magic = [0; 8];
+    socket.read_exact(&mut magic).await.map_err(|e| format!("read magic err = {:?}", e))?;
+    if magic != GOSSIP_MAGIC {
+        return Err(String::from("invalid magic"));
+    }
+    membership.merge(&read_table(&mut socket).await?).ok_or("invalid member table")?;
+    membership.refresh();
+    socket.write_all(&membership.encode()).await.map_err(|e| format!("write member table err = {:?}", e))
+}
+
+async fn read_table(socket: &mut TcpStream) -> Result<Vec<u8>, String> {
+    let len = socket.read_u32().await.map_err(|e| format!("read member table err = {:?}", e))? as usize;
+    if len > GOSSIP_MAX_LEN {
+        return Err(format!("member table too large, len = {}", len));
+    }
+    let mut buf = vec![0; len];
+    socket.read_exact(&mut buf).await.map_err(|e| format!("read member table err = {:?}", e))?;
+    Ok(buf)
+}
+
+// 和 gossip 地址为 target 的节点交换成员表
+async fn exchange(target: &str, membership: &Membership) -> Result<(), String> {
+    let mut socket = TcpStream::connect(target).await.map_err(|e| format!("connect err = {:?}", e))?;
+    let mut buf = GOSSIP_MAGIC.to_vec();
+    buf.extend(membership.encode());
+    socket.write_all(&buf).await.map_err(|e| format!("write member table err = {:?}", e))?;
+    membership.merge(&read_table(&mut socket).await?).ok_or("invalid member table")?;
+    Ok(())
+}
+
+// 定时增加心跳、判断故障并和其他节点交换成员表；seeds 为启动时联系的节点的 gossip 地址，之后也会定期联系
+pub fn start(membership: Arc<Membership>, seeds: Vec<String>) {
+    tokio::spawn(async move {
+        let mut ticker = interval(GOSSIP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            membership.beat();
+            if let Some(target) = membership.target(&seeds) {
+                if let Err(e) = timeout(GOSSIP_TIMEOUT, exchange(&target, &membership)).await.unwrap_or(Err(String::from("timeout"))) {
+                    info!("Gossip with {} fail, {}", &target, e);
+                }
+            }
+            membership.refresh();
+        }
+    }.instrument(info_span!("gossip")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(port: u16) -> Arc<Membership> {
+        Arc::new(Membership::new(format!("127.0.0.1:{}", port), format!("127.0.0.1:{}", port + 1), 16))
+    }
+
+    // encode 的结果去掉 len
+    fn table(membership: &Membership) -> Vec<u8> {
+        membership.encode()[4..].to_vec()
+    }
+
+    #[test]
+    fn merge_keeps_newer_heartbeats() {
+        let (a, b) = (node(8000), node(9000));
+        b.beat();
+        a.merge(&table(&b)).unwrap();
+        a.refresh();
+        assert_eq!(a.subscribe().borrow().nodes(), ["127.0.0.1:8000", "127.0.0.1:9000"]);
+
+        // 旧的心跳不覆盖本地的
+        let old = table(&b);
+        b.beat();
+        a.merge(&table(&b)).unwrap();
+        a.merge(&old).unwrap();
+        let heartbeat = a.members.lock().unwrap()["127.0.0.1:9000"].heartbeat;
+        assert_eq!(heartbeat, 2);
+
+        // 其他节点发来的本节点不覆盖本地的
+        b.merge(&table(&a)).unwrap();
+        assert_eq!(b.members.lock().unwrap()["127.0.0.1:9000"].heartbeat, 2);
+        assert!(a.merge(&table(&b)[..5]).is_none());
+    }
+
+    #[test]
+    fn dead_node_leaves_ring() {
+        let (a, b) = (node(8000), node(9000));
+        a.merge(&table(&b)).unwrap();
+        a.refresh();
+        assert_eq!(a.subscribe().borrow().nodes().len(), 2);
+
+        a.members.lock().unwrap().get_mut("127.0.0.1:9000").unwrap().updated = Instant::now() - GOSSIP_SUSPECT;
+        a.refresh();
+        assert_eq!(a.members()[1].2, Health::Suspect);
+        assert_eq!(a.subscribe().borrow().nodes().len(), 2);
+
+        a.members.lock().unwrap().get_mut("127.0.0.1:9000").unwrap().updated = Instant::now() - GOSSIP_DEAD;
+        a.refresh();
+        assert_eq!(a.members()[1].2, Health::Dead);
+        assert_eq!(a.subscribe().borrow().nodes(), ["127.0.0.1:8000"]);
+        // 故障的节点不再发给其他节点，也不再联系
+        let c = node(7000);
+        c.merge(&table(&a)).unwrap();
+        assert_eq!(c.members().len(), 2);
+        assert_eq!(a.target(&[]), None);
+
+        // 心跳增加后恢复
+        b.beat();
+        a.merge(&table(&b)).unwrap();
+        a.refresh();
+        assert_eq!(a.members()[1].2, Health::Alive);
+        assert_eq!(a.subscribe().borrow().nodes().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn exchange_through_seed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seed_addr = listener.local_addr().unwrap().to_string();
+        let seed = Arc::new(Membership::new(String::from("127.0.0.1:8000"), seed_addr.clone(), 16));
+        tokio::spawn(serve(listener, seed.clone()));
+
+        // 两个节点只知道种子节点，交换后都看到对方
+        let (a, b) = (node(9000), node(10000));
+        assert_eq!(a.target(std::slice::from_ref(&seed_addr)), Some(seed_addr.clone()));
+        exchange(&seed_addr, &a).await.unwrap();
+        exchange(&seed_addr, &b).await.unwrap();
+        b.refresh();
+        assert_eq!(b.subscribe().borrow().nodes(), ["127.0.0.1:10000", "127.0.0.1:8000", "127.0.0.1:9000"]);
+        assert_eq!(seed.subscribe().borrow().nodes().len(), 3);
+        assert_eq!(a.members().len(), 2);
+    }
+}
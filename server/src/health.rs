@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error};
+
+// 健康检查端口，给负载均衡探测：连接后读一次请求（不解析，兼容 HTTP 和纯 TCP 探测），回复一个 HTTP 响应后关闭
+// 所有分片打开存储引擎、回放完 WAL 并且没有暂停写入时回复 200 ready，否则 503 和原因
+// 探测很频繁，只记 debug 日志
+
+// 等待探测方发送请求的时间，纯 TCP 探测不发送
+const HEALTH_READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+// 各分片的就绪状态，由事件循环更新
+pub struct Readiness {
+    recovered: Vec<AtomicBool>,
+    stalled: Vec<AtomicBool>,
+}
+
+impl Readiness {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            recovered: (0..shards).map(|_| AtomicBool::new(false)).collect(),
+            stalled: (0..shards).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    // 分片的存储引擎已经打开
+    pub fn recovered(&self, shard: usize) {
+        self.recovered[shard].store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_stalled(&self, shard: usize, stalled: bool) {
+        self.stalled[shard].store(stalled, Ordering::Relaxed);
+    }
+
+    pub fn stalled(&self, shard: usize) -> bool {
+        self.stalled[shard].load(Ordering::Relaxed)
+    }
+
+    // 没有就绪时返回原因
+    pub fn check(&self) -> Result<(), &'static str> {
+        if !self.recovered.iter().all(|recovered| recovered.load(Ordering::Relaxed)) {
+            Err("recovering")
+        } else if self.stalled.iter().any(|stalled| stalled.load(Ordering::Relaxed)) {
+            Err("overloaded")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub async fn serve(listener: TcpListener, readiness: Arc<Readiness>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let readiness = readiness.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = probe(socket, &readiness).await {
+                        debug!("Health probe from {} fail, err = {:?}", addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Fail to accept health connection; err = {:?}", e);
+            }
+        }
+    }
+}
+
+async fn probe(mut socket: TcpStream, readiness: &Readiness) -> std::io::Result<()> {
+    let mut request = [0; 1024];
+    let _ = timeout(HEALTH_READ_TIMEOUT, socket.read(&mut request)).await;
+    let (status, body) = match readiness.check() {
+        Ok(()) => ("200 OK", "ready"),
+        Err(reason) => ("503 Service Unavailable", reason),
+    };
+    debug!("Health probe, {}", body);
+    let response = format!("HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n", status, body.len() + 1, body);
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(addr: std::net::SocketAddr) -> String {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"GET /health HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let readiness = Arc::new(Readiness::new(2));
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, readiness.clone()));
+
+        // 还有分片没有打开
+        readiness.recovered(0);
+        let response = get(addr).await;
+        assert!(response.starts_with("HTTP/1.0 503") && response.ends_with("\r\n\r\nrecovering\n"), "{}", response);
+
+        readiness.recovered(1);
+        assert!(get(addr).await.starts_with("HTTP/1.0 200"));
+
+        readiness.set_stalled(1, true);
+        assert!(get(addr).await.ends_with("overloaded\n"));
+        readiness.set_stalled(1, false);
+
+        // 纯 TCP 探测不发送请求，也会收到回复
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("ready\n"));
+    }
+}
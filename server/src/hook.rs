@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+#[cfg(feature = "changes-hook")]
+use std::io::Write;
+#[cfg(feature = "changes-hook")]
+use std::sync::Mutex;
+use std::thread;
+use tracing::{info, warn};
+#[cfg(feature = "changes-hook")]
+use crate::audit::to_hex;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+// 读写钩子，在独立的线程中同步执行，可以阻塞，不占用事件循环和 tokio 的工作线程
+pub trait Hook: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    fn on_set(&self, _key: &[u8], _value: &[u8]) {}
+
+    fn on_delete(&self, _key: &[u8]) {}
+
+    // 范围删除不展开成逐个 key，end 为空表示没有上界
+    fn on_delete_range(&self, _start: &[u8], _end: &[u8]) {}
+
+    // 批量导入同样不展开，只给出条数和 key 的范围 [first, last]
+    fn on_ingest(&self, _count: usize, _first: &[u8], _last: &[u8]) {}
+
+    fn on_get_miss(&self, _key: &[u8]) {}
+}
+
+#[allow(clippy::upper_case_acronyms)]
+enum HookEvent {
+    SET {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    DELETE {
+        key: Vec<u8>,
+    },
+    #[allow(non_camel_case_types)]
+    DELETE_RANGE {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    MISS {
+        key: Vec<u8>,
+    },
+    INGEST {
+        count: usize,
+        first: Vec<u8>,
+        last: Vec<u8>,
+    },
+}
+
+// 创建一个钩子，配置的 hooks 中按名字启用
+pub type HookFactory = fn() -> Box<dyn Hook>;
+
+// 配置中可以启用的钩子；新的钩子在这里加一项，可选的用 feature 开启
+const HOOKS: &[(&str, HookFactory)] = &[
+    ("log", || Box::new(LogHook)),
+    #[cfg(feature = "changes-hook")]
+    ("changes", || Box::new(ChangesHook(Mutex::new(std::io::stdout())))),
+];
+
+// 启动时注册钩子
+pub struct HookRegistry {
+    factories: HashMap<String, HookFactory>,
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+impl HookRegistry {
+    pub fn new() -> Self {
+        Self {
+            factories: HOOKS.iter().map(|(name, factory)| (name.to_string(), *factory)).collect(),
+            hooks: Vec::new(),
+        }
+    }
+
+    // 按名字创建并注册钩子，名字没有定义时返回错误
+    pub fn enable(&mut self, name: &str) -> Result<(), String> {
+        let factory = self.factories.get(name).ok_or_else(|| format!("Unknown hook {}", name))?;
+        self.register(factory());
+        Ok(())
+    }
+
+    pub fn register(&mut self, hook: Box<dyn Hook>) {
+        info!("Register hook {}", hook.name());
+        self.hooks.push(hook);
+    }
+
+    // 启动钩子线程；没有钩子时返回 None
+    pub fn start(self, capacity: usize) -> Option<HookDispatcher> {
+        if self.hooks.is_empty() {
+            return None;
+        }
+        let (sender, mut receiver) = mpsc::channel(capacity);
+        let hooks = self.hooks;
+        // 所有发送端关闭后线程退出
+        thread::Builder::new().name(String::from("lsm-hooks")).spawn(move || {
+            while let Some(event) = receiver.blocking_recv() {
+                for hook in hooks.iter() {
+                    match &event {
+                        HookEvent::SET { key, value } => hook.on_set(key, value),
+                        HookEvent::DELETE { key } => hook.on_delete(key),
+                        HookEvent::DELETE_RANGE { start, end } => hook.on_delete_range(start, end),
+                        HookEvent::MISS { key } => hook.on_get_miss(key),
+                        HookEvent::INGEST { count, first, last } => hook.on_ingest(*count, first, last),
+                    }
+                }
+            }
+        }).expect("Start hook thread fail");
+        Some(HookDispatcher {
+            sender,
+            dropped: 0,
+        })
+    }
+}
+
+// 队列满时丢弃事件，慢钩子不会拖慢事件循环；各事件循环 clone 一份，共用钩子线程
+#[derive(Clone)]
+pub struct HookDispatcher {
+    sender: mpsc::Sender<HookEvent>,
+    dropped: u64,
+}
+
+impl HookDispatcher {
+    fn dispatch(&mut self, event: HookEvent) {
+        match self.sender.try_send(event) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                warn!("Hook queue full, dropped {} events", self.dropped);
+            }
+            Err(TrySendError::Closed(_)) => {
+                warn!("Hook thread closed");
+            }
+        }
+    }
+
+    pub fn on_set(&mut self, key: &[u8], value: &[u8]) {
+        self.dispatch(HookEvent::SET { key: key.to_vec(), value: value.to_vec() });
+    }
+
+    pub fn on_delete(&mut self, key: &[u8]) {
+        self.dispatch(HookEvent::DELETE { key: key.to_vec() });
+    }
+
+    pub fn on_delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.dispatch(HookEvent::DELETE_RANGE { start: start.to_vec(), end: end.to_vec() });
+    }
+
+    pub fn on_get_miss(&mut self, key: &[u8]) {
+        self.dispatch(HookEvent::MISS { key: key.to_vec() });
+    }
+
+    pub fn on_ingest(&mut self, count: usize, first: &[u8], last: &[u8]) {
+        self.dispatch(HookEvent::INGEST { count, first: first.to_vec(), last: last.to_vec() });
+    }
+}
+
+// 内置钩子：把写操作和未命中记录到日志
+pub struct LogHook;
+
+impl Hook for LogHook {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn on_set(&self, key: &[u8], value: &[u8]) {
+        info!("Hook set key = {:?}, value len = {}", key, value.len());
+    }
+
+    fn on_delete(&self, key: &[u8]) {
+        info!("Hook delete key = {:?}", key);
+    }
+
+    fn on_delete_range(&self, start: &[u8], end: &[u8]) {
+        info!("Hook delete range start = {:?}, end = {:?}", start, end);
+    }
+
+    fn on_get_miss(&self, key: &[u8]) {
+        info!("Hook get miss key = {:?}", key);
+    }
+
+    fn on_ingest(&self, count: usize, first: &[u8], last: &[u8]) {
+        info!("Hook ingest {} keys, first = {:?}, last = {:?}", count, first, last);
+    }
+}
+
+// changes-hook 特性开启的钩子：把写操作逐行输出，外部程序从标准输出订阅变更
+// <op>\t<hex key>[\t<hex value 或 end>]，导入为 INGEST\t<count>\t<hex first>\t<hex last>
+#[cfg(feature = "changes-hook")]
+pub struct ChangesHook<W: Write + Send + 'static>(Mutex<W>);
+
+#[cfg(feature = "changes-hook")]
+impl<W: Write + Send + 'static> ChangesHook<W> {
+    fn write(&self, line: String) {
+        let mut out = self.0.lock().expect("Lock changes output fail");
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            warn!("Write change fail, err = {:?}", e);
+        }
+    }
+}
+
+#[cfg(feature = "changes-hook")]
+impl<W: Write + Send + 'static> Hook for ChangesHook<W> {
+    fn name(&self) -> &str {
+        "changes"
+    }
+
+    fn on_set(&self, key: &[u8], value: &[u8]) {
+        self.write(format!("SET\t{}\t{}", to_hex(key), to_hex(value)));
+    }
+
+    fn on_delete(&self, key: &[u8]) {
+        self.write(format!("DELETE\t{}", to_hex(key)));
+    }
+
+    fn on_delete_range(&self, start: &[u8], end: &[u8]) {
+        self.write(format!("DELETE_RANGE\t{}\t{}", to_hex(start), to_hex(end)));
+    }
+
+    fn on_ingest(&self, count: usize, first: &[u8], last: &[u8]) {
+        self.write(format!("INGEST\t{}\t{}\t{}", count, to_hex(first), to_hex(last)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    // 收到的 key，执行钩子的线程名，是否在 tokio runtime 中
+    type Seen = Vec<(Vec<u8>, Option<String>, bool)>;
+
+    // 记录收到的 key 和执行钩子的线程，每次 on_set 阻塞 delay
+    struct Recorder {
+        seen: Arc<Mutex<Seen>>,
+        delay: Duration,
+    }
+
+    impl Hook for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn on_set(&self, key: &[u8], _value: &[u8]) {
+            thread::sleep(self.delay);
+            let current = thread::current();
+            let in_runtime = tokio::runtime::Handle::try_current().is_ok();
+            self.seen.lock().unwrap().push((key.to_vec(), current.name().map(String::from), in_runtime));
+        }
+    }
+
+    // 配置中的名字按 HOOKS 创建钩子，没有开启的 feature 对应的名字未知
+    #[test]
+    fn enable_by_name() {
+        let mut registry = HookRegistry::new();
+        assert_eq!(registry.enable("recorder"), Err(String::from("Unknown hook recorder")));
+        registry.enable("log").unwrap();
+        assert_eq!(registry.enable("changes").is_ok(), cfg!(feature = "changes-hook"));
+        let names: Vec<&str> = registry.hooks.iter().map(|hook| hook.name()).collect();
+        assert_eq!(names[0], "log");
+    }
+
+    #[cfg(feature = "changes-hook")]
+    #[test]
+    fn changes_lines() {
+        let hook = ChangesHook(Mutex::new(Vec::new()));
+        hook.on_set(b"a", b"1");
+        hook.on_delete(b"b");
+        hook.on_delete_range(b"c", b"");
+        hook.on_ingest(3, b"d", b"f");
+        hook.on_get_miss(b"g");
+        let out = String::from_utf8(hook.0.into_inner().unwrap()).unwrap();
+        assert_eq!(out, "SET\t61\t31\nDELETE\t62\nDELETE_RANGE\t63\t\nINGEST\t3\t64\t66\n");
+    }
+
+    // 钩子在独立的线程中执行，阻塞时单线程的 runtime 照常运行
+    #[tokio::test(flavor = "current_thread")]
+    async fn blocking_hook_off_runtime() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = HookRegistry::new();
+        registry.register(Box::new(Recorder { seen: seen.clone(), delay: Duration::from_millis(300) }));
+        let mut hooks = registry.start(16).unwrap();
+        hooks.on_set(b"a", b"1");
+        hooks.on_set(b"b", b"2");
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        while seen.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let thread = Some(String::from("lsm-hooks"));
+        assert_eq!(*seen.lock().unwrap(), [(b"a".to_vec(), thread.clone(), false), (b"b".to_vec(), thread, false)]);
+    }
+}
@@ -1,35 +1,312 @@
 mod event;
+mod audit;
 mod client;
 mod utils;
 mod trie;
+mod skiplist;
+mod memtable;
+mod storage;
+mod memory;
+mod throttle;
+mod hook;
+mod glob;
+mod merge;
+mod sstable;
+mod bloom;
+mod cache;
+mod manifest;
+mod dump;
+mod shard;
+mod admin;
+mod acl;
+mod replication;
+mod raft;
+mod cluster;
+mod gossip;
+mod health;
+mod dashboard;
+mod codec;
+mod parser;
+mod pending;
+mod connection;
+#[cfg(test)]
+mod testing;
 
-use std::collections::HashMap;
-use log::{error, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use tracing::{error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 use std::env;
+use std::io::IsTerminal;
+use std::iter::once;
 use std::sync::Arc;
-use dashmap::DashMap;
 use serde_derive::Deserialize;
 use tokio::fs::File;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::select;
-use tokio::sync::mpsc;
-use crate::client::Client;
-use crate::event::{Event, EventHandler, EventRes, LEN_MASK, NONE_VALUE_LEN, RES_GET, RES_SET};
-use crate::trie::Trie;
+use tokio::net::TcpListener;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Duration;
+use crate::acl::{Access, Permission};
+use crate::audit::{AuditLog, DEFAULT_AUDIT_MAX_SIZE};
+use crate::cache::BlockCache;
+use crate::hook::HookRegistry;
+use crate::event::{EventHandler, Settings, SizeLimits, DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_NAMESPACES, DEFAULT_MAX_REQUEST_SIZE, DEFAULT_MAX_VALUE_SIZE, MAX_LEN};
+use crate::pending::DEFAULT_MAX_INFLIGHT;
+use crate::connection::{Context, Users};
+use crate::throttle::{IoThrottle, WriteThrottle};
+use crate::storage::{EngineKind, LsmExt, LsmStorage, StorageEngine, StorageOptions, Tunables, DEFAULT_BLOCK_CACHE_BYTES, DEFAULT_DURABILITY_INTERVAL_MS, DEFAULT_L0_STOP_TRIGGER, DEFAULT_MAX_IMMUTABLE_MEMTABLES, DEFAULT_MEMTABLE_BYTES, DEFAULT_WAL_SEGMENT_BYTES, Durability};
+use crate::sstable::Compression;
+use crate::memtable::MemtableKind;
+use crate::memory::MemStorage;
+use crate::dump::{dump_file, import_file, DumpFormat};
+use crate::raft::RaftPeer;
+use crate::cluster::{Ring, DEFAULT_CLUSTER_VNODES};
+use crate::gossip::Membership;
+use crate::health::Readiness;
+use crate::replication::{ReplicationLog, ReplicationSink, SnapshotFeed, DEFAULT_REPLICATION_BACKLOG_BYTES};
+use crate::shard::{check_shards, shard_path, EventLoop, Shards};
 use crate::utils::get_id;
+use sha2::{Digest, Sha256};
 
 const SUB: &str = "-";
 
-// 握手数字
-const HELLO_NUM: u8 = 77;
+// 钩子队列长度
+const HOOK_QUEUE_SIZE: usize = 1024;
+
+// 请求中 op、请求编号和长度字段的字节数，max_request_size 至少为 key 和 value 的上限加上它
+const REQUEST_OVERHEAD: usize = 64;
+
+// 监听地址
+#[derive(Deserialize)]
+struct ListenConfig {
+    ip: String,
+    port: u32,
+}
+
+// 用户，例如 { name = "reader", password = "...", permission = "read-only", prefixes = ["app/"] }
+#[derive(Deserialize)]
+struct UserConfig {
+    name: String,
+    password: String,
+    // read-only、read-write 或 admin，默认 read-only
+    permission: Option<String>,
+    // 只能访问以其中之一开头的 key，不配置则不限制
+    prefixes: Option<Vec<String>>,
+}
 
 // 文件配置参数
 #[derive(Deserialize)]
 struct FileConfig {
     ip: String,
     port: u32,
+    // 除 ip 和 port 之外同时监听的地址，例如 [{ ip = "10.0.0.1", port = 8080 }]，所有地址的连接都一样处理
+    listen: Option<Vec<ListenConfig>>,
     data_path: Option<String>,
+    audit_path: Option<String>,
+    audit_max_size: Option<u64>,
+    write_ops_per_sec: Option<u64>,
+    write_bytes_per_sec: Option<u64>,
+    // 启用的钩子，名字见 hook.rs 的 HOOKS，例如 ["log"]
+    hooks: Option<Vec<String>>,
+    // 日志级别，格式同 RUST_LOG，例如 "info" 或 "server=debug"；不配置则由 RUST_LOG 指定
+    log_level: Option<String>,
+    // 是否允许 FLUSHALL 等管理命令，默认不允许
+    enable_admin_ops: Option<bool>,
+    // 管理端口，只监听 127.0.0.1，文本协议见 admin.rs；不配置则不开启
+    admin_port: Option<u32>,
+    // 管理页面的 HTTP 端口，只监听 127.0.0.1，接口见 dashboard.rs；不配置则不开启
+    dashboard_port: Option<u32>,
+    // 健康检查端口，回复 HTTP 200 或 503 表示是否就绪，见 health.rs；不配置则不开启
+    health_port: Option<u32>,
+    // 向支持 FEATURE_PING 的客户端发送 PING 的间隔，不配置则不发送
+    ping_interval_secs: Option<u64>,
+    // 请求从收到到返回响应的最长时间，超过时返回 ERR_TIMEOUT，之后到达的响应丢弃；不配置则不限制
+    request_timeout_ms: Option<u64>,
+    // 一个连接最多的未完成请求数，事务中缓存的 op 也算，达到后暂停读取，直到有请求完成；一个事务最多比它少一个 op，超过时断开连接；默认 DEFAULT_MAX_INFLIGHT
+    max_inflight_requests: Option<usize>,
+    // AUTH 密码，任一匹配即可，不限制权限；和 users 都不配置则不需要 AUTH
+    passwords: Option<Vec<String>>,
+    // 按用户认证，权限和可以访问的 key 前缀由事件循环检查
+    users: Option<Vec<UserConfig>>,
+    // 最多打开的命名空间数，包括默认命名空间，默认 DEFAULT_MAX_NAMESPACES
+    max_namespaces: Option<usize>,
+    // 请求中 key 的最大长度，超过时返回错误，默认 DEFAULT_MAX_KEY_SIZE
+    max_key_size: Option<usize>,
+    // 请求中 value 以及 APPEND、MERGE 结果的最大长度，默认 DEFAULT_MAX_VALUE_SIZE
+    max_value_size: Option<usize>,
+    // 一个请求的最大字节数，包括 MSET、INGEST 的所有项，连接读到长度字段即检查，超过时断开；默认 DEFAULT_MAX_REQUEST_SIZE
+    // 修改后只对新的连接生效
+    max_request_size: Option<usize>,
+    // SST block 缓存的字节数，所有命名空间共用，0 表示不缓存；默认 DEFAULT_BLOCK_CACHE_BYTES
+    block_cache_bytes: Option<usize>,
+    // 存储引擎，lsm 或 memory；memory 只在内存中，重启后数据为空，不支持复制、Raft、直接读取和离线导入导出；默认 lsm
+    engine: Option<String>,
+    // 内存表的实现，trie 或 skiplist，key 长且随机时 skiplist 占用的内存少；默认 trie
+    memtable: Option<String>,
+    // 内存表估计占用的内存超过该字节数时落盘，默认 DEFAULT_MEMTABLE_BYTES
+    memtable_bytes: Option<usize>,
+    // WAL 换段时把内存表写入检查点，启动时恢复检查点，只回放之后的 WAL 段；默认 false
+    memtable_checkpoint: Option<bool>,
+    // 内存表中最早的写入超过该秒数时落盘，不配置或为 0 则只按大小落盘
+    memtable_max_age_secs: Option<u64>,
+    // 排队落盘的只读内存表达到该数量时写入返回忙，默认 DEFAULT_MAX_IMMUTABLE_MEMTABLES
+    max_immutable_memtables: Option<usize>,
+    // 第 0 层文件达到该数量时写入返回忙，默认 DEFAULT_L0_STOP_TRIGGER
+    l0_stop_trigger: Option<usize>,
+    // WAL 段超过该字节数时换新的段，默认 DEFAULT_WAL_SEGMENT_BYTES
+    wal_segment_bytes: Option<u64>,
+    // WAL 何时 sync：always 每次写入后，interval 每 durability_interval_ms 毫秒，never 交给操作系统；默认 never
+    durability: Option<String>,
+    // durability 为 interval 时的间隔，默认 DEFAULT_DURABILITY_INTERVAL_MS
+    durability_interval_ms: Option<u64>,
+    // 每层 SST 文件 data block 的压缩方式，none、snappy 或 lz4，层数超过长度时沿用最后一个；默认 ["snappy"]
+    sst_compression: Option<Vec<String>>,
+    // GET、EXISTS 和 MGET 在连接任务中直接从默认命名空间最近一次写入后的快照读取，不经过事件循环；默认 false
+    // 每次写入都要发布快照，写多读少时不建议开启；配置了钩子时不开启，未命中在事件循环中通知钩子
+    direct_reads: Option<bool>,
+    // 事件循环数，每个事件循环按 key 的哈希负责一部分 key，有自己的内存表、WAL 和 SST 文件；默认 1
+    // 数据目录记录第一次启动时的数量，之后不能修改；大于 1 时 MSET 和事务中的 key 必须在同一个分片，否则返回 ERR_CROSS_SHARD
+    event_loops: Option<usize>,
+    // SST 文件映射到内存读取，默认 false
+    sst_mmap: Option<bool>,
+    // 落盘和合并每秒最多写入 SST 文件的字节数，不配置则不限速
+    background_io_bytes_per_sec: Option<u64>,
+    // 每层 SST 文件的目录，例如 ["/nvme/lsm", "/hdd/lsm"]，层数超过长度时沿用最后一个；不配置则放在 data_path
+    sst_level_paths: Option<Vec<String>>,
+    // 主节点的复制端口，监听 ip，从节点从这里接收写入，协议见 replication.rs；不配置则不开启
+    // 数据目录为空的从节点连接时先发送快照，快照在事件循环中生成，期间不处理其他请求
+    replication_port: Option<u32>,
+    // 每个分片的复制日志保留的字节数，从节点落后更多时无法接续；默认 DEFAULT_REPLICATION_BACKLOG_BYTES
+    replication_backlog_bytes: Option<usize>,
+    // leader、follower 或 raft，默认 leader；从节点回放主节点的写入，拒绝客户端的写入，读取在本地执行
+    // raft 为集群模式，写入经过 Raft 日志，多数节点写入后才响应，见 raft.rs；需要 event_loops = 1，只有默认命名空间
+    role: Option<String>,
+    // role 为 follower 时主节点的复制地址，例如 "10.0.0.1:8090"，event_loops 必须与主节点相同
+    leader: Option<String>,
+    // role 为 raft 时本节点的 id，集群中唯一且不为 0
+    raft_id: Option<u64>,
+    // role 为 raft 时节点之间通信的端口，监听 ip
+    raft_port: Option<u32>,
+    // role 为 raft 时集群中的其他节点，例如 [{ id = 2, addr = "10.0.0.2:8091" }]
+    raft_peers: Option<Vec<RaftPeerConfig>>,
+    // 集群模式中所有节点的客户端地址，包括本节点，例如 ["10.0.0.1:8080", "10.0.0.2:8080"]；各节点配置相同，不配置则不开启
+    // 每个节点按哈希环负责一部分 key，不负责的 key 返回 RES_MOVED，见 cluster.rs
+    cluster_nodes: Option<Vec<String>>,
+    // 本节点在 cluster_nodes 中的地址，默认 "ip:port"
+    cluster_addr: Option<String>,
+    // 每个节点在哈希环上的位置数，各节点必须相同；默认 DEFAULT_CLUSTER_VNODES
+    cluster_vnodes: Option<usize>,
+    // 集群模式中节点之间交换成员表的端口，监听 ip，协议见 gossip.rs；和 cluster_nodes 只能配置一个
+    // 哈希环由 gossip 发现的没有故障的节点组成，本节点的地址为 cluster_addr
+    gossip_port: Option<u32>,
+    // 启动时联系的其他节点的 gossip 地址，例如 ["10.0.0.1:8092"]；第一个节点可以不配置
+    gossip_seeds: Option<Vec<String>>,
+}
+
+// Raft 集群中的其他节点
+#[derive(Deserialize)]
+struct RaftPeerConfig {
+    id: u64,
+    addr: String,
+}
+
+// 离线导入每个 SST 文件的条数
+const INGEST_BATCH: usize = 100000;
+
+// 文件每行一条 "key value"，key 严格递增；按 INGEST_BATCH 条一批写成第 0 层的 SST 文件
+async fn ingest_file(storage: &mut LsmStorage, path: &str, limits: &SizeLimits) {
+    let file = File::open(path).await.unwrap_or_else(|e| panic!("Open ingest file {} fail, err = {:?}", path, e));
+    let mut lines = BufReader::new(file).lines();
+    let mut batch: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(INGEST_BATCH);
+    let mut last: Option<Vec<u8>> = None;
+    let (mut entries, mut keys) = (0, 0);
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await.unwrap_or_else(|e| panic!("Read ingest file {} fail, err = {:?}", path, e)) {
+        line_number += 1;
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((&line, ""));
+        let (key, value) = (key.as_bytes().to_vec(), value.as_bytes().to_vec());
+        if last.as_ref().is_some_and(|last| *last >= key) {
+            panic!("Ingest file {} line {}, key not sorted", path, line_number);
+        }
+        if key.len() > limits.max_key || value.len() > limits.max_value {
+            panic!("Ingest file {} line {}, key or value too large", path, line_number);
+        }
+        last = Some(key.clone());
+        batch.push((key, value));
+        if batch.len() == INGEST_BATCH {
+            entries += batch.len();
+            keys += storage.ingest(std::mem::take(&mut batch)).await;
+            info!("Ingest {} entries", entries);
+        }
+    }
+    entries += batch.len();
+    keys += storage.ingest(batch).await;
+    // 第 0 层文件多时已在后台合并，等它完成再退出
+    storage.wait_background().await;
+    info!("Ingest file {} done, {} entries, {} new keys", path, entries, keys);
+}
+
+// SIGHUP 时重新读取配置文件，这些项的修改立即生效；其他项修改后需要重启，重新读取时保持启动时的值
+const RELOADABLE: [&str; 13] = [
+    "log_level", "write_ops_per_sec", "write_bytes_per_sec", "max_key_size", "max_value_size", "max_request_size", "memtable_bytes",
+    "memtable_max_age_secs", "max_immutable_memtables", "l0_stop_trigger", "wal_segment_bytes", "durability", "durability_interval_ms",
+];
+
+// 配置中运行时可以修改的部分，启动和重新读取配置文件时都由这里计算
+fn settings(file_config: &FileConfig) -> Result<Settings, String> {
+    // WAL sync 策略
+    let durability = match file_config.durability.as_deref().unwrap_or("never") {
+        "always" => Durability::Always,
+        "interval" => Durability::Interval(Duration::from_millis(file_config.durability_interval_ms.unwrap_or(DEFAULT_DURABILITY_INTERVAL_MS).max(1))),
+        "never" => Durability::Never,
+        other => return Err(format!("Unknown durability {}", other)),
+    };
+    // key 和 value 的长度上限，一个请求至少能放下一个最长的 key 和 value
+    let max_key = file_config.max_key_size.unwrap_or(DEFAULT_MAX_KEY_SIZE);
+    let max_value = file_config.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE).min(MAX_LEN);
+    let max_request = file_config.max_request_size.unwrap_or(DEFAULT_MAX_REQUEST_SIZE).max(max_key.saturating_add(max_value).saturating_add(REQUEST_OVERHEAD));
+    Ok(Settings {
+        limits: SizeLimits { max_key, max_value, max_request },
+        tunables: Tunables {
+            memtable_bytes: file_config.memtable_bytes.unwrap_or(DEFAULT_MEMTABLE_BYTES),
+            wal_segment_bytes: file_config.wal_segment_bytes.unwrap_or(DEFAULT_WAL_SEGMENT_BYTES),
+            durability,
+            memtable_max_age: file_config.memtable_max_age_secs.filter(|secs| *secs > 0).map(Duration::from_secs),
+            max_immutable_memtables: file_config.max_immutable_memtables.unwrap_or(DEFAULT_MAX_IMMUTABLE_MEMTABLES),
+            l0_stop_trigger: file_config.l0_stop_trigger.unwrap_or(DEFAULT_L0_STOP_TRIGGER),
+        },
+    })
+}
+
+// 日志级别，没有配置 log_level 时由 RUST_LOG 指定
+fn log_filter(file_config: &FileConfig) -> Result<EnvFilter, String> {
+    match file_config.log_level.as_deref() {
+        Some(level) => EnvFilter::try_new(level).map_err(|e| format!("Invalid log level {}, err = {}", level, e)),
+        None => Ok(EnvFilter::from_default_env()),
+    }
+}
+
+// 重新读取配置文件；和启动时相比修改了不能修改的项时，这些项保持启动时的值并记录日志，其他项照常应用
+fn reload_config(path: &str, startup: &toml::Table) -> Result<(FileConfig, Settings, EnvFilter), String> {
+    let config_str = std::fs::read_to_string(path).map_err(|e| format!("Error when reading file : {} err : {}", path, e))?;
+    let mut table: toml::Table = toml::from_str(&config_str).map_err(|e| format!("Error when convert str to config err : {}", e))?;
+    let keys: BTreeSet<String> = startup.keys().chain(table.keys()).cloned().collect();
+    for key in keys.iter().filter(|key| !RELOADABLE.contains(&key.as_str())) {
+        if startup.get(key) != table.get(key) {
+            error!("Config {} can't be changed without restart, keep {:?}", key, startup.get(key));
+            match startup.get(key) {
+                Some(value) => table.insert(key.clone(), value.clone()),
+                None => table.remove(key),
+            };
+        }
+    }
+    let file_config: FileConfig = table.try_into().map_err(|e| format!("Error when convert str to config err : {}", e))?;
+    let settings = settings(&file_config)?;
+    let filter = log_filter(&file_config)?;
+    Ok((file_config, settings, filter))
 }
 
 // 命令行参数
@@ -43,8 +320,15 @@ fn get_config_file_path(args_map: &HashMap<String, String>, default: &String) ->
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // init logger
-    env_logger::init();
+    // init logger，级别由 RUST_LOG 指定，默认只输出 error；和之前一样写到 stderr，不是终端时不带颜色
+    // 读取配置文件后按 log_level 修改，SIGHUP 时重新读取
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_filter_reloading();
+    let log_handle = logger.reload_handle();
+    logger.init();
 
     // parse env config
     let args: Vec<String> = env::args().collect();
@@ -60,7 +344,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let env_config = EnvConfig {
-        config_file_path: String::from(get_config_file_path(&args_map, &String::from("./server_config.toml"))),
+        config_file_path: get_config_file_path(&args_map, &String::from("./server_config.toml")),
     };
 
     info!("LSM server start with config");
@@ -80,254 +364,421 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("LSM server file config \n{}", &config_str);
 
-    let file_config: FileConfig = match toml::from_str(&config_str) {
+    // 启动时的配置，重新读取时比较不能修改的项
+    let startup_config: toml::Table = match toml::from_str(&config_str) {
+        Ok(s) => s,
+        Err(e) => panic!("Error when convert str to config err : {}", e),
+    };
+    let file_config: FileConfig = match startup_config.clone().try_into() {
         Ok(s) => s,
         Err(e) => panic!("Error when convert str to config err : {}", e),
     };
+    let settings = settings(&file_config).unwrap_or_else(|e| panic!("{}", e));
+    if file_config.log_level.is_some() {
+        let filter = log_filter(&file_config).unwrap_or_else(|e| panic!("{}", e));
+        log_handle.reload(filter).expect("Set log level fail");
+    }
 
     info!("LSM server start with ip {} port {}", file_config.ip, file_config.port);
 
     // create event mpsc
-    let (event_tx, event_rx) = mpsc::channel(128);
-    info!("LSM server create event mpsc");
+    let event_loops = file_config.event_loops.unwrap_or(1).max(1);
+    let hook_names = file_config.hooks.unwrap_or_default();
+    let engine = file_config.engine.as_deref().map_or(Some(EngineKind::Lsm), EngineKind::parse)
+        .unwrap_or_else(|| panic!("Unknown engine {:?}", file_config.engine));
+    let mut direct_reads = file_config.direct_reads.unwrap_or(false);
+    if direct_reads && !hook_names.is_empty() {
+        warn!("Direct reads disabled, hooks are configured");
+        direct_reads = false;
+    }
+    let role = file_config.role.clone().unwrap_or_else(|| String::from("leader"));
+    let leader = match (role.as_str(), file_config.leader.clone()) {
+        ("leader" | "raft", None) => None,
+        ("follower", Some(leader)) => Some(leader),
+        ("follower", None) => panic!("Role follower needs leader address"),
+        (_, Some(leader)) => panic!("Leader address {} needs role = \"follower\"", leader),
+        (role, _) => panic!("Unknown role {}", role),
+    };
+    let raft = role == "raft";
+    if raft && event_loops > 1 {
+        panic!("Role raft needs event_loops = 1");
+    }
+    // Raft 主节点上的写入提交后才能读到，连接不直接读取
+    if direct_reads && raft {
+        warn!("Direct reads disabled, role is raft");
+        direct_reads = false;
+    }
+    let cluster_addr = file_config.cluster_addr.clone().unwrap_or_else(|| format!("{}:{}", &file_config.ip, &file_config.port));
+    let vnodes = file_config.cluster_vnodes.unwrap_or(DEFAULT_CLUSTER_VNODES);
+    // 节点列表来自配置时哈希环不变，来自 gossip 时随节点加入和故障更新
+    let membership = match (file_config.cluster_nodes.is_some(), file_config.gossip_port) {
+        (true, Some(_)) => panic!("Config cluster_nodes and gossip_port can't be both set"),
+        (false, Some(port)) => Some(Arc::new(Membership::new(cluster_addr.clone(), format!("{}:{}", &file_config.ip, port), vnodes))),
+        (_, None) => None,
+    };
+    let ring = match (file_config.cluster_nodes.clone(), membership.as_ref()) {
+        (Some(nodes), _) => Some(watch::channel(Arc::new(Ring::new(nodes, &cluster_addr, vnodes))).1),
+        (None, Some(membership)) => Some(membership.subscribe()),
+        (None, None) => None,
+    };
+    // 直接读取不检查 key 是否由本节点负责
+    if direct_reads && ring.is_some() {
+        warn!("Direct reads disabled, cluster mode");
+        direct_reads = false;
+    }
+    // 内存存储引擎没有 WAL 记录可以复制，也不发布连接直接读取的快照
+    if engine == EngineKind::Memory {
+        if role != "leader" || file_config.replication_port.is_some() {
+            panic!("Engine memory needs role = \"leader\" without replication_port");
+        }
+        if ["--ingest", "--import", "--dump"].iter().any(|arg| args_map.contains_key(*arg)) {
+            panic!("Offline ingest, import and dump need engine = \"lsm\"");
+        }
+        if direct_reads {
+            warn!("Direct reads disabled, engine is memory");
+            direct_reads = false;
+        }
+    }
+    info!("LSM server create event mpsc for {} event loops", event_loops);
 
-    // clientMap
-    let client_map: Arc<DashMap<String, Client>> = Arc::new(DashMap::new());
-    let event_client_map = client_map.clone();
+    // 全局写限流
+    let throttle = Arc::new(WriteThrottle::new(file_config.write_ops_per_sec, file_config.write_bytes_per_sec));
 
-    // create event loop
-    tokio::spawn(async move {
-        let trie = Trie::new();
-        let mut event_handler = EventHandler::new(event_rx, trie, event_client_map, match file_config.data_path {
+    // 管理命令开关
+    let admin_ops = file_config.enable_admin_ops.unwrap_or(false);
+
+    // 服务端 PING 间隔
+    let ping_interval = file_config.ping_interval_secs.map(Duration::from_secs);
+    let request_timeout = file_config.request_timeout_ms.map(Duration::from_millis);
+    // 一个连接不能占满事件循环的通道；至少为 2，事务中至少能缓存一个 op
+    let max_inflight = file_config.max_inflight_requests.unwrap_or(DEFAULT_MAX_INFLIGHT).max(2);
+
+    // 只保存密码摘要，比较摘要避免逐字节比较泄露密码前缀
+    let passwords: Arc<Vec<Vec<u8>>> = Arc::new(file_config.passwords.unwrap_or_default().iter()
+        .map(|password| Sha256::digest(password.as_bytes()).to_vec())
+        .collect());
+    let mut users: Users = HashMap::new();
+    for user in file_config.users.unwrap_or_default() {
+        let permission = user.permission.as_deref().map_or(Some(Permission::Read), Permission::parse)
+            .unwrap_or_else(|| panic!("Unknown permission {:?} of user {}", user.permission, user.name));
+        let access = Access {
+            user: user.name.clone(),
+            permission,
+            prefixes: user.prefixes.unwrap_or_default().into_iter().map(String::into_bytes).collect(),
+        };
+        if users.insert(user.name.clone(), (Sha256::digest(user.password.as_bytes()).to_vec(), Arc::new(access))).is_some() {
+            panic!("Duplicate user {}", user.name);
+        }
+    }
+    let users = Arc::new(users);
+
+    // audit log，每个事件循环一个文件，分片 i > 0 的文件名后加 .shard{i}
+    let mut audits = Vec::with_capacity(event_loops);
+    for shard in 0..event_loops {
+        audits.push(match &file_config.audit_path {
             Some(path) => {
-                path.clone()
-            },
-            None => {
-                String::from("./data")
+                let path = if shard == 0 { path.clone() } else { format!("{}.shard{}", path, shard) };
+                Some(AuditLog::open(path, file_config.audit_max_size.unwrap_or(DEFAULT_AUDIT_MAX_SIZE)).await)
             }
-        }).await;
-        event_handler.start_event_loop().await;
-        panic!("Event loop end!!!")
-    });
+            None => None
+        });
+    }
 
-    info!("LSM server create event loop");
+    // hooks
+    let mut hook_registry = HookRegistry::new();
+    for name in hook_names {
+        hook_registry.enable(&name).unwrap_or_else(|e| panic!("{}", e));
+    }
 
-    // create tcp
-    let addr = format!("{}:{}", &file_config.ip, &file_config.port);
-    let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
-        panic!("Fail to open tcp server; err = {:?}", err);
-    });
-    info!("LSM server bind socket");
-
-    // tcp close func
-    async fn shutdown(id: &String, client_map: &Arc<DashMap<String, Client>>, mut socket: TcpStream) {
-        info!("Client [{}] disconnect", id);
-        client_map.remove(id);
-        socket.shutdown().await.unwrap_or_else(|e| {
-            info!("Fail close client [{}]; err = {:?} ", id, e);
+    // SST 压缩方式
+    let compression = file_config.sst_compression.unwrap_or_else(|| vec![String::from("snappy")]).iter()
+        .map(|name| Compression::parse(name).unwrap_or_else(|| panic!("Unknown sst compression {}", name)))
+        .collect();
+
+    // 内存表的实现
+    let memtable = file_config.memtable.as_deref().map_or(Some(MemtableKind::Trie), MemtableKind::parse)
+        .unwrap_or_else(|| panic!("Unknown memtable {:?}", file_config.memtable));
+
+    // 数据目录和各层 SST 目录，不存在时打开存储引擎时创建，已存在时必须是目录
+    let data_path = file_config.data_path.unwrap_or_else(|| String::from("./data"));
+    let level_paths = file_config.sst_level_paths.unwrap_or_default();
+    for path in once(&data_path).chain(level_paths.iter()) {
+        if path.trim().is_empty() {
+            panic!("Data path is empty");
+        }
+        match std::fs::metadata(path) {
+            Ok(meta) if !meta.is_dir() => panic!("Data path {} is not a directory", path),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => panic!("Check data path {} fail, err = {:?}", path, e),
+        }
+    }
+    info!("LSM server data path {}, sst level paths {:?}", data_path, level_paths);
+
+    // 长度上限等运行时可以修改的配置，SIGHUP 后发送新的值
+    let limits = settings.limits;
+    let (settings_tx, settings_rx) = watch::channel(settings);
+    let (shards, loops) = Shards::new(event_loops, direct_reads, settings_rx.clone());
+    let shards = Arc::new(shards);
+
+    let options = StorageOptions {
+        block_cache: Arc::new(BlockCache::new(file_config.block_cache_bytes.unwrap_or(DEFAULT_BLOCK_CACHE_BYTES))),
+        memtable,
+        memtable_bytes: settings.tunables.memtable_bytes,
+        wal_segment_bytes: settings.tunables.wal_segment_bytes,
+        durability: settings.tunables.durability,
+        compression,
+        mmap: file_config.sst_mmap.unwrap_or(false),
+        io_throttle: file_config.background_io_bytes_per_sec.filter(|r| *r > 0).map(|r| Arc::new(IoThrottle::new(r))),
+        level_paths,
+        memtable_max_age: settings.tunables.memtable_max_age,
+        max_immutable_memtables: settings.tunables.max_immutable_memtables,
+        l0_stop_trigger: settings.tunables.l0_stop_trigger,
+        checkpoint: file_config.memtable_checkpoint.unwrap_or(false),
+        shared_view: None,
+        replication: None,
+    };
+
+    // 数据目录的分片数必须与 event_loops 一致；离线工具只打开一个存储引擎，不支持分片
+    check_shards(&data_path, event_loops);
+    if event_loops > 1 && ["--ingest", "--import", "--dump"].iter().any(|arg| args_map.contains_key(*arg)) {
+        panic!("Offline ingest, import and dump need event_loops = 1");
+    }
+
+    // 离线导入：不启动服务，把文件中的数据直接写成 SST 文件后退出，导入期间服务端不能运行
+    if let Some(path) = args_map.get("--ingest") {
+        let mut storage = LsmStorage::open(data_path.clone(), options).await;
+        ingest_file(&mut storage, path, &limits).await;
+        return Ok(());
+    }
+
+    // 离线导入：读取 --dump 格式的文件，通过 WAL 和内存表写入后退出，格式由 --import-format 指定，默认 jsonl
+    // 带 --dry-run 时只检查文件，不打开存储引擎
+    if let Some(path) = args_map.get("--import") {
+        let format = args_map.get("--import-format").map_or(Some(DumpFormat::JsonLines), |name| DumpFormat::parse(name))
+            .unwrap_or_else(|| panic!("Unknown import format {:?}", args_map.get("--import-format")));
+        if args.iter().any(|arg| arg == "--dry-run") {
+            import_file(None, path, format, &limits).await;
+        } else {
+            let mut storage = LsmStorage::open(data_path.clone(), options).await;
+            import_file(Some(&mut storage), path, format, &limits).await;
+        }
+        return Ok(());
+    }
+
+    // 离线导出：把所有未过期的数据写入文件后退出，格式由 --dump-format 指定，jsonl 或 csv，默认 jsonl
+    if let Some(path) = args_map.get("--dump") {
+        let format = args_map.get("--dump-format").map_or(Some(DumpFormat::JsonLines), |name| DumpFormat::parse(name))
+            .unwrap_or_else(|| panic!("Unknown dump format {:?}", args_map.get("--dump-format")));
+        let storage = LsmStorage::open(data_path.clone(), options).await;
+        dump_file(&storage, path, format);
+        return Ok(());
+    }
+
+    // create event loops，每个分片在自己的目录下打开存储引擎，钩子任务共用
+    let hooks = hook_registry.start(HOOK_QUEUE_SIZE);
+    // Raft 日志只复制默认命名空间
+    let max_namespaces = if raft { 1 } else { file_config.max_namespaces.unwrap_or(DEFAULT_MAX_NAMESPACES) };
+    // 开启复制端口或 Raft 时每个分片一个复制日志
+    let replication_logs: Vec<Arc<ReplicationLog>> = if file_config.replication_port.is_some() || raft {
+        let limit = file_config.replication_backlog_bytes.unwrap_or(DEFAULT_REPLICATION_BACKLOG_BYTES);
+        (0..event_loops).map(|_| Arc::new(ReplicationLog::new(limit))).collect()
+    } else {
+        Vec::new()
+    };
+    // 复制端口为数据目录为空的从节点向事件循环请求快照
+    let (mut snapshot_feeds, snapshot_requests): (Vec<Option<SnapshotFeed>>, Vec<_>) = match file_config.replication_port {
+        Some(_) => replication_logs.iter().map(|log| {
+            let (feed, requests) = SnapshotFeed::new(log.clone());
+            (Some(feed), requests)
+        }).unzip(),
+        None => (Vec::new(), Vec::new()),
+    };
+    let mut raft_node = match raft {
+        true => {
+            let id = file_config.raft_id.filter(|id| *id > 0).unwrap_or_else(|| panic!("Role raft needs raft_id > 0"));
+            let port = file_config.raft_port.unwrap_or_else(|| panic!("Role raft needs raft_port"));
+            let peers: Vec<RaftPeer> = file_config.raft_peers.unwrap_or_default().into_iter()
+                .map(|peer| RaftPeer { id: peer.id, addr: peer.addr })
+                .collect();
+            if peers.iter().any(|peer| peer.id == id || peer.id == 0) {
+                panic!("Raft peer id must be unique and > 0");
+            }
+            let addr = format!("{}:{}", &file_config.ip, port);
+            let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+                panic!("Fail to open raft server on {}; err = {:?}", addr, err);
+            });
+            info!("LSM server bind raft socket {}, node {}, {} peers", addr, id, peers.len());
+            let state = raft::read_state(&data_path)?;
+            Some((id, listener, peers, state))
+        }
+        false => None,
+    };
+    // 开启健康检查端口时，各分片打开存储引擎后就绪
+    let readiness = file_config.health_port.map(|_| Arc::new(Readiness::new(event_loops)));
+    for (shard, (event_loop, audit)) in loops.into_iter().zip(audits).enumerate() {
+        let EventLoop { receiver: event_rx, client_map, shared_view } = event_loop;
+        let mut options = options.clone();
+        options.level_paths = options.level_paths.iter().map(|dir| shard_path(dir, shard)).collect();
+        options.shared_view = shared_view;
+        options.replication = replication_logs.get(shard).map(|log| ReplicationSink::new(log.clone()));
+        let data_path = shard_path(&data_path, shard);
+        let hooks = hooks.clone();
+        let settings = settings_rx.clone();
+        let leader = leader.clone();
+        let raft_node = raft_node.take();
+        let proposals = replication_logs.get(shard).cloned();
+        let ring = ring.clone();
+        let snapshot_feed = snapshot_feeds.get_mut(shard).and_then(Option::take);
+        let readiness = readiness.clone();
+        let throttle = throttle.clone();
+        tokio::spawn(async move {
+            // 内存存储引擎不会是从节点或 Raft 节点，也不提供复制快照
+            if engine == EngineKind::Memory {
+                let storage = MemStorage::open(data_path.clone(), options).await;
+                let mut event_handler = EventHandler::new(event_rx, storage, data_path, max_namespaces, settings, client_map, audit, hooks);
+                event_handler.throttled(throttle);
+                if let Some(readiness) = readiness {
+                    readiness.recovered(shard);
+                    event_handler.report(readiness, shard);
+                }
+                if let Some(ring) = ring {
+                    event_handler.cluster(ring);
+                }
+                event_handler.start_event_loop().await;
+                panic!("Event loop end!!!")
+            }
+            let storage = LsmStorage::open(data_path.clone(), options).await;
+            let mut event_handler = EventHandler::new(event_rx, storage, data_path.clone(), max_namespaces, settings, client_map, audit, hooks);
+            event_handler.throttled(throttle);
+            if let Some(readiness) = readiness {
+                readiness.recovered(shard);
+                event_handler.report(readiness, shard);
+            }
+            if let Some(ring) = ring {
+                event_handler.cluster(ring);
+            }
+            if let Some(feed) = snapshot_feed {
+                event_handler.replicate(feed);
+            }
+            if let Some(leader) = leader {
+                event_handler.follow(replication::follow(leader, shard, event_loops, data_path));
+            } else if let (Some((id, listener, peers, state)), Some(proposals)) = (raft_node, proposals) {
+                event_handler.join(raft::start(id, listener, peers, data_path, state, proposals).await);
+            }
+            event_handler.start_event_loop().await;
+            panic!("Event loop end!!!")
         });
     }
 
-    loop {
-        match listener.accept().await {
-            // new client
-            Ok((mut socket, addr)) => {
-                let event_tx = event_tx.clone();
-                let client_map_clone = client_map.clone();
-                tokio::spawn(async move {
-                    // create client mpsc
-                    let (client_tx, mut client_rx) = mpsc::channel(16);
-                    let id = get_id(&addr.ip().to_string(), addr.port());
-                    info!("Receive connection from [{}]", id);
-                    // create client
-                    let client = Client::new(id.clone(), client_tx);
-                    client_map_clone.insert(id.clone(), client);
-                    info!("New client from id [{}]", id);
-
-                    // write hello
-                    info!("Hello to client {}", id);
-                    if let Err(e) = socket.write_u8(HELLO_NUM).await {
-                        eprintln!("Failed to write hello to [{}]; err = {:?}", id, e);
-                        shutdown(&id, &client_map_clone, socket).await;
-                        return;
-                    }
+    info!("LSM server create {} event loops", event_loops);
+
+    // create tcp，每个监听地址一个 accept 任务，新连接都交给下面的循环处理
+    let (accept_tx, mut accept_rx) = mpsc::channel(128);
+    let listen = file_config.listen.unwrap_or_default();
+    for addr in once(format!("{}:{}", &file_config.ip, &file_config.port)).chain(listen.iter().map(|listen| format!("{}:{}", &listen.ip, &listen.port))) {
+        let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+            panic!("Fail to open tcp server on {}; err = {:?}", addr, err);
+        });
+        info!("LSM server bind socket {}", addr);
+        let accept_tx = accept_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                if accept_tx.send(listener.accept().await).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(port) = file_config.admin_port {
+        let addr = format!("{}:{}", admin::ADMIN_IP, port);
+        let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+            panic!("Fail to open admin server on {}; err = {:?}", addr, err);
+        });
+        info!("LSM server bind admin socket {}", addr);
+        tokio::spawn(admin::serve(listener, shards.clone(), throttle.clone(), membership.clone()));
+    }
+
+    if let Some(port) = file_config.dashboard_port {
+        let addr = format!("{}:{}", admin::ADMIN_IP, port);
+        let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+            panic!("Fail to open dashboard server on {}; err = {:?}", addr, err);
+        });
+        info!("LSM server bind dashboard socket {}", addr);
+        tokio::spawn(dashboard::serve(listener, shards.clone(), throttle.clone()));
+    }
+
+    if let (Some(port), Some(readiness)) = (file_config.health_port, readiness) {
+        let addr = format!("{}:{}", &file_config.ip, port);
+        let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+            panic!("Fail to open health server on {}; err = {:?}", addr, err);
+        });
+        info!("LSM server bind health socket {}", addr);
+        tokio::spawn(health::serve(listener, readiness));
+    }
 
-                    // wait hello
-                    match socket.read_u8().await {
-                        Ok(n) if n != HELLO_NUM => {
-                            warn!("Client [{}] verify hello fail", id);
-                            shutdown(&id, &client_map_clone, socket).await;
-                            return;
-                        }
-                        Ok(n) => n,
-                        Err(e) => {
-                            eprintln!("Failed to read hello from [{}]; err = {:?}", id, e);
-                            shutdown(&id, &client_map_clone, socket).await;
-                            return;
-                        }
-                    };
-
-                    // 消息缓存
-                    let mut b = Vec::new();
-                    let mut buf = [0; 1024];
-                    info!("Alloc buffer for client [{}]", id);
-
-                    loop {
-                        // 解析消息
-                        if let Some(op) = b.first() {
-                            match *op {
-                                event::OP_GET => {
-                                    if b.len() > 3 {
-                                        let key_len = ((b[1] as usize) * 0x100 + b[2] as usize) & LEN_MASK as usize;
-                                        // 1 bit op
-                                        // 2 bit key len
-                                        // n bit key
-                                        if b.len() >= (1 + 2 + key_len) {
-                                            let next = b.split_off(1 + 2 + key_len);
-                                            let content = b.split_off(1 + 2);
-                                            b = next;
-                                            info!("Receive get from [{}] len {} content {:?}", id, &key_len, &content);
-                                            let event = Event::GET {
-                                                id: id.clone(),
-                                                key: content,
-                                            };
-                                            event_tx.send(event).await.unwrap_or_else(|e| {
-                                                error!("Client {} send event error; {:?}", id, e);
-                                            });
-                                        }
-                                    }
-                                }
-                                event::OP_SET => {
-                                    if b.len() > 3 {
-                                        let key_len = ((b[1] as usize) * 0x100 + b[2] as usize) & LEN_MASK as usize;
-                                        // 1 bit op
-                                        // 2 bit key len
-                                        // n bit key
-                                        // 2 bit value len; if 65535 value None
-                                        // n bit value
-                                        if b.len() >= (1 + 2 + key_len + 2) {
-                                            let value_len = (b[1 + 2 + key_len] as usize) * 0x100 + b[1 + 2 + key_len + 1] as usize;
-                                            if value_len == NONE_VALUE_LEN as usize {
-                                                let next = b.split_off(1 + 2 + key_len + 2);
-                                                let _ = b.split_off(1 + 2 + key_len);
-                                                let key = b.split_off(1 + 2);
-                                                b = next;
-                                                info!("Receive set from [{}] len {} key {:?} value None", id, &key_len, &key);
-                                                let event = Event::SET {
-                                                    id: id.clone(),
-                                                    key,
-                                                    value: None,
-                                                };
-                                                event_tx.send(event).await.unwrap_or_else(|e| {
-                                                    error!("Client {} send event error; {:?}", id, e);
-                                                });
-                                            } else {
-                                                let value_len = value_len & LEN_MASK as usize;
-                                                if b.len() >= (1 + 2 + key_len + 2 + value_len) {
-                                                    let next = b.split_off(1 + 2 + key_len + 2 + value_len);
-                                                    let mut pre_value = b.split_off(1 + 2 + key_len);
-                                                    let mut pre_key = b;
-                                                    let value = pre_value.split_off(2);
-                                                    let key = pre_key.split_off(1 + 2);
-                                                    b = next;
-                                                    info!("Receive set from [{}] len {} key {:?} value {:?}", id, &key_len, &key, &value);
-                                                    let event = Event::SET {
-                                                        id: id.clone(),
-                                                        key,
-                                                        value: Some(value),
-                                                    };
-                                                    event_tx.send(event).await.unwrap_or_else(|e| {
-                                                        error!("Client {} send event error; {:?}", id, e);
-                                                    });
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                n => {
-                                    warn!("Unknown op {} from client [{}]", n, id);
-                                    shutdown(&id, &client_map_clone, socket).await;
-                                    return;
-                                }
-                            }
-                        }
-                        // 读取消息
-                        select! {
-                            read_res = socket.read(&mut buf) => {
-                                match read_res {
-                                    Ok(n) => {
-                                        if n == 0 {
-                                            warn!("Client [{}] read fail", id);
-                                            shutdown(&id, &client_map_clone, socket).await;
-                                            return;
-                                        } else {
-                                            b.extend_from_slice(&buf[0..n]);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Failed to read from [{}]; err = {:?}", id, e);
-                                        return;
-                                    }
-                                }
-                            }
-                            message = client_rx.recv() => {
-                                match message {
-                                    Some(event_res) => {
-                                        match event_res {
-                                            EventRes::GET {id, value} => {
-                                                match value {
-                                                    Some(v) => {
-                                                        info!("Receive get event result, value = {:?}", &v);
-                                                        if let Err(e) = socket.write_u8(RES_GET).await {
-                                                            eprintln!("Failed to write get result op to [{}]; err = {:?}", id, e);
-                                                            shutdown(&id, &client_map_clone, socket).await;
-                                                            return;
-                                                        };
-                                                        let len = v.len() as u16 & LEN_MASK;
-                                                        if let Err(e) = socket.write_u16(len).await {
-                                                            eprintln!("Failed to write get result len to [{}]; err = {:?}", id, e);
-                                                            shutdown(&id, &client_map_clone, socket).await;
-                                                            return;
-                                                        };
-                                                        if let Err(e) = socket.write_all(v.as_slice()).await {
-                                                            eprintln!("Failed to write get result value to [{}]; err = {:?}", id, e);
-                                                            shutdown(&id, &client_map_clone, socket).await;
-                                                            return;
-                                                        };
-                                                    },
-                                                    None => {
-                                                        info!("Receive get event result, value = None");
-                                                        if let Err(e) = socket.write_u8(RES_GET).await {
-                                                            eprintln!("Failed to write get result op to [{}]; err = {:?}", id, e);
-                                                            shutdown(&id, &client_map_clone, socket).await;
-                                                            return;
-                                                        };
-                                                        if let Err(e) = socket.write_u16(NONE_VALUE_LEN).await {
-                                                            eprintln!("Failed to write get result len to [{}]; err = {:?}", id, e);
-                                                            shutdown(&id, &client_map_clone, socket).await;
-                                                            return;
-                                                        };
-                                                    }
-                                                }
-                                            },
-                                            EventRes::SET {id} => {
-                                                info!("Receive set event result");
-                                                if let Err(e) = socket.write_u8(RES_SET).await {
-                                                    eprintln!("Failed to write get result op to [{}]; err = {:?}", id, e);
-                                                    shutdown(&id, &client_map_clone, socket).await;
-                                                    return;
-                                                };
-                                            }
-                                        }
-                                    }
-                                    None => {
-                                       warn!("Client [{}] receive event result none", id);
-                                    }
-                                }
-                            }
-                        }
+    if let Some(port) = file_config.replication_port {
+        let addr = format!("{}:{}", &file_config.ip, port);
+        let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+            panic!("Fail to open replication server on {}; err = {:?}", addr, err);
+        });
+        info!("LSM server bind replication socket {}", addr);
+        tokio::spawn(replication::serve(listener, replication_logs, snapshot_requests));
+    }
+    if let Some(leader) = leader.as_ref() {
+        info!("LSM server is a read-only follower of {}", leader);
+    }
+    if let Some(membership) = membership.as_ref() {
+        let addr = format!("{}:{}", &file_config.ip, file_config.gossip_port.unwrap_or_default());
+        let listener = TcpListener::bind(&addr).await.unwrap_or_else(|err| {
+            panic!("Fail to open gossip server on {}; err = {:?}", addr, err);
+        });
+        info!("LSM server bind gossip socket {}", addr);
+        tokio::spawn(gossip::serve(listener, membership.clone()));
+        gossip::start(membership.clone(), file_config.gossip_seeds.clone().unwrap_or_default());
+    }
+    if let Some(ring) = ring.as_ref() {
+        info!("LSM server is a cluster node, ring {:?}", *ring.borrow());
+    }
+
+    // SIGHUP 时重新读取配置文件，写限流、日志级别在这里修改，其他的发给事件循环和连接
+    let mut hangup = signal(SignalKind::hangup())?;
+    let config_path = env_config.config_file_path.clone();
+    let reload_throttle = throttle.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            warn!("Receive SIGHUP, reload config file {}", &config_path);
+            match reload_config(&config_path, &startup_config) {
+                Ok((file_config, settings, filter)) => {
+                    reload_throttle.set_rates(file_config.write_ops_per_sec, file_config.write_bytes_per_sec);
+                    if let Err(e) = log_handle.reload(filter) {
+                        error!("Set log level fail, err = {:?}", e);
                     }
-                });
+                    settings_tx.send_if_modified(|current| {
+                        let modified = *current != settings;
+                        *current = settings;
+                        modified
+                    });
+                    info!("Reload config file {} done, settings = {:?}", &config_path, settings);
+                }
+                Err(e) => {
+                    error!("Reload config file {} fail, keep the current config; {}", &config_path, e);
+                }
+            }
+        }
+    });
+
+    // 连接任务共用的配置
+    let context = Arc::new(Context { shards, throttle, passwords, users, admin_ops, ping_interval, request_timeout, max_inflight });
+    loop {
+        match accept_rx.recv().await.expect("Accept tasks end") {
+            // new client
+            Ok((socket, addr)) => {
+                let id = get_id(&addr.ip().to_string(), addr.port());
+                // 连接中的日志都带上客户端 id
+                let span = info_span!("connection", client = %id);
+                tokio::spawn(connection::serve(socket, id, context.clone()).instrument(span));
             }
             Err(e) => {
                 error!("Fail to accept new client connection; err = {:?}", e);
@@ -0,0 +1,220 @@
+use tracing::{info, warn};
+use tokio::fs::{File, rename, try_exists};
+use tokio::io::AsyncWriteExt;
+use crate::event::{push_value, read_len, LONG_LEN_SIZE};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+// MANIFEST 由若干条记录组成，每条记录是一组修改，追加写入后 sync，整条生效
+// 4 bit edits len
+// n bit edits
+// 4 bit crc32 of edits
+//
+// 每个修改
+// 1 bit tag
+// EDIT_ADD_WAL, EDIT_REMOVE_WAL, EDIT_REMOVE_SST: 8 bit file number
+// EDIT_ADD_SST: 8 bit file number, 1 bit level, 4 bit smallest key len, n bit smallest key, 4 bit largest key len, n bit largest key
+const EDIT_ADD_WAL: u8 = 1;
+const EDIT_REMOVE_WAL: u8 = 2;
+const EDIT_ADD_SST: u8 = 3;
+const EDIT_REMOVE_SST: u8 = 4;
+
+const LS: usize = LONG_LEN_SIZE;
+
+#[derive(Clone, Debug)]
+pub struct TableFile {
+    pub number: u64,
+    pub level: u8,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+}
+
+pub enum Edit {
+    AddWal(u64),
+    RemoveWal(u64),
+    AddTable(TableFile),
+    RemoveTable(u64),
+}
+
+impl Edit {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Edit::AddWal(number) => {
+                buf.push(EDIT_ADD_WAL);
+                buf.extend(number.to_be_bytes());
+            }
+            Edit::RemoveWal(number) => {
+                buf.push(EDIT_REMOVE_WAL);
+                buf.extend(number.to_be_bytes());
+            }
+            Edit::AddTable(table) => {
+                buf.push(EDIT_ADD_SST);
+                buf.extend(table.number.to_be_bytes());
+                buf.push(table.level);
+                push_value(buf, Some(&table.smallest), LS);
+                push_value(buf, Some(&table.largest), LS);
+            }
+            Edit::RemoveTable(number) => {
+                buf.push(EDIT_REMOVE_SST);
+                buf.extend(number.to_be_bytes());
+            }
+        }
+    }
+
+    // 返回修改和下一个修改的位置，数据不完整或无法识别时返回 None
+    fn decode(buf: &[u8], index: usize) -> Option<(Edit, usize)> {
+        let tag = *buf.get(index)?;
+        let number = u64::from_be_bytes(buf.get(index + 1..index + 9)?.try_into().ok()?);
+        let next = index + 9;
+        match tag {
+            EDIT_ADD_WAL => Some((Edit::AddWal(number), next)),
+            EDIT_REMOVE_WAL => Some((Edit::RemoveWal(number), next)),
+            EDIT_REMOVE_SST => Some((Edit::RemoveTable(number), next)),
+            EDIT_ADD_SST => {
+                let level = *buf.get(next)?;
+                let read_key = |i: usize| -> Option<(Vec<u8>, usize)> {
+                    buf.get(i..i + LS)?;
+                    let len = read_len(buf, i, LS)?;
+                    Some((buf.get(i + LS..i + LS + len)?.to_vec(), i + LS + len))
+                };
+                let (smallest, next) = read_key(next + 1)?;
+                let (largest, next) = read_key(next)?;
+                Some((Edit::AddTable(TableFile { number, level, smallest, largest }), next))
+            }
+            _ => None,
+        }
+    }
+}
+
+// MANIFEST 描述的文件集合
+#[derive(Default)]
+pub struct FileSet {
+    // 下一个可用的文件编号
+    next_file: u64,
+    // 按编号递增，最后一个是正在写入的
+    pub wals: Vec<u64>,
+    pub tables: Vec<TableFile>,
+}
+
+impl FileSet {
+    pub fn new_file_number(&mut self) -> u64 {
+        self.next_file = self.next_file.max(1);
+        let number = self.next_file;
+        self.next_file += 1;
+        number
+    }
+
+    pub fn apply(&mut self, edit: Edit) {
+        match edit {
+            Edit::AddWal(number) => {
+                self.next_file = self.next_file.max(number + 1);
+                self.wals.push(number);
+                self.wals.sort_unstable();
+            }
+            Edit::RemoveWal(number) => self.wals.retain(|n| *n != number),
+            Edit::AddTable(table) => {
+                self.next_file = self.next_file.max(table.number + 1);
+                self.tables.push(table);
+            }
+            Edit::RemoveTable(number) => self.tables.retain(|table| table.number != number),
+        }
+    }
+
+    // 重建当前集合的修改
+    fn edits(&self) -> Vec<Edit> {
+        let wals = self.wals.iter().map(|number| Edit::AddWal(*number));
+        let tables = self.tables.iter().map(|table| Edit::AddTable(table.clone()));
+        wals.chain(tables).collect()
+    }
+}
+
+// 4 bit edits len
+// n bit edits
+// 4 bit crc32 of edits
+fn encode_record(edits: &[Edit]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for edit in edits {
+        edit.encode(&mut body);
+    }
+    let mut buf = Vec::with_capacity(body.len() + 8);
+    buf.extend((body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
+    buf.extend(crc32fast::hash(&body).to_be_bytes());
+    buf
+}
+
+pub struct Manifest {
+    file: File,
+}
+
+impl Manifest {
+    pub async fn exists(data_path: &str) -> bool {
+        try_exists(format!("{}/{}", data_path, MANIFEST_FILE)).await.unwrap_or(false)
+    }
+
+    // 读取 MANIFEST，最后一条不完整或校验失败的记录是写入时崩溃留下的，忽略
+    pub async fn load(data_path: &str) -> FileSet {
+        let path = format!("{}/{}", data_path, MANIFEST_FILE);
+        let buf = tokio::fs::read(&path).await.expect("Read manifest fail");
+        let mut files = FileSet::default();
+        let mut index = 0;
+        while index < buf.len() {
+            let Some(len) = buf.get(index..index + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize) else {
+                warn!("Manifest record truncated at {}", index);
+                break;
+            };
+            let Some(body) = buf.get(index + 4..index + 4 + len) else {
+                warn!("Manifest record truncated at {}", index);
+                break;
+            };
+            if buf.get(index + 4 + len..index + 8 + len) != Some(&crc32fast::hash(body).to_be_bytes()[..]) {
+                warn!("Manifest record crc mismatch at {}", index);
+                break;
+            }
+            let mut edits = Vec::new();
+            let mut i = 0;
+            while i < body.len() {
+                match Edit::decode(body, i) {
+                    Some((edit, next)) => {
+                        edits.push(edit);
+                        i = next;
+                    }
+                    None => break,
+                }
+            }
+            if i < body.len() {
+                warn!("Manifest record invalid at {}", index);
+                break;
+            }
+            for edit in edits {
+                files.apply(edit);
+            }
+            index += 8 + len;
+        }
+        info!("Load manifest, wals = {:?}, tables = {:?}", files.wals, files.tables.iter().map(|table| table.number).collect::<Vec<_>>());
+        files
+    }
+
+    // 把 files 写成新的 MANIFEST，先写临时文件再改名，旧的修改记录不再保留
+    pub async fn create(data_path: &str, files: &FileSet) -> Self {
+        let path = format!("{}/{}", data_path, MANIFEST_FILE);
+        let tmp_path = format!("{}.tmp", &path);
+        let err_message = "Write manifest fail";
+        let mut file = File::create(&tmp_path).await.expect(err_message);
+        file.write_all(&encode_record(&files.edits())).await.expect(err_message);
+        file.sync_all().await.expect(err_message);
+        rename(&tmp_path, &path).await.expect("Rename manifest fail");
+        let file = File::options().append(true).open(&path).await.expect("Open manifest fail");
+        Self { file }
+    }
+
+    // 追加一组修改，sync 之后应用到 files
+    pub async fn log(&mut self, files: &mut FileSet, edits: Vec<Edit>) {
+        let err_message = "Write manifest fail";
+        self.file.write_all(&encode_record(&edits)).await.expect(err_message);
+        self.file.sync_all().await.expect(err_message);
+        for edit in edits {
+            files.apply(edit);
+        }
+    }
+}
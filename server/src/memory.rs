@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::sync::Arc;
+use tracing::info;
+use crate::glob::Pattern;
+use crate::storage::{live, KeyMeta, LsmExt, ReadView, StorageEngine, StorageOptions, StorageStats};
+use crate::utils::now_millis;
+
+// 只在内存中的存储引擎，配置 engine = "memory" 时使用：没有 WAL 和 SST 文件，重启后数据为空
+// 不支持复制、Raft 和直接读取，启动时检查
+pub struct MemStorage {
+    options: StorageOptions,
+    // 打开快照时共享，之后的写入拷贝一份
+    data: Arc<BTreeMap<Vec<u8>, Entry>>,
+    // 带过期时间的 key，按过期时间排序
+    expirations: BTreeSet<(u64, Vec<u8>)>,
+    // 所有 key 和 value 的总字节数
+    bytes: usize,
+    last_version: u64,
+}
+
+#[derive(Clone)]
+struct Entry {
+    value: Vec<u8>,
+    version: u64,
+    expire_at: Option<u64>,
+}
+
+// 打开时的数据，之后的写入看不到
+pub struct MemSnapshot {
+    data: Arc<BTreeMap<Vec<u8>, Entry>>,
+    last_version: u64,
+}
+
+impl MemStorage {
+    fn view(&self) -> MemSnapshot {
+        MemSnapshot {
+            data: self.data.clone(),
+            last_version: self.last_version,
+        }
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.last_version += 1;
+        self.last_version
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = Arc::make_mut(&mut self.data).remove(key)?;
+        self.bytes -= key.len() + entry.value.len();
+        if let Some(expire_at) = entry.expire_at {
+            self.expirations.remove(&(expire_at, key.to_vec()));
+        }
+        Some(entry)
+    }
+
+    // value 为 None 表示删除
+    fn apply(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, expire_at: Option<u64>, version: u64) {
+        self.remove(&key);
+        let Some(value) = value else {
+            return;
+        };
+        self.bytes += key.len() + value.len();
+        if let Some(expire_at) = expire_at {
+            self.expirations.insert((expire_at, key.clone()));
+        }
+        Arc::make_mut(&mut self.data).insert(key, Entry { value, version, expire_at });
+    }
+}
+
+impl MemSnapshot {
+    fn live(&self, key: &[u8], now: u64) -> Option<&Entry> {
+        self.data.get(key).filter(|entry| live(Some(entry.value.as_slice()), entry.expire_at, now).is_some())
+    }
+
+    // 从 start 开始按 key 顺序的未过期数据
+    fn iter_from<'a>(&'a self, start: &[u8], now: u64) -> impl Iterator<Item = (&'a Vec<u8>, &'a Entry)> + 'a {
+        self.data.range::<[u8], _>((Bound::Included(start), Bound::Unbounded))
+            .filter(move |(_, entry)| live(Some(entry.value.as_slice()), entry.expire_at, now).is_some())
+    }
+}
+
+impl ReadView for MemSnapshot {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.live(key, now_millis()).map(|entry| entry.value.clone())
+    }
+
+    fn version(&self, key: &[u8]) -> u64 {
+        self.live(key, now_millis()).map_or(0, |entry| entry.version)
+    }
+
+    fn meta(&self, key: &[u8]) -> Option<KeyMeta> {
+        self.live(key, now_millis()).map(|entry| KeyMeta { len: entry.value.len() as u64, version: entry.version, expire_at: entry.expire_at })
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.live(key, now_millis()).is_some()
+    }
+
+    fn longest_prefix(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let now = now_millis();
+        (0..=key.len()).rev().find_map(|len| Some((key[..len].to_vec(), self.live(&key[..len], now)?.value.clone())))
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.iter_from(start, now_millis())
+            .take_while(|(key, _)| end.is_empty() || key.as_slice() < end)
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    fn prefix(&self, prefix: &[u8], with_values: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.iter_from(prefix, now_millis())
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, entry)| (key.clone(), if with_values { entry.value.clone() } else { Vec::new() }))
+            .collect()
+    }
+
+    fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        let pattern = Pattern::parse(pattern);
+        let literal_prefix = pattern.literal_prefix();
+        self.iter_from(&literal_prefix, now_millis())
+            .take_while(|(key, _)| key.starts_with(&literal_prefix))
+            .filter(|(key, _)| pattern.matches(key))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn last_version(&self) -> u64 {
+        self.last_version
+    }
+}
+
+impl ReadView for MemStorage {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.view().get(key)
+    }
+
+    fn version(&self, key: &[u8]) -> u64 {
+        self.view().version(key)
+    }
+
+    fn meta(&self, key: &[u8]) -> Option<KeyMeta> {
+        self.view().meta(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.view().contains(key)
+    }
+
+    fn longest_prefix(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.view().longest_prefix(key)
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.view().scan(start, end)
+    }
+
+    fn prefix(&self, prefix: &[u8], with_values: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.view().prefix(prefix, with_values)
+    }
+
+    fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        self.view().keys(pattern)
+    }
+
+    fn last_version(&self) -> u64 {
+        self.last_version
+    }
+}
+
+impl StorageEngine for MemStorage {
+    type Snapshot = MemSnapshot;
+
+    async fn open(data_path: String, options: StorageOptions) -> Self {
+        info!("Memory storage open, data path {} unused", data_path);
+        Self {
+            options,
+            data: Arc::new(BTreeMap::new()),
+            expirations: BTreeSet::new(),
+            bytes: 0,
+            last_version: 0,
+        }
+    }
+
+    fn options(&self) -> &StorageOptions {
+        &self.options
+    }
+
+    async fn snapshot(&mut self) -> MemSnapshot {
+        self.view()
+    }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    async fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        let version = self.next_version();
+        self.apply(key, Some(value), None, version);
+    }
+
+    async fn put_expire(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: u64) {
+        let version = self.next_version();
+        self.apply(key, Some(value), Some(expire_at), version);
+    }
+
+    async fn delete(&mut self, key: Vec<u8>) {
+        let version = self.next_version();
+        self.apply(key, None, None, version);
+    }
+
+    async fn delete_range(&mut self, start: Vec<u8>, end: Vec<u8>) -> usize {
+        if !end.is_empty() && start >= end {
+            return 0;
+        }
+        self.next_version();
+        let now = now_millis();
+        let keys: Vec<Vec<u8>> = self.data.range(start..)
+            .take_while(|(key, _)| end.is_empty() || key.as_slice() < end.as_slice())
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut deleted = 0;
+        for key in keys {
+            if let Some(entry) = self.remove(&key) {
+                deleted += usize::from(live(Some(entry.value.as_slice()), entry.expire_at, now).is_some());
+            }
+        }
+        deleted
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> usize {
+        let version = self.next_version();
+        // 未过期时保留过期时间
+        let (value, expire_at) = match self.view().live(&key, now_millis()) {
+            Some(entry) => ([entry.value.as_slice(), &suffix].concat(), entry.expire_at),
+            None => (suffix, None),
+        };
+        let len = value.len();
+        self.apply(key, Some(value), expire_at, version);
+        len
+    }
+
+    async fn write_batch(&mut self, entries: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+        // 整批使用同一个版本号
+        let version = self.next_version();
+        for (key, value) in entries {
+            self.apply(key, value, None, version);
+        }
+    }
+
+    async fn clear(&mut self) {
+        // 版本号不重置，清空前读到的版本号不会和之后写入的相同
+        self.data = Arc::new(BTreeMap::new());
+        self.expirations.clear();
+        self.bytes = 0;
+        info!("Memory storage clear all data");
+    }
+
+    async fn stats(&self) -> StorageStats {
+        StorageStats {
+            keys: self.data.len() as u64,
+            memtable_bytes: self.bytes as u64,
+            memtable_memory: self.bytes as u64,
+            wal_bytes: 0,
+            block_cache_hits: 0,
+            block_cache_misses: 0,
+        }
+    }
+
+    async fn remove_expired(&mut self, now: u64, limit: usize) -> Vec<Vec<u8>> {
+        let expired: Vec<Vec<u8>> = self.expirations.iter()
+            .take_while(|(expire_at, _)| *expire_at <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in expired.iter() {
+            let version = self.next_version();
+            self.apply(key.clone(), None, None, version);
+        }
+        expired
+    }
+}
+
+// 没有需要落盘的数据，都使用默认实现
+impl LsmExt for MemStorage {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::options;
+
+    async fn open() -> MemStorage {
+        MemStorage::open(String::from("memory"), options()).await
+    }
+
+    #[tokio::test]
+    async fn read_write() {
+        let mut storage = open().await;
+        storage.put(b"a".to_vec(), b"1".to_vec()).await;
+        storage.put(b"ab".to_vec(), b"2".to_vec()).await;
+        storage.write_batch(vec![(b"b".to_vec(), Some(b"3".to_vec())), (b"c".to_vec(), Some(b"4".to_vec())), (b"a".to_vec(), None)]).await;
+        assert_eq!(storage.get(b"a"), None);
+        assert_eq!(storage.version(b"b"), 3);
+        assert_eq!(storage.version(b"c"), 3);
+        assert_eq!(storage.size(), 3);
+        assert_eq!(storage.append(b"c".to_vec(), b"5".to_vec()).await, 2);
+        assert_eq!(storage.get(b"c"), Some(b"45".to_vec()));
+        assert_eq!(storage.longest_prefix(b"abc"), Some((b"ab".to_vec(), b"2".to_vec())));
+        assert_eq!(storage.scan(b"ab", b"c"), [(b"ab".to_vec(), b"2".to_vec()), (b"b".to_vec(), b"3".to_vec())]);
+        assert_eq!(storage.prefix(b"a", false), [(b"ab".to_vec(), Vec::new())]);
+        assert_eq!(storage.keys(b"?"), [b"b".to_vec(), b"c".to_vec()]);
+
+        assert_eq!(storage.delete_range(b"b".to_vec(), Vec::new()).await, 2);
+        assert_eq!(storage.scan(&[], &[]), [(b"ab".to_vec(), b"2".to_vec())]);
+        let last_version = storage.last_version();
+        storage.clear().await;
+        assert_eq!(storage.size(), 0);
+        assert_eq!(storage.stats().await.memtable_bytes, 0);
+        storage.put(b"a".to_vec(), b"1".to_vec()).await;
+        assert_eq!(storage.version(b"a"), last_version + 1);
+    }
+
+    // 快照看不到之后的写入
+    #[tokio::test]
+    async fn snapshot_isolated() {
+        let mut storage = open().await;
+        storage.put(b"a".to_vec(), b"1".to_vec()).await;
+        let snapshot = storage.snapshot().await;
+        storage.put(b"a".to_vec(), b"2".to_vec()).await;
+        storage.delete(b"a".to_vec()).await;
+        storage.put(b"b".to_vec(), b"3".to_vec()).await;
+        assert_eq!(snapshot.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(snapshot.scan(&[], &[]).len(), 1);
+        assert_eq!(snapshot.last_version(), 1);
+        assert_eq!(storage.get(b"a"), None);
+    }
+
+    // 过期的 key 读不到，remove_expired 按过期时间删除，重新写入过的跳过
+    #[tokio::test]
+    async fn expire() {
+        let mut storage = open().await;
+        let now = now_millis();
+        storage.put_expire(b"a".to_vec(), b"1".to_vec(), now - 1).await;
+        storage.put_expire(b"b".to_vec(), b"2".to_vec(), now - 1).await;
+        storage.put_expire(b"c".to_vec(), b"3".to_vec(), now + 60_000).await;
+        storage.put(b"b".to_vec(), b"4".to_vec()).await;
+        assert_eq!(storage.get(b"a"), None);
+        assert_eq!(storage.meta(b"c").unwrap().expire_at, Some(now + 60_000));
+        assert_eq!(storage.append(b"c".to_vec(), b"5".to_vec()).await, 2);
+        assert_eq!(storage.meta(b"c").unwrap().expire_at, Some(now + 60_000));
+        assert_eq!(storage.size(), 3);
+        assert_eq!(storage.remove_expired(now, 10).await, [b"a".to_vec()]);
+        assert_eq!(storage.size(), 2);
+        assert_eq!(storage.ingest(vec![(b"b".to_vec(), b"6".to_vec()), (b"d".to_vec(), b"7".to_vec())]).await, 1);
+        assert_eq!(storage.get(b"b"), Some(b"6".to_vec()));
+    }
+}
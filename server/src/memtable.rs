@@ -0,0 +1,247 @@
+use crate::glob::Pattern;
+use crate::sstable::{self, RangeTombstone};
+use crate::skiplist::SkipList;
+use crate::storage::live;
+use crate::trie::Trie;
+
+// value（不论是否过期）、过期时间、版本号
+pub type Record<'a> = (Option<&'a [u8]>, Option<u64>, u64);
+
+// 按 key 顺序遍历的记录，包括删除记录
+pub type Records<'a> = Box<dyn Iterator<Item = (Vec<u8>, Record<'a>)> + 'a>;
+
+// 内存表的二进制快照，与实现无关，数值都是大端
+// 8 bit SNAPSHOT_MAGIC，最后一个字节是格式版本
+// 8 bit last version
+// 4 bit range count，之后每个范围删除按写入顺序：
+//   4 bit start len, start, 4 bit end len, end, 8 bit version
+// 4 bit record count，之后每条记录按 key 排序：
+//   1 bit flags
+//   8 bit version
+//   8 bit expire at，有 SNAPSHOT_EXPIRE 时
+//   4 bit key len, key
+//   4 bit value len, value，有 SNAPSHOT_VALUE 时，否则是删除记录
+// 4 bit crc32 of all above
+const SNAPSHOT_MAGIC: [u8; 8] = [0xff, b'L', b'S', b'M', b'M', b'E', b'M', 1];
+const SNAPSHOT_VALUE: u8 = 1;
+const SNAPSHOT_EXPIRE: u8 = 1 << 1;
+
+// 内存表的实现，由配置选择
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemtableKind {
+    // 路径压缩的基数树，共享前缀的 key 占用的内存少
+    Trie,
+    // 跳表，key 长且随机时占用的内存少，读取不加锁
+    SkipList,
+}
+
+impl MemtableKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "trie" => Some(MemtableKind::Trie),
+            "skiplist" => Some(MemtableKind::SkipList),
+            _ => None,
+        }
+    }
+
+    pub fn create(self) -> Box<dyn Memtable> {
+        match self {
+            MemtableKind::Trie => Box::new(Trie::new()),
+            MemtableKind::SkipList => Box::new(SkipList::new()),
+        }
+    }
+
+    // 从 Memtable::serialize 的结果恢复为这种实现的内存表，数据不完整或校验失败时返回 None
+    pub fn deserialize(self, buf: &[u8]) -> Option<Box<dyn Memtable>> {
+        let (body, crc) = buf.split_at(buf.len().checked_sub(4)?);
+        if crc32fast::hash(body).to_be_bytes() != crc || !body.starts_with(&SNAPSHOT_MAGIC) {
+            return None;
+        }
+        let mut reader = Reader { buf: body, index: SNAPSHOT_MAGIC.len() };
+        let last_version = reader.u64()?;
+        let mut ranges = Vec::new();
+        for _ in 0..reader.u32()? {
+            let start = reader.field()?.to_vec();
+            let end = reader.field()?.to_vec();
+            ranges.push(RangeTombstone { start, end, version: reader.u64()? });
+        }
+        let mut memtable = self.create();
+        for _ in 0..reader.u32()? {
+            let flags = reader.u8()?;
+            let version = reader.u64()?;
+            let expire_at = if flags & SNAPSHOT_EXPIRE != 0 { Some(reader.u64()?) } else { None };
+            let key = reader.field()?;
+            let value = if flags & SNAPSHOT_VALUE != 0 { Some(reader.field()?.to_vec()) } else { None };
+            memtable.set_expire(key, value, expire_at, version);
+        }
+        if reader.index != body.len() {
+            return None;
+        }
+        // 被遮住的记录在快照中已经删除或者是删除记录，范围删除最后写入
+        for range in ranges {
+            memtable.delete_range(&range.start, &range.end, range.version);
+        }
+        memtable.raise_version(last_version);
+        Some(memtable)
+    }
+}
+
+// 4 bit len, bytes
+fn put_field(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend((bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// 按顺序读取快照中的字段，越界时返回 None
+struct Reader<'a> {
+    buf: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.index..self.index.checked_add(len)?)?;
+        self.index += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).and_then(|b| b.try_into().ok()).map(u32::from_be_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).and_then(|b| b.try_into().ok()).map(u64::from_be_bytes)
+    }
+
+    fn field(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+}
+
+// 内存表：写入和删除都留下记录，value 为 None 表示删除
+// 范围删除记在内存表上，遮住更旧的内存表和 SST 文件；范围内已有的记录同时删除或改为删除记录，不再从内存表中读到
+pub trait Memtable: Send + Sync {
+    // key 和 value 的总字节数，用于统计
+    fn bytes(&self) -> usize;
+
+    // 引用的堆内存，超过 memtable_bytes 时落盘
+    fn memory(&self) -> usize;
+
+    fn is_empty(&self) -> bool;
+
+    // key 的记录，没有记录时返回 None
+    fn record(&self, key: &[u8]) -> Option<Record<'_>>;
+
+    fn ranges(&self) -> &[RangeTombstone];
+
+    fn last_version(&self) -> u64;
+
+    // 之后分配的版本号不小于 version
+    fn raise_version(&mut self, version: u64);
+
+    // expire_at 为 unix 毫秒，None 表示不过期
+    fn set_expire(&mut self, key: &[u8], value: Option<Vec<u8>>, expire_at: Option<u64>, version: u64);
+
+    // 删除 [start, end)，end 为空表示没有上界；范围内版本号更小的记录删除或改为删除记录
+    fn delete_range(&mut self, start: &[u8], end: &[u8], version: u64);
+
+    // 追加到未过期的 value 后，没有 value 时创建；返回新长度
+    fn append(&mut self, key: &[u8], suffix: &[u8], version: u64) -> usize;
+
+    // 按顺序遍历 [start, end) 中的记录，end 为空表示没有上界
+    fn range(&self, start: &[u8], end: &[u8]) -> Records<'_>;
+
+    // O(1) 的只读快照，之后的写入不影响快照
+    fn snapshot(&self) -> Box<dyn Memtable>;
+
+    // 写成二进制快照，格式见 SNAPSHOT_MAGIC；用 MemtableKind::deserialize 恢复，可以换成另一种实现
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        buf.extend(self.last_version().to_be_bytes());
+        buf.extend((self.ranges().len() as u32).to_be_bytes());
+        for range in self.ranges() {
+            put_field(&mut buf, &range.start);
+            put_field(&mut buf, &range.end);
+            buf.extend(range.version.to_be_bytes());
+        }
+        // 记录数最后回填
+        let count_index = buf.len();
+        buf.extend(0u32.to_be_bytes());
+        let mut count: u32 = 0;
+        for (key, (value, expire_at, version)) in self.iter() {
+            let flags = if value.is_some() { SNAPSHOT_VALUE } else { 0 } | if expire_at.is_some() { SNAPSHOT_EXPIRE } else { 0 };
+            buf.push(flags);
+            buf.extend(version.to_be_bytes());
+            if let Some(expire_at) = expire_at {
+                buf.extend(expire_at.to_be_bytes());
+            }
+            put_field(&mut buf, &key);
+            if let Some(value) = value {
+                put_field(&mut buf, value);
+            }
+            count += 1;
+        }
+        buf[count_index..count_index + 4].copy_from_slice(&count.to_be_bytes());
+        buf.extend(crc32fast::hash(&buf).to_be_bytes());
+        buf
+    }
+
+    // 覆盖 key 的范围删除的最大版本号，key 自己的记录优先
+    fn deleted_at(&self, key: &[u8]) -> Option<u64> {
+        sstable::deleted_at(self.ranges(), key)
+    }
+
+    // 按 key 顺序遍历所有记录，包括删除记录
+    fn iter(&self) -> Records<'_> {
+        self.range(&[], &[])
+    }
+
+    // 从第一个不小于 start 的 key 开始按顺序遍历
+    fn iter_from(&self, start: &[u8]) -> Records<'_> {
+        self.range(start, &[])
+    }
+
+    // [start, end) 范围内的记录，按 key 排序；end 为空表示没有上界
+    // 已删除或已过期的记录 value 为 None，now 之前过期的视为已过期
+    fn scan(&self, start: &[u8], end: &[u8], now: u64) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.range(start, end)
+            .map(|(key, (value, expire_at, _))| (key, live(value, expire_at, now).map(<[u8]>::to_vec)))
+            .collect()
+    }
+
+    // 以 prefix 开头的记录；with_values 为 false 时 value 为空
+    // 已删除或已过期的记录 value 为 None，now 之前过期的视为已过期
+    fn prefix(&self, prefix: &[u8], with_values: bool, now: u64) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.iter_from(prefix)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (value, expire_at, _))| {
+                let value = live(value, expire_at, now).map(|value| if with_values { value.to_vec() } else { Vec::new() });
+                (key, value)
+            })
+            .collect()
+    }
+
+    // 匹配 glob 模式的记录，按 key 排序，同时返回是否未删除且在 now 时未过期
+    // 只遍历以模式字面量前缀开头的记录
+    fn keys(&self, pattern: &Pattern, now: u64) -> Vec<(Vec<u8>, bool)> {
+        let literal = pattern.literal_prefix();
+        self.iter_from(&literal)
+            .take_while(|(key, _)| key.starts_with(&literal))
+            .filter(|(key, _)| pattern.matches(key))
+            .map(|(key, (value, expire_at, _))| (key, live(value, expire_at, now).is_some()))
+            .collect()
+    }
+
+    // key 的前缀中未删除、未过期的最长的一个：前缀的长度和 value，key 本身也算前缀
+    fn longest_prefix(&self, key: &[u8], now: u64) -> Option<(usize, &[u8])> {
+        (0..=key.len()).rev().find_map(|len| {
+            let (value, expire_at, _) = self.record(&key[..len])?;
+            live(value, expire_at, now).map(|value| (len, value))
+        })
+    }
+}
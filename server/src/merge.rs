@@ -0,0 +1,128 @@
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+// 服务端合并函数，把 operand 合并到原来的 value 上，客户端不需要先读再写
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    // old 为 None 表示 key 不存在；无法合并时返回 None，不写入
+    fn merge(&self, old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>>;
+}
+
+// 追加到 value 后
+struct AppendMerge;
+
+impl MergeOperator for AppendMerge {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn merge(&self, old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        let mut value = old.unwrap_or_default().to_vec();
+        value.extend_from_slice(operand);
+        Some(value)
+    }
+}
+
+// value 和 operand 按十进制 i64 解析，不存在视为 0
+fn parse_int(value: &[u8]) -> Option<i64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn merge_int(old: Option<&[u8]>, operand: &[u8], f: fn(i64, i64) -> Option<i64>) -> Option<Vec<u8>> {
+    let old = match old {
+        Some(old) => parse_int(old)?,
+        None => return parse_int(operand).map(|v| v.to_string().into_bytes()),
+    };
+    f(old, parse_int(operand)?).map(|v| v.to_string().into_bytes())
+}
+
+// 整数相加，溢出时不写入
+struct AddMerge;
+
+impl MergeOperator for AddMerge {
+    fn name(&self) -> &str {
+        "add"
+    }
+
+    fn merge(&self, old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        merge_int(old, operand, i64::checked_add)
+    }
+}
+
+struct MaxMerge;
+
+impl MergeOperator for MaxMerge {
+    fn name(&self) -> &str {
+        "max"
+    }
+
+    fn merge(&self, old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        merge_int(old, operand, |a, b| Some(a.max(b)))
+    }
+}
+
+struct MinMerge;
+
+impl MergeOperator for MinMerge {
+    fn name(&self) -> &str {
+        "min"
+    }
+
+    fn merge(&self, old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        merge_int(old, operand, |a, b| Some(a.min(b)))
+    }
+}
+
+// 逗号分隔的集合取并集，保持原来的顺序，新成员加在后面，空成员忽略
+struct UnionMerge;
+
+impl MergeOperator for UnionMerge {
+    fn name(&self) -> &str {
+        "union"
+    }
+
+    fn merge(&self, old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        let mut seen = HashSet::new();
+        let mut value = Vec::new();
+        let members = old.unwrap_or_default().split(|c| *c == b',').chain(operand.split(|c| *c == b','));
+        for member in members.filter(|member| !member.is_empty()) {
+            if seen.insert(member) {
+                if !value.is_empty() {
+                    value.push(b',');
+                }
+                value.extend_from_slice(member);
+            }
+        }
+        Some(value)
+    }
+}
+
+// 按名字查找合并函数，内置的在创建时注册
+pub struct MergeRegistry {
+    operators: HashMap<String, Box<dyn MergeOperator>>,
+}
+
+impl MergeRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self {
+            operators: HashMap::new(),
+        };
+        registry.register(Box::new(AppendMerge));
+        registry.register(Box::new(AddMerge));
+        registry.register(Box::new(MaxMerge));
+        registry.register(Box::new(MinMerge));
+        registry.register(Box::new(UnionMerge));
+        registry
+    }
+
+    pub fn register(&mut self, operator: Box<dyn MergeOperator>) {
+        info!("Register merge operator {}", operator.name());
+        self.operators.insert(operator.name().to_string(), operator);
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&dyn MergeOperator> {
+        let name = std::str::from_utf8(name).ok()?;
+        self.operators.get(name).map(|operator| operator.as_ref())
+    }
+}
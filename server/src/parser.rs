@@ -0,0 +1,321 @@
+use std::io::Error;
+use bytes::{Buf, BytesMut};
+use lsm_protocol::{unseal, Format, Limits, Request, RequestDecoder};
+
+// 一个连接的解析状态，协商 FEATURE_CRC 或 FEATURE_LZ4 后先从外层拆出帧
+// 请求的增量解析由 RequestDecoder 完成，数据不足时记住读到哪一项，每个请求只读一遍
+pub struct Parser {
+    features: u32,
+    format: Format,
+    // 从外层拆出、还没有解析的请求
+    plain: BytesMut,
+    decoder: RequestDecoder,
+    // 外层帧也按一个请求的上限检查
+    max_request: usize,
+}
+
+impl Parser {
+    // 长度字段超过 limits 时不等数据到齐就返回错误
+    pub fn new(features: u32, limits: Limits) -> Self {
+        Self { features, format: Format::new(features), plain: BytesMut::new(), decoder: RequestDecoder::with_limits(limits), max_request: limits.max_request }
+    }
+
+    // 解析 src 中的下一个请求；数据不足时为 None，已读到的部分留在缓冲区中，下次从记住的位置继续
+    // 出错之后不再解析，连接应当断开
+    pub fn parse(&mut self, src: &mut BytesMut) -> Result<Option<(Option<u32>, Request)>, Error> {
+        // 包一层时每次拆出一帧，前面完整的请求先返回
+        loop {
+            let buf = if self.format.sealed { &mut self.plain } else { &mut *src };
+            if let Some((req, request, used)) = self.decoder.decode(buf, self.format)? {
+                buf.advance(used);
+                return Ok(Some((req, request)));
+            }
+            if !self.format.sealed {
+                return Ok(None);
+            }
+            let Some((frame, used)) = unseal(src, self.features, self.max_request)? else {
+                return Ok(None);
+            };
+            self.plain.extend_from_slice(&frame);
+            src.advance(used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use lsm_protocol::{seal, FEATURE_CRC, FEATURE_LONG_LEN, FEATURE_LZ4, FEATURE_REQUEST_ID, OP_DBSIZE, OP_GET, OP_INGEST, OP_SET};
+
+    const FEATURES: [u32; 4] = [0, FEATURE_REQUEST_ID, FEATURE_REQUEST_ID | FEATURE_LONG_LEN, FEATURE_REQUEST_ID | FEATURE_CRC | FEATURE_LZ4];
+
+    // 每种 op 至少一个，覆盖 NONE、空字段和多次重复
+    fn requests() -> Vec<Request> {
+        let k = |s: &str| s.as_bytes().to_vec();
+        vec![
+            Request::GET { key: k("k") },
+            Request::EXISTS { key: Vec::new() },
+            Request::SET { key: k("k"), value: Some(k("v")) },
+            Request::SET { key: k("k"), value: None },
+            Request::SCAN { start: k("a"), end: Vec::new() },
+            Request::PREFIX { prefix: k("p"), with_values: true },
+            Request::KEYS { pattern: k("*") },
+            Request::MGET { keys: vec![k("a"), k("b"), Vec::new()] },
+            Request::MGET { keys: Vec::new() },
+            Request::MSET { entries: vec![(k("a"), Some(k("1"))), (k("b"), None)] },
+            Request::SETEX { key: k("k"), value: k("v"), ttl: 10 },
+            Request::CAS { key: k("k"), expected: None, value: Some(Vec::new()) },
+            Request::META { key: k("k") },
+            Request::LPM { key: k("k") },
+            Request::GETV { key: k("k") },
+            Request::SETV { key: k("k"), version: 7, value: None },
+            Request::MERGE { name: k("add"), key: k("k"), operand: k("1") },
+            Request::INCR { key: k("k"), delta: -3 },
+            Request::APPEND { key: k("k"), suffix: k("s") },
+            Request::GETSET { key: k("k"), value: k("v") },
+            Request::FLUSHALL,
+            Request::COMPACT { start: Vec::new(), end: Vec::new() },
+            Request::DELETE_RANGE { start: k("a"), end: k("b") },
+            Request::SCRUB,
+            Request::INGEST { entries: vec![(k("a"), k("1")), (k("b"), k("2"))] },
+            Request::INGEST { entries: Vec::new() },
+            Request::PING { payload: 1 },
+            Request::PONG { payload: 2 },
+            Request::STATS,
+            Request::MULTI,
+            Request::EXEC,
+            Request::AUTH { password: k("p") },
+            Request::AUTH_USER { user: k("u"), password: k("p") },
+            Request::SELECT { name: k("n") },
+            Request::SNAPSHOT,
+            Request::RELEASE,
+            Request::CLUSTER,
+            Request::DBSIZE,
+        ]
+    }
+
+    // 按协商的特性编码，需要时包一层
+    fn encode(requests: &[Request], features: u32) -> Vec<u8> {
+        let format = Format::new(features);
+        let mut buf = Vec::new();
+        for (i, request) in requests.iter().enumerate() {
+            let mut frame = Vec::new();
+            request.encode(format.request_id.then_some(i as u32), format, &mut frame);
+            if format.sealed {
+                seal(&frame, features, &mut buf);
+            } else {
+                buf.extend_from_slice(&frame);
+            }
+        }
+        buf
+    }
+
+    // 数据按 chunks 的长度依次到达，解析出所有请求，直到出错
+    fn feed(parser: &mut Parser, bytes: &[u8], chunks: impl Iterator<Item = usize>) -> (Vec<(Option<u32>, Request)>, Option<Error>) {
+        let mut src = BytesMut::new();
+        let mut parsed = Vec::new();
+        let mut rest = bytes;
+        for chunk in chunks {
+            if rest.is_empty() {
+                break;
+            }
+            let (head, tail) = rest.split_at(chunk.clamp(1, rest.len()));
+            src.extend_from_slice(head);
+            rest = tail;
+            loop {
+                match parser.parse(&mut src) {
+                    Ok(Some(request)) => parsed.push(request),
+                    Ok(None) => break,
+                    Err(e) => return (parsed, Some(e)),
+                }
+            }
+        }
+        (parsed, None)
+    }
+
+    fn expected(features: u32) -> Vec<(Option<u32>, Request)> {
+        let request_id = Format::new(features).request_id;
+        requests().into_iter().enumerate().map(|(i, request)| (request_id.then_some(i as u32), request)).collect()
+    }
+
+    // xorshift，测试可以重现
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn whole_and_byte_by_byte() {
+        for features in FEATURES {
+            let bytes = encode(&requests(), features);
+            let (parsed, e) = feed(&mut Parser::new(features, Limits::UNLIMITED), &bytes, std::iter::once(bytes.len()));
+            assert!(e.is_none());
+            assert_eq!(parsed, expected(features));
+            let (parsed, e) = feed(&mut Parser::new(features, Limits::UNLIMITED), &bytes, std::iter::repeat(1));
+            assert!(e.is_none());
+            assert_eq!(parsed, expected(features));
+        }
+    }
+
+    // 每个请求都在最后一个字节到达时才完整
+    #[test]
+    fn partial_frames() {
+        for request in requests() {
+            let bytes = encode(std::slice::from_ref(&request), FEATURE_REQUEST_ID);
+            let mut parser = Parser::new(FEATURE_REQUEST_ID, Limits::UNLIMITED);
+            let mut src = BytesMut::new();
+            for byte in &bytes[..bytes.len() - 1] {
+                src.extend_from_slice(&[*byte]);
+                assert!(parser.parse(&mut src).unwrap().is_none(), "{:?}", request);
+            }
+            src.extend_from_slice(&bytes[bytes.len() - 1..]);
+            assert_eq!(parser.parse(&mut src).unwrap(), Some((Some(0), request)));
+            assert!(src.is_empty());
+        }
+    }
+
+    // 未知的 op 不等请求编号到达
+    #[test]
+    fn unknown_op() {
+        for op in [0x00, 0x01, 0x7f, 0xc0, 0xe4, 0xff] {
+            let mut parser = Parser::new(FEATURE_REQUEST_ID, Limits::UNLIMITED);
+            let mut src = BytesMut::from(&[op][..]);
+            assert_eq!(parser.parse(&mut src).unwrap_err().kind(), ErrorKind::InvalidData);
+            // 出错之后不再解析
+            let mut src = BytesMut::from(&[OP_DBSIZE][..]);
+            assert!(parser.parse(&mut src).is_err());
+        }
+    }
+
+    // 2 bit 的长度最高位只能出现在 NONE 中，不等字段的数据到达
+    #[test]
+    fn invalid_length() {
+        let mut parser = Parser::new(0, Limits::UNLIMITED);
+        let mut src = BytesMut::from(&[OP_GET, 0x80, 0x00][..]);
+        assert_eq!(parser.parse(&mut src).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // NONE 的 key 视为空
+        let mut parser = Parser::new(0, Limits::UNLIMITED);
+        let mut src = BytesMut::from(&[OP_GET, 0xff, 0xff][..]);
+        assert_eq!(parser.parse(&mut src).unwrap(), Some((None, Request::GET { key: Vec::new() })));
+
+        // 4 bit 的长度只有数据到达后才完整
+        let mut parser = Parser::new(FEATURE_LONG_LEN, Limits::UNLIMITED);
+        let mut src = BytesMut::from(&[OP_GET, 0x80, 0x00, 0x00, 0x00][..]);
+        assert!(parser.parse(&mut src).unwrap().is_none());
+    }
+
+    // 数量很大的 INGEST 不按数量预先分配，逐项等待
+    #[test]
+    fn large_count() {
+        let mut parser = Parser::new(FEATURE_LONG_LEN, Limits::UNLIMITED);
+        let mut src = BytesMut::from(&[OP_INGEST, 0xff, 0xff, 0xff, 0xfe][..]);
+        for _ in 0..100 {
+            src.extend_from_slice(&[0, 0, 0, 1, b'k', 0, 0, 0, 0]);
+            assert!(parser.parse(&mut src).unwrap().is_none());
+        }
+    }
+
+    // 随机切分到达的数据，结果与整段到达相同
+    #[test]
+    fn random_chunks() {
+        let mut state = 0x2545f4914f6cdd1d;
+        for features in FEATURES {
+            let bytes = encode(&requests(), features);
+            for _ in 0..50 {
+                let chunks: Vec<usize> = (0..bytes.len()).map(|_| (next(&mut state) % 16) as usize).collect();
+                let (parsed, e) = feed(&mut Parser::new(features, Limits::UNLIMITED), &bytes, chunks.into_iter());
+                assert!(e.is_none());
+                assert_eq!(parsed, expected(features));
+            }
+        }
+    }
+
+    // 随机字节和合法请求中随机改动的字节：不会 panic，解析出的请求与 Request::decode 一致
+    #[test]
+    fn fuzz_corpus() {
+        let mut state = 0x9e3779b97f4a7c15;
+        let valid = encode(&requests(), FEATURE_REQUEST_ID);
+        let mut corpus: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..500 {
+            let len = (next(&mut state) % 64) as usize;
+            corpus.push((0..len).map(|_| next(&mut state) as u8).collect());
+        }
+        for _ in 0..500 {
+            let mut bytes = valid.clone();
+            for _ in 0..1 + next(&mut state) % 4 {
+                let i = (next(&mut state) % bytes.len() as u64) as usize;
+                bytes[i] = next(&mut state) as u8;
+            }
+            let cut = (next(&mut state) % bytes.len() as u64) as usize;
+            bytes.truncate(bytes.len() - cut / 2);
+            corpus.push(bytes);
+        }
+        for bytes in corpus {
+            for features in [0, FEATURE_REQUEST_ID, FEATURE_LONG_LEN] {
+                let format = Format::new(features);
+                let chunks: Vec<usize> = (0..bytes.len()).map(|_| (next(&mut state) % 8) as usize).collect();
+                let (parsed, e) = feed(&mut Parser::new(features, Limits::UNLIMITED), &bytes, chunks.into_iter());
+                let mut index = 0;
+                for (req, request) in parsed.iter() {
+                    let (expected_req, expected, used) = Request::decode(&bytes[index..], format).unwrap().unwrap();
+                    assert_eq!((req, request), (&expected_req, &expected));
+                    index += used;
+                }
+                if e.is_none() {
+                    // 剩下的不完整，Request::decode 也需要更多数据
+                    let rest = Request::decode(&bytes[index..], format);
+                    assert!(matches!(rest, Ok(None)), "{:?}", bytes);
+                }
+            }
+        }
+    }
+
+    // 长度字段超过上限时只凭头部就断开，不等也不缓存后面的数据
+    #[test]
+    fn oversized_header() {
+        let limits = Limits { max_field: 1024, max_request: 4096 };
+        let mut parser = Parser::new(FEATURE_REQUEST_ID | FEATURE_LONG_LEN, limits);
+        let mut src = BytesMut::from(&[OP_SET, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xfe][..]);
+        assert_eq!(parser.parse(&mut src).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(parser.parse(&mut src).is_err());
+
+        // 逐项都在上限之内，累计超过一个请求的上限
+        let mut parser = Parser::new(FEATURE_LONG_LEN, limits);
+        let mut src = BytesMut::from(&[OP_INGEST, 0xff, 0xff, 0xff, 0xfe][..]);
+        let entry = [&[0, 0, 0, 1, b'k', 0, 0, 3, 0xf0][..], &[0; 1008]].concat();
+        let mut rejected = false;
+        for _ in 0..8 {
+            src.extend_from_slice(&entry);
+            match parser.parse(&mut src) {
+                Ok(parsed) => assert!(parsed.is_none()),
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::InvalidData);
+                    rejected = true;
+                    break;
+                }
+            }
+        }
+        assert!(rejected);
+
+        // 外层帧的长度同样检查
+        let mut parser = Parser::new(FEATURE_CRC, limits);
+        let mut src = BytesMut::from(&0x7fff_fff0u32.to_be_bytes()[..]);
+        assert_eq!(parser.parse(&mut src).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    // 外层帧校验失败后不再解析
+    #[test]
+    fn sealed_crc_mismatch() {
+        let features = FEATURE_CRC;
+        let mut bytes = encode(&[Request::DBSIZE, Request::DBSIZE], features);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let (parsed, e) = feed(&mut Parser::new(features, Limits::UNLIMITED), &bytes, std::iter::once(bytes.len()));
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(e.unwrap().kind(), ErrorKind::InvalidData);
+    }
+}
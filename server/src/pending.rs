@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use tokio::time::{sleep_until, Duration, Instant};
+use crate::client::Cancelled;
+use crate::event::EventRes;
+use crate::shard::is_last;
+
+// 一个连接最多的未完成请求数，达到后暂停读取它的 socket；事务中等待 EXEC 的 op 也算，事务最多缓存比它少一个的 op，留出读取 EXEC 的位置
+pub const DEFAULT_MAX_INFLIGHT: usize = 32;
+
+// 一个连接上未完成的请求，按请求顺序排列，响应严格按这个顺序发给客户端
+// 带请求编号的响应先于排在前面的请求到达时暂存，轮到它时再发送；没有请求编号的响应视为最早的请求的
+// 配置了时限时，超时的请求先返回超时错误，记入 cancelled，事件循环还没执行的直接跳过；已经在执行的，之后到达的响应丢弃
+pub struct Pending {
+    timeout: Option<Duration>,
+    requests: VecDeque<Outstanding>,
+    // 先于排在前面的请求到达的响应，按到达顺序
+    early: Vec<EventRes>,
+    cancelled: Cancelled,
+}
+
+struct Outstanding {
+    req: Option<u32>,
+    // 事务中等待 EXEC 的 op
+    queued: bool,
+    // 事务中缓存的 op 在 EXEC 发出后才有时限，没有配置时限时为 None
+    deadline: Option<Instant>,
+    // 已返回超时错误，之后到达的响应丢弃
+    expired: bool,
+}
+
+impl Pending {
+    pub fn new(timeout: Option<Duration>, cancelled: Cancelled) -> Self {
+        Self { timeout, requests: VecDeque::new(), early: Vec::new(), cancelled }
+    }
+
+    // 发出一个请求，之前缓存在事务中的 op 与它同时到期
+    pub fn start(&mut self, req: Option<u32>) {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        for request in self.requests.iter_mut().rev().take_while(|request| request.queued) {
+            request.queued = false;
+            request.deadline = deadline;
+        }
+        self.requests.push_back(Outstanding { req, queued: false, deadline, expired: false });
+    }
+
+    // 发出一个事务中的 op，等待 EXEC 的时间不算在内
+    pub fn queue(&mut self, req: Option<u32>) {
+        self.requests.push_back(Outstanding { req, queued: true, deadline: None, expired: false });
+    }
+
+    // 已发给事件循环、还没有完成的请求数，包括等待 EXEC 的 op，它们同样占着事件循环的内存
+    pub fn inflight(&self) -> usize {
+        self.requests.len()
+    }
+
+    // 事务中等待 EXEC 的 op 数
+    pub fn queued(&self) -> usize {
+        self.requests.iter().filter(|request| request.queued).count()
+    }
+
+    // 收到一条响应，返回按请求顺序可以发给客户端的响应
+    pub fn finish(&mut self, res: EventRes) -> Vec<EventRes> {
+        let mut ready = Vec::new();
+        let position = res.req().and_then(|req| self.requests.iter().position(|request| request.req == Some(req)));
+        if position.is_some_and(|position| position > 0) {
+            self.early.push(res);
+            return ready;
+        }
+        self.deliver(res, &mut ready);
+        // 前面的请求完成后，轮到的请求已经到达的响应
+        while let Some(req) = self.requests.front().and_then(|request| request.req) {
+            let Some(index) = self.early.iter().position(|res| res.req() == Some(req)) else {
+                break;
+            };
+            let res = self.early.remove(index);
+            self.deliver(res, &mut ready);
+        }
+        ready
+    }
+
+    // 最早的请求的响应，最后一条响应到达时它完成
+    fn deliver(&mut self, res: EventRes, ready: &mut Vec<EventRes>) {
+        let Some(head) = self.requests.front() else {
+            ready.push(res);
+            return;
+        };
+        let expired = head.expired;
+        if is_last(&res) {
+            if let Some(Outstanding { req: Some(req), expired: true, .. }) = self.requests.pop_front() {
+                self.cancelled.remove(&req);
+            }
+        }
+        if !expired {
+            ready.push(res);
+        }
+    }
+
+    // 最早的没有超时的请求到期时返回它的请求号，没有到期的请求时永远不会完成
+    pub async fn expired(&mut self) -> Option<u32> {
+        let Some((index, deadline)) = self.requests.iter().enumerate()
+            .find(|(_, request)| !request.expired)
+            .and_then(|(index, request)| Some((index, request.deadline?))) else {
+            return std::future::pending().await;
+        };
+        sleep_until(deadline).await;
+        let request = &mut self.requests[index];
+        request.expired = true;
+        if let Some(req) = request.req {
+            self.cancelled.insert(req);
+        }
+        request.req
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CLIENT;
+
+    const TIMEOUT: Duration = Duration::from_millis(20);
+
+    fn set(req: u32) -> EventRes {
+        EventRes::SET { id: CLIENT.to_string(), req: Some(req) }
+    }
+
+    fn entry(req: u32, entry: Option<(Vec<u8>, Vec<u8>)>) -> EventRes {
+        EventRes::SCAN { id: CLIENT.to_string(), req: Some(req), entry }
+    }
+
+    fn reqs(ready: Vec<EventRes>) -> Vec<Option<u32>> {
+        ready.iter().map(EventRes::req).collect()
+    }
+
+    // 后发出的请求的响应先到达时，等前面的请求完成后一起发送
+    #[test]
+    fn reorder_by_request_id() {
+        let mut pending = Pending::new(None, Cancelled::default());
+        for req in 1..=3 {
+            pending.start(Some(req));
+        }
+        assert!(pending.finish(set(3)).is_empty());
+        assert!(pending.finish(entry(2, Some((b"k".to_vec(), b"v".to_vec())))).is_empty());
+        assert!(pending.finish(entry(2, None)).is_empty());
+        assert_eq!(reqs(pending.finish(set(1))), [Some(1), Some(2), Some(2), Some(3)]);
+        // 没有未完成的请求时原样发送
+        assert_eq!(reqs(pending.finish(set(4))), [Some(4)]);
+    }
+
+    // 没有请求编号时按到达顺序
+    #[test]
+    fn fifo_without_request_id() {
+        let mut pending = Pending::new(None, Cancelled::default());
+        pending.start(None);
+        pending.start(None);
+        let res = EventRes::SET { id: CLIENT.to_string(), req: None };
+        assert_eq!(pending.finish(res).len(), 1);
+        assert_eq!(pending.requests.len(), 1);
+    }
+
+    // 最早的请求超时后，它迟到的响应丢弃并取消记录，之后请求的响应照常发送
+    #[tokio::test]
+    async fn expire_and_drop_stale() {
+        let cancelled = Cancelled::default();
+        let mut pending = Pending::new(Some(TIMEOUT), cancelled.clone());
+        pending.start(Some(1));
+        pending.start(Some(2));
+        assert_eq!(pending.expired().await, Some(1));
+        assert!(cancelled.contains(&1));
+        assert!(pending.finish(set(1)).is_empty());
+        assert!(!cancelled.contains(&1));
+        assert_eq!(reqs(pending.finish(set(2))), [Some(2)]);
+        assert!(tokio::time::timeout(TIMEOUT * 3, pending.expired()).await.is_err());
+    }
+
+    // 超时的请求之后的响应先到达时，等它迟到的响应丢弃后再发送
+    #[tokio::test]
+    async fn early_behind_expired() {
+        let mut pending = Pending::new(Some(TIMEOUT), Cancelled::default());
+        pending.start(Some(1));
+        pending.start(Some(2));
+        assert!(pending.finish(set(2)).is_empty());
+        assert_eq!(pending.expired().await, Some(1));
+        assert_eq!(reqs(pending.finish(set(1))), [Some(2)]);
+    }
+
+    // 事务中的 op 在 EXEC 发出前不会到期
+    #[tokio::test]
+    async fn queued_wait_for_exec() {
+        let mut pending = Pending::new(Some(TIMEOUT), Cancelled::default());
+        pending.queue(Some(1));
+        assert!(tokio::time::timeout(TIMEOUT * 3, pending.expired()).await.is_err());
+        pending.start(Some(2));
+        assert_eq!(pending.expired().await, Some(1));
+        assert_eq!(pending.expired().await, Some(2));
+    }
+
+    // 等待 EXEC 的 op 也计入
+    #[test]
+    fn inflight_includes_queued() {
+        let mut pending = Pending::new(None, Cancelled::default());
+        pending.start(Some(1));
+        pending.queue(Some(2));
+        pending.queue(Some(3));
+        assert_eq!((pending.inflight(), pending.queued()), (3, 2));
+        pending.start(Some(4));
+        assert_eq!((pending.inflight(), pending.queued()), (4, 0));
+        pending.finish(set(1));
+        assert_eq!(pending.inflight(), 3);
+    }
+
+    // 不配置时限时不会到期
+    #[tokio::test]
+    async fn no_timeout() {
+        let mut pending = Pending::new(None, Cancelled::default());
+        pending.start(Some(1));
+        assert!(tokio::time::timeout(TIMEOUT * 3, pending.expired()).await.is_err());
+        assert_eq!(reqs(pending.finish(set(1))), [Some(1)]);
+    }
+}
@@ -0,0 +1,1397 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::iter::once;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, sleep_until, timeout, Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::replication::{put_field, Reader, Replicated, ReplicationLog};
+use crate::utils::get_id;
+
+// Raft 集群模式：所有写入经过 Raft 日志，多数节点写入日志后才响应客户端，少数节点故障时不丢失已响应的写入
+// 主节点先在本地执行写入，存储引擎把写入的记录放进复制日志，这里按顺序追加到 Raft 日志并复制到其他节点
+// 从节点只回放已提交的日志；主节点本地执行过、但没有提交的写入在它失去主节点身份后可能和新的主节点不一致
+// 这样的节点标记为 dirty，由新的主节点发送快照覆盖本地数据
+// 每个任期的第一条日志把版本号提高到 term << TERM_VERSION_SHIFT，新的主节点的写入总比之前任期的版本号大
+// 只支持一个分片和默认命名空间
+
+// 节点状态文件，内容为 "term voted_for snapshot_index snapshot_term dirty"
+const RAFT_STATE_FILE: &str = "RAFT";
+// 日志文件，每条日志一帧
+// 4 bit len
+// 4 bit crc32
+// 8 bit index
+// 8 bit term
+// n bit record, Replicated::encode 的结果
+const RAFT_LOG_FILE: &str = "RAFT_LOG";
+const RAFT_FRAME_HEAD: usize = 4 + 4;
+// 日志超过该字节数时丢弃已提交并 sync 过的部分，落后更多的节点改为安装快照
+const RAFT_LOG_COMPACT_BYTES: u64 = 1024 * 1024 * 64;
+const TERM_VERSION_SHIFT: u32 = 40;
+
+// 主节点发送心跳的间隔，从节点超过选举超时没有收到主节点的消息时发起选举，超时在 [1, 2) 倍之间随机
+const RAFT_HEARTBEAT: Duration = Duration::from_millis(100);
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(1000);
+// 等待其他节点回复的时间，安装快照的第一段要先清空本地数据，单独计算
+const RPC_TIMEOUT: Duration = Duration::from_secs(3);
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(60);
+// 快照分段发送，每段的记录超过该字节数后不再添加，至少一条
+const INSTALL_CHUNK_BYTES: usize = 1024 * 1024 * 4;
+// 每次 AppendEntries 最多发送的日志条数和字节数，第一条超过字节数时也发送
+const MAX_APPEND_ENTRIES: usize = 256;
+const MAX_APPEND_BYTES: usize = 1024 * 1024 * 4;
+// 一条消息的最大字节数，读取时先检查长度再分配内存，超过时断开连接
+const MAX_RAFT_MESSAGE: usize = 1024 * 1024 * 256;
+// 发给事件循环、还没有处理的命令数
+const RAFT_QUEUE_SIZE: usize = 1024;
+
+// 节点之间的消息，每条前加 4 bit 长度，之后 1 bit kind
+// MSG_VOTE: 8 bit term, 8 bit candidate, 8 bit last index, 8 bit last term
+// MSG_VOTE_REPLY: 8 bit term, 1 bit granted
+// MSG_APPEND: 8 bit term, 8 bit leader, 8 bit prev index, 8 bit prev term, 8 bit commit, 4 bit count, n * (8 bit term, 4 bit record len, record)
+// MSG_APPEND_REPLY: 8 bit term, 1 bit success, 8 bit match index, 1 bit need snapshot
+// MSG_INSTALL: 8 bit term, 8 bit leader, 8 bit index, 8 bit index term, 8 bit version, 4 bit chunk, 1 bit last, 4 bit count, n * (4 bit record len, record)
+// MSG_INSTALL 的回复也是 MSG_APPEND_REPLY，快照从第 0 段开始按顺序发送，前一段成功后才发送下一段
+const MSG_VOTE: u8 = 1;
+const MSG_VOTE_REPLY: u8 = 2;
+const MSG_APPEND: u8 = 3;
+const MSG_APPEND_REPLY: u8 = 4;
+const MSG_INSTALL: u8 = 5;
+
+// 集群中的其他节点
+pub struct RaftPeer {
+    pub id: u64,
+    pub addr: String,
+}
+
+// 默认命名空间的全部数据，每个 key 一条 StorageEngine::export 编码的记录，带原来的版本号和过期时间
+pub struct RaftSnapshot {
+    // 生成时复制日志的最新序号
+    pub seq: u64,
+    // 已分配的最大版本号
+    pub version: u64,
+    pub records: Vec<Vec<u8>>,
+}
+
+// 发给事件循环的命令，按顺序处理
+pub enum RaftCommand {
+    // 从节点回放已提交的一条日志
+    Apply(Replicated),
+    // 主节点序号不大于它的写入已提交，可以响应
+    Commit(u64),
+    // 成为主节点：回放还没有回放的日志后开始接受写入，回复此时复制日志的最新序号
+    Lead {
+        records: Vec<Replicated>,
+        reply: oneshot::Sender<u64>,
+    },
+    // 不再是主节点：拒绝写入，等待提交的响应返回错误，回复此时复制日志的最新序号
+    StepDown(oneshot::Sender<u64>),
+    Snapshot(oneshot::Sender<RaftSnapshot>),
+    // 安装快照的一段：第一段先清空本地数据，记录按原来的版本号写入；最后一段带快照的版本号，写入后 sync WAL
+    Install {
+        first: bool,
+        records: Vec<Vec<u8>>,
+        version: Option<u64>,
+        reply: oneshot::Sender<()>,
+    },
+    // sync 所有 WAL，之后可以丢弃已回放的日志
+    Sync(oneshot::Sender<()>),
+}
+
+// 事件循环持有的一端
+pub struct RaftFeed {
+    pub commands: mpsc::Receiver<RaftCommand>,
+    // 存储引擎写入的记录，主节点从这里取出追加到 Raft 日志
+    pub proposals: Arc<ReplicationLog>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    term: u64,
+    record: Vec<u8>,
+}
+
+enum Message {
+    Vote {
+        term: u64,
+        candidate: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    VoteReply {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        leader: u64,
+        prev_index: u64,
+        prev_term: u64,
+        commit: u64,
+        entries: Vec<Entry>,
+    },
+    AppendReply {
+        term: u64,
+        success: bool,
+        match_index: u64,
+        snapshot: bool,
+    },
+    Install {
+        term: u64,
+        leader: u64,
+        index: u64,
+        index_term: u64,
+        version: u64,
+        chunk: u32,
+        last: bool,
+        records: Vec<Vec<u8>>,
+    },
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Message::Vote { term, candidate, last_index, last_term } => {
+                buf.push(MSG_VOTE);
+                for v in [term, candidate, last_index, last_term] {
+                    buf.extend(v.to_be_bytes());
+                }
+            }
+            Message::VoteReply { term, granted } => {
+                buf.push(MSG_VOTE_REPLY);
+                buf.extend(term.to_be_bytes());
+                buf.push(*granted as u8);
+            }
+            Message::Append { term, leader, prev_index, prev_term, commit, entries } => {
+                buf.push(MSG_APPEND);
+                for v in [term, leader, prev_index, prev_term, commit] {
+                    buf.extend(v.to_be_bytes());
+                }
+                buf.extend((entries.len() as u32).to_be_bytes());
+                for entry in entries {
+                    buf.extend(entry.term.to_be_bytes());
+                    put_field(&mut buf, &entry.record);
+                }
+            }
+            Message::AppendReply { term, success, match_index, snapshot } => {
+                buf.push(MSG_APPEND_REPLY);
+                buf.extend(term.to_be_bytes());
+                buf.push(*success as u8);
+                buf.extend(match_index.to_be_bytes());
+                buf.push(*snapshot as u8);
+            }
+            Message::Install { term, leader, index, index_term, version, chunk, last, records } => {
+                buf.push(MSG_INSTALL);
+                for v in [term, leader, index, index_term, version] {
+                    buf.extend(v.to_be_bytes());
+                }
+                buf.extend(chunk.to_be_bytes());
+                buf.push(*last as u8);
+                buf.extend((records.len() as u32).to_be_bytes());
+                for record in records {
+                    put_field(&mut buf, record);
+                }
+            }
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(buf);
+        let message = match reader.u8()? {
+            MSG_VOTE => Message::Vote { term: reader.u64()?, candidate: reader.u64()?, last_index: reader.u64()?, last_term: reader.u64()? },
+            MSG_VOTE_REPLY => Message::VoteReply { term: reader.u64()?, granted: reader.u8()? != 0 },
+            MSG_APPEND => {
+                let (term, leader, prev_index, prev_term, commit) = (reader.u64()?, reader.u64()?, reader.u64()?, reader.u64()?, reader.u64()?);
+                let count = reader.u32()? as usize;
+                let mut entries = Vec::with_capacity(count.min(MAX_APPEND_ENTRIES));
+                for _ in 0..count {
+                    entries.push(Entry { term: reader.u64()?, record: reader.field()?.to_vec() });
+                }
+                Message::Append { term, leader, prev_index, prev_term, commit, entries }
+            }
+            MSG_APPEND_REPLY => Message::AppendReply { term: reader.u64()?, success: reader.u8()? != 0, match_index: reader.u64()?, snapshot: reader.u8()? != 0 },
+            MSG_INSTALL => {
+                let (term, leader, index, index_term, version) = (reader.u64()?, reader.u64()?, reader.u64()?, reader.u64()?, reader.u64()?);
+                let (chunk, last) = (reader.u32()?, reader.u8()? != 0);
+                let count = reader.u32()? as usize;
+                let mut records = Vec::with_capacity(count.min(MAX_APPEND_ENTRIES));
+                for _ in 0..count {
+                    records.push(reader.field()?.to_vec());
+                }
+                Message::Install { term, leader, index, index_term, version, chunk, last, records }
+            }
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+async fn write_message(stream: &mut TcpStream, message: &Message) -> std::io::Result<()> {
+    let body = message.encode();
+    if body.len() > MAX_RAFT_MESSAGE {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Raft message too large, len = {}", body.len())));
+    }
+    let mut buf = Vec::with_capacity(4 + body.len());
+    buf.extend((body.len() as u32).to_be_bytes());
+    buf.extend(body);
+    stream.write_all(&buf).await
+}
+
+async fn read_message(stream: &mut TcpStream) -> std::io::Result<Message> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_RAFT_MESSAGE {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Raft message too large, len = {}", len)));
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+    Message::decode(&buf).ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid raft message"))
+}
+
+// 节点状态，写入后才回复投票和日志
+#[derive(Default)]
+pub struct HardState {
+    term: u64,
+    // 本任期投票给的节点，0 表示还没有投票
+    voted_for: u64,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    // 本地数据可能包含没有提交的写入，需要安装快照
+    dirty: bool,
+}
+
+impl HardState {
+    // 文件不存在时为初始状态，内容不完整或无法解析时返回错误
+    fn read(path: &str) -> std::io::Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(Error::new(e.kind(), format!("Read raft state file {} fail, err = {:?}", path, e))),
+        };
+        let fields: Option<Vec<u64>> = content.split_whitespace().map(|field| field.parse().ok()).collect();
+        match fields.as_deref() {
+            Some(&[term, voted_for, snapshot_index, snapshot_term, dirty]) => Ok(Self { term, voted_for, snapshot_index, snapshot_term, dirty: dirty != 0 }),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid raft state file {}", path))),
+        }
+    }
+
+    // 先写临时文件再改名
+    fn write(&self, path: &str) {
+        let tmp = format!("{}.tmp", path);
+        let content = format!("{} {} {} {} {}", self.term, self.voted_for, self.snapshot_index, self.snapshot_term, self.dirty as u8);
+        std::fs::File::create(&tmp)
+            .and_then(|mut file| {
+                std::io::Write::write_all(&mut file, content.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .unwrap_or_else(|e| panic!("Write raft state file {} fail, err = {:?}", path, e));
+    }
+}
+
+// 快照之后的日志，第 i 条的 index 为 start + 1 + i
+struct RaftLog {
+    path: String,
+    file: File,
+    // 快照包含的最后一条日志
+    start: u64,
+    start_term: u64,
+    entries: VecDeque<Entry>,
+    bytes: u64,
+}
+
+impl RaftLog {
+    // 读取日志文件，跳过快照已包含的部分，只有最后一帧可能因为崩溃写了一半
+    async fn open(path: String, start: u64, start_term: u64) -> Self {
+        let buf = match tokio::fs::read(&path).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => panic!("Read raft log {} fail, err = {:?}", &path, e),
+        };
+        let mut entries = VecDeque::new();
+        let mut index = 0;
+        while let Some(head) = buf.get(index..index + RAFT_FRAME_HEAD) {
+            let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+            let crc = u32::from_be_bytes([head[4], head[5], head[6], head[7]]);
+            let Some(body) = buf.get(index + RAFT_FRAME_HEAD..index + RAFT_FRAME_HEAD + len).filter(|body| body.len() >= 16) else {
+                warn!("Raft log {} frame truncated at {}, skip the rest", &path, index);
+                break;
+            };
+            if crc32fast::hash(body) != crc {
+                warn!("Raft log {} frame crc mismatch at {}, skip the rest", &path, index);
+                break;
+            }
+            let entry_index = u64::from_be_bytes(body[..8].try_into().expect("Index bytes"));
+            let term = u64::from_be_bytes(body[8..16].try_into().expect("Term bytes"));
+            index += RAFT_FRAME_HEAD + len;
+            if entry_index <= start {
+                continue;
+            }
+            if entry_index != start + 1 + entries.len() as u64 {
+                warn!("Raft log {} index {} not continuous, skip the rest", &path, entry_index);
+                break;
+            }
+            entries.push_back(Entry { term, record: body[16..].to_vec() });
+        }
+        let (file, bytes) = write_log(&path, start, &entries).await;
+        Self { path, file, start, start_term, entries, bytes }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.start + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.back().map_or(self.start_term, |entry| entry.term)
+    }
+
+    // 已被快照丢弃或不存在时为 None
+    fn term(&self, index: u64) -> Option<u64> {
+        if index == self.start {
+            return Some(self.start_term);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    fn get(&self, index: u64) -> Option<&Entry> {
+        let position = index.checked_sub(self.start + 1)?;
+        self.entries.get(position as usize)
+    }
+
+    fn frame(index: u64, entry: &Entry) -> Vec<u8> {
+        let mut body = Vec::with_capacity(16 + entry.record.len());
+        body.extend(index.to_be_bytes());
+        body.extend(entry.term.to_be_bytes());
+        body.extend_from_slice(&entry.record);
+        let mut frame = Vec::with_capacity(RAFT_FRAME_HEAD + body.len());
+        frame.extend((body.len() as u32).to_be_bytes());
+        frame.extend(crc32fast::hash(&body).to_be_bytes());
+        frame.extend(body);
+        frame
+    }
+
+    // 追加并 sync 后返回
+    async fn append(&mut self, entries: Vec<Entry>) {
+        if entries.is_empty() {
+            return;
+        }
+        let mut buf = Vec::new();
+        for entry in entries {
+            buf.extend(Self::frame(self.last_index() + 1, &entry));
+            self.entries.push_back(entry);
+        }
+        self.file.write_all(&buf).await.expect("Write raft log fail");
+        self.file.sync_data().await.expect("Sync raft log fail");
+        self.bytes += buf.len() as u64;
+    }
+
+    // 删除 index 及之后的日志
+    async fn truncate(&mut self, index: u64) {
+        self.entries.truncate(index.saturating_sub(self.start + 1) as usize);
+        self.rewrite().await;
+    }
+
+    // 删除 index 及之前的日志，index 可以超过最后一条；调用前先写入节点状态
+    async fn compact(&mut self, index: u64, term: u64) {
+        let count = index.saturating_sub(self.start).min(self.entries.len() as u64);
+        self.entries.drain(..count as usize);
+        if index > self.last_index() {
+            self.entries.clear();
+        }
+        self.start = index;
+        self.start_term = term;
+        self.rewrite().await;
+    }
+
+    async fn rewrite(&mut self) {
+        (self.file, self.bytes) = write_log(&self.path, self.start, &self.entries).await;
+    }
+}
+
+// 把 start 之后的日志写入临时文件后改名，返回追加写入的新文件和它的字节数
+async fn write_log(path: &str, start: u64, entries: &VecDeque<Entry>) -> (File, u64) {
+    let tmp = format!("{}.tmp", path);
+    let mut buf = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        buf.extend(RaftLog::frame(start + 1 + i as u64, entry));
+    }
+    let mut file = File::create(&tmp).await.unwrap_or_else(|e| panic!("Create raft log {} fail, err = {:?}", &tmp, e));
+    file.write_all(&buf).await.expect("Write raft log fail");
+    file.sync_all().await.expect("Sync raft log fail");
+    tokio::fs::rename(&tmp, path).await.unwrap_or_else(|e| panic!("Rename raft log {} fail, err = {:?}", &tmp, e));
+    let file = OpenOptions::new().append(true).open(path).await
+        .unwrap_or_else(|e| panic!("Open raft log {} fail, err = {:?}", path, e));
+    (file, buf.len() as u64)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// 主节点记录的其他节点的复制进度
+struct Progress {
+    next: u64,
+    matched: u64,
+    // 有还没回复的请求，收到回复或失败前不再发送
+    inflight: bool,
+    // 需要安装快照
+    install: bool,
+    // 正在分段发送的快照
+    outgoing: Option<Outgoing>,
+}
+
+// 发给一个节点的快照，position 之前的记录已确认
+struct Outgoing {
+    index: u64,
+    index_term: u64,
+    version: u64,
+    records: Vec<Vec<u8>>,
+    // 下一段的序号和第一条记录
+    chunk: u32,
+    position: usize,
+    // 已发送、等待确认的一段的结束位置
+    end: usize,
+}
+
+impl Outgoing {
+    // 从 position 开始的一段，没有确认时重发同一段
+    fn message(&mut self, term: u64, leader: u64) -> Message {
+        let mut bytes = 0;
+        self.end = self.position;
+        while let Some(record) = self.records.get(self.end).filter(|_| self.end == self.position || bytes < INSTALL_CHUNK_BYTES) {
+            bytes += record.len();
+            self.end += 1;
+        }
+        let records = self.records[self.position..self.end].to_vec();
+        let last = self.end == self.records.len();
+        Message::Install { term, leader, index: self.index, index_term: self.index_term, version: self.version, chunk: self.chunk, last, records }
+    }
+
+    // 当前段已确认，返回是否还有下一段
+    fn ack(&mut self) -> bool {
+        self.position = self.end;
+        self.chunk += 1;
+        self.position < self.records.len()
+    }
+}
+
+struct RaftNode {
+    id: u64,
+    peers: HashMap<u64, mpsc::Sender<Message>>,
+    state_path: String,
+    term: u64,
+    voted_for: u64,
+    dirty: bool,
+    log: RaftLog,
+    role: Role,
+    leader: u64,
+    commit: u64,
+    // 已发给事件循环回放的最后一条，主节点上为最后一条日志
+    applied: u64,
+    votes: usize,
+    progress: HashMap<u64, Progress>,
+    // 从节点正在安装的快照：主节点的任期、快照位置和下一段的序号
+    installing: Option<(u64, u64, u32)>,
+    deadline: Instant,
+    commands: mpsc::Sender<RaftCommand>,
+    proposals: Arc<ReplicationLog>,
+    // 主节点下一条要从复制日志中取出的序号
+    next_seq: u64,
+    // 成为主节点时的第一条日志和此时复制日志的最新序号，之后序号为 s 的记录在 lead_index + s - lead_seq
+    lead_index: u64,
+    lead_seq: u64,
+    rng: u64,
+}
+
+// 读取 data_path 下保存的节点状态，启动前检查，文件损坏时返回错误
+pub fn read_state(data_path: &str) -> std::io::Result<HardState> {
+    HardState::read(&format!("{}/{}", data_path, RAFT_STATE_FILE))
+}
+
+// 启动节点，在后台监听 listener 并连接 peers；data_path 下保存节点状态和日志，state 由 read_state 读取
+pub async fn start(id: u64, listener: TcpListener, peers: Vec<RaftPeer>, data_path: String, state: HardState, proposals: Arc<ReplicationLog>) -> RaftFeed {
+    let (commands, receiver) = mpsc::channel(RAFT_QUEUE_SIZE);
+    let (inbound_tx, inbound) = mpsc::channel(RAFT_QUEUE_SIZE);
+    let (responses_tx, responses) = mpsc::channel(RAFT_QUEUE_SIZE);
+    let mut senders = HashMap::new();
+    for peer in peers {
+        let (tx, rx) = mpsc::channel(RAFT_QUEUE_SIZE);
+        senders.insert(peer.id, tx);
+        let span = info_span!("raft_peer", peer = peer.id);
+        tokio::spawn(call_peer(peer, rx, responses_tx.clone()).instrument(span));
+    }
+    tokio::spawn(accept(listener, inbound_tx));
+    let mut node = RaftNode::open(id, senders, &data_path, state, commands, proposals.clone()).await;
+    tokio::spawn(async move {
+        node.run(inbound, responses).await;
+    }.instrument(info_span!("raft", node = id)));
+    RaftFeed { commands: receiver, proposals }
+}
+
+// 其他节点的请求交给节点任务处理后回复
+async fn accept(listener: TcpListener, inbound: mpsc::Sender<(Message, oneshot::Sender<Message>)>) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Fail to accept raft connection; err = {:?}", e);
+                continue;
+            }
+        };
+        let id = get_id(&addr.ip().to_string(), addr.port());
+        let inbound = inbound.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = match read_message(&mut stream).await {
+                    Ok(message) => message,
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => {
+                        warn!("Read raft message from [{}] fail, err = {:?}", id, e);
+                        break;
+                    }
+                };
+                let (tx, rx) = oneshot::channel();
+                if inbound.send((message, tx)).await.is_err() {
+                    break;
+                }
+                let Ok(reply) = rx.await else {
+                    break;
+                };
+                if let Err(e) = write_message(&mut stream, &reply).await {
+                    warn!("Write raft message to [{}] fail, err = {:?}", id, e);
+                    break;
+                }
+            }
+        });
+    }
+}
+
+// 按顺序把请求发给一个节点，回复或失败交给节点任务；连接断开后下一个请求时重连
+async fn call_peer(peer: RaftPeer, mut requests: mpsc::Receiver<Message>, responses: mpsc::Sender<(u64, Option<Message>)>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut reachable = true;
+    while let Some(message) = requests.recv().await {
+        let limit = if matches!(message, Message::Install { .. }) { INSTALL_TIMEOUT } else { RPC_TIMEOUT };
+        let reply = match timeout(limit, call(&mut stream, &peer.addr, &message)).await {
+            Ok(Ok(reply)) => Some(reply),
+            Ok(Err(e)) => {
+                if reachable {
+                    warn!("Raft peer {} at {} unreachable, err = {:?}", peer.id, &peer.addr, e);
+                }
+                None
+            }
+            Err(_) => {
+                if reachable {
+                    warn!("Raft peer {} at {} timeout", peer.id, &peer.addr);
+                }
+                None
+            }
+        };
+        if reply.is_none() {
+            stream = None;
+        } else if !reachable {
+            info!("Raft peer {} at {} reachable", peer.id, &peer.addr);
+        }
+        reachable = reply.is_some();
+        if responses.send((peer.id, reply)).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn call(stream: &mut Option<TcpStream>, addr: &str, message: &Message) -> std::io::Result<Message> {
+    if stream.is_none() {
+        *stream = Some(TcpStream::connect(addr).await?);
+    }
+    let stream = stream.as_mut().expect("Raft peer stream");
+    write_message(stream, message).await?;
+    read_message(stream).await
+}
+
+impl RaftNode {
+    // 读取日志文件，以从节点身份开始；peers 为发给其他节点的消息
+    async fn open(id: u64, peers: HashMap<u64, mpsc::Sender<Message>>, data_path: &str, state: HardState, commands: mpsc::Sender<RaftCommand>, proposals: Arc<ReplicationLog>) -> Self {
+        let log = RaftLog::open(format!("{}/{}", data_path, RAFT_LOG_FILE), state.snapshot_index, state.snapshot_term).await;
+        info!("Raft node {} term {}, log ({}, {}], dirty {}", id, state.term, log.start, log.last_index(), state.dirty);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("System time before unix epoch").as_nanos() as u64;
+        let mut node = Self {
+            id,
+            peers,
+            state_path: format!("{}/{}", data_path, RAFT_STATE_FILE),
+            term: state.term,
+            voted_for: state.voted_for,
+            dirty: state.dirty,
+            // 快照之前的日志都已提交，本地数据至少包含到这里
+            commit: log.start,
+            applied: log.start,
+            log,
+            role: Role::Follower,
+            leader: 0,
+            votes: 0,
+            progress: HashMap::new(),
+            installing: None,
+            deadline: Instant::now(),
+            commands,
+            proposals,
+            next_seq: 0,
+            lead_index: 0,
+            lead_seq: 0,
+            rng: now ^ id.rotate_left(32) | 1,
+        };
+        node.reset_deadline();
+        node
+    }
+
+    async fn run(&mut self, mut inbound: mpsc::Receiver<(Message, oneshot::Sender<Message>)>, mut responses: mpsc::Receiver<(u64, Option<Message>)>) {
+        let mut heartbeat = interval(RAFT_HEARTBEAT);
+        let mut proposed = self.proposals.subscribe();
+        loop {
+            let leading = self.role == Role::Leader;
+            select! {
+                Some((message, reply)) = inbound.recv() => {
+                    let res = self.receive(message).await;
+                    let _ = reply.send(res);
+                }
+                Some((peer, res)) = responses.recv() => self.on_response(peer, res).await,
+                _ = sleep_until(self.deadline), if !leading => self.campaign().await,
+                _ = heartbeat.tick(), if leading => self.replicate().await,
+                Ok(()) = proposed.changed(), if leading => {
+                    self.propose().await;
+                    self.replicate().await;
+                }
+            }
+            self.maybe_compact().await;
+        }
+    }
+
+    fn persist(&self) {
+        self.persist_snapshot(self.log.start, self.log.start_term);
+    }
+
+    // 丢弃日志之前先写入新的快照位置，崩溃后重新打开时跳过快照已包含的日志
+    fn persist_snapshot(&self, snapshot_index: u64, snapshot_term: u64) {
+        HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+            snapshot_index,
+            snapshot_term,
+            dirty: self.dirty,
+        }.write(&self.state_path);
+    }
+
+    fn reset_deadline(&mut self) {
+        // xorshift
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let jitter = self.rng % ELECTION_TIMEOUT.as_millis() as u64;
+        self.deadline = Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter);
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    async fn command(&self, command: RaftCommand) {
+        self.commands.send(command).await.expect("Raft event loop closed");
+    }
+
+    // 转为 term 任期的从节点；之前是主节点时通知事件循环，并判断本地数据是否包含没有提交的写入
+    async fn become_follower(&mut self, term: u64, leader: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = 0;
+        }
+        if self.role == Role::Leader {
+            let (tx, rx) = oneshot::channel();
+            self.command(RaftCommand::StepDown(tx)).await;
+            let seq = rx.await.unwrap_or(u64::MAX);
+            self.dirty = seq >= self.next_seq || self.log.last_index() > self.commit;
+            warn!("Raft node {} step down in term {}, dirty {}", self.id, self.term, self.dirty);
+            self.progress.clear();
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.persist();
+        self.reset_deadline();
+    }
+
+    async fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = self.id;
+        self.votes = 1;
+        self.leader = 0;
+        self.persist();
+        self.reset_deadline();
+        info!("Raft node {} start election for term {}", self.id, self.term);
+        if self.votes >= self.quorum() {
+            self.lead().await;
+            return;
+        }
+        for sender in self.peers.values() {
+            let _ = sender.try_send(Message::Vote { term: self.term, candidate: self.id, last_index: self.log.last_index(), last_term: self.log.last_term() });
+        }
+    }
+
+    async fn lead(&mut self) {
+        info!("Raft node {} become leader of term {}", self.id, self.term);
+        // 本地数据可能和其他节点不一致时，先给所有节点发送快照
+        let install = self.dirty;
+        self.role = Role::Leader;
+        self.leader = self.id;
+        self.dirty = true;
+        self.persist();
+        self.progress = self.peers.keys().map(|peer| (*peer, Progress { next: self.log.last_index() + 1, matched: 0, inflight: false, install, outgoing: None })).collect();
+        // 新任期的第一条日志提高版本号，提交后之前任期的日志也随之提交
+        let version = Replicated::Version { namespace: String::new(), version: self.term << TERM_VERSION_SHIFT };
+        self.log.append(vec![Entry { term: self.term, record: version.encode() }]).await;
+        let records = (self.applied + 1..=self.log.last_index())
+            .filter_map(|index| self.log.get(index).and_then(|entry| Replicated::parse(&entry.record)))
+            .collect();
+        let (tx, rx) = oneshot::channel();
+        self.command(RaftCommand::Lead { records, reply: tx }).await;
+        self.lead_seq = rx.await.expect("Raft event loop closed");
+        self.lead_index = self.log.last_index();
+        self.next_seq = self.lead_seq + 1;
+        self.applied = self.lead_index;
+        self.advance_commit().await;
+        self.replicate().await;
+    }
+
+    // 把事件循环写入的记录追加到日志
+    async fn propose(&mut self) {
+        loop {
+            let Some(records) = self.proposals.records(self.next_seq) else {
+                error!("Raft proposals from {} dropped before appended to log", self.next_seq);
+                let term = self.term;
+                self.become_follower(term, 0).await;
+                return;
+            };
+            let Some((last_seq, _)) = records.last() else {
+                break;
+            };
+            self.next_seq = last_seq + 1;
+            let entries = records.into_iter().map(|(_, record)| Entry { term: self.term, record }).collect();
+            self.log.append(entries).await;
+            self.applied = self.log.last_index();
+        }
+        self.advance_commit().await;
+    }
+
+    // 多数节点都有的最后一条日志是本任期的时提交
+    async fn advance_commit(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let mut matched: Vec<u64> = self.progress.values().map(|progress| progress.matched).chain(once(self.log.last_index())).collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.log.term(index) == Some(self.term) {
+            self.commit = index;
+            self.command(RaftCommand::Commit(self.lead_seq + (index - self.lead_index))).await;
+        }
+    }
+
+    // 从节点把新提交的日志发给事件循环回放
+    async fn apply(&mut self) {
+        while self.applied < self.commit {
+            let Some(entry) = self.log.get(self.applied + 1) else {
+                break;
+            };
+            match Replicated::parse(&entry.record) {
+                Some(record) => self.command(RaftCommand::Apply(record)).await,
+                None => error!("Invalid raft log record at {}", self.applied + 1),
+            }
+            self.applied += 1;
+        }
+    }
+
+    async fn replicate(&mut self) {
+        let peers: Vec<u64> = self.peers.keys().copied().collect();
+        for peer in peers {
+            self.send_to(peer).await;
+        }
+    }
+
+    // 发送 peer 缺少的日志，没有缺少时作为心跳；需要的日志已被丢弃时分段发送快照
+    async fn send_to(&mut self, peer: u64) {
+        let Some(progress) = self.progress.get(&peer).filter(|progress| !progress.inflight) else {
+            return;
+        };
+        if progress.outgoing.is_none() && (progress.install || progress.next <= self.log.start) {
+            let Some(outgoing) = self.snapshot().await else {
+                return;
+            };
+            info!("Send snapshot at {} with {} keys to raft peer {}", outgoing.index, outgoing.records.len(), peer);
+            let Some(progress) = self.progress.get_mut(&peer) else {
+                return;
+            };
+            progress.outgoing = Some(outgoing);
+        }
+        let Some(progress) = self.progress.get_mut(&peer) else {
+            return;
+        };
+        let message = if let Some(outgoing) = progress.outgoing.as_mut() {
+            outgoing.message(self.term, self.id)
+        } else {
+            let prev_index = progress.next - 1;
+            let mut bytes = 0;
+            let entries = (progress.next..=self.log.last_index()).take(MAX_APPEND_ENTRIES)
+                .filter_map(|index| self.log.get(index))
+                .take_while(|entry| {
+                    let first = bytes == 0;
+                    bytes += entry.record.len();
+                    first || bytes <= MAX_APPEND_BYTES
+                })
+                .cloned()
+                .collect();
+            Message::Append { term: self.term, leader: self.id, prev_index, prev_term: self.log.term(prev_index).unwrap_or(0), commit: self.commit, entries }
+        };
+        let sent = self.peers.get(&peer).is_some_and(|sender| sender.try_send(message).is_ok());
+        if let Some(progress) = self.progress.get_mut(&peer) {
+            progress.inflight = sent;
+        }
+    }
+
+    async fn snapshot(&mut self) -> Option<Outgoing> {
+        let (tx, rx) = oneshot::channel();
+        self.command(RaftCommand::Snapshot(tx)).await;
+        let snapshot = rx.await.ok()?;
+        // 快照包含的记录都追加到日志后才能确定它对应的位置
+        self.propose().await;
+        if self.role != Role::Leader {
+            return None;
+        }
+        let index = self.lead_index + (snapshot.seq - self.lead_seq);
+        let index_term = self.log.term(index).unwrap_or(self.term);
+        Some(Outgoing { index, index_term, version: snapshot.version, records: snapshot.records, chunk: 0, position: 0, end: 0 })
+    }
+
+    fn reject(&self) -> Message {
+        Message::AppendReply { term: self.term, success: false, match_index: 0, snapshot: false }
+    }
+
+    async fn receive(&mut self, message: Message) -> Message {
+        match message {
+            Message::Vote { term, candidate, last_index, last_term } => {
+                if term > self.term {
+                    self.become_follower(term, 0).await;
+                }
+                let up_to_date = (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+                let granted = term == self.term && (self.voted_for == 0 || self.voted_for == candidate) && up_to_date;
+                if granted {
+                    self.voted_for = candidate;
+                    self.persist();
+                    self.reset_deadline();
+                }
+                Message::VoteReply { term: self.term, granted }
+            }
+            Message::Append { term, leader, prev_index, prev_term, commit, entries } => {
+                if term < self.term {
+                    return self.reject();
+                }
+                self.follow(term, leader).await;
+                if self.dirty {
+                    return Message::AppendReply { term: self.term, success: false, match_index: 0, snapshot: true };
+                }
+                if prev_index > self.log.last_index() {
+                    return Message::AppendReply { term: self.term, success: false, match_index: self.log.last_index(), snapshot: false };
+                }
+                // 快照已包含的日志都已提交，一定相同，跳过
+                let skip = self.log.start.saturating_sub(prev_index) as usize;
+                let mut index = prev_index.max(self.log.start);
+                if skip == 0 && self.log.term(prev_index) != Some(prev_term) {
+                    let hint = prev_index.saturating_sub(1).max(self.log.start);
+                    return Message::AppendReply { term: self.term, success: false, match_index: hint, snapshot: false };
+                }
+                let mut new = Vec::new();
+                for entry in entries.into_iter().skip(skip) {
+                    index += 1;
+                    if new.is_empty() {
+                        match self.log.term(index) {
+                            Some(term) if term == entry.term => continue,
+                            Some(_) => {
+                                if index <= self.commit {
+                                    error!("Raft log conflict at committed index {}", index);
+                                }
+                                self.log.truncate(index).await;
+                            }
+                            None => {}
+                        }
+                    }
+                    new.push(entry);
+                }
+                self.log.append(new).await;
+                if commit > self.commit {
+                    self.commit = commit.min(index);
+                    self.apply().await;
+                }
+                Message::AppendReply { term: self.term, success: true, match_index: index, snapshot: false }
+            }
+            Message::Install { term, leader, index, index_term, version, chunk, last, records } => {
+                if term < self.term {
+                    return self.reject();
+                }
+                self.follow(term, leader).await;
+                // 第 0 段重新开始；其他段接着同一个快照，重发的上一段再写入一次结果相同；对不上时让主节点重新发送
+                let first = chunk == 0;
+                let next = self.installing.filter(|(at_term, at, _)| (*at_term, *at) == (term, index)).map(|(_, _, next)| next);
+                if !first && !next.is_some_and(|next| chunk == next || chunk + 1 == next) {
+                    return Message::AppendReply { term: self.term, success: false, match_index: 0, snapshot: true };
+                }
+                if first {
+                    info!("Install raft snapshot at {} from {}", index, leader);
+                    // 安装完成前本地数据不完整，崩溃重启后也要重新安装
+                    self.dirty = true;
+                    self.persist();
+                }
+                let (tx, rx) = oneshot::channel();
+                self.command(RaftCommand::Install { first, records, version: last.then_some(version), reply: tx }).await;
+                if rx.await.is_err() {
+                    return self.reject();
+                }
+                if !last {
+                    self.installing = Some((term, index, chunk + 1));
+                    return Message::AppendReply { term: self.term, success: true, match_index: 0, snapshot: false };
+                }
+                info!("Raft snapshot at {} installed in {} chunks", index, chunk + 1);
+                self.installing = None;
+                self.dirty = false;
+                self.commit = self.commit.max(index);
+                self.applied = index;
+                self.persist_snapshot(index, index_term);
+                self.log.compact(index, index_term).await;
+                Message::AppendReply { term: self.term, success: true, match_index: index, snapshot: false }
+            }
+            Message::VoteReply { .. } | Message::AppendReply { .. } => self.reject(),
+        }
+    }
+
+    // 收到 term 任期主节点的消息
+    async fn follow(&mut self, term: u64, leader: u64) {
+        if term > self.term || self.role != Role::Follower {
+            self.become_follower(term, leader).await;
+        }
+        if self.leader != leader {
+            info!("Raft node {} follow leader {} in term {}", self.id, leader, term);
+            self.leader = leader;
+        }
+        self.reset_deadline();
+    }
+
+    async fn on_response(&mut self, peer: u64, res: Option<Message>) {
+        let Some(message) = res else {
+            if let Some(progress) = self.progress.get_mut(&peer) {
+                progress.inflight = false;
+            }
+            return;
+        };
+        match message {
+            Message::VoteReply { term, granted } => {
+                if term > self.term {
+                    self.become_follower(term, 0).await;
+                } else if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes += 1;
+                    if self.votes >= self.quorum() {
+                        self.lead().await;
+                    }
+                }
+            }
+            Message::AppendReply { term, success, match_index, snapshot } => {
+                if term > self.term {
+                    self.become_follower(term, 0).await;
+                    return;
+                }
+                let last_index = self.log.last_index();
+                let Some(progress) = self.progress.get_mut(&peer).filter(|_| self.role == Role::Leader && term == self.term) else {
+                    return;
+                };
+                progress.inflight = false;
+                if let Some(outgoing) = progress.outgoing.as_mut() {
+                    // 快照的一段写入后发送下一段；最后一段写入后和日志一样更新进度，失败时重新生成快照
+                    if success && outgoing.ack() {
+                        self.send_to(peer).await;
+                        return;
+                    }
+                    progress.outgoing = None;
+                    progress.install = !success;
+                }
+                if success {
+                    progress.matched = progress.matched.max(match_index);
+                    progress.next = progress.matched + 1;
+                    progress.install = false;
+                } else if snapshot {
+                    progress.install = true;
+                } else {
+                    progress.next = match_index + 1;
+                }
+                let behind = progress.install || progress.next <= last_index;
+                self.advance_commit().await;
+                if behind {
+                    self.send_to(peer).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 日志太大时丢弃已提交、已回放并 sync 过的部分
+    async fn maybe_compact(&mut self) {
+        if self.log.bytes < RAFT_LOG_COMPACT_BYTES {
+            return;
+        }
+        let index = self.commit.min(self.applied);
+        let Some(term) = self.log.term(index).filter(|_| index > self.log.start) else {
+            return;
+        };
+        let (tx, rx) = oneshot::channel();
+        self.command(RaftCommand::Sync(tx)).await;
+        if rx.await.is_err() {
+            return;
+        }
+        info!("Compact raft log to {}", index);
+        self.persist_snapshot(index, term);
+        self.log.compact(index, term).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    // 代替事件循环回复命令，记录收到的命令
+    #[derive(Debug, PartialEq)]
+    enum Seen {
+        Apply,
+        Commit(u64),
+        Lead,
+        StepDown,
+        Snapshot,
+        // first, 记录数, version
+        Install(bool, usize, Option<u64>),
+    }
+
+    fn fake_loop(mut commands: mpsc::Receiver<RaftCommand>, snapshot: Vec<Vec<u8>>) -> mpsc::UnboundedReceiver<Seen> {
+        let (seen, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            // 先记录再回复，节点收到回复时测试已能看到
+            while let Some(command) = commands.recv().await {
+                let record = |command| {
+                    let _ = seen.send(command);
+                };
+                match command {
+                    RaftCommand::Apply(_) => record(Seen::Apply),
+                    RaftCommand::Commit(seq) => record(Seen::Commit(seq)),
+                    RaftCommand::Lead { reply, .. } => {
+                        record(Seen::Lead);
+                        let _ = reply.send(0);
+                    }
+                    RaftCommand::StepDown(reply) => {
+                        record(Seen::StepDown);
+                        let _ = reply.send(0);
+                    }
+                    RaftCommand::Snapshot(reply) => {
+                        record(Seen::Snapshot);
+                        let _ = reply.send(RaftSnapshot { seq: 0, version: 9, records: snapshot.clone() });
+                    }
+                    RaftCommand::Install { first, records, version, reply } => {
+                        record(Seen::Install(first, records.len(), version));
+                        let _ = reply.send(());
+                    }
+                    RaftCommand::Sync(reply) => {
+                        let _ = reply.send(());
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    // 消息都在内存中，发给其他节点的消息留在 outbox 里由测试取出
+    struct TestNode {
+        path: String,
+        node: RaftNode,
+        outbox: HashMap<u64, mpsc::Receiver<Message>>,
+        // 和节点共用的命令通道，用来等待之前的命令处理完
+        commands: mpsc::Sender<RaftCommand>,
+        seen: mpsc::UnboundedReceiver<Seen>,
+    }
+
+    impl TestNode {
+        async fn open(path: String, id: u64, peers: &[u64], snapshot: Vec<Vec<u8>>) -> Self {
+            let (senders, outbox) = peers.iter().map(|peer| {
+                let (tx, rx) = mpsc::channel(RAFT_QUEUE_SIZE);
+                ((*peer, tx), (*peer, rx))
+            }).unzip();
+            let (commands, receiver) = mpsc::channel(RAFT_QUEUE_SIZE);
+            let state = read_state(&path).unwrap();
+            let node = RaftNode::open(id, senders, &path, state, commands.clone(), Arc::new(ReplicationLog::new(1 << 20))).await;
+            Self { path, node, outbox, commands, seen: fake_loop(receiver, snapshot) }
+        }
+
+        async fn new(name: &str, id: u64, peers: &[u64]) -> Self {
+            Self::open(temp_path(name), id, peers, Vec::new()).await
+        }
+
+        // 最早一条还没取出的发给 peer 的消息
+        fn sent(&mut self, peer: u64) -> Message {
+            self.outbox.get_mut(&peer).unwrap().try_recv().expect("No message to peer")
+        }
+
+        // 节点发出的、不等回复的命令也处理完后取出
+        async fn seen(&mut self) -> Vec<Seen> {
+            let (tx, rx) = oneshot::channel();
+            self.commands.send(RaftCommand::Sync(tx)).await.unwrap();
+            rx.await.unwrap();
+            std::iter::from_fn(|| self.seen.try_recv().ok()).collect()
+        }
+
+        fn terms(&self) -> Vec<u64> {
+            self.node.log.entries.iter().map(|entry| entry.term).collect()
+        }
+    }
+
+    fn entries(terms: &[u64]) -> Vec<Entry> {
+        terms.iter().map(|term| Entry { term: *term, record: Replicated::Version { namespace: String::new(), version: *term }.encode() }).collect()
+    }
+
+    // 节点 1 作为主节点发送的日志
+    fn append(term: u64, prev_index: u64, prev_term: u64, commit: u64, terms: &[u64]) -> Message {
+        Message::Append { term, leader: 1, prev_index, prev_term, commit, entries: entries(terms) }
+    }
+
+    fn append_reply(message: Message) -> (bool, u64, bool) {
+        match message {
+            Message::AppendReply { success, match_index, snapshot, .. } => (success, match_index, snapshot),
+            _ => panic!("Expect append reply"),
+        }
+    }
+
+    fn granted(message: Message) -> bool {
+        matches!(message, Message::VoteReply { granted: true, .. })
+    }
+
+    #[tokio::test]
+    async fn election_and_step_down() {
+        let mut t = TestNode::new("raft-election", 1, &[2, 3]).await;
+        t.node.campaign().await;
+        assert_eq!((t.node.role, t.node.term, t.node.voted_for), (Role::Candidate, 1, 1));
+        for peer in [2, 3] {
+            assert!(matches!(t.sent(peer), Message::Vote { term: 1, candidate: 1, last_index: 0, last_term: 0 }));
+        }
+
+        // 旧任期的投票不计入，加上自己的一票达到多数后成为主节点，先追加一条本任期的日志
+        t.node.on_response(2, Some(Message::VoteReply { term: 0, granted: true })).await;
+        assert_eq!(t.node.role, Role::Candidate);
+        t.node.on_response(2, Some(Message::VoteReply { term: 1, granted: true })).await;
+        assert_eq!((t.node.role, t.node.leader), (Role::Leader, 1));
+        assert_eq!(t.seen().await, [Seen::Lead]);
+        assert_eq!(t.terms(), [1]);
+        assert!(matches!(t.sent(3), Message::Append { term: 1, prev_index: 0, ref entries, .. } if entries.len() == 1));
+
+        // 更高任期的投票请求让主节点退位，候选人的日志落后时不投票
+        assert!(!granted(t.node.receive(Message::Vote { term: 2, candidate: 3, last_index: 0, last_term: 0 }).await));
+        assert_eq!((t.node.role, t.node.term, t.node.voted_for), (Role::Follower, 2, 0));
+        assert_eq!(t.seen().await, [Seen::StepDown]);
+        // 没有提交的日志可能和新的主节点不同
+        assert!(t.node.dirty);
+
+        // 同一任期只投给一个候选人
+        assert!(granted(t.node.receive(Message::Vote { term: 2, candidate: 2, last_index: 1, last_term: 1 }).await));
+        assert!(!granted(t.node.receive(Message::Vote { term: 2, candidate: 3, last_index: 5, last_term: 2 }).await));
+        assert_eq!(t.node.voted_for, 2);
+
+        // 更高任期的回复也让候选人退回从节点
+        t.node.campaign().await;
+        t.node.on_response(3, Some(Message::VoteReply { term: 4, granted: false })).await;
+        assert_eq!((t.node.role, t.node.term), (Role::Follower, 4));
+    }
+
+    #[tokio::test]
+    async fn log_conflict_truncated() {
+        let mut t = TestNode::new("raft-conflict", 2, &[1, 3]).await;
+        assert_eq!(append_reply(t.node.receive(append(1, 0, 0, 0, &[1, 1, 1])).await), (true, 3, false));
+        assert_eq!((t.node.term, t.node.leader), (1, 1));
+
+        // 旧任期的主节点被拒绝
+        assert_eq!(append_reply(t.node.receive(append(0, 3, 1, 0, &[0])).await), (false, 0, false));
+        // 前一条的任期不同时拒绝，让主节点从更早的位置重试
+        assert_eq!(append_reply(t.node.receive(append(2, 3, 2, 0, &[2])).await), (false, 2, false));
+        // 缺少前一条时返回本地最后一条的位置
+        assert_eq!(append_reply(t.node.receive(append(2, 7, 2, 0, &[2])).await), (false, 3, false));
+
+        // 新的主节点的日志从第 2 条开始不同，删除本地第 2 条及之后的日志
+        assert_eq!(append_reply(t.node.receive(append(2, 1, 1, 0, &[2])).await), (true, 2, false));
+        assert_eq!(t.terms(), [1, 2]);
+        // 重复收到已有的日志不改变什么
+        assert_eq!(append_reply(t.node.receive(append(2, 0, 0, 0, &[1, 2])).await), (true, 2, false));
+        assert_eq!(t.terms(), [1, 2]);
+
+        // 提交后按顺序回放，不超过收到的日志
+        assert_eq!(append_reply(t.node.receive(append(2, 2, 2, 9, &[])).await), (true, 2, false));
+        assert_eq!((t.node.commit, t.node.applied), (2, 2));
+        assert_eq!(t.seen().await, [Seen::Apply, Seen::Apply]);
+
+        // 截断和追加都写入了日志文件
+        let log = RaftLog::open(format!("{}/{}", &t.path, RAFT_LOG_FILE), 0, 0).await;
+        assert_eq!(log.entries.iter().map(|entry| entry.term).collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn commit_only_current_term() {
+        let mut t = TestNode::new("raft-commit", 1, &[2, 3]).await;
+        // 作为从节点收到任期 1 的两条日志，没有提交
+        assert_eq!(append_reply(t.node.receive(Message::Append { term: 1, leader: 2, prev_index: 0, prev_term: 0, commit: 0, entries: entries(&[1, 1]) }).await), (true, 2, false));
+        t.node.campaign().await;
+        t.node.on_response(2, Some(Message::VoteReply { term: 2, granted: true })).await;
+        assert_eq!(t.node.role, Role::Leader);
+        assert_eq!(t.terms(), [1, 1, 2]);
+        assert_eq!(t.seen().await, [Seen::Lead]);
+
+        // 多数节点都有的是之前任期的日志，不提交
+        t.node.on_response(2, Some(Message::AppendReply { term: 2, success: true, match_index: 2, snapshot: false })).await;
+        assert_eq!(t.node.commit, 0);
+        assert_eq!(t.seen().await, []);
+
+        // 本任期的日志到达多数节点后提交，之前的日志一起提交
+        t.node.on_response(2, Some(Message::AppendReply { term: 2, success: true, match_index: 3, snapshot: false })).await;
+        assert_eq!(t.node.commit, 3);
+        assert_eq!(t.seen().await, [Seen::Commit(0)]);
+
+        // 旧任期的回复不改变进度
+        t.node.on_response(3, Some(Message::AppendReply { term: 1, success: true, match_index: 3, snapshot: false })).await;
+        assert_eq!(t.node.progress[&3].matched, 0);
+    }
+
+    fn install(chunk: u32, last: bool, records: usize) -> Message {
+        Message::Install { term: 1, leader: 1, index: 5, index_term: 1, version: 9, chunk, last, records: vec![b"r".to_vec(); records] }
+    }
+
+    #[tokio::test]
+    async fn snapshot_installed_in_chunks() {
+        let mut t = TestNode::new("raft-install", 2, &[1]).await;
+        assert_eq!(append_reply(t.node.receive(append(1, 0, 0, 0, &[1])).await), (true, 1, false));
+
+        // 不是第 0 段、也不接着正在安装的快照时让主节点重新发送
+        assert_eq!(append_reply(t.node.receive(install(1, false, 1)).await), (false, 0, true));
+        assert_eq!(t.seen().await, []);
+
+        // 第 0 段清空本地数据，安装完成前节点是 dirty，崩溃后需要重新安装
+        assert_eq!(append_reply(t.node.receive(install(0, false, 2)).await), (true, 0, false));
+        assert!(t.node.dirty && read_state(&t.path).unwrap().dirty);
+        // 日志要求安装快照
+        assert_eq!(append_reply(t.node.receive(append(1, 1, 1, 0, &[])).await), (false, 0, true));
+        // 回复丢失时主节点重发同一段
+        assert_eq!(append_reply(t.node.receive(install(1, false, 1)).await), (true, 0, false));
+        assert_eq!(append_reply(t.node.receive(install(1, false, 1)).await), (true, 0, false));
+        assert_eq!(append_reply(t.node.receive(install(2, true, 1)).await), (true, 5, false));
+        assert_eq!(t.seen().await, [Seen::Install(true, 2, None), Seen::Install(false, 1, None), Seen::Install(false, 1, None), Seen::Install(false, 1, Some(9))]);
+
+        // 快照之前的日志被丢弃，之后的日志接着追加
+        assert!(!t.node.dirty);
+        assert_eq!((t.node.log.start, t.node.log.last_index(), t.node.commit, t.node.applied), (5, 5, 5, 5));
+        assert_eq!(append_reply(t.node.receive(append(1, 5, 1, 0, &[1])).await), (true, 6, false));
+        let state = read_state(&t.path).unwrap();
+        assert_eq!((state.snapshot_index, state.snapshot_term, state.dirty), (5, 1, false));
+    }
+
+    #[tokio::test]
+    async fn snapshot_sent_in_chunks() {
+        // 前两条记录一段，最后一条一段
+        let record = vec![0; INSTALL_CHUNK_BYTES / 2 + 1];
+        let mut t = TestNode::open(temp_path("raft-send-snapshot"), 1, &[2], vec![record; 3]).await;
+        t.node.campaign().await;
+        assert!(matches!(t.sent(2), Message::Vote { .. }));
+        t.node.on_response(2, Some(Message::VoteReply { term: 1, granted: true })).await;
+        assert!(matches!(t.sent(2), Message::Append { .. }));
+        assert_eq!(t.seen().await, [Seen::Lead]);
+
+        let chunk = |message: Message| match message {
+            Message::Install { index, chunk, last, records, .. } => (index, chunk, last, records.len()),
+            _ => panic!("Expect install"),
+        };
+        let success = |match_index| Some(Message::AppendReply { term: 1, success: true, match_index, snapshot: false });
+        t.node.on_response(2, Some(Message::AppendReply { term: 1, success: false, match_index: 0, snapshot: true })).await;
+        assert_eq!(chunk(t.sent(2)), (1, 0, false, 2));
+        // 安装失败时重新生成快照，从第 0 段开始
+        t.node.on_response(2, Some(Message::AppendReply { term: 1, success: false, match_index: 0, snapshot: true })).await;
+        assert_eq!(chunk(t.sent(2)), (1, 0, false, 2));
+        assert_eq!(t.seen().await, [Seen::Snapshot, Seen::Snapshot]);
+
+        t.node.on_response(2, success(0)).await;
+        assert_eq!(chunk(t.sent(2)), (1, 1, true, 1));
+        // 没有回复时重发同一段
+        t.node.on_response(2, None).await;
+        t.node.send_to(2).await;
+        assert_eq!(chunk(t.sent(2)), (1, 1, true, 1));
+        // 最后一段写入后和日志一样更新进度，本任期的日志随之提交
+        t.node.on_response(2, success(1)).await;
+        let progress = &t.node.progress[&2];
+        assert!(progress.outgoing.is_none() && !progress.install);
+        assert_eq!((progress.matched, t.node.commit), (1, 1));
+        assert_eq!(t.seen().await, [Seen::Commit(0)]);
+    }
+
+    #[tokio::test]
+    async fn restart_keeps_state() {
+        let path = temp_path("raft-restart");
+        let mut t = TestNode::open(path.clone(), 2, &[1, 3], Vec::new()).await;
+        assert!(granted(t.node.receive(Message::Vote { term: 3, candidate: 1, last_index: 0, last_term: 0 }).await));
+        assert_eq!(append_reply(t.node.receive(append(3, 0, 0, 1, &[3, 3])).await), (true, 2, false));
+        drop(t);
+
+        // 重新打开后任期、投票和日志不变，同一任期不再投给其他节点
+        let mut t = TestNode::open(path, 2, &[1, 3], Vec::new()).await;
+        assert_eq!((t.node.term, t.node.voted_for, t.node.role), (3, 1, Role::Follower));
+        assert_eq!(t.terms(), [3, 3]);
+        assert!(!granted(t.node.receive(Message::Vote { term: 3, candidate: 3, last_index: 2, last_term: 3 }).await));
+    }
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn oversized_message_rejected() {
+        let (mut client, mut server) = pair().await;
+        write_message(&mut client, &Message::VoteReply { term: 3, granted: true }).await.unwrap();
+        assert!(matches!(read_message(&mut server).await.unwrap(), Message::VoteReply { term: 3, granted: true }));
+
+        // 只发送长度，读取方不等消息体，直接返回错误
+        client.write_all(&(MAX_RAFT_MESSAGE as u32 + 1).to_be_bytes()).await.unwrap();
+        let err = read_message(&mut server).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hard_state_round_trip() {
+        let path = format!("{}/{}", temp_path("raft-state"), RAFT_STATE_FILE);
+        let state = HardState::read(&path).unwrap();
+        assert_eq!((state.term, state.voted_for, state.snapshot_index, state.snapshot_term, state.dirty), (0, 0, 0, 0, false));
+
+        HardState { term: 7, voted_for: 2, snapshot_index: 40, snapshot_term: 6, dirty: true }.write(&path);
+        let state = HardState::read(&path).unwrap();
+        assert_eq!((state.term, state.voted_for, state.snapshot_index, state.snapshot_term, state.dirty), (7, 2, 40, 6, true));
+
+        // 字段不全或不是数字时返回错误，不当作初始状态
+        for content in ["7 2 40", "7 2 40 6 x", ""] {
+            std::fs::write(&path, content).unwrap();
+            assert_eq!(HardState::read(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
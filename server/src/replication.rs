@@ -0,0 +1,782 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::utils::get_id;
+
+// 主从复制：主节点每个分片把写入 WAL 的每一帧按顺序编号，放在内存中的复制日志里
+// 从节点每个分片一个连接，从上次确认的序号之后开始接收，回放到自己的存储，并定时确认回放到的序号
+// 两边的分片数必须相同；只复制写入，从节点不应再接受客户端的写入，否则版本号会和主节点冲突
+// 没有复制位置的从节点（数据目录为空）先从主节点接收分片所有命名空间的快照，再从快照对应的序号之后接收复制日志
+// 快照是逻辑记录而不是 SST 文件：每个未过期的 key 一条 REPL_LOAD，带原来的版本号和过期时间，和 Raft 安装快照共用回放路径
+// 不发送 SST 文件和 WAL 尾部，因为从节点的存储引擎在复制开始前已经打开，两边的层级目录和压缩等选项也可以不同；
+// 快照之后的写入由复制日志补齐，相当于 WAL 尾部。代价是主节点生成快照时要把所有记录读进内存，从节点回放后重新落盘
+
+// 复制连接的握手
+// 从节点发送：
+// 8 bit REPL_MAGIC
+// 2 bit shard
+// 2 bit shard count
+// 8 bit run id, 8 bit next seq: 上次确认的位置，没有时都为 0
+// 主节点回复：
+// 1 bit status
+// 8 bit run id
+// 8 bit next seq: 从这个序号开始发送
+// status 为 REPL_SNAPSHOT 时先发送快照，见 send_snapshot
+const REPL_MAGIC: [u8; 8] = [0xff, b'L', b'S', b'M', b'R', b'E', b'P', 1];
+const HANDSHAKE_LEN: usize = 8 + 2 + 2 + 8 + 8;
+const REPL_OK: u8 = 0;
+// 确认的位置已不在主节点的复制日志中，或者主节点重启过，需要全量同步
+const REPL_RESYNC: u8 = 1;
+// 分片数不一致或分片编号超出
+const REPL_MISMATCH: u8 = 2;
+// 从节点没有复制位置，先发送快照
+const REPL_SNAPSHOT: u8 = 3;
+
+// 主节点发送的记录帧
+// 4 bit len, 之后的字节数，为 0 时是心跳
+// 8 bit seq
+// 1 bit kind
+// 2 bit namespace len
+// n bit namespace
+// REPL_WAL: n bit payload，格式同 WAL 的一帧
+// REPL_CLEAR: 8 bit version, 清空前已分配的最大版本号
+// REPL_INGEST: 8 bit version, 4 bit count, n * (4 bit key len, key, 4 bit value len, value)
+// REPL_VERSION: 8 bit version, 之后分配的版本号不小于它，用于 Raft 模式和快照
+// REPL_LOAD: n bit payload，格式同 WAL 的一帧，用于 Raft 模式安装快照和发送快照，版本号不递增
+// 从节点发送的确认：8 bit seq, 已回放的最大序号
+const REPL_WAL: u8 = 1;
+const REPL_CLEAR: u8 = 2;
+const REPL_INGEST: u8 = 3;
+const REPL_VERSION: u8 = 4;
+const REPL_LOAD: u8 = 5;
+
+// 默认每个分片的复制日志最多保留 64M 的记录，从节点落后更多时需要全量同步
+pub const DEFAULT_REPLICATION_BACKLOG_BYTES: usize = 1024 * 1024 * 64;
+// 主节点空闲时发送心跳的间隔，从节点超过 REPL_TIMEOUT 没有收到任何帧时重连
+const REPL_HEARTBEAT: Duration = Duration::from_secs(1);
+const REPL_TIMEOUT: Duration = Duration::from_secs(10);
+// 没有复制位置的从节点等待握手回复的时间，主节点生成快照后才回复
+const REPL_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(300);
+// 连接失败或断开后重连的间隔
+const REPL_RETRY: Duration = Duration::from_secs(3);
+// 每次从复制日志中取出的最大帧数
+const REPL_BATCH: usize = 256;
+// 发送快照时攒够这么多字节写一次
+const REPL_SNAPSHOT_CHUNK: usize = 1024 * 1024;
+// 从节点收到、还没有回放的记录数
+const REPL_QUEUE_SIZE: usize = 1024;
+// 从节点记录复制位置的文件，在分片的数据目录下，内容为 "run_id next_seq"
+const REPLICA_FILE: &str = "REPLICA";
+
+// 复制的一条记录，namespace 为空是默认命名空间
+pub enum Replicated {
+    Wal {
+        namespace: String,
+        payload: Vec<u8>,
+    },
+    Clear {
+        namespace: String,
+        version: u64,
+    },
+    Ingest {
+        namespace: String,
+        version: u64,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    Version {
+        namespace: String,
+        version: u64,
+    },
+    Load {
+        namespace: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl Replicated {
+    pub fn namespace(&self) -> &str {
+        match self {
+            Replicated::Wal { namespace, .. } | Replicated::Clear { namespace, .. } |
+            Replicated::Ingest { namespace, .. } | Replicated::Version { namespace, .. } |
+            Replicated::Load { namespace, .. } => namespace,
+        }
+    }
+
+    // 记录帧中 seq 之后的部分：kind, namespace 和记录内容
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let kind = match self {
+            Replicated::Wal { payload, .. } => {
+                body.extend_from_slice(payload);
+                REPL_WAL
+            }
+            Replicated::Clear { version, .. } => {
+                body.extend(version.to_be_bytes());
+                REPL_CLEAR
+            }
+            Replicated::Ingest { version, entries, .. } => {
+                body = ingest_body(*version, entries);
+                REPL_INGEST
+            }
+            Replicated::Version { version, .. } => {
+                body.extend(version.to_be_bytes());
+                REPL_VERSION
+            }
+            Replicated::Load { payload, .. } => {
+                body.extend_from_slice(payload);
+                REPL_LOAD
+            }
+        };
+        record_bytes(kind, self.namespace(), &body)
+    }
+
+    // encode 的结果，数据不完整时返回 None
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(buf);
+        let kind = reader.u8()?;
+        let namespace_len = u16::from_be_bytes(reader.bytes(2)?.try_into().ok()?) as usize;
+        let namespace = String::from_utf8(reader.bytes(namespace_len)?.to_vec()).ok()?;
+        let record = match kind {
+            REPL_WAL => Replicated::Wal { namespace, payload: reader.rest().to_vec() },
+            REPL_CLEAR => Replicated::Clear { namespace, version: reader.u64()? },
+            REPL_INGEST => {
+                let version = reader.u64()?;
+                let count = reader.u32()? as usize;
+                let mut entries = Vec::with_capacity(count.min(REPL_BATCH));
+                for _ in 0..count {
+                    let key = reader.field()?.to_vec();
+                    let value = reader.field()?.to_vec();
+                    entries.push((key, value));
+                }
+                Replicated::Ingest { namespace, version, entries }
+            }
+            REPL_VERSION => Replicated::Version { namespace, version: reader.u64()? },
+            REPL_LOAD => Replicated::Load { namespace, payload: reader.rest().to_vec() },
+            _ => return None,
+        };
+        Some(record)
+    }
+
+    // 记录帧中 len 之后的部分
+    fn decode(buf: &[u8]) -> Option<(u64, Self)> {
+        let seq = u64::from_be_bytes(buf.get(..8)?.try_into().ok()?);
+        Some((seq, Self::parse(&buf[8..])?))
+    }
+}
+
+// 4 bit len, 8 bit seq, record
+fn frame_bytes(seq: u64, record: &[u8]) -> Vec<u8> {
+    let len = 8 + record.len();
+    let mut frame = Vec::with_capacity(4 + len);
+    frame.extend((len as u32).to_be_bytes());
+    frame.extend(seq.to_be_bytes());
+    frame.extend_from_slice(record);
+    frame
+}
+
+// 1 bit kind, 2 bit namespace len, namespace, body
+fn record_bytes(kind: u8, namespace: &str, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + 2 + namespace.len() + body.len());
+    buf.push(kind);
+    buf.extend((namespace.len() as u16).to_be_bytes());
+    buf.extend_from_slice(namespace.as_bytes());
+    buf.extend_from_slice(body);
+    buf
+}
+
+fn ingest_body(version: u64, entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut body = version.to_be_bytes().to_vec();
+    body.extend((entries.len() as u32).to_be_bytes());
+    for (key, value) in entries {
+        put_field(&mut body, key);
+        put_field(&mut body, value);
+    }
+    body
+}
+
+// 按大端读取定长字段，数据不够时返回 None
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, index: 0 }
+    }
+
+    // 还没有读取的部分
+    pub fn rest(&self) -> &'a [u8] {
+        &self.buf[self.index..]
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.index..self.index.checked_add(len)?)?;
+        self.index += len;
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).and_then(|b| b.try_into().ok()).map(u32::from_be_bytes)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).and_then(|b| b.try_into().ok()).map(u64::from_be_bytes)
+    }
+
+    // 4 bit len, bytes
+    pub fn field(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+}
+
+// 4 bit len, bytes
+pub fn put_field(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend((bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// 一个分片的复制日志，写入在事件循环中，读取在各从节点的发送任务中
+pub struct ReplicationLog {
+    // 每次启动不同，从节点据此判断序号是否还有效
+    run_id: u64,
+    backlog: Mutex<Backlog>,
+    // 最新记录的序号，有新记录时唤醒发送任务
+    last_seq: watch::Sender<u64>,
+}
+
+struct Backlog {
+    // frames 中第一帧的序号，序号从 1 开始
+    first_seq: u64,
+    // 编码好的记录帧
+    frames: VecDeque<Arc<Vec<u8>>>,
+    bytes: usize,
+    limit: usize,
+}
+
+impl ReplicationLog {
+    pub fn new(limit: usize) -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).expect("System time before unix epoch").as_nanos() as u64;
+        Self {
+            run_id: nanos ^ ((std::process::id() as u64) << 32),
+            backlog: Mutex::new(Backlog { first_seq: 1, frames: VecDeque::new(), bytes: 0, limit }),
+            last_seq: watch::Sender::new(0),
+        }
+    }
+
+    // 分配序号并编码记录帧，超过上限时丢弃最旧的帧，至少保留最新的一帧
+    fn push(&self, kind: u8, namespace: &str, body: &[u8]) {
+        let record = record_bytes(kind, namespace, body);
+        let seq = {
+            let mut backlog = self.backlog.lock().expect("Lock replication backlog fail");
+            let seq = backlog.first_seq + backlog.frames.len() as u64;
+            let frame = frame_bytes(seq, &record);
+            backlog.bytes += frame.len();
+            backlog.frames.push_back(Arc::new(frame));
+            while backlog.bytes > backlog.limit && backlog.frames.len() > 1 {
+                if let Some(frame) = backlog.frames.pop_front() {
+                    backlog.bytes -= frame.len();
+                    backlog.first_seq += 1;
+                }
+            }
+            seq
+        };
+        self.last_seq.send_replace(seq);
+    }
+
+    // 从节点上次确认到 (run_id, next_seq)，返回开始发送的序号；无法接续时返回 None
+    // 没有确认过的从节点只有在复制日志没有丢弃过记录时才能接续，启动前已有的数据不会复制
+    fn resume(&self, run_id: u64, next_seq: u64) -> Option<u64> {
+        let backlog = self.backlog.lock().expect("Lock replication backlog fail");
+        let end = backlog.first_seq + backlog.frames.len() as u64;
+        match run_id {
+            0 if backlog.first_seq == 1 => Some(1),
+            id if id == self.run_id && backlog.first_seq <= next_seq && next_seq <= end => Some(next_seq),
+            _ => None,
+        }
+    }
+
+    // 最新记录的序号，还没有记录时为 0
+    pub fn last_seq(&self) -> u64 {
+        *self.last_seq.borrow()
+    }
+
+    // 有新记录时唤醒
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.last_seq.subscribe()
+    }
+
+    // 从 seq 开始最多 REPL_BATCH 条记录的序号和 Replicated::encode 的结果；seq 之前的记录已被丢弃时返回 None
+    pub fn records(&self, seq: u64) -> Option<Vec<(u64, Vec<u8>)>> {
+        let frames = self.read(seq)?;
+        Some(frames.iter().filter_map(|frame| {
+            let seq = u64::from_be_bytes(frame.get(4..12)?.try_into().ok()?);
+            Some((seq, frame[12..].to_vec()))
+        }).collect())
+    }
+
+    // 从 seq 开始最多 REPL_BATCH 帧；seq 之前的帧已被丢弃时返回 None
+    fn read(&self, seq: u64) -> Option<Vec<Arc<Vec<u8>>>> {
+        let backlog = self.backlog.lock().expect("Lock replication backlog fail");
+        let skip = seq.checked_sub(backlog.first_seq)? as usize;
+        Some(backlog.frames.iter().skip(skip).take(REPL_BATCH).cloned().collect())
+    }
+}
+
+// 存储引擎写入复制日志的一端，按命名空间区分
+#[derive(Clone)]
+pub struct ReplicationSink {
+    log: Arc<ReplicationLog>,
+    namespace: String,
+}
+
+impl ReplicationSink {
+    pub fn new(log: Arc<ReplicationLog>) -> Self {
+        Self { log, namespace: String::new() }
+    }
+
+    pub fn namespace(&self, namespace: &str) -> Self {
+        Self { log: self.log.clone(), namespace: namespace.to_string() }
+    }
+
+    // 写入 WAL 的一帧
+    pub fn wal(&self, payload: &[u8]) {
+        self.log.push(REPL_WAL, &self.namespace, payload);
+    }
+
+    pub fn clear(&self, version: u64) {
+        self.log.push(REPL_CLEAR, &self.namespace, &version.to_be_bytes());
+    }
+
+    pub fn ingest(&self, version: u64, entries: &[(Vec<u8>, Vec<u8>)]) {
+        self.log.push(REPL_INGEST, &self.namespace, &ingest_body(version, entries));
+    }
+}
+
+// 一个分片所有命名空间的快照，由主节点的事件循环生成
+pub struct ReplicaSnapshot {
+    // 生成时复制日志的最新序号，快照之后从下一条开始发送
+    pub seq: u64,
+    // (命名空间, 已分配的最大版本号, StorageEngine::export 的记录)
+    pub namespaces: Vec<(String, u64, Vec<Vec<u8>>)>,
+}
+
+pub type SnapshotRequest = oneshot::Sender<ReplicaSnapshot>;
+
+// 主节点事件循环生成快照的一端，log 为分片的复制日志，快照的序号从这里读取
+pub struct SnapshotFeed {
+    pub log: Arc<ReplicationLog>,
+    pub requests: mpsc::Receiver<SnapshotRequest>,
+}
+
+impl SnapshotFeed {
+    // 事件循环的一端和复制端口请求快照的一端
+    pub fn new(log: Arc<ReplicationLog>) -> (Self, mpsc::Sender<SnapshotRequest>) {
+        let (tx, requests) = mpsc::channel(REPL_QUEUE_SIZE);
+        (Self { log, requests }, tx)
+    }
+}
+
+// 主节点：接受从节点的连接，每个连接按握手中的分片发送复制日志；snapshots 为各分片请求快照的一端，为空时不发送快照
+pub async fn serve(listener: TcpListener, logs: Vec<Arc<ReplicationLog>>, snapshots: Vec<mpsc::Sender<SnapshotRequest>>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                let id = get_id(&addr.ip().to_string(), addr.port());
+                let span = info_span!("replica", follower = %id);
+                tokio::spawn(feed(id, socket, logs.clone(), snapshots.clone()).instrument(span));
+            }
+            Err(e) => {
+                error!("Fail to accept replication connection; err = {:?}", e);
+            }
+        }
+    }
+}
+
+// 事件循环生成的快照，没有请求快照的一端或事件循环已结束时返回 None
+async fn request_snapshot(snapshots: &[mpsc::Sender<SnapshotRequest>], shard: usize) -> Option<ReplicaSnapshot> {
+    let (tx, rx) = oneshot::channel();
+    snapshots.get(shard)?.send(tx).await.ok()?;
+    rx.await.ok()
+}
+
+async fn feed(id: String, mut socket: TcpStream, logs: Vec<Arc<ReplicationLog>>, snapshots: Vec<mpsc::Sender<SnapshotRequest>>) {
+    let mut handshake = [0; HANDSHAKE_LEN];
+    if let Err(e) = timeout(REPL_TIMEOUT, socket.read_exact(&mut handshake)).await.unwrap_or(Err(std::io::ErrorKind::TimedOut.into())) {
+        warn!("Read replication handshake from [{}] fail, err = {:?}", id, e);
+        return;
+    }
+    if handshake[..8] != REPL_MAGIC {
+        warn!("Invalid replication handshake from [{}]", id);
+        return;
+    }
+    let shard = u16::from_be_bytes([handshake[8], handshake[9]]) as usize;
+    let shards = u16::from_be_bytes([handshake[10], handshake[11]]) as usize;
+    let run_id = u64::from_be_bytes(handshake[12..20].try_into().unwrap_or_default());
+    let next_seq = u64::from_be_bytes(handshake[20..28].try_into().unwrap_or_default());
+    let mut snapshot = None;
+    let (status, start) = if shards != logs.len() || shard >= shards {
+        warn!("Follower [{}] has {} shards, but leader has {}", id, shards, logs.len());
+        (REPL_MISMATCH, 0)
+    } else if run_id == 0 && !snapshots.is_empty() {
+        info!("Follower [{}] shard {} has no replication position, send snapshot", id, shard);
+        snapshot = request_snapshot(&snapshots, shard).await;
+        match snapshot.as_ref() {
+            Some(snapshot) => (REPL_SNAPSHOT, snapshot.seq + 1),
+            None => (REPL_RESYNC, 0),
+        }
+    } else {
+        match logs[shard].resume(run_id, next_seq) {
+            Some(start) => (REPL_OK, start),
+            None => {
+                warn!("Follower [{}] shard {} can't resume from {:x}:{}, need full sync", id, shard, run_id, next_seq);
+                (REPL_RESYNC, 0)
+            }
+        }
+    };
+    let mut reply = vec![status];
+    reply.extend(logs.get(shard).map_or(0, |log| log.run_id).to_be_bytes());
+    reply.extend(start.to_be_bytes());
+    if socket.write_all(&reply).await.is_err() || (status != REPL_OK && status != REPL_SNAPSHOT) {
+        return;
+    }
+    let (reader, mut writer) = socket.into_split();
+    if let Some(snapshot) = snapshot {
+        if let Err(e) = send_snapshot(&mut writer, snapshot).await {
+            warn!("Send snapshot to follower [{}] fail, err = {:?}", id, e);
+            return;
+        }
+    }
+    info!("Follower [{}] shard {} resume from {}", id, shard, start);
+    let log = logs[shard].clone();
+    select! {
+        _ = read_acks(&id, reader) => {}
+        _ = send_frames(&id, writer, &log, start) => {}
+    }
+    info!("Follower [{}] shard {} disconnect", id, shard);
+}
+
+// 快照的记录帧：每个命名空间的 REPL_LOAD 记录之后是一条 REPL_VERSION
+// 最后一帧的序号为快照的序号，从节点回放后确认，之后从下一条接续；其他帧的序号为 0，从节点不确认
+// 快照的序号为 0 时从节点在收到第一条复制日志后才有位置，之前断开的话重新接收快照
+async fn send_snapshot(writer: &mut OwnedWriteHalf, snapshot: ReplicaSnapshot) -> std::io::Result<()> {
+    let count = snapshot.namespaces.len();
+    for (index, (namespace, version, records)) in snapshot.namespaces.into_iter().enumerate() {
+        info!("Send snapshot of namespace {:?}, {} keys", &namespace, records.len());
+        let mut buf = Vec::new();
+        for payload in records {
+            buf.extend(frame_bytes(0, &Replicated::Load { namespace: namespace.clone(), payload }.encode()));
+            if buf.len() >= REPL_SNAPSHOT_CHUNK {
+                writer.write_all(&buf).await?;
+                buf.clear();
+            }
+        }
+        let seq = if index + 1 == count { snapshot.seq } else { 0 };
+        buf.extend(frame_bytes(seq, &Replicated::Version { namespace, version }.encode()));
+        writer.write_all(&buf).await?;
+    }
+    Ok(())
+}
+
+// 确认只用于日志，从节点重连时自己给出位置
+async fn read_acks(id: &str, mut reader: OwnedReadHalf) {
+    let mut ack = [0; 8];
+    while reader.read_exact(&mut ack).await.is_ok() {
+        info!("Follower [{}] applied to {}", id, u64::from_be_bytes(ack));
+    }
+}
+
+async fn send_frames(id: &str, mut writer: OwnedWriteHalf, log: &ReplicationLog, mut seq: u64) {
+    let mut last_seq = log.last_seq.subscribe();
+    loop {
+        let Some(frames) = log.read(seq) else {
+            warn!("Follower [{}] falls behind the replication backlog at {}, need full sync", id, seq);
+            return;
+        };
+        if frames.is_empty() {
+            // 没有新记录时按间隔发送心跳
+            if let Ok(Err(_)) = timeout(REPL_HEARTBEAT, last_seq.changed()).await {
+                return;
+            }
+            if *last_seq.borrow_and_update() < seq && writer.write_all(&0u32.to_be_bytes()).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        seq += frames.len() as u64;
+        let buf: Vec<u8> = frames.iter().flat_map(|frame| frame.iter().copied()).collect();
+        if let Err(e) = writer.write_all(&buf).await {
+            warn!("Write to follower [{}] fail, err = {:?}", id, e);
+            return;
+        }
+    }
+}
+
+// 从节点事件循环回放复制记录的一端：收到的记录和回放到的序号
+pub struct ReplicaFeed {
+    pub receiver: mpsc::Receiver<(u64, Replicated)>,
+    pub applied: watch::Sender<u64>,
+}
+
+// 从节点：在后台连接主节点的复制端口，断开后重连；data_path 为分片的数据目录
+pub fn follow(leader: String, shard: usize, shards: usize, data_path: String) -> ReplicaFeed {
+    let (tx, receiver) = mpsc::channel(REPL_QUEUE_SIZE);
+    let (applied, applied_rx) = watch::channel(0);
+    let span = info_span!("follower", shard);
+    tokio::spawn(async move {
+        let mut applied_rx = applied_rx;
+        loop {
+            match replicate(&leader, shard, shards, &data_path, &tx, &mut applied_rx).await {
+                Ok(()) => warn!("Replication from {} shard {} closed", &leader, shard),
+                Err(e) => error!("Replication from {} shard {} fail, {}", &leader, shard, e),
+            }
+            sleep(REPL_RETRY).await;
+        }
+    }.instrument(span));
+    ReplicaFeed { receiver, applied }
+}
+
+// 上次确认的位置，没有时为 (0, 0)
+fn read_position(data_path: &str) -> (u64, u64) {
+    let path = format!("{}/{}", data_path, REPLICA_FILE);
+    std::fs::read_to_string(&path).ok()
+        .and_then(|s| {
+            let (run_id, seq) = s.trim().split_once(' ')?;
+            Some((u64::from_str_radix(run_id, 16).ok()?, seq.parse().ok()?))
+        })
+        .unwrap_or((0, 0))
+}
+
+// 先写临时文件再改名
+fn write_position(data_path: &str, run_id: u64, next_seq: u64) {
+    let path = format!("{}/{}", data_path, REPLICA_FILE);
+    let tmp = format!("{}.tmp", &path);
+    if let Err(e) = std::fs::write(&tmp, format!("{:x} {}", run_id, next_seq)).and_then(|_| std::fs::rename(&tmp, &path)) {
+        warn!("Write replication position {} fail, err = {:?}", &path, e);
+    }
+}
+
+async fn replicate(leader: &str, shard: usize, shards: usize, data_path: &str, tx: &mpsc::Sender<(u64, Replicated)>, applied: &mut watch::Receiver<u64>) -> Result<(), String> {
+    let (run_id, next_seq) = read_position(data_path);
+    let mut socket = TcpStream::connect(leader).await.map_err(|e| format!("connect err = {:?}", e))?;
+    let mut handshake = REPL_MAGIC.to_vec();
+    handshake.extend((shard as u16).to_be_bytes());
+    handshake.extend((shards as u16).to_be_bytes());
+    handshake.extend(run_id.to_be_bytes());
+    handshake.extend(next_seq.to_be_bytes());
+    socket.write_all(&handshake).await.map_err(|e| format!("write handshake err = {:?}", e))?;
+    let mut reply = [0; 1 + 8 + 8];
+    let wait = if run_id == 0 { REPL_SNAPSHOT_TIMEOUT } else { REPL_TIMEOUT };
+    timeout(wait, socket.read_exact(&mut reply)).await
+        .map_err(|_| String::from("read handshake timeout"))?
+        .map_err(|e| format!("read handshake err = {:?}", e))?;
+    match reply[0] {
+        REPL_OK | REPL_SNAPSHOT => {}
+        REPL_RESYNC => return Err(format!("can't resume from {:x}:{}, need full sync", run_id, next_seq)),
+        _ => return Err(format!("leader doesn't have {} shards", shards)),
+    }
+    let run_id = u64::from_be_bytes(reply[1..9].try_into().unwrap_or_default());
+    let start = u64::from_be_bytes(reply[9..17].try_into().unwrap_or_default());
+    info!("Replicate from {} shard {}, run id {:x}, start at {}", leader, shard, run_id, start);
+    // 快照回放完成并确认后才记录位置
+    if reply[0] == REPL_OK {
+        write_position(data_path, run_id, start);
+    } else {
+        info!("Receive snapshot from {} shard {}", leader, shard);
+    }
+    // 之前连接收到的记录回放完成的确认不再发送
+    applied.borrow_and_update();
+    let (reader, writer) = socket.into_split();
+    select! {
+        res = receive_frames(reader, tx) => res,
+        res = send_acks(writer, applied, data_path, run_id) => res,
+    }
+}
+
+async fn receive_frames(mut reader: OwnedReadHalf, tx: &mpsc::Sender<(u64, Replicated)>) -> Result<(), String> {
+    loop {
+        let mut len = [0; 4];
+        match timeout(REPL_TIMEOUT, reader.read_exact(&mut len)).await {
+            Err(_) => return Err(String::from("leader timeout")),
+            Ok(Err(e)) => return Err(format!("read frame err = {:?}", e)),
+            Ok(Ok(_)) => {}
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            continue;
+        }
+        let mut buf = vec![0; len];
+        reader.read_exact(&mut buf).await.map_err(|e| format!("read frame err = {:?}", e))?;
+        let Some(record) = Replicated::decode(&buf) else {
+            return Err(String::from("invalid frame"));
+        };
+        if tx.send(record).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// 事件循环回放后确认，同时记录位置，重连时从这里接续
+async fn send_acks(mut writer: OwnedWriteHalf, applied: &mut watch::Receiver<u64>, data_path: &str, run_id: u64) -> Result<(), String> {
+    while applied.changed().await.is_ok() {
+        let seq = *applied.borrow_and_update();
+        writer.write_all(&seq.to_be_bytes()).await.map_err(|e| format!("write ack err = {:?}", e))?;
+        write_position(data_path, run_id, seq + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn push(log: &ReplicationLog, version: u64) {
+        log.push(REPL_CLEAR, "", &version.to_be_bytes());
+    }
+
+    #[test]
+    fn resume_within_backlog() {
+        // 每帧 4 + 8 + 1 + 2 + 8 = 23 字节，最多保留 3 帧
+        let log = ReplicationLog::new(23 * 3);
+        for version in 1..=3 {
+            push(&log, version);
+        }
+        assert_eq!(log.resume(0, 0), Some(1));
+        assert_eq!(log.resume(log.run_id, 2), Some(2));
+        // 已全部回放的从节点等下一条
+        assert_eq!(log.resume(log.run_id, 4), Some(4));
+        assert_eq!(log.resume(log.run_id, 5), None);
+        assert_eq!(log.resume(log.run_id ^ 1, 2), None);
+
+        // 丢弃第一帧后，之前的位置和没有复制过的从节点都无法接续
+        push(&log, 4);
+        assert_eq!(log.resume(log.run_id, 1), None);
+        assert_eq!(log.resume(log.run_id, 2), Some(2));
+        assert_eq!(log.resume(0, 0), None);
+    }
+
+    // 连接主节点的复制任务，结束测试时中止
+    fn connect(addr: String, data_path: &str) -> (mpsc::Receiver<(u64, Replicated)>, watch::Sender<u64>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(REPL_QUEUE_SIZE);
+        let (applied, mut applied_rx) = watch::channel(0);
+        let data_path = data_path.to_string();
+        let task = tokio::spawn(async move {
+            let _ = replicate(&addr, 0, 1, &data_path, &tx, &mut applied_rx).await;
+        });
+        (rx, applied, task)
+    }
+
+    async fn record(rx: &mut mpsc::Receiver<(u64, Replicated)>) -> u64 {
+        match rx.recv().await {
+            Some((seq, Replicated::Clear { version, .. })) => {
+                assert_eq!(seq, version);
+                seq
+            }
+            _ => panic!("Expect a clear record"),
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_after_reconnect() {
+        let log = Arc::new(ReplicationLog::new(DEFAULT_REPLICATION_BACKLOG_BYTES));
+        for version in 1..=3 {
+            push(&log, version);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, vec![log.clone()], Vec::new()));
+
+        // 第一次连接从头开始，确认回放到 2 后断开
+        let path = temp_path("replication-resume");
+        let (mut rx, applied, task) = connect(addr.clone(), &path);
+        for seq in 1..=3 {
+            assert_eq!(record(&mut rx).await, seq);
+        }
+        applied.send(2).unwrap();
+        for _ in 0..100 {
+            if read_position(&path) == (log.run_id, 3) {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(read_position(&path), (log.run_id, 3));
+        task.abort();
+
+        // 重连后从确认的位置之后接续，没有确认的 3 重新发送
+        push(&log, 4);
+        let (mut rx, _applied, task) = connect(addr.clone(), &path);
+        assert_eq!(record(&mut rx).await, 3);
+        assert_eq!(record(&mut rx).await, 4);
+        task.abort();
+
+        // 其他 run id 的位置无法接续，需要全量同步
+        write_position(&path, log.run_id ^ 1, 3);
+        let (tx, _rx) = mpsc::channel(REPL_QUEUE_SIZE);
+        let (_applied, mut applied_rx) = watch::channel(0);
+        let err = replicate(&addr, 0, 1, &path, &tx, &mut applied_rx).await.unwrap_err();
+        assert!(err.contains("need full sync"));
+    }
+
+    #[tokio::test]
+    async fn snapshot_for_empty_follower() {
+        let log = Arc::new(ReplicationLog::new(DEFAULT_REPLICATION_BACKLOG_BYTES));
+        for version in 1..=3 {
+            push(&log, version);
+        }
+        // 代替事件循环回复快照，快照包含到 3 的记录
+        let (feed, requests) = SnapshotFeed::new(log.clone());
+        tokio::spawn(async move {
+            let SnapshotFeed { log, mut requests } = feed;
+            while let Some(reply) = requests.recv().await {
+                let namespaces = vec![(String::new(), 3, vec![b"a".to_vec(), b"b".to_vec()]), (String::from("ns1"), 2, Vec::new())];
+                let _ = reply.send(ReplicaSnapshot { seq: log.last_seq(), namespaces });
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, vec![log.clone()], vec![requests]));
+
+        let path = temp_path("replication-snapshot");
+        let (mut rx, applied, task) = connect(addr, &path);
+        for payload in [b"a", b"b"] {
+            match rx.recv().await {
+                Some((0, Replicated::Load { namespace, payload: record })) => assert_eq!((namespace.as_str(), record.as_slice()), ("", payload.as_slice())),
+                _ => panic!("Expect a load record"),
+            }
+        }
+        assert!(matches!(rx.recv().await, Some((0, Replicated::Version { version: 3, .. }))));
+        // 最后一帧带快照的序号
+        match rx.recv().await {
+            Some((3, Replicated::Version { namespace, version: 2 })) => assert_eq!(namespace, "ns1"),
+            _ => panic!("Expect the last version record"),
+        }
+        // 回放完成前没有位置
+        assert_eq!(read_position(&path), (0, 0));
+
+        // 之后接收快照之后的复制日志
+        push(&log, 4);
+        assert_eq!(record(&mut rx).await, 4);
+        applied.send(3).unwrap();
+        for _ in 0..100 {
+            if read_position(&path) == (log.run_id, 4) {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(read_position(&path), (log.run_id, 4));
+        task.abort();
+    }
+}
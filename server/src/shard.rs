@@ -0,0 +1,682 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::iter::once;
+use std::sync::Arc;
+use dashmap::DashMap;
+use tracing::{info, warn};
+use tokio::select;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver};
+use tokio::sync::watch;
+use crate::client::{Cancelled, Client};
+use crate::event::{Event, EventRes, Settings, SizeLimits, ERR_CROSS_SHARD};
+use crate::storage::{ReadView, SharedView};
+
+// 分片 i > 0 的数据在 data_path/SHARD_DIR/i 下，各层 SST 目录同样；分片 0 直接在 data_path 下，只有一个分片时与不分片相同
+const SHARD_DIR: &str = "shard";
+// 打开时的分片数记录在 data_path 下，key 按分片数取模，分片数变了已有的 key 会找不到
+const SHARDS_FILE: &str = "SHARDS";
+// 连接到分片的事件通道长度，与连接的响应通道长度
+const EVENT_QUEUE_SIZE: usize = 128;
+const RES_QUEUE_SIZE: usize = 16;
+// STATS 中各分片相同的项：连接数、共用的 block 缓存的命中、未命中数和全局写限流的计数，不累加
+const SHARED_STATS: [usize; 5] = [3, 5, 6, 9, 10];
+
+// 分片 shard 的目录
+pub fn shard_path(path: &str, shard: usize) -> String {
+    if shard == 0 {
+        path.to_string()
+    } else {
+        format!("{}/{}/{}", path, SHARD_DIR, shard)
+    }
+}
+
+// key 所在的分片；crc32 在不同版本间不变，重启后同一个 key 仍在同一个分片
+pub fn shard_of(key: &[u8], shards: usize) -> usize {
+    crc32fast::hash(key) as usize % shards
+}
+
+// 检查 data_path 的分片数与配置一致，第一次打开时记录；没有记录但已有数据的目录是不分片时写入的
+pub fn check_shards(data_path: &str, shards: usize) {
+    let path = format!("{}/{}", data_path, SHARDS_FILE);
+    let recorded = match std::fs::read_to_string(&path) {
+        Ok(s) => s.trim().parse::<usize>().unwrap_or_else(|_| panic!("Invalid shards file {}", &path)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let used = std::fs::read_dir(data_path).is_ok_and(|mut entries| entries.next().is_some());
+            let recorded = if used { 1 } else { shards };
+            if recorded == shards {
+                std::fs::create_dir_all(data_path).unwrap_or_else(|e| panic!("Create data path {} fail, err = {:?}", data_path, e));
+                std::fs::write(&path, shards.to_string()).unwrap_or_else(|e| panic!("Write shards file {} fail, err = {:?}", &path, e));
+            }
+            recorded
+        }
+        Err(e) => panic!("Read shards file {} fail, err = {:?}", &path, e),
+    };
+    if recorded != shards {
+        panic!("Data path {} has {} shards, but event_loops is {}", data_path, recorded, shards);
+    }
+}
+
+// 连接 id 到连接
+pub type ClientMap = Arc<DashMap<String, Client>>;
+
+// 一个分片的事件循环需要的通道、连接表，以及开启直接读取时存储引擎发布快照的位置
+pub struct EventLoop {
+    pub receiver: Receiver<Event>,
+    pub client_map: ClientMap,
+    pub shared_view: Option<SharedView>,
+}
+
+// 各分片事件循环的通道和连接表；连接在每个分片的连接表中都有一项，分片只向自己的那一项发送响应
+pub struct Shards {
+    senders: Vec<Sender<Event>>,
+    client_maps: Vec<ClientMap>,
+    // 各分片发布的快照，没有开启直接读取时为空
+    views: Vec<SharedView>,
+    // 长度上限以最新的配置为准
+    settings: watch::Receiver<Settings>,
+}
+
+impl Shards {
+    pub fn new(shards: usize, direct_reads: bool, settings: watch::Receiver<Settings>) -> (Self, Vec<EventLoop>) {
+        let mut senders = Vec::with_capacity(shards);
+        let mut client_maps = Vec::with_capacity(shards);
+        let mut views = Vec::new();
+        let mut loops = Vec::with_capacity(shards);
+        for _ in 0..shards {
+            let (sender, receiver) = mpsc::channel(EVENT_QUEUE_SIZE);
+            let client_map = Arc::new(DashMap::new());
+            let shared_view = direct_reads.then(SharedView::default);
+            senders.push(sender);
+            client_maps.push(client_map.clone());
+            views.extend(shared_view.clone());
+            loops.push(EventLoop { receiver, client_map, shared_view });
+        }
+        (Self { senders, client_maps, views, settings }, loops)
+    }
+
+    // 当前配置的长度上限，新连接按它解码请求
+    pub fn limits(&self) -> SizeLimits {
+        self.settings.borrow().limits
+    }
+
+    // 注册连接，返回它发送事件的一端，响应按请求顺序发到 client_tx
+    // 只有一个分片时直接连到事件循环；否则经过连接自己的转发任务，按 key 分发事件，合并多个分片的响应
+    pub fn connect(&self, id: &str, client_tx: Sender<EventRes>) -> EventSender {
+        let cancelled = Cancelled::default();
+        let sender = self.relay(id, client_tx.clone(), &cancelled);
+        EventSender {
+            sender,
+            replies: client_tx,
+            cancelled,
+            views: self.views.clone(),
+            settings: self.settings.clone(),
+            inflight: 0,
+            multi: false,
+            pinned: false,
+        }
+    }
+
+    fn relay(&self, id: &str, client_tx: Sender<EventRes>, cancelled: &Cancelled) -> Sender<Event> {
+        if self.senders.len() == 1 {
+            self.client_maps[0].insert(id.to_string(), Client::new(id.to_string(), client_tx, cancelled.clone()));
+            return self.senders[0].clone();
+        }
+        let (event_tx, event_rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        let (res_tx, res_rx) = mpsc::unbounded_channel();
+        for (shard, client_map) in self.client_maps.iter().enumerate() {
+            let (tx, mut rx) = mpsc::channel(RES_QUEUE_SIZE);
+            client_map.insert(id.to_string(), Client::new(id.to_string(), tx, cancelled.clone()));
+            // 立即取走分片的响应，分片不会因为连接还在等其他分片而阻塞
+            let res_tx = res_tx.clone();
+            tokio::spawn(async move {
+                while let Some(res) = rx.recv().await {
+                    if res_tx.send((shard, res)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let relay = Relay {
+            id: id.to_string(),
+            senders: self.senders.clone(),
+            pending: VecDeque::new(),
+            buffers: (0..self.senders.len()).map(|_| VecDeque::new()).collect(),
+            tx: None,
+        };
+        tokio::spawn(relay.run(event_rx, res_rx, client_tx));
+        event_tx
+    }
+
+    // 所有连接的 id，每个连接在各分片都有注册，取第一个分片的
+    pub fn clients(&self) -> Vec<String> {
+        let mut clients: Vec<String> = self.client_maps[0].iter().map(|entry| entry.key().clone()).collect();
+        clients.sort();
+        clients
+    }
+
+    pub fn disconnect(&self, id: &str) {
+        for client_map in self.client_maps.iter() {
+            client_map.remove(id);
+        }
+    }
+}
+
+// 连接发送事件的一端
+// 开启直接读取时，没有未完成的请求、不在事务中、没有选择过命名空间、打开过快照或按用户认证的连接，GET、EXISTS 和 MGET 直接从发布的快照读取
+// 之前的响应都已取走，直接读取的响应排在它们之后，也能读到自己之前的写入
+pub struct EventSender {
+    sender: Sender<Event>,
+    // 连接的响应通道，直接读取的响应也放在这里
+    replies: Sender<EventRes>,
+    // 连接在各分片的连接表中共用的已取消请求
+    cancelled: Cancelled,
+    views: Vec<SharedView>,
+    settings: watch::Receiver<Settings>,
+    // 已发送、连接还没有取走最后一条响应的请求数
+    inflight: usize,
+    multi: bool,
+    // 选择过命名空间、打开过快照或按用户认证过，之后的读取都经过事件循环
+    pinned: bool,
+}
+
+impl EventSender {
+    pub async fn send(&mut self, event: Event) -> Result<(), SendError<Event>> {
+        self.inflight += 1;
+        if self.inflight == 1 && !self.multi && !self.pinned {
+            // 通道是空的，不会满
+            if let Some(res) = self.read(&event) {
+                if self.replies.try_send(res).is_ok() {
+                    return Ok(());
+                }
+            }
+        }
+        match event {
+            Event::MULTI { .. } => self.multi = true,
+            Event::EXEC { .. } => self.multi = false,
+            Event::SELECT { .. } | Event::SNAPSHOT { .. } | Event::AUTH { access: Some(_), .. } => self.pinned = true,
+            _ => {}
+        }
+        self.sender.send(event).await
+    }
+
+    pub fn cancelled(&self) -> Cancelled {
+        self.cancelled.clone()
+    }
+
+    // 连接从响应通道取走一条响应
+    pub fn received(&mut self, res: &EventRes) {
+        if is_last(res) {
+            self.inflight = self.inflight.saturating_sub(1);
+        }
+    }
+
+    // 从 key 所在分片发布的快照读取；没有开启、存储引擎还没有打开或不是读取时返回 None
+    fn read(&self, event: &Event) -> Option<EventRes> {
+        if self.views.is_empty() || !matches!(event, Event::GET { .. } | Event::EXISTS { .. } | Event::MGET { .. }) {
+            return None;
+        }
+        if let Some(res) = event.check_size(&self.settings.borrow().limits) {
+            return Some(res);
+        }
+        let view = |key: &[u8]| self.views[shard_of(key, self.views.len())].load();
+        match event {
+            Event::GET { id, req, key } => Some(EventRes::GET { id: id.clone(), req: *req, value: view(key)?.get(key) }),
+            Event::EXISTS { id, req, key } => Some(EventRes::EXISTS { id: id.clone(), req: *req, exists: view(key)?.contains(key) }),
+            Event::MGET { id, req, keys } => {
+                let values = keys.iter().map(|key| view(key).map(|view| view.get(key))).collect::<Option<_>>()?;
+                Some(EventRes::MGET { id: id.clone(), req: *req, values })
+            }
+            _ => None,
+        }
+    }
+}
+
+// 已发往分片、还没有响应的请求
+struct Pending {
+    shards: Vec<usize>,
+    // MGET 拆分后各分片的 key 在原请求中的位置，与 shards 对应
+    positions: Vec<Vec<usize>>,
+    // 转发任务直接拒绝、没有发往分片的请求的响应
+    rejected: Option<EventRes>,
+}
+
+// MULTI 之后事务中的 op 所在的分片
+#[derive(Default)]
+struct TxRoute {
+    // 第一个 op 的 key 所在的分片
+    shard: Option<usize>,
+    // 有 op 的 key 在其他分片，EXEC 时整个拒绝
+    crossed: bool,
+}
+
+// 一个连接的转发任务：事件按 key 发往所在的分片，没有 key 或涉及所有 key 的事件发往所有分片
+// 每个分片按收到的顺序响应，按请求顺序等齐各分片的响应，合并后发给连接
+// MSET 和事务只在一个分片上原子执行，key 跨分片时返回 ERR_CROSS_SHARD，不会只执行一部分
+// 快照在各分片分别打开，单个 MSET 和事务的结果在快照中是完整的，但不是所有分片同一时刻的状态
+struct Relay {
+    id: String,
+    senders: Vec<Sender<Event>>,
+    pending: VecDeque<Pending>,
+    // 各分片已收到、还没有合并的响应
+    buffers: Vec<VecDeque<EventRes>>,
+    // MULTI 之后、EXEC 之前
+    tx: Option<TxRoute>,
+}
+
+impl Relay {
+    async fn run(mut self, mut events: Receiver<Event>, mut responses: UnboundedReceiver<(usize, EventRes)>, client_tx: Sender<EventRes>) {
+        // 已合并、等待发给连接的响应
+        let mut ready = VecDeque::new();
+        loop {
+            select! {
+                event = events.recv() => match event {
+                    Some(event) => {
+                        self.route(event).await;
+                        // 直接拒绝的请求之前没有等待的响应时立即发出
+                        while let Some(merged) = self.complete() {
+                            ready.extend(merged);
+                        }
+                    }
+                    None => break,
+                },
+                Some((shard, res)) = responses.recv() => {
+                    self.buffers[shard].push_back(res);
+                    while let Some(merged) = self.complete() {
+                        ready.extend(merged);
+                    }
+                }
+                // 连接写得慢时继续接收事件和响应，不阻塞分片
+                permit = client_tx.reserve(), if !ready.is_empty() => match (permit, ready.pop_front()) {
+                    (Ok(permit), Some(res)) => permit.send(res),
+                    _ => break,
+                },
+            }
+        }
+        info!("Client [{}] relay end", self.id);
+    }
+
+    async fn send(&mut self, shard: usize, event: Event) {
+        if let Err(e) = self.senders[shard].send(event).await {
+            warn!("Client [{}] send event to shard {} fail, err = {:?}", self.id, shard, e);
+        }
+    }
+
+    async fn route(&mut self, event: Event) {
+        let n = self.senders.len();
+        let event = match self.transact(event) {
+            Ok(event) => event,
+            // 跨分片的事务中的 op 都发往第一个 op 的分片缓存，EXEC 时一起拒绝
+            Err((shard, event)) => {
+                self.send(shard, event).await;
+                self.pending.push_back(Pending { shards: vec![shard], positions: Vec::new(), rejected: None });
+                return;
+            }
+        };
+        let (shards, positions) = match event {
+            Event::GET { ref key, .. } | Event::SET { ref key, .. } | Event::EXISTS { ref key, .. } |
+            Event::SETEX { ref key, .. } | Event::CAS { ref key, .. } | Event::META { ref key, .. } |
+            Event::GETV { ref key, .. } | Event::SETV { ref key, .. } | Event::INCR { ref key, .. } |
+            Event::MERGE { ref key, .. } | Event::APPEND { ref key, .. } | Event::GETSET { ref key, .. } => {
+                let shard = shard_of(key, n);
+                self.send(shard, event).await;
+                (vec![shard], Vec::new())
+            }
+            Event::MGET { id, req, keys } if !keys.is_empty() => {
+                let mut shards = Vec::new();
+                let mut positions = Vec::new();
+                for (shard, indexes, keys) in split(keys, |key| key, n) {
+                    self.send(shard, Event::MGET { id: id.clone(), req, keys }).await;
+                    shards.push(shard);
+                    positions.push(indexes);
+                }
+                (shards, positions)
+            }
+            Event::SETM { id, req, ref entries } if key_shards(entries.iter().map(|(key, _)| key), n).len() > 1 => {
+                let rejected = EventRes::ERROR { id, req, code: ERR_CROSS_SHARD };
+                self.pending.push_back(Pending { shards: Vec::new(), positions: Vec::new(), rejected: Some(rejected) });
+                return;
+            }
+            Event::SETM { id, req, entries } if !entries.is_empty() => {
+                let mut shards = Vec::new();
+                for (shard, _, entries) in split(entries, |(key, _)| key, n) {
+                    self.send(shard, Event::SETM { id: id.clone(), req, entries }).await;
+                    shards.push(shard);
+                }
+                (shards, Vec::new())
+            }
+            // 拆分后各分片的 key 仍然递增；无序时交给一个分片拒绝
+            Event::INGEST { id, req, entries } if !entries.is_empty() && entries.windows(2).all(|pair| pair[0].0 < pair[1].0) => {
+                let mut shards = Vec::new();
+                for (shard, _, entries) in split(entries, |(key, _)| key, n) {
+                    self.send(shard, Event::INGEST { id: id.clone(), req, entries }).await;
+                    shards.push(shard);
+                }
+                (shards, Vec::new())
+            }
+            event @ (Event::MGET { .. } | Event::SETM { .. } | Event::INGEST { .. }) => {
+                self.send(0, event).await;
+                (vec![0], Vec::new())
+            }
+            event => {
+                for shard in 1..n {
+                    self.send(shard, event.clone()).await;
+                }
+                self.send(0, event).await;
+                ((0..n).collect(), Vec::new())
+            }
+        };
+        self.pending.push_back(Pending { shards, positions, rejected: None });
+    }
+
+    // 记录事务中的 op 所在的分片；事务已经跨分片时返回 Err 和缓存它的分片，跨分片的事务的 EXEC 带上 ERR_CROSS_SHARD
+    fn transact(&mut self, event: Event) -> Result<Event, (usize, Event)> {
+        let n = self.senders.len();
+        match event {
+            Event::MULTI { .. } => {
+                self.tx = Some(TxRoute::default());
+                Ok(event)
+            }
+            Event::EXEC { id, req, abort } => {
+                let crossed = self.tx.take().is_some_and(|tx| tx.crossed);
+                Ok(Event::EXEC { id, req, abort: if crossed { Some(ERR_CROSS_SHARD) } else { abort } })
+            }
+            event => {
+                let Some(tx) = self.tx.as_mut().filter(|_| event.tx_id().is_some()) else {
+                    return Ok(event);
+                };
+                let shards = match &event {
+                    Event::MGET { keys, .. } => key_shards(keys.iter(), n),
+                    Event::SETM { entries, .. } => key_shards(entries.iter().map(|(key, _)| key), n),
+                    Event::GET { key, .. } | Event::EXISTS { key, .. } | Event::SET { key, .. } |
+                    Event::CAS { key, .. } | Event::INCR { key, .. } | Event::APPEND { key, .. } |
+                    Event::GETSET { key, .. } => vec![shard_of(key, n)],
+                    _ => Vec::new(),
+                };
+                let shard = *tx.shard.get_or_insert(shards.first().copied().unwrap_or(0));
+                if !tx.crossed && shards.iter().all(|s| *s == shard) {
+                    return Ok(event);
+                }
+                if !tx.crossed {
+                    warn!("Client [{}] transaction spans shards, reject at exec", self.id);
+                    tx.crossed = true;
+                }
+                Err((shard, event))
+            }
+        }
+    }
+
+    // 最早的请求在各分片的响应都已收到时取出合并
+    fn complete(&mut self) -> Option<Vec<EventRes>> {
+        let head = self.pending.front()?;
+        if head.rejected.is_some() {
+            return self.pending.pop_front()?.rejected.map(|res| vec![res]);
+        }
+        let mut lens = Vec::with_capacity(head.shards.len());
+        for &shard in head.shards.iter() {
+            lens.push(self.buffers[shard].iter().position(is_last)? + 1);
+        }
+        let head = self.pending.pop_front()?;
+        let parts = head.shards.iter().zip(lens)
+            .map(|(&shard, len)| self.buffers[shard].drain(..len).collect())
+            .collect();
+        Some(merge(head.positions, parts))
+    }
+}
+
+// 一个请求的最后一条响应，SCAN 逐条返回直到结束标记
+pub fn is_last(res: &EventRes) -> bool {
+    !matches!(res, EventRes::SCAN { entry: Some(_), .. })
+}
+
+// keys 所在的分片，从小到大，不重复
+fn key_shards<'a>(keys: impl Iterator<Item = &'a Vec<u8>>, shards: usize) -> Vec<usize> {
+    let mut shards: Vec<usize> = keys.map(|key| shard_of(key, shards)).collect();
+    shards.sort_unstable();
+    shards.dedup();
+    shards
+}
+
+// 按 key 所在的分片拆分，同时返回在原来顺序中的位置；按分片编号排列，不含空的分片
+fn split<T>(items: Vec<T>, key: impl Fn(&T) -> &Vec<u8>, shards: usize) -> Vec<(usize, Vec<usize>, Vec<T>)> {
+    let mut parts: Vec<(Vec<usize>, Vec<T>)> = (0..shards).map(|_| (Vec::new(), Vec::new())).collect();
+    for (index, item) in items.into_iter().enumerate() {
+        let part = &mut parts[shard_of(key(&item), shards)];
+        part.0.push(index);
+        part.1.push(item);
+    }
+    parts.into_iter().enumerate()
+        .filter(|(_, (indexes, _))| !indexes.is_empty())
+        .map(|(shard, (indexes, items))| (shard, indexes, items))
+        .collect()
+}
+
+// 合并一个请求在各分片的响应，parts 与发往的分片对应，每个分片的最后一条是 is_last 的响应
+// 任一分片返回错误或 MOVED 时整个请求返回这个响应；计数相加，SCAN 的条目按 key 排序
+fn merge(positions: Vec<Vec<usize>>, mut parts: Vec<Vec<EventRes>>) -> Vec<EventRes> {
+    if parts.len() == 1 {
+        return parts.pop().unwrap_or_default();
+    }
+    if let Some(index) = parts.iter().position(|part| matches!(part.last(), Some(EventRes::ERROR { .. } | EventRes::MOVED { .. }))) {
+        return parts.swap_remove(index).pop().into_iter().collect();
+    }
+    let mut lasts = parts.iter_mut().filter_map(Vec::pop).collect::<Vec<_>>().into_iter();
+    let Some(first) = lasts.next() else {
+        return Vec::new();
+    };
+    let res = match first {
+        EventRes::SCAN { id, req, .. } => {
+            let mut entries: Vec<(Vec<u8>, Vec<u8>)> = parts.into_iter().flatten()
+                .filter_map(|res| match res {
+                    EventRes::SCAN { entry, .. } => entry,
+                    _ => None,
+                })
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            return entries.into_iter()
+                .map(|entry| EventRes::SCAN { id: id.clone(), req, entry: Some(entry) })
+                .chain(once(EventRes::SCAN { id: id.clone(), req, entry: None }))
+                .collect();
+        }
+        EventRes::MGET { id, req, values } => {
+            let mut merged = vec![None; positions.iter().map(Vec::len).sum()];
+            let rest = lasts.map(|res| match res {
+                EventRes::MGET { values, .. } => values,
+                _ => Vec::new(),
+            });
+            for (indexes, values) in positions.iter().zip(once(values).chain(rest)) {
+                for (&index, value) in indexes.iter().zip(values) {
+                    merged[index] = value;
+                }
+            }
+            EventRes::MGET { id, req, values: merged }
+        }
+        EventRes::LPM { id, req, entry } => {
+            let entry = once(entry)
+                .chain(lasts.map(|res| match res {
+                    EventRes::LPM { entry, .. } => entry,
+                    _ => None,
+                }))
+                .flatten()
+                .max_by_key(|(prefix, _)| prefix.len());
+            EventRes::LPM { id, req, entry }
+        }
+        EventRes::DBSIZE { id, req, size } => {
+            let size = size + lasts.map(|res| match res {
+                EventRes::DBSIZE { size, .. } => size,
+                _ => 0,
+            }).sum::<u64>();
+            EventRes::DBSIZE { id, req, size }
+        }
+        EventRes::STATS { id, req, mut stats } => {
+            for res in lasts {
+                if let EventRes::STATS { stats: other, .. } = res {
+                    for (index, (stat, other)) in stats.iter_mut().zip(other).enumerate() {
+                        if !SHARED_STATS.contains(&index) {
+                            *stat += other;
+                        }
+                    }
+                }
+            }
+            EventRes::STATS { id, req, stats }
+        }
+        EventRes::SELECT { id, req, ok } => {
+            let ok = ok && lasts.all(|res| matches!(res, EventRes::SELECT { ok: true, .. }));
+            EventRes::SELECT { id, req, ok }
+        }
+        EventRes::SNAPSHOT { id, req, version } => {
+            let version = lasts.fold(version, |version, res| match res {
+                EventRes::SNAPSHOT { version: other, .. } => version.max(other),
+                _ => version,
+            });
+            EventRes::SNAPSHOT { id, req, version }
+        }
+        EventRes::RELEASE { id, req, ok } => {
+            let ok = ok | lasts.any(|res| matches!(res, EventRes::RELEASE { ok: true, .. }));
+            EventRes::RELEASE { id, req, ok }
+        }
+        EventRes::COMPACT { id, req, files } => {
+            let files = files + lasts.map(|res| match res {
+                EventRes::COMPACT { files, .. } => files,
+                _ => 0,
+            }).sum::<u32>();
+            EventRes::COMPACT { id, req, files }
+        }
+        EventRes::SCRUB { id, req, mut files, mut corrupt } => {
+            for res in lasts {
+                if let EventRes::SCRUB { files: f, corrupt: c, .. } = res {
+                    files += f;
+                    corrupt += c;
+                }
+            }
+            EventRes::SCRUB { id, req, files, corrupt }
+        }
+        EventRes::INGEST { id, req, keys } => {
+            let keys = keys + lasts.map(|res| match res {
+                EventRes::INGEST { keys, .. } => keys,
+                _ => 0,
+            }).sum::<u32>();
+            EventRes::INGEST { id, req, keys }
+        }
+        EventRes::DELETE_RANGE { id, req, keys } => {
+            let keys = keys + lasts.map(|res| match res {
+                EventRes::DELETE_RANGE { keys, .. } => keys,
+                _ => 0,
+            }).sum::<u64>();
+            EventRes::DELETE_RANGE { id, req, keys }
+        }
+        // SET、SETM、FLUSHALL、PONG、MULTI、EXEC 等各分片相同
+        res => res,
+    };
+    vec![res]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{settings, spawn_loop, CLIENT};
+
+    fn set(key: &[u8]) -> Event {
+        Event::SET { id: CLIENT.to_string(), req: None, key: key.to_vec(), value: Some(b"v".to_vec()) }
+    }
+
+    // 分片 shard 上的第 n 个 key
+    fn key_on(shard: usize, shards: usize, n: usize) -> Vec<u8> {
+        (0..).map(|i| format!("k{}", i).into_bytes()).filter(|key| shard_of(key, shards) == shard).nth(n).unwrap()
+    }
+
+    // 两个分片，各自在临时目录中运行事件循环
+    async fn sharded(name: &str) -> (EventSender, Receiver<EventRes>) {
+        let (_settings_tx, settings_rx) = watch::channel(settings());
+        let (shards, loops) = Shards::new(2, false, settings_rx);
+        for (shard, EventLoop { receiver, client_map, .. }) in loops.into_iter().enumerate() {
+            spawn_loop(&format!("{}-{}", name, shard), receiver, client_map, None, |_| {}).await;
+        }
+        let (client_tx, client_rx) = mpsc::channel(RES_QUEUE_SIZE);
+        (shards.connect(CLIENT, client_tx), client_rx)
+    }
+
+    // 依次发送，按请求顺序返回各请求的响应，每个请求一条
+    async fn call(sender: &mut EventSender, client_rx: &mut Receiver<EventRes>, events: Vec<Event>) -> Vec<EventRes> {
+        let count = events.len();
+        for event in events {
+            sender.send(event).await.unwrap();
+        }
+        let mut replies = Vec::new();
+        while replies.len() < count {
+            let res = client_rx.recv().await.unwrap();
+            sender.received(&res);
+            replies.push(res);
+        }
+        replies
+    }
+
+    fn get(key: &[u8]) -> Event {
+        Event::GET { id: CLIENT.to_string(), req: None, key: key.to_vec() }
+    }
+
+    fn mset(keys: &[&[u8]]) -> Event {
+        Event::SETM { id: CLIENT.to_string(), req: None, entries: keys.iter().map(|key| (key.to_vec(), Some(b"v".to_vec()))).collect() }
+    }
+
+    fn cross_shard(res: &EventRes) -> bool {
+        matches!(res, EventRes::ERROR { code: ERR_CROSS_SHARD, .. })
+    }
+
+    #[tokio::test]
+    async fn mset_on_one_shard_applies() {
+        let (mut sender, mut client_rx) = sharded("mset-one-shard").await;
+        let (a, b) = (key_on(1, 2, 0), key_on(1, 2, 1));
+        let replies = call(&mut sender, &mut client_rx, vec![mset(&[&a, &b]), get(&a), get(&b)]).await;
+        assert!(matches!(replies[0], EventRes::SETM { .. }));
+        assert!(replies[1..].iter().all(|res| matches!(res, EventRes::GET { value: Some(_), .. })));
+    }
+
+    #[tokio::test]
+    async fn mset_across_shards_rejected() {
+        let (mut sender, mut client_rx) = sharded("mset-cross-shard").await;
+        let (a, b) = (key_on(0, 2, 0), key_on(1, 2, 0));
+        let replies = call(&mut sender, &mut client_rx, vec![mset(&[&a, &b]), get(&a), get(&b)]).await;
+        assert!(cross_shard(&replies[0]));
+        assert!(replies[1..].iter().all(|res| matches!(res, EventRes::GET { value: None, .. })));
+    }
+
+    #[tokio::test]
+    async fn tx_on_one_shard_commits() {
+        let (mut sender, mut client_rx) = sharded("tx-one-shard").await;
+        let (a, b) = (key_on(0, 2, 0), key_on(0, 2, 1));
+        let replies = call(&mut sender, &mut client_rx, vec![
+            Event::MULTI { id: CLIENT.to_string(), req: None },
+            set(&a),
+            mset(&[&b]),
+            Event::EXEC { id: CLIENT.to_string(), req: None, abort: None },
+            get(&b),
+        ]).await;
+        assert!(matches!(replies[1], EventRes::SET { .. }));
+        assert!(matches!(replies[2], EventRes::SETM { .. }));
+        assert!(matches!(replies[3], EventRes::EXEC { .. }));
+        assert!(matches!(replies[4], EventRes::GET { value: Some(_), .. }));
+    }
+
+    #[tokio::test]
+    async fn tx_across_shards_rejected() {
+        let (mut sender, mut client_rx) = sharded("tx-cross-shard").await;
+        let (a, b, c) = (key_on(0, 2, 0), key_on(1, 2, 0), key_on(0, 2, 1));
+        let replies = call(&mut sender, &mut client_rx, vec![
+            Event::MULTI { id: CLIENT.to_string(), req: None },
+            set(&a),
+            set(&b),
+            set(&c),
+            Event::EXEC { id: CLIENT.to_string(), req: None, abort: None },
+            get(&a),
+            get(&b),
+        ]).await;
+        assert!(matches!(replies[0], EventRes::MULTI { .. }));
+        // 每个 op 和 EXEC 各一个错误，没有一个分片执行
+        assert!(replies[1..5].iter().all(cross_shard));
+        assert!(replies[5..].iter().all(|res| matches!(res, EventRes::GET { value: None, .. })));
+        // EXEC 之后回到事务外
+        let replies = call(&mut sender, &mut client_rx, vec![set(&b), get(&b)]).await;
+        assert!(matches!(replies[1], EventRes::GET { value: Some(_), .. }));
+    }
+}
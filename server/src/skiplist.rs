@@ -0,0 +1,398 @@
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::memtable::{Memtable, Record, Records};
+use crate::sstable::RangeTombstone;
+use crate::storage::live;
+use crate::utils::now_millis;
+
+// 最大层数，每层的节点数约为下一层的 1 / BRANCHING
+const MAX_HEIGHT: usize = 12;
+const BRANCHING: u64 = 4;
+// 节点单独分配，key、value 和每层的指针另算
+const NODE_BYTES: usize = std::mem::size_of::<Node>();
+const POINTER_BYTES: usize = std::mem::size_of::<AtomicPtr<Node>>();
+const RANGE_BYTES: usize = std::mem::size_of::<RangeTombstone>();
+
+// 跳表内存表：同一个 key 的每次写入都是一个新节点，按 key 升序、版本号降序排列
+// 节点插入后不再修改，整个表释放时才释放；写入加锁串行，读取不加锁
+// clone 共享节点，只读取 clone 时已有的版本，是 O(1) 的快照
+// 被覆盖的旧版本在落盘前一直占用内存，落盘时只写出每个 key 的最新版本
+#[derive(Clone)]
+pub struct SkipList {
+    list: Arc<List>,
+    // 只读取版本号不超过 limit 的节点：可写的内存表为 u64::MAX，快照为复制时的 last_version
+    limit: u64,
+    // 所有节点的 key 和 value 的总字节数，包括被覆盖的旧版本，范围删除计入边界的字节数
+    bytes: usize,
+    // 节点和范围删除占用的堆内存，与快照共享的部分也计入
+    heap_bytes: usize,
+    // 已分配的最大版本号，包括删除
+    last_version: u64,
+    // 范围删除，按写入顺序排列
+    ranges: Vec<RangeTombstone>,
+}
+
+struct List {
+    // 每层的第一个节点
+    head: [AtomicPtr<Node>; MAX_HEIGHT],
+    // 串行化写入，同时是选择层数的随机数状态
+    writer: Mutex<u64>,
+}
+
+struct Node {
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    // 过期时间，unix 毫秒
+    expire_at: Option<u64>,
+    version: u64,
+    // 每层的下一个节点，长度为节点的层数；插入时先写好再发布，之后只有更高的节点插入到它后面时修改
+    next: Box<[AtomicPtr<Node>]>,
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        SkipList {
+            list: Arc::new(List::new()),
+            limit: u64::MAX,
+            bytes: 0,
+            heap_bytes: 0,
+            last_version: 0,
+            ranges: Vec::new(),
+        }
+    }
+
+    // key 对这个表可见的最新节点
+    fn latest(&self, key: &[u8]) -> Option<&Node> {
+        self.list.seek(key, self.limit).filter(|node| node.key == key)
+    }
+
+    fn insert(&mut self, key: &[u8], value: Option<Vec<u8>>, expire_at: Option<u64>, version: u64) {
+        assert_eq!(self.limit, u64::MAX, "Write to skiplist snapshot");
+        self.raise_version(version);
+        self.bytes += key.len() + value.as_ref().map_or(0, Vec::len);
+        self.heap_bytes += self.list.insert(key.to_vec(), value, expire_at, version);
+    }
+}
+
+impl Memtable for SkipList {
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn memory(&self) -> usize {
+        self.heap_bytes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.iter().next().is_none()
+    }
+
+    fn record(&self, key: &[u8]) -> Option<Record<'_>> {
+        self.latest(key).map(|node| (node.value.as_deref(), node.expire_at, node.version))
+    }
+
+    fn ranges(&self) -> &[RangeTombstone] {
+        &self.ranges
+    }
+
+    fn last_version(&self) -> u64 {
+        self.last_version
+    }
+
+    fn raise_version(&mut self, version: u64) {
+        self.last_version = self.last_version.max(version);
+    }
+
+    fn set_expire(&mut self, key: &[u8], value: Option<Vec<u8>>, expire_at: Option<u64>, version: u64) {
+        self.insert(key, value, expire_at, version);
+    }
+
+    // 范围内版本号更小的 key 各插入一个删除节点
+    fn delete_range(&mut self, start: &[u8], end: &[u8], version: u64) {
+        let keys: Vec<Vec<u8>> = self.range(start, end)
+            .filter(|(_, (_, _, current))| *current < version)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.insert(&key, None, None, version);
+        }
+        self.raise_version(version);
+        self.bytes += start.len() + end.len();
+        let capacity = self.ranges.capacity();
+        self.ranges.push(RangeTombstone { start: start.to_vec(), end: end.to_vec(), version });
+        self.heap_bytes += start.len() + end.len() + (self.ranges.capacity() - capacity) * RANGE_BYTES;
+    }
+
+    // 复制未过期的 value 追加后作为新版本插入
+    fn append(&mut self, key: &[u8], suffix: &[u8], version: u64) -> usize {
+        let now = now_millis();
+        let (mut value, expire_at) = self.latest(key)
+            .and_then(|node| live(node.value.as_deref(), node.expire_at, now).map(|value| (value.to_vec(), node.expire_at)))
+            .unwrap_or_default();
+        value.extend_from_slice(suffix);
+        let len = value.len();
+        self.insert(key, Some(value), expire_at, version);
+        len
+    }
+
+    // 从第一个不小于 start 的节点开始沿最底层前进，每个 key 只取可见的最新版本
+    fn range(&self, start: &[u8], end: &[u8]) -> Records<'_> {
+        Box::new(Iter { node: self.list.seek(start, u64::MAX), end: end.to_vec(), limit: self.limit, last: None })
+    }
+
+    fn snapshot(&self) -> Box<dyn Memtable> {
+        Box::new(SkipList { limit: self.limit.min(self.last_version), ..self.clone() })
+    }
+}
+
+// 按 key 顺序遍历跳表中的记录，只借用跳表
+struct Iter<'a> {
+    node: Option<&'a Node>,
+    // 上界，为空表示没有上界
+    end: Vec<u8>,
+    limit: u64,
+    // 上一个返回的 key，它更旧的版本跳过
+    last: Option<&'a [u8]>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Vec<u8>, Record<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.node {
+            if !self.end.is_empty() && node.key >= self.end {
+                self.node = None;
+                return None;
+            }
+            self.node = node.next(0);
+            if node.version > self.limit || self.last == Some(node.key.as_slice()) {
+                continue;
+            }
+            self.last = Some(&node.key);
+            return Some((node.key.clone(), (node.value.as_deref(), node.expire_at, node.version)));
+        }
+        None
+    }
+}
+
+impl List {
+    fn new() -> Self {
+        List {
+            head: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            writer: Mutex::new(0x2545f4914f6cdd1d),
+        }
+    }
+
+    // prev 在 level 层的下一个节点的指针，prev 为 None 表示表头
+    fn link<'a>(&'a self, prev: Option<&'a Node>, level: usize) -> &'a AtomicPtr<Node> {
+        match prev {
+            Some(node) => &node.next[level],
+            None => &self.head[level],
+        }
+    }
+
+    // 每层排在 (key, version) 之前的最后一个节点
+    fn prevs(&self, key: &[u8], version: u64) -> [Option<&Node>; MAX_HEIGHT] {
+        let mut prevs = [None; MAX_HEIGHT];
+        let mut prev = None;
+        for level in (0..MAX_HEIGHT).rev() {
+            // SAFETY: 非空指针都来自 insert 中的 Box::into_raw，节点在 List 释放前不会释放，&self 保证 List 还在；
+            // 发布时的 Release 与这里的 Acquire 配对，读到的节点已经写好，之后只有 next 会被原子地修改
+            while let Some(node) = unsafe { self.link(prev, level).load(Ordering::Acquire).as_ref() } {
+                if !node.before(key, version) {
+                    break;
+                }
+                prev = Some(node);
+            }
+            prevs[level] = prev;
+        }
+        prevs
+    }
+
+    // 第一个不排在 (key, version) 之前的节点：key 更大，或者 key 相同且版本号不超过 version
+    fn seek(&self, key: &[u8], version: u64) -> Option<&Node> {
+        let prev = self.prevs(key, version)[0];
+        // SAFETY: 同 prevs，节点由 Box::into_raw 发布，在 List 释放前一直有效
+        unsafe { self.link(prev, 0).load(Ordering::Acquire).as_ref() }
+    }
+
+    // 插入到 key 和版本号都相同的节点之前，读取时先看到后写入的；返回新节点占用的堆内存
+    fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>, expire_at: Option<u64>, version: u64) -> usize {
+        let mut state = self.writer.lock().expect("Lock skiplist writer fail");
+        let height = random_height(&mut state);
+        let prevs = self.prevs(&key, version);
+        let next = (0..height).map(|level| AtomicPtr::new(self.link(prevs[level], level).load(Ordering::Relaxed))).collect();
+        let node = Box::new(Node { key, value, expire_at, version, next });
+        let heap = node.heap();
+        let node = Box::into_raw(node);
+        // 从下往上发布，读取时在高层看到的节点在底层一定也能看到
+        for (level, prev) in prevs.iter().enumerate().take(height) {
+            self.link(*prev, level).store(node, Ordering::Release);
+        }
+        heap
+    }
+}
+
+impl Drop for List {
+    fn drop(&mut self) {
+        let mut next = *self.head[0].get_mut();
+        while !next.is_null() {
+            // SAFETY: 所有节点都在最底层，每个只释放一次；&mut self 保证没有其他引用，读取借用的 List 已经结束
+            let mut node = unsafe { Box::from_raw(next) };
+            next = *node.next[0].get_mut();
+        }
+    }
+}
+
+impl Node {
+    // 按 key 升序、版本号降序排在 (key, version) 之前
+    fn before(&self, key: &[u8], version: u64) -> bool {
+        match self.key.as_slice().cmp(key) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Equal => self.version > version,
+            std::cmp::Ordering::Greater => false,
+        }
+    }
+
+    fn next(&self, level: usize) -> Option<&Node> {
+        // SAFETY: 同 List::prevs，返回的引用不长于 self，self 在所属的 List 释放前一直有效
+        unsafe { self.next[level].load(Ordering::Acquire).as_ref() }
+    }
+
+    fn heap(&self) -> usize {
+        NODE_BYTES + self.key.capacity() + self.value.as_ref().map_or(0, Vec::capacity) + self.next.len() * POINTER_BYTES
+    }
+}
+
+// 节点的层数，每多一层的概率为 1 / BRANCHING；state 为 xorshift 的状态
+fn random_height(state: &mut u64) -> usize {
+    let mut height = 1;
+    while height < MAX_HEIGHT {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        if !state.is_multiple_of(BRANCHING) {
+            break;
+        }
+        height += 1;
+    }
+    height
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("k{:04}", i).into_bytes()
+    }
+
+    // 沿最底层按顺序列出 (key, version)
+    fn nodes(list: &List) -> Vec<(Vec<u8>, u64)> {
+        let mut nodes = Vec::new();
+        let mut node = list.seek(b"", u64::MAX);
+        while let Some(current) = node {
+            nodes.push((current.key.clone(), current.version));
+            node = current.next(0);
+        }
+        nodes
+    }
+
+    // 乱序写入后按 key 升序遍历，覆盖和删除只返回最新的版本，旧版本仍然计入字节数
+    #[test]
+    fn order_and_overwrite() {
+        const N: usize = 100;
+        let mut list = SkipList::new();
+        let mut version = 0;
+        for i in (0..N).map(|i| i * 37 % N) {
+            version += 1;
+            list.set_expire(&key(i), Some(b"old".to_vec()), None, version);
+        }
+        for i in (0..N).step_by(3) {
+            version += 1;
+            list.set_expire(&key(i), Some(b"new".to_vec()), None, version);
+        }
+        version += 1;
+        list.set_expire(&key(1), None, None, version);
+        let snapshot = list.snapshot();
+        version += 1;
+        list.set_expire(&key(2), Some(b"later".to_vec()), None, version);
+
+        let expected = |i: usize| match i {
+            1 => None,
+            2 => Some(b"later".as_slice()),
+            i if i % 3 == 0 => Some(b"new".as_slice()),
+            _ => Some(b"old".as_slice()),
+        };
+        let records: Vec<(Vec<u8>, Option<&[u8]>)> = list.iter().map(|(key, (value, _, _))| (key, value)).collect();
+        assert_eq!(records, (0..N).map(|i| (key(i), expected(i))).collect::<Vec<_>>());
+        assert_eq!(list.record(&key(3)).map(|(value, _, _)| value), Some(Some(b"new".as_slice())));
+        assert_eq!(list.record(&key(1)).map(|(value, _, _)| value), Some(None));
+        assert_eq!(list.record(b"missing"), None);
+        assert_eq!(list.last_version(), version);
+        let overwritten = N.div_ceil(3) + 2;
+        assert_eq!(list.bytes(), (N + overwritten) * key(0).len() + N * 3 + N.div_ceil(3) * 3 + 5);
+        assert_eq!(list.range(&key(10), &key(13)).map(|(key, _)| key).collect::<Vec<_>>(), [key(10), key(11), key(12)]);
+
+        // 快照看不到之后的写入
+        assert_eq!(snapshot.record(&key(2)).map(|(value, _, _)| value), Some(Some(b"old".as_slice())));
+        assert_eq!(snapshot.iter().count(), N);
+        // 每个版本都是一个节点，同一个 key 按版本号降序
+        let nodes = nodes(&list.list);
+        assert_eq!(nodes.len(), N + overwritten);
+        assert!(nodes.windows(2).all(|pair| pair[0].0 < pair[1].0 || (pair[0].0 == pair[1].0 && pair[0].1 > pair[1].1)));
+    }
+
+    // 多个线程同时写入和读取：读到的节点总是有序的，每层都是下一层的子序列，写入完成后所有节点都在
+    #[test]
+    fn concurrent_insert_and_read() {
+        const WRITERS: usize = 4;
+        const READERS: usize = 4;
+        const N: usize = 2000;
+        let list = Arc::new(List::new());
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..READERS).map(|_| {
+            let list = list.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut seen = 0;
+                while !done.load(Ordering::Acquire) {
+                    let nodes = nodes(&list);
+                    assert!(nodes.windows(2).all(|pair| pair[0] < pair[1]));
+                    // 只插入不删除，读到的节点数不会变少
+                    assert!(nodes.len() >= seen);
+                    seen = nodes.len();
+                    for level in 1..MAX_HEIGHT {
+                        let mut node = list.link(None, level).load(Ordering::Acquire);
+                        let mut last: Option<Vec<u8>> = None;
+                        // SAFETY: 节点在 list 释放前一直有效，list 由这个线程持有
+                        while let Some(current) = unsafe { node.as_ref() } {
+                            assert!(last.as_ref().is_none_or(|last| *last < current.key));
+                            assert!(list.seek(&current.key, current.version).is_some_and(|found| found.key == current.key));
+                            last = Some(current.key.clone());
+                            node = current.next[level].load(Ordering::Acquire);
+                        }
+                    }
+                }
+            })
+        }).collect();
+        let writers: Vec<_> = (0..WRITERS).map(|w| {
+            let list = list.clone();
+            std::thread::spawn(move || {
+                for i in (w..N).step_by(WRITERS) {
+                    list.insert(key(i), Some(b"v".to_vec()), None, i as u64 + 1);
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(nodes(&list), (0..N).map(|i| (key(i), i as u64 + 1)).collect::<Vec<_>>());
+    }
+}
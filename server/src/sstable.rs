@@ -0,0 +1,836 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::iter::Peekable;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+use memmap2::Mmap;
+use crate::bloom::{self, Bloom};
+use crate::cache::{Block, BlockCache};
+use crate::event::{push_value, read_len, LONG_LEN_SIZE};
+use crate::throttle::IoThrottle;
+
+// SSTable 文件格式
+// n data blocks
+// 1 bloom block
+// 1 index block
+// 1 range block
+// footer
+//
+// data block 中的记录按 key 递增排列
+// 1 bit kind; KIND_VALUE or KIND_TOMBSTONE
+// 4 bit key len
+// n bit key
+// 8 bit version
+// 以下字段只有 KIND_VALUE 才有
+// 8 bit expire at; if 0 不过期
+// 4 bit value len
+// n bit value
+//
+// bloom block 见 bloom::Bloom
+//
+// data block 整体按 Compression 压缩，压缩后不更短则原样写入
+//
+// index block 中每个 data block 一条
+// 4 bit last key len
+// n bit last key
+// 8 bit block offset
+// 4 bit block len; 压缩后的长度
+// 4 bit block crc32; 压缩后的数据
+// 1 bit compression
+//
+// range block 中每个范围删除一条，按写入顺序排列
+// 4 bit start len
+// n bit start
+// 4 bit end len; 0 表示没有上界
+// n bit end
+// 8 bit version
+//
+// footer
+// 8 bit index offset
+// 4 bit index len
+// 4 bit index crc32
+// 8 bit bloom offset
+// 4 bit bloom len
+// 4 bit bloom crc32
+// 8 bit range offset
+// 4 bit range len
+// 4 bit range crc32
+// 8 bit last version
+// 8 bit magic
+//
+// 格式版本 1 没有 bloom block，footer 中没有 bloom 的三项
+// 格式版本 1 和 2 的记录没有 kind，value len 为 NONE 表示删除，删除时也有 expire at
+// 格式版本 1 到 3 的 data block 不压缩，index 中没有 compression
+// 格式版本 1 到 4 没有 range block，footer 中没有 range 的三项
+
+// data block 超过 4K 时结束
+const BLOCK_SIZE: usize = 4096;
+const FOOTER_SIZE: usize = 64;
+const FOOTER_SIZE_V4: usize = 48;
+const FOOTER_SIZE_V1: usize = 32;
+// "LSM_SST" + 格式版本
+const MAGIC: u64 = 0x4c534d5f53535405;
+const MAGIC_V4: u64 = 0x4c534d5f53535404;
+const MAGIC_V3: u64 = 0x4c534d5f53535403;
+const MAGIC_V2: u64 = 0x4c534d5f53535402;
+const MAGIC_V1: u64 = 0x4c534d5f53535401;
+
+const KIND_VALUE: u8 = 0;
+// 删除记录，在更旧的数据之上遮住同一个 key
+const KIND_TOMBSTONE: u8 = 1;
+
+const LS: usize = LONG_LEN_SIZE;
+
+// data block 的压缩方式，写入 index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "snappy" => Some(Compression::Snappy),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Snappy => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Snappy),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    // 压缩失败或不更短时为 None
+    fn compress(self, block: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => return None,
+            Compression::Snappy => snap::raw::Encoder::new().compress_vec(block).ok()?,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(block),
+        };
+        (compressed.len() < block.len()).then_some(compressed)
+    }
+
+    fn decompress(self, block: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(block),
+            Compression::Snappy => snap::raw::Decoder::new().decompress_vec(&block).map_err(|_| corrupted("SSTable block decompress fail")),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&block).map_err(|_| corrupted("SSTable block decompress fail")),
+        }
+    }
+}
+
+// 每次打开 SST 文件分配一个编号，区分缓存中不同文件的 block
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    // None 表示删除
+    pub value: Option<Vec<u8>>,
+    pub version: u64,
+    // unix 毫秒，None 表示不过期
+    pub expire_at: Option<u64>,
+}
+
+// 删除 [start, end) 中版本号小于 version 的记录，end 为空表示没有上界
+#[derive(Clone)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub version: u64,
+}
+
+impl RangeTombstone {
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key >= self.start.as_slice() && (self.end.is_empty() || key < self.end.as_slice())
+    }
+}
+
+// 覆盖 key 的范围删除中最大的版本号
+pub fn deleted_at(ranges: &[RangeTombstone], key: &[u8]) -> Option<u64> {
+    ranges.iter().filter(|range| range.contains(key)).map(|range| range.version).max()
+}
+
+// 被版本号更大的范围删除覆盖的记录换成删除记录
+fn mask(entry: Entry, ranges: &[RangeTombstone]) -> Entry {
+    match deleted_at(ranges, &entry.key) {
+        Some(version) if version > entry.version => Entry { key: entry.key, value: None, version, expire_at: None },
+        _ => entry,
+    }
+}
+
+fn read_u32(b: &[u8], index: usize) -> u32 {
+    let mut n = [0; 4];
+    n.copy_from_slice(&b[index..index + 4]);
+    u32::from_be_bytes(n)
+}
+
+fn read_u64(b: &[u8], index: usize) -> u64 {
+    let mut n = [0; 8];
+    n.copy_from_slice(&b[index..index + 8]);
+    u64::from_be_bytes(n)
+}
+
+fn corrupted(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// 按 key 递增写入，写完调用 finish
+pub struct SsTableWriter {
+    file: BufWriter<File>,
+    block: Vec<u8>,
+    // 写入的第一个 key，没有写入时为 None
+    first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    index: Vec<u8>,
+    ranges: Vec<u8>,
+    // 范围删除覆盖的最小的 start 和最大的 end（没有上界时为 start），没有范围删除时为 None
+    range_bounds: Option<(Vec<u8>, Vec<u8>)>,
+    // 所有 key 的 hash，finish 时构建 bloom
+    hashes: Vec<u64>,
+    offset: u64,
+    compression: Compression,
+    throttle: Option<Arc<IoThrottle>>,
+}
+
+impl SsTableWriter {
+    // throttle 限制写入 data block 的速度
+    pub fn create(path: &str, compression: Compression, throttle: Option<Arc<IoThrottle>>) -> Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            block: Vec::with_capacity(BLOCK_SIZE * 2),
+            first_key: None,
+            last_key: Vec::new(),
+            index: Vec::new(),
+            ranges: Vec::new(),
+            range_bounds: None,
+            hashes: Vec::new(),
+            offset: 0,
+            compression,
+            throttle,
+        })
+    }
+
+    // value 为 None 时写入删除记录
+    pub fn add(&mut self, key: &[u8], value: Option<&[u8]>, expire_at: Option<u64>, version: u64) -> Result<()> {
+        self.block.push(if value.is_some() { KIND_VALUE } else { KIND_TOMBSTONE });
+        push_value(&mut self.block, Some(key), LS);
+        self.block.extend(version.to_be_bytes());
+        if let Some(value) = value {
+            self.block.extend(expire_at.unwrap_or(0).to_be_bytes());
+            push_value(&mut self.block, Some(value), LS);
+        }
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.hashes.push(bloom::hash(key));
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    // 范围删除不进入 data block 和 bloom，可以在任何时候写入
+    pub fn add_range(&mut self, range: &RangeTombstone) {
+        push_value(&mut self.ranges, Some(&range.start), LS);
+        push_value(&mut self.ranges, Some(&range.end), LS);
+        self.ranges.extend(range.version.to_be_bytes());
+        let end = if range.end.is_empty() { &range.start } else { &range.end };
+        self.range_bounds = Some(match self.range_bounds.take() {
+            Some((smallest, largest)) => (smallest.min(range.start.clone()), largest.max(end.clone())),
+            None => (range.start.clone(), end.clone()),
+        });
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let (compression, block) = match self.compression.compress(&self.block) {
+            Some(compressed) => (self.compression, compressed),
+            None => (Compression::None, std::mem::take(&mut self.block)),
+        };
+        if let Some(throttle) = self.throttle.as_ref() {
+            throttle.acquire_blocking(block.len());
+        }
+        self.file.write_all(&block)?;
+        push_value(&mut self.index, Some(&self.last_key), LS);
+        self.index.extend(self.offset.to_be_bytes());
+        self.index.extend((block.len() as u32).to_be_bytes());
+        self.index.extend(crc32fast::hash(&block).to_be_bytes());
+        self.index.push(compression.code());
+        self.offset += block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    // 最小和最大的 key，包括范围删除的边界，没有写入时为 None
+    pub fn key_range(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let keys = self.first_key.clone().map(|first_key| (first_key, self.last_key.clone()));
+        match (keys, self.range_bounds.clone()) {
+            (Some((smallest, largest)), Some((start, end))) => Some((smallest.min(start), largest.max(end))),
+            (keys, bounds) => keys.or(bounds),
+        }
+    }
+
+    // last_version 为写入时已分配的最大版本号
+    pub fn finish(mut self, last_version: u64) -> Result<()> {
+        self.finish_block()?;
+        let bloom = Bloom::build(&self.hashes).encode();
+        let bloom_offset = self.offset;
+        self.file.write_all(&bloom)?;
+        let index_offset = bloom_offset + bloom.len() as u64;
+        self.file.write_all(&self.index)?;
+        let range_offset = index_offset + self.index.len() as u64;
+        self.file.write_all(&self.ranges)?;
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        footer.extend(index_offset.to_be_bytes());
+        footer.extend((self.index.len() as u32).to_be_bytes());
+        footer.extend(crc32fast::hash(&self.index).to_be_bytes());
+        footer.extend(bloom_offset.to_be_bytes());
+        footer.extend((bloom.len() as u32).to_be_bytes());
+        footer.extend(crc32fast::hash(&bloom).to_be_bytes());
+        footer.extend(range_offset.to_be_bytes());
+        footer.extend((self.ranges.len() as u32).to_be_bytes());
+        footer.extend(crc32fast::hash(&self.ranges).to_be_bytes());
+        footer.extend(last_version.to_be_bytes());
+        footer.extend(MAGIC.to_be_bytes());
+        self.file.write_all(&footer)?;
+        self.file.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: usize,
+    crc: u32,
+    compression: Compression,
+}
+
+// 打开时读入 footer、bloom 和 index，data block 按需读取并校验，读过的 block 放入缓存
+// 按位置读取，不共享文件偏移，可以在多个线程同时读
+pub struct SsTable {
+    id: u64,
+    file: File,
+    // 映射整个文件时 data block 从这里拷贝，不再调用 pread
+    map: Option<Mmap>,
+    cache: Arc<BlockCache>,
+    blocks: Vec<BlockHandle>,
+    // 格式版本 1 没有 bloom
+    bloom: Option<Bloom>,
+    // 格式版本 1 和 2 的记录没有 kind
+    legacy_records: bool,
+    // 格式版本 1 到 4 没有范围删除
+    ranges: Vec<RangeTombstone>,
+    last_version: u64,
+}
+
+impl SsTable {
+    // mmap 为 true 时映射整个文件
+    pub fn open(path: &str, cache: Arc<BlockCache>, mmap: bool) -> Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut magic = [0; 8];
+        if file_len < FOOTER_SIZE_V1 as u64 {
+            return Err(corrupted("SSTable too short"));
+        }
+        file.read_exact_at(&mut magic, file_len - 8)?;
+        let magic = u64::from_be_bytes(magic);
+        let footer_size = match magic {
+            MAGIC => FOOTER_SIZE,
+            MAGIC_V4 | MAGIC_V3 | MAGIC_V2 => FOOTER_SIZE_V4,
+            MAGIC_V1 => FOOTER_SIZE_V1,
+            _ => return Err(corrupted("SSTable magic mismatch")),
+        };
+        if file_len < footer_size as u64 {
+            return Err(corrupted("SSTable too short"));
+        }
+        let footer_offset = file_len - footer_size as u64;
+        let mut footer = vec![0; footer_size];
+        file.read_exact_at(&mut footer, footer_offset)?;
+
+        let read_section = |offset: u64, len: u64, crc: u32, name: &str| -> Result<Vec<u8>> {
+            if offset + len > footer_offset {
+                return Err(corrupted(&format!("SSTable {} out of range", name)));
+            }
+            let mut buf = vec![0; len as usize];
+            file.read_exact_at(&mut buf, offset)?;
+            if crc32fast::hash(&buf) != crc {
+                return Err(corrupted(&format!("SSTable {} crc mismatch", name)));
+            }
+            Ok(buf)
+        };
+        let index_offset = read_u64(&footer, 0);
+        let index = read_section(index_offset, read_u32(&footer, 8) as u64, read_u32(&footer, 12), "index")?;
+        let bloom = if footer_size != FOOTER_SIZE_V1 {
+            let bloom = read_section(read_u64(&footer, 16), read_u32(&footer, 24) as u64, read_u32(&footer, 28), "bloom")?;
+            Some(Bloom::decode(&bloom).ok_or_else(|| corrupted("SSTable bloom invalid"))?)
+        } else {
+            None
+        };
+        let ranges = if footer_size == FOOTER_SIZE {
+            let ranges = read_section(read_u64(&footer, 32), read_u32(&footer, 40) as u64, read_u32(&footer, 44), "range")?;
+            decode_ranges(&ranges).ok_or_else(|| corrupted("SSTable range block truncated"))?
+        } else {
+            Vec::new()
+        };
+        let last_version = read_u64(&footer, footer_size - 16);
+
+        // 格式版本 1 到 3 没有 compression
+        let compressed = magic == MAGIC || magic == MAGIC_V4;
+        let handle_size = if compressed { 17 } else { 16 };
+        let mut blocks = Vec::new();
+        let mut i = 0;
+        while i < index.len() {
+            let key_len = match (i + LS <= index.len()).then(|| read_len(&index, i, LS)).flatten() {
+                Some(key_len) if i + LS + key_len + handle_size <= index.len() => key_len,
+                _ => return Err(corrupted("SSTable index truncated")),
+            };
+            let next = i + LS + key_len;
+            let compression = if compressed {
+                Compression::from_code(index[next + 16]).ok_or_else(|| corrupted("SSTable block compression invalid"))?
+            } else {
+                Compression::None
+            };
+            let handle = BlockHandle {
+                last_key: index[i + LS..next].to_vec(),
+                offset: read_u64(&index, next),
+                len: read_u32(&index, next + 8) as usize,
+                crc: read_u32(&index, next + 12),
+                compression,
+            };
+            if handle.offset + handle.len as u64 > index_offset {
+                return Err(corrupted("SSTable block out of range"));
+            }
+            blocks.push(handle);
+            i = next + handle_size;
+        }
+        // SST 文件写完后改名才打开，之后不再修改，删除后映射仍然有效
+        let map = if mmap { Some(unsafe { Mmap::map(&file)? }) } else { None };
+        Ok(Self {
+            id: NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed),
+            file,
+            map,
+            cache,
+            blocks,
+            bloom,
+            legacy_records: magic == MAGIC_V2 || magic == MAGIC_V1,
+            ranges,
+            last_version,
+        })
+    }
+
+    pub fn last_version(&self) -> u64 {
+        self.last_version
+    }
+
+    // 文件中覆盖 key 的范围删除的最大版本号
+    pub fn deleted_at(&self, key: &[u8]) -> Option<u64> {
+        deleted_at(&self.ranges, key)
+    }
+
+    // fill_cache 为 false 时不经过缓存，用于落盘和启动时的整表扫描，避免挤掉热点 block
+    fn read_block(&self, handle: &BlockHandle, fill_cache: bool) -> Result<Block> {
+        if !fill_cache {
+            return self.decode_block(handle).map(Arc::new);
+        }
+        if let Some(block) = self.cache.get(self.id, handle.offset) {
+            return Ok(block);
+        }
+        let block = Arc::new(self.decode_block(handle)?);
+        let charge = handle.len + block.len() * std::mem::size_of::<Entry>();
+        self.cache.insert(self.id, handle.offset, block.clone(), charge);
+        Ok(block)
+    }
+
+    fn decode_block(&self, handle: &BlockHandle) -> Result<Vec<Entry>> {
+        let mut block = vec![0; handle.len];
+        match self.map.as_ref() {
+            Some(map) => block.copy_from_slice(&map[handle.offset as usize..handle.offset as usize + handle.len]),
+            None => self.file.read_exact_at(&mut block, handle.offset)?,
+        }
+        if crc32fast::hash(&block) != handle.crc {
+            return Err(corrupted("SSTable block crc mismatch"));
+        }
+        let block = handle.compression.decompress(block)?;
+        let mut entries = Vec::new();
+        let mut i = 0;
+        while i < block.len() {
+            let kind = if self.legacy_records {
+                None
+            } else {
+                i += 1;
+                Some(block[i - 1])
+            };
+            let key_len = match (i + LS <= block.len()).then(|| read_len(&block, i, LS)).flatten() {
+                Some(key_len) if i + LS + key_len + 8 <= block.len() => key_len,
+                _ => return Err(corrupted("SSTable block truncated")),
+            };
+            let key = block[i + LS..i + LS + key_len].to_vec();
+            i += LS + key_len;
+            let version = read_u64(&block, i);
+            i += 8;
+            match kind {
+                Some(KIND_TOMBSTONE) => {
+                    entries.push(Entry { key, value: None, version, expire_at: None });
+                    continue;
+                }
+                Some(KIND_VALUE) | None => {}
+                Some(_) => return Err(corrupted("SSTable record kind invalid")),
+            }
+            if i + 8 + LS > block.len() {
+                return Err(corrupted("SSTable block truncated"));
+            }
+            let expire_at = Some(read_u64(&block, i)).filter(|expire_at| *expire_at != 0);
+            i += 8;
+            let value = match read_len(&block, i, LS) {
+                Some(value_len) if i + LS + value_len <= block.len() => {
+                    let value = block[i + LS..i + LS + value_len].to_vec();
+                    i += LS + value_len;
+                    Some(value)
+                }
+                Some(_) => return Err(corrupted("SSTable block truncated")),
+                // 格式版本 1 和 2 的删除记录
+                None if kind.is_none() => {
+                    i += LS;
+                    None
+                }
+                None => return Err(corrupted("SSTable value len invalid")),
+            };
+            entries.push(Entry { key, value, version, expire_at });
+        }
+        if entries.last().map(|entry| &entry.key) != Some(&handle.last_key) {
+            return Err(corrupted("SSTable block last key mismatch"));
+        }
+        Ok(entries)
+    }
+
+    // 第一个可能包含 key 的 block
+    fn block_index(&self, key: &[u8]) -> usize {
+        self.blocks.partition_point(|handle| handle.last_key.as_slice() < key)
+    }
+
+    // 最小和最大的 key，最大的 key 在 index 中，只读第一个 block；没有记录时为 None
+    pub fn key_range(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let (Some(first), Some(last)) = (self.blocks.first(), self.blocks.last()) else {
+            return Ok(None);
+        };
+        let block = self.read_block(first, false)?;
+        Ok(block.first().map(|entry| (entry.key.clone(), last.last_key.clone())))
+    }
+
+    // 不经过缓存重新读取并校验每个 data block，返回损坏的 block 数
+    pub fn verify(&self) -> usize {
+        self.blocks.iter().filter(|handle| match self.decode_block(handle) {
+            Ok(_) => false,
+            Err(e) => {
+                warn!("Sst block at {} corrupted, err = {:?}", handle.offset, e);
+                true
+            }
+        }).count()
+    }
+
+    // 只在 index 中二分查找一次，再读一个 block；bloom 判断不存在时不读文件
+    // 被文件中的范围删除覆盖时返回删除记录
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let tombstone = || self.deleted_at(key).map(|version| Entry { key: key.to_vec(), value: None, version, expire_at: None });
+        if self.bloom.as_ref().is_some_and(|bloom| !bloom.may_contain(key)) {
+            return Ok(tombstone());
+        }
+        let Some(handle) = self.blocks.get(self.block_index(key)) else {
+            return Ok(tombstone());
+        };
+        let block = self.read_block(handle, true)?;
+        match block.binary_search_by(|entry| entry.key.as_slice().cmp(key)) {
+            Ok(i) => Ok(Some(mask(block[i].clone(), &self.ranges))),
+            Err(_) => Ok(tombstone()),
+        }
+    }
+
+    // 整表扫描，不经过缓存
+    pub fn iter(&self) -> SsTableIter<'_> {
+        self.iter_at(&[], false)
+    }
+
+    // 从第一个不小于 start 的 key 开始，读过的 block 放入缓存
+    pub fn iter_from(&self, start: &[u8]) -> SsTableIter<'_> {
+        self.iter_at(start, true)
+    }
+
+    // 同 iter_from，但不经过缓存，用于启动时分段扫描
+    pub fn scan_from(&self, start: &[u8]) -> SsTableIter<'_> {
+        self.iter_at(start, false)
+    }
+
+    fn iter_at(&self, start: &[u8], fill_cache: bool) -> SsTableIter<'_> {
+        SsTableIter {
+            table: self,
+            block: self.block_index(start),
+            entries: Arc::new(Vec::new()),
+            index: 0,
+            start: start.to_vec(),
+            fill_cache,
+        }
+    }
+
+    // 按 block 把 key 空间大致均分为最多 parts 段，返回从小到大的分界 key
+    pub fn split_keys(&self, parts: usize) -> Vec<Vec<u8>> {
+        let step = self.blocks.len().div_ceil(parts.max(1)).max(1);
+        self.blocks.iter().step_by(step).skip(1).map(|handle| handle.last_key.clone()).collect()
+    }
+}
+
+// range block 格式见文件开头，数据不完整时返回 None
+fn decode_ranges(buf: &[u8]) -> Option<Vec<RangeTombstone>> {
+    let field = |i: usize| -> Option<(Vec<u8>, usize)> {
+        let len = buf.get(i..i + LS).and_then(|_| read_len(buf, i, LS))?;
+        Some((buf.get(i + LS..i + LS + len)?.to_vec(), i + LS + len))
+    };
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < buf.len() {
+        let (start, next) = field(i)?;
+        let (end, next) = field(next)?;
+        let version = u64::from_be_bytes(buf.get(next..next + 8)?.try_into().ok()?);
+        ranges.push(RangeTombstone { start, end, version });
+        i = next + 8;
+    }
+    Some(ranges)
+}
+
+impl Drop for SsTable {
+    fn drop(&mut self) {
+        self.cache.evict_table(self.id);
+    }
+}
+
+// 按 key 顺序读出记录，损坏的 block 记录日志后跳过
+pub struct SsTableIter<'a> {
+    table: &'a SsTable,
+    // 下一个读取的 block
+    block: usize,
+    entries: Block,
+    // entries 中下一条记录
+    index: usize,
+    start: Vec<u8>,
+    fill_cache: bool,
+}
+
+impl Iterator for SsTableIter<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            if let Some(entry) = self.entries.get(self.index) {
+                self.index += 1;
+                if entry.key < self.start {
+                    continue;
+                }
+                return Some(entry.clone());
+            }
+            let handle = self.table.blocks.get(self.block)?;
+            self.block += 1;
+            match self.table.read_block(handle, self.fill_cache) {
+                Ok(entries) => {
+                    self.entries = entries;
+                    self.index = 0;
+                }
+                Err(e) => warn!("Skip sst block at {}, err = {:?}", handle.offset, e),
+            }
+        }
+    }
+}
+
+// 按 key 合并多个 SST 文件的记录，同一个 key 只返回版本号最大的记录，不论来自哪个文件
+// sources 从新到旧排列，版本号相同时取前面的
+// 被任意一个文件中版本号更大的范围删除覆盖的记录换成删除记录
+pub struct MergeIter<'a> {
+    sources: Vec<Peekable<SsTableIter<'a>>>,
+    ranges: Vec<RangeTombstone>,
+}
+
+impl<'a> MergeIter<'a> {
+    pub fn new(sources: impl IntoIterator<Item = SsTableIter<'a>>) -> Self {
+        let sources: Vec<SsTableIter<'a>> = sources.into_iter().collect();
+        let ranges = sources.iter().flat_map(|source| source.table.ranges.iter().cloned()).collect();
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            ranges,
+        }
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let key = self.sources.iter_mut().filter_map(|source| source.peek().map(|entry| entry.key.clone())).min()?;
+        let mut newest: Option<Entry> = None;
+        for source in self.sources.iter_mut() {
+            if let Some(entry) = source.next_if(|entry| entry.key == key) {
+                if newest.as_ref().is_none_or(|newest| entry.version > newest.version) {
+                    newest = Some(entry);
+                }
+            }
+        }
+        newest.map(|entry| mask(entry, &self.ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    // (key, value, version)，value 为 None 表示删除
+    type Record = (&'static [u8], Option<&'static [u8]>, u64);
+
+    const RECORDS: [Record; 3] = [(b"a", Some(b"1"), 1), (b"b", None, 2), (b"c", Some(b"3"), 3)];
+
+    fn cache() -> Arc<BlockCache> {
+        Arc::new(BlockCache::new(1 << 20))
+    }
+
+    // 按格式版本 1 到 4 写一个只有一个 data block 的文件，格式见文件开头
+    fn write_legacy(path: &str, version: u64, records: &[Record]) {
+        let mut block = Vec::new();
+        for (key, value, v) in records {
+            if version >= 3 {
+                block.push(if value.is_some() { KIND_VALUE } else { KIND_TOMBSTONE });
+            }
+            push_value(&mut block, Some(key), LS);
+            block.extend(v.to_be_bytes());
+            if version < 3 || value.is_some() {
+                block.extend(0u64.to_be_bytes());
+                push_value(&mut block, *value, LS);
+            }
+        }
+        let mut file = block.clone();
+        let mut index = Vec::new();
+        push_value(&mut index, Some(records.last().unwrap().0), LS);
+        index.extend(0u64.to_be_bytes());
+        index.extend((block.len() as u32).to_be_bytes());
+        index.extend(crc32fast::hash(&block).to_be_bytes());
+        if version >= 4 {
+            index.push(Compression::None.code());
+        }
+        let bloom_offset = file.len() as u64;
+        let bloom = Bloom::build(&records.iter().map(|(key, _, _)| bloom::hash(key)).collect::<Vec<_>>()).encode();
+        if version >= 2 {
+            file.extend(&bloom);
+        }
+        let index_offset = file.len() as u64;
+        file.extend(&index);
+        file.extend(index_offset.to_be_bytes());
+        file.extend((index.len() as u32).to_be_bytes());
+        file.extend(crc32fast::hash(&index).to_be_bytes());
+        if version >= 2 {
+            file.extend(bloom_offset.to_be_bytes());
+            file.extend((bloom.len() as u32).to_be_bytes());
+            file.extend(crc32fast::hash(&bloom).to_be_bytes());
+        }
+        file.extend(records.iter().map(|(_, _, v)| *v).max().unwrap().to_be_bytes());
+        file.extend((MAGIC_V1 - 1 + version).to_be_bytes());
+        std::fs::write(path, file).unwrap();
+    }
+
+    fn check_records(table: &SsTable) {
+        for (key, value, version) in RECORDS {
+            let entry = table.get(key).unwrap().unwrap();
+            assert_eq!((entry.value.as_deref(), entry.version), (value, version));
+        }
+        let keys: Vec<Vec<u8>> = table.iter().map(|entry| entry.key).collect();
+        assert_eq!(keys, [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(table.get(b"d").unwrap().map(|entry| entry.key), None);
+        assert_eq!(table.last_version(), 3);
+    }
+
+    // 旧版本的文件仍能按当前的代码读取
+    #[test]
+    fn read_legacy_versions() {
+        let dir = temp_path("sst-legacy");
+        for version in 1..=4 {
+            let path = format!("{}/v{}", dir, version);
+            write_legacy(&path, version, &RECORDS);
+            let table = SsTable::open(&path, cache(), false).unwrap();
+            assert_eq!(table.bloom.is_some(), version >= 2);
+            assert_eq!(table.legacy_records, version <= 2);
+            check_records(&table);
+        }
+    }
+
+    // 当前版本写入后读回，data block 压缩，范围删除遮住更旧的记录
+    #[test]
+    fn round_trip() {
+        let dir = temp_path("sst-round-trip");
+        for compression in [Compression::None, Compression::Snappy, Compression::Lz4] {
+            let path = format!("{}/{:?}", dir, compression);
+            let mut writer = SsTableWriter::create(&path, compression, None).unwrap();
+            for (key, value, version) in RECORDS {
+                writer.add(key, value, None, version).unwrap();
+            }
+            // 足够多的可压缩记录，写满多个 block
+            let value = b"text text text text text text text text".repeat(4);
+            for i in 0..200 {
+                writer.add(format!("k{:04}", i).as_bytes(), Some(&value), Some(u64::MAX), 10).unwrap();
+            }
+            writer.add_range(&RangeTombstone { start: b"k0100".to_vec(), end: b"k0150".to_vec(), version: 11 });
+            writer.finish(11).unwrap();
+
+            let table = SsTable::open(&path, cache(), false).unwrap();
+            assert!(table.blocks.len() > 1);
+            assert!(table.blocks.iter().all(|handle| handle.compression == compression));
+            assert_eq!(table.verify(), 0);
+            for (key, value, version) in RECORDS {
+                let entry = table.get(key).unwrap().unwrap();
+                assert_eq!((entry.value.as_deref(), entry.version), (value, version));
+            }
+            let entry = table.get(b"k0099").unwrap().unwrap();
+            assert_eq!((entry.value, entry.expire_at), (Some(value.clone()), Some(u64::MAX)));
+            assert_eq!(table.get(b"k0100").unwrap().map(|entry| (entry.value, entry.version)), Some((None, 11)));
+            assert!(table.get(b"k0150").unwrap().unwrap().value.is_some());
+            assert_eq!(table.iter().count(), RECORDS.len() + 200);
+            assert_eq!(table.last_version(), 11);
+        }
+    }
+
+    // bloom 判断不存在的 key 不读 data block
+    #[test]
+    fn bloom_miss() {
+        let path = format!("{}/sst", temp_path("sst-bloom"));
+        let mut writer = SsTableWriter::create(&path, Compression::Snappy, None).unwrap();
+        for i in 0..100 {
+            writer.add(format!("k{:04}", i).as_bytes(), Some(b"v"), None, 1).unwrap();
+        }
+        writer.finish(1).unwrap();
+        let cache = cache();
+        let table = SsTable::open(&path, cache.clone(), false).unwrap();
+        let bloom = table.bloom.as_ref().unwrap();
+        let absent = (0..).map(|i| format!("k{:04}x", i)).find(|key| !bloom.may_contain(key.as_bytes())).unwrap();
+        assert!(table.get(absent.as_bytes()).unwrap().is_none());
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+        assert!(table.get(b"k0042").unwrap().is_some());
+        assert_eq!(cache.misses(), 1);
+    }
+}
@@ -0,0 +1,2372 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::fs::{create_dir_all, hard_link, remove_file, rename};
+use std::io::ErrorKind;
+use std::iter::once;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use tokio::fs::{File, try_exists};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+use crate::cache::BlockCache;
+use crate::event::{push_value, read_len, LEN_MASK, LONG_LEN_SIZE, SHORT_LEN_SIZE};
+use crate::glob::Pattern;
+use crate::manifest::{Edit, FileSet, Manifest, TableFile};
+use crate::sstable::{Compression, MergeIter, RangeTombstone, SsTable, SsTableWriter};
+use crate::throttle::IoThrottle;
+use crate::memtable::{Memtable, MemtableKind};
+use crate::replication::{Replicated, ReplicationSink};
+use crate::utils::now_millis;
+
+// WAL 文件和 SST 文件按编号命名，哪些文件有效由 MANIFEST 记录
+const WAL_FILE_PREFIX: &str = "WAL_";
+const SST_FILE_PREFIX: &str = "SST_";
+// 落盘得到的 SST 文件在第 0 层，保留删除记录，文件之间 key 的范围可能重叠
+// 第 0 层的文件达到 L0_COMPACTION_TRIGGER 个时和第 1 层一起合并为新的第 1 层文件
+// 第 1 层是最底层，只有一个文件，下面没有更旧的数据，合并时删除记录不再写入
+const L0: u8 = 0;
+const BOTTOM_LEVEL: u8 = 1;
+const L0_COMPACTION_TRIGGER: usize = 4;
+
+// 旧版本的文件，INDEX 文件中的一个字节选择当前使用哪一组；启动时转换为 MANIFEST 管理的文件
+const LEGACY_WAL_FILE_PREFIX: &str = "WAL_FILE_";
+const LEGACY_SST_FILE_PREFIX: &str = "SST_FILE_";
+// 更旧版本落盘的文件，内容与 WAL 格式相同
+const LEGACY_LOG_FILE_PREFIX: &str = "LOG_FILE_";
+const LEGACY_INDEX_FILE: &str = "INDEX";
+const LEGACY_FILE_BATCH: u64 = 2;
+
+// WAL 文件以 WAL_MAGIC 开头，之后每次写入是一帧，回放时遇到不完整或校验失败的帧停止
+// 4 bit payload len
+// 4 bit crc32 of payload
+// n bit payload: 一次写入的若干条记录
+// 旧版本的 WAL 文件没有 WAL_MAGIC 和帧，直接是记录；首字节 0xff 不是合法的记录
+const WAL_MAGIC: [u8; 8] = [0xff, b'L', b'S', b'M', b'W', b'A', b'L', 1];
+const WAL_FRAME_HEAD: usize = 8;
+
+// 普通记录以 key 长度开头，最高位恒为 0（见 LEN_MASK）
+// 最高位为 1 的首字节表示带类型的记录
+const REC_TAG: u8 = 0x80;
+// 批量记录，整体生效
+const REC_BATCH: u8 = 0x80;
+// 带过期时间的记录
+const REC_EXPIRE: u8 = 0x81;
+// 追加记录，value 只有追加的部分
+const REC_APPEND: u8 = 0x82;
+// 长记录，长度字段为 4 bit，可以单独出现，也可以出现在其他记录中代替普通记录
+const REC_LONG: u8 = 0x83;
+// 记录数超过 2 bit 的批量记录，记录数为 4 bit
+const REC_BATCH_LONG: u8 = 0x84;
+// 带版本号的记录，版本号作用于后面一条记录中所有的 key；没有版本号的旧记录按回放顺序分配
+const REC_VERSION: u8 = 0x85;
+// 已分配的最大版本号，单独出现
+const REC_LAST_VERSION: u8 = 0x86;
+// 删除记录，没有 value 字段，可以单独出现，也可以出现在批量记录中；旧版本的删除记录是 value 长度为 NONE 的普通记录
+const REC_DELETE: u8 = 0x87;
+// 范围删除，单独出现，整个范围只有一条记录
+const REC_DELETE_RANGE: u8 = 0x88;
+
+// 内存表检查点，WAL 换段后写入，见 write_checkpoint
+const CHECKPOINT_FILE: &str = "CHECKPOINT";
+
+// 不再被 MANIFEST 引用的文件至少保留 OBSOLETE_FILE_GRACE 才删除，给正在读取的快照等留出时间
+const OBSOLETE_FILE_GRACE: Duration = Duration::from_secs(10);
+
+// 反复覆盖同一批 key 时内存表不大但 WAL 一直增长，内存表对应的 WAL 段总共超过 64M 时也落盘
+const MEMTABLE_WAL_LIMIT: u64 = 1024 * 1024 * 64;
+
+// 默认 block 缓存 8M
+pub const DEFAULT_BLOCK_CACHE_BYTES: usize = 1024 * 1024 * 8;
+// 默认内存表超过 32M 时落盘
+pub const DEFAULT_MEMTABLE_BYTES: usize = 1024 * 1024 * 32;
+// 默认 WAL 段超过 8M 时换新的段
+pub const DEFAULT_WAL_SEGMENT_BYTES: u64 = 1024 * 1024 * 8;
+// Durability::Interval 默认每秒 sync 一次
+pub const DEFAULT_DURABILITY_INTERVAL_MS: u64 = 1000;
+// 默认排队落盘的只读内存表达到 4 个，或第 0 层文件达到 12 个时暂停写入
+pub const DEFAULT_MAX_IMMUTABLE_MEMTABLES: usize = 4;
+pub const DEFAULT_L0_STOP_TRIGGER: usize = 12;
+
+// WAL 写入后何时 sync
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    // 每次写入后 sync
+    Always,
+    // 由事件循环按间隔 sync，崩溃时最多丢失一个间隔内的写入
+    Interval(Duration),
+    // 交给操作系统，只在换段和清空时 sync
+    Never,
+}
+
+// 存储引擎的配置，同一进程中的所有命名空间共用
+#[derive(Clone)]
+pub struct StorageOptions {
+    // SST 文件的 block 缓存，所有 SST 文件共用
+    pub block_cache: Arc<BlockCache>,
+    // 内存表的实现
+    pub memtable: MemtableKind,
+    // 内存表占用的堆内存超过该字节数时落盘，见 Memtable::memory
+    pub memtable_bytes: usize,
+    // WAL 段超过该字节数时换新的段，落盘完成后删除内容都已写入 SST 文件的段
+    pub wal_segment_bytes: u64,
+    pub durability: Durability,
+    // 每层 SST 文件 data block 的压缩方式，层数超过长度时沿用最后一个，为空时不压缩
+    pub compression: Vec<Compression>,
+    // SST 文件映射到内存读取，data block 不再逐个 pread
+    pub mmap: bool,
+    // 落盘和合并写 SST 文件的限速，None 表示不限速
+    pub io_throttle: Option<Arc<IoThrottle>>,
+    // 每层 SST 文件所在的目录，层数超过长度时沿用最后一个，为空时和 WAL 一起放在数据目录
+    pub level_paths: Vec<String>,
+    // 内存表中最早的写入超过该时长时落盘，限制崩溃后需要回放的 WAL；None 表示只按大小落盘
+    pub memtable_max_age: Option<Duration>,
+    // 只读内存表或第 0 层文件达到上限时写入返回忙，等待落盘和合并追上，见 write_stalled
+    pub max_immutable_memtables: usize,
+    pub l0_stop_trigger: usize,
+    // WAL 换段时把所有内存表写入检查点，启动时恢复检查点，只回放之后的 WAL 段
+    pub checkpoint: bool,
+    // 每次写入后把快照发布到这里，连接任务不经过事件循环直接读取；只用于默认命名空间，打开其他命名空间时为 None
+    pub shared_view: Option<SharedView>,
+    // 主节点把每次写入发到复制日志，见 replication.rs；打开其他命名空间时换成对应命名空间的
+    pub replication: Option<ReplicationSink>,
+}
+
+// StorageOptions 中运行中可以修改的项，SIGHUP 重新读取配置文件后由事件循环应用到所有已打开的命名空间
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tunables {
+    pub memtable_bytes: usize,
+    pub wal_segment_bytes: u64,
+    pub durability: Durability,
+    pub memtable_max_age: Option<Duration>,
+    pub max_immutable_memtables: usize,
+    pub l0_stop_trigger: usize,
+}
+
+impl StorageOptions {
+    pub fn tune(&mut self, tunables: &Tunables) {
+        self.memtable_bytes = tunables.memtable_bytes;
+        self.wal_segment_bytes = tunables.wal_segment_bytes;
+        self.durability = tunables.durability;
+        self.memtable_max_age = tunables.memtable_max_age;
+        self.max_immutable_memtables = tunables.max_immutable_memtables;
+        self.l0_stop_trigger = tunables.l0_stop_trigger;
+    }
+}
+
+// 最近一次写入后的只读快照，事件循环写入、连接任务读取；还没有打开存储引擎时为 None
+// 发布的快照引用的 SST 文件在下一次发布前不会删除
+#[derive(Clone, Default)]
+pub struct SharedView(Arc<RwLock<Option<Arc<LsmSnapshot>>>>);
+
+impl SharedView {
+    pub fn load(&self) -> Option<Arc<LsmSnapshot>> {
+        self.0.read().expect("Lock shared view fail").clone()
+    }
+
+    fn store(&self, snapshot: LsmSnapshot) {
+        *self.0.write().expect("Lock shared view fail") = Some(Arc::new(snapshot));
+    }
+}
+
+impl StorageOptions {
+    fn compression(&self, level: u8) -> Compression {
+        self.compression.get(level as usize).or(self.compression.last()).copied().unwrap_or(Compression::None)
+    }
+
+    fn sst_dir<'a>(&'a self, data_path: &'a str, level: u8) -> &'a str {
+        self.level_paths.get(level as usize).or(self.level_paths.last()).map_or(data_path, String::as_str)
+    }
+}
+
+// 存储引擎的统计信息
+pub struct StorageStats {
+    pub keys: u64,
+    // 内存表中 key 和 value 的总字节数
+    pub memtable_bytes: u64,
+    // 可写的和等待落盘的内存表占用的堆内存
+    pub memtable_memory: u64,
+    // 所有 WAL 文件的总大小
+    pub wal_bytes: u64,
+    // block 缓存的命中和未命中次数，所有命名空间共用
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+}
+
+pub struct ScrubReport {
+    // 校验过的文件数
+    pub files: u32,
+    // 有损坏的文件
+    pub corrupt: Vec<String>,
+}
+
+// 未过期 key 的元数据
+#[derive(Debug)]
+pub struct KeyMeta {
+    pub len: u64,
+    pub version: u64,
+    // unix 毫秒，None 表示不过期
+    pub expire_at: Option<u64>,
+}
+
+// 只读的数据视图，存储引擎和它的快照都实现
+pub trait ReadView {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    // 未过期的 key 最后一次写入的版本号，不存在时为 0；每次写入分配的版本号都比之前的大，删除也分配
+    fn version(&self, key: &[u8]) -> u64;
+
+    // 不拷贝 value 的元数据，不存在时为 None
+    fn meta(&self, key: &[u8]) -> Option<KeyMeta>;
+
+    // 不拷贝 value 的存在性判断
+    fn contains(&self, key: &[u8]) -> bool;
+
+    // key 的前缀中存在的最长的一个和它的 value，key 本身也算前缀；都不存在时为 None
+    fn longest_prefix(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    // 范围读取返回开始读取时的一致视图：事件循环中同步执行，期间不会有写入；所有来源按同一个时间判断过期
+    // [start, end) 范围内的数据，按 key 排序；end 为空表示没有上界
+    fn scan(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+    // 以 prefix 开头的数据，按 key 排序；with_values 为 false 时 value 为空
+    fn prefix(&self, prefix: &[u8], with_values: bool) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+    // 匹配 glob 模式的 key，按 key 排序；模式语法见 glob::Pattern
+    fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>>;
+
+    // 视图中已分配的最大版本号
+    fn last_version(&self) -> u64;
+}
+
+// 存储引擎的实现，由配置选择
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineKind {
+    // LsmStorage，数据写入 WAL 和 SST 文件
+    Lsm,
+    // MemStorage，只在内存中
+    Memory,
+}
+
+impl EngineKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "lsm" => Some(EngineKind::Lsm),
+            "memory" => Some(EngineKind::Memory),
+            _ => None,
+        }
+    }
+}
+
+// 存储引擎，EventHandler 只通过它和 LsmExt 访问数据；这里只有读写数据的操作，任何引擎都要实现
+pub trait StorageEngine: ReadView {
+    type Snapshot: ReadView;
+
+    // 打开 data_path 下的数据，目录不存在时创建
+    async fn open(data_path: String, options: StorageOptions) -> Self;
+
+    // 打开时的配置，打开其他命名空间时沿用
+    fn options(&self) -> &StorageOptions;
+
+    // 打开快照：之后的读取从快照进行时看不到快照之后的写入，快照释放前它引用的数据不会因为合并而丢失
+    async fn snapshot(&mut self) -> Self::Snapshot;
+
+    // key 的数量，O(1)
+    fn size(&self) -> usize;
+
+    async fn put(&mut self, key: Vec<u8>, value: Vec<u8>);
+
+    // expire_at 为 unix 毫秒，过期后视为不存在
+    async fn put_expire(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: u64);
+
+    async fn delete(&mut self, key: Vec<u8>);
+
+    // 删除 [start, end) 中所有的 key，end 为空表示没有上界；只写一条范围删除记录，返回删除的 key 的数量
+    async fn delete_range(&mut self, start: Vec<u8>, end: Vec<u8>) -> usize;
+
+    // 追加到 value 后，不存在时创建；返回新长度
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> usize;
+
+    // 原子写入一批数据，None 表示删除
+    async fn write_batch(&mut self, entries: Vec<(Vec<u8>, Option<Vec<u8>>)>);
+
+    // 清空所有数据，包括已落盘的
+    async fn clear(&mut self);
+
+    async fn stats(&self) -> StorageStats;
+
+    // 删除 now 之前过期的 key 并写入删除记录，最多 limit 个；返回删除的 key
+    async fn remove_expired(&mut self, now: u64, limit: usize) -> Vec<Vec<u8>>;
+}
+
+// WAL、内存表落盘、SST 文件合并和复制相关的操作，LsmStorage 实现
+// 没有这些结构的引擎使用默认实现：没有需要落盘和校验的数据，不支持复制，启动时检查
+pub trait LsmExt: StorageEngine {
+    // 修改运行中可以修改的配置，之后的写入、落盘和暂停写入的判断按新配置进行
+    fn tune(&mut self, _tunables: &Tunables) {}
+
+    // 把上次 sync 之后写入的 WAL 刷到磁盘，Durability::Interval 时由事件循环定时调用
+    async fn sync_wal(&mut self) {}
+
+    // 把内存数据落盘
+    async fn flush(&mut self) {}
+
+    // 内存表中最早的写入超过 memtable_max_age 时落盘，由事件循环定时调用
+    async fn flush_aged(&mut self) {}
+
+    // 落盘或合并跟不上写入时返回 true，此时应拒绝写入
+    async fn write_stalled(&mut self) -> bool {
+        false
+    }
+
+    // 删除已过保留时间且没有其他引用的旧文件，由事件循环定时调用
+    async fn collect_garbage(&mut self) {}
+
+    // 重新读取所有 SST 文件和已写完的 WAL 段并校验，只报告不修改
+    async fn scrub(&mut self) -> ScrubReport {
+        ScrubReport { files: 0, corrupt: Vec::new() }
+    }
+
+    // 把内存数据落盘，并立即合并与 [start, end) 重叠的 SST 文件，end 为空表示没有上界；完成后返回合并的文件数
+    async fn compact(&mut self, _start: &[u8], _end: &[u8]) -> usize {
+        0
+    }
+
+    // 按 key 严格递增的 entries 直接生成 SST 文件，不写 WAL；返回其中之前不存在的 key 的数量
+    // 默认作为一批写入
+    async fn ingest(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> usize {
+        let added = entries.iter().filter(|(key, _)| !self.contains(key)).count();
+        self.write_batch(entries.into_iter().map(|(key, value)| (key, Some(value))).collect()).await;
+        added
+    }
+
+    // 从节点回放主节点的一条复制记录，已回放过的跳过
+    async fn replay(&mut self, _record: Replicated) {
+        warn!("Storage engine does not support replication, skip record");
+    }
+
+    // 所有未过期的 key 各编码成一条 WAL 记录，带原来的版本号和过期时间，用于 Raft 快照
+    fn export(&self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+// 默认存储引擎：内存表 + WAL 文件 + SST 文件
+// 内存表只包含上次转为只读之后的写入，读取时依次查找内存表、只读的内存表和 SST 文件
+pub struct LsmStorage {
+    data_path: String,
+    options: StorageOptions,
+    memtable: Box<dyn Memtable>,
+    // 只读的内存表，从旧到新排列，落盘完成前仍然从它们读取；最旧的一个正在落盘
+    imms: VecDeque<Immutable>,
+    // 后台落盘任务，完成后得到最旧的只读内存表的第 0 层 SST 文件，内存表为空时没有文件
+    flushing: Option<JoinHandle<Option<LiveTable>>>,
+    // 后台合并任务
+    compacting: Option<Compaction>,
+    // 从新到旧排列：第 0 层按编号从大到小，然后是第 1 层
+    tables: Vec<LiveTable>,
+    // key 的数量，已过期但未删除的 key 也计入
+    keys: usize,
+    // 正在写入的 WAL 段，即 files.wals 中的最后一个
+    wal: File,
+    // 正在写入的 WAL 段的字节数
+    wal_bytes: u64,
+    // 正在写入的 WAL 段有没有 sync 的内容
+    wal_dirty: bool,
+    // 当前内存表对应的所有 WAL 段的字节数
+    memtable_wal_bytes: u64,
+    // 当前内存表第一次写入的时间，没有写入时为 None
+    memtable_since: Option<Instant>,
+    // 上次检查时是否暂停写入，只在状态变化时打日志
+    stalled: bool,
+    // 已从 MANIFEST 中移除、等待删除的文件
+    obsolete: Vec<ObsoleteFile>,
+    manifest: Manifest,
+    // MANIFEST 中记录的有效文件
+    files: FileSet,
+    // 过期时间的小顶堆，key 重新写入后旧的条目在弹出时跳过
+    expirations: BinaryHeap<Reverse<(u64, Vec<u8>)>>,
+    // WAL 换段后等待写入检查点
+    checkpoint_due: bool,
+    // 后台写入检查点的任务
+    checkpointing: Option<JoinHandle<()>>,
+}
+
+// 一组用于读取的数据：内存表、从旧到新的只读内存表和从新到旧的 SST 文件
+// 快照没有可写的内存表
+struct View<'a> {
+    memtable: Option<&'a dyn Memtable>,
+    imms: &'a VecDeque<Immutable>,
+    tables: &'a [LiveTable],
+}
+
+impl View<'_> {
+    // key 最新的记录：value（不论是否过期）、过期时间、版本号，value 为 None 表示已删除
+    // 覆盖 key 的范围删除视为版本号为范围删除的版本号的删除记录
+    fn lookup<T>(&self, key: &[u8], f: impl FnOnce(Option<&[u8]>, Option<u64>, u64) -> T) -> Option<T> {
+        for memtable in self.memtables().rev() {
+            if let Some((value, expire_at, version)) = memtable.record(key) {
+                return Some(f(value, expire_at, version));
+            }
+            if let Some(version) = memtable.deleted_at(key) {
+                return Some(f(None, None, version));
+            }
+        }
+        for LiveTable { meta, table, .. } in self.tables.iter() {
+            // 没有上界的范围删除可能覆盖 largest 之后的 key
+            if (key < meta.smallest.as_slice() || key > meta.largest.as_slice()) && table.deleted_at(key).is_none() {
+                continue;
+            }
+            if let Some(entry) = table.get(key).expect("Read sst file fail") {
+                return Some(f(entry.value.as_deref(), entry.expire_at, entry.version));
+            }
+        }
+        None
+    }
+
+    // 从旧到新的内存表，可写的内存表在最后
+    fn memtables(&self) -> impl DoubleEndedIterator<Item = &dyn Memtable> {
+        self.imms.iter().map(|imm| imm.memtable.as_ref()).chain(self.memtable)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lookup(key, |value, expire_at, _| live(value, expire_at, now_millis()).map(|value| value.to_vec())).flatten()
+    }
+
+    fn version(&self, key: &[u8]) -> u64 {
+        self.lookup(key, |value, expire_at, version| if live(value, expire_at, now_millis()).is_some() { version } else { 0 }).unwrap_or(0)
+    }
+
+    fn meta(&self, key: &[u8]) -> Option<KeyMeta> {
+        self.lookup(key, |value, expire_at, version| live(value, expire_at, now_millis()).map(|value| KeyMeta {
+            len: value.len() as u64,
+            version,
+            expire_at,
+        })).flatten()
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.lookup(key, |value, expire_at, _| live(value, expire_at, now_millis()).is_some()).unwrap_or(false)
+    }
+
+    // 只有可写的内存表时直接交给它；否则较新的删除可能遮住较旧的前缀，从长到短逐个查找
+    // SST 文件中不存在的前缀大多由 bloom 过滤，不读文件
+    fn longest_prefix(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let now = now_millis();
+        if let (Some(memtable), true, true) = (self.memtable, self.imms.is_empty(), self.tables.is_empty()) {
+            return memtable.longest_prefix(key, now).map(|(len, value)| (key[..len].to_vec(), value.to_vec()));
+        }
+        (0..=key.len()).rev().find_map(|len| {
+            let value = self.lookup(&key[..len], |value, expire_at, _| live(value, expire_at, now).map(<[u8]>::to_vec)).flatten()?;
+            Some((key[..len].to_vec(), value))
+        })
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let now = now_millis();
+        let table = MergeIter::new(self.tables.iter().map(|live| live.table.iter_from(start)))
+            .take_while(|entry| end.is_empty() || entry.key.as_slice() < end)
+            .map(|entry| {
+                let value = live(entry.value.as_deref(), entry.expire_at, now).map(|value| value.to_vec());
+                (entry.key, value)
+            })
+            .collect();
+        let memtables = self.memtables().map(|memtable| (memtable.scan(start, end, now), memtable.ranges()));
+        merge_records(once((table, &[][..])).chain(memtables))
+    }
+
+    fn prefix(&self, prefix: &[u8], with_values: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let now = now_millis();
+        let table = MergeIter::new(self.tables.iter().map(|live| live.table.iter_from(prefix)))
+            .take_while(|entry| entry.key.starts_with(prefix))
+            .map(|entry| {
+                let value = live(entry.value.as_deref(), entry.expire_at, now).map(|value| if with_values { value.to_vec() } else { Vec::new() });
+                (entry.key, value)
+            })
+            .collect();
+        let memtables = self.memtables().map(|memtable| (memtable.prefix(prefix, with_values, now), memtable.ranges()));
+        merge_records(once((table, &[][..])).chain(memtables))
+    }
+
+    fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        let pattern = Pattern::parse(pattern);
+        let literal_prefix = pattern.literal_prefix();
+        let now = now_millis();
+        let table = MergeIter::new(self.tables.iter().map(|live| live.table.iter_from(&literal_prefix)))
+            .take_while(|entry| entry.key.starts_with(&literal_prefix))
+            .filter(|entry| pattern.matches(&entry.key))
+            .map(|entry| {
+                let value = live(entry.value.as_deref(), entry.expire_at, now).map(|_| ());
+                (entry.key, value)
+            })
+            .collect();
+        let memtables = self.memtables().map(|memtable| {
+            let records = memtable.keys(&pattern, now).into_iter().map(|(key, live)| (key, live.then_some(()))).collect();
+            (records, memtable.ranges())
+        });
+        merge_records(once((table, &[][..])).chain(memtables)).into_iter().map(|(key, _)| key).collect()
+    }
+
+    // 按 key 顺序遍历 [start, end) 中未删除的 key：key, value（不论是否过期）, 过期时间，end 为空表示没有上界
+    // SST 文件逐条读取，不经过 block 缓存，只有内存表中范围内的记录整体读入内存
+    fn for_each_record(&self, start: &[u8], end: &[u8], f: &mut impl FnMut(&[u8], &[u8], Option<u64>)) {
+        // 内存表比所有 SST 文件都新，较新的内存表覆盖较旧的，范围删除遮住更旧的内存表
+        let mut memory = BTreeMap::new();
+        let mut ranges = Vec::new();
+        for memtable in self.memtables() {
+            for range in memtable.ranges() {
+                memory.retain(|key: &Vec<u8>, _| !range.contains(key));
+            }
+            ranges.extend(memtable.ranges().iter().cloned());
+            for (key, (value, expire_at, _)) in memtable.range(start, end) {
+                memory.insert(key, (value.map(<[u8]>::to_vec), expire_at));
+            }
+        }
+        let mut memory = memory.into_iter().peekable();
+        let mut tables = MergeIter::new(self.tables.iter().map(|live| live.table.scan_from(start)))
+            .take_while(|entry| end.is_empty() || entry.key.as_slice() < end)
+            .peekable();
+        loop {
+            // 同一个 key 以内存表为准
+            let from_memory = match (memory.peek(), tables.peek()) {
+                (None, None) => break,
+                (Some((key, _)), Some(entry)) => *key <= entry.key,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+            };
+            let (key, value, expire_at) = if from_memory {
+                let (key, (value, expire_at)) = memory.next().expect("No memtable record");
+                tables.next_if(|entry| entry.key == key);
+                (key, value, expire_at)
+            } else {
+                let entry = tables.next().expect("No sst record");
+                if ranges.iter().any(|range| range.contains(&entry.key)) {
+                    continue;
+                }
+                (entry.key, entry.value, entry.expire_at)
+            };
+            if let Some(value) = value {
+                f(&key, &value, expire_at);
+            }
+        }
+    }
+}
+
+// 快照共享打开时的内存表，之后的写入只复制被修改的路径，不影响快照；只读内存表和 SST 文件直接引用
+// 它们落盘或合并后文件被删除，但快照持有的 SsTable 仍然打开，释放快照后才关闭
+pub struct LsmSnapshot {
+    memtable: Box<dyn Memtable>,
+    imms: VecDeque<Immutable>,
+    tables: Vec<LiveTable>,
+}
+
+impl LsmSnapshot {
+    fn view(&self) -> View<'_> {
+        View { memtable: Some(self.memtable.as_ref()), imms: &self.imms, tables: &self.tables }
+    }
+}
+
+// 打开的 SST 文件和它在 MANIFEST 中的描述
+#[derive(Clone)]
+struct LiveTable {
+    meta: TableFile,
+    path: String,
+    table: Arc<SsTable>,
+}
+
+// 只读的内存表，等待落盘或正在落盘
+#[derive(Clone)]
+struct Immutable {
+    memtable: Arc<dyn Memtable>,
+    // 转为只读时新建的 WAL 文件编号，更早的 WAL 文件只包含这个和更早的内存表的数据
+    wal: u64,
+}
+
+struct Compaction {
+    // 参与合并的 SST 文件编号
+    inputs: Vec<u64>,
+    // 完成后得到第 1 层的新文件，合并后为空时没有文件；以及丢弃的已过期的 key
+    handle: JoinHandle<(Option<LiveTable>, Vec<Vec<u8>>)>,
+}
+
+// 等待删除的文件
+struct ObsoleteFile {
+    path: String,
+    // SST 文件，还有其他引用时不删除
+    table: Option<Arc<SsTable>>,
+    since: Instant,
+}
+
+// 2 bit key length
+// n bit key
+// 2 bit value length; if 65535 value None
+// n bit value
+// key 或 value 超过 LEN_MASK 时写长记录，长度字段为 4 bit
+// 1 bit REC_LONG
+// 4 bit key length
+// n bit key
+// 4 bit value length; if NONE value None
+// n bit value
+// value 为 None 时写删除记录
+// 1 bit REC_DELETE
+// 4 bit key length
+// n bit key
+fn encode_record(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let Some(value) = value else {
+        let mut buf = vec![REC_DELETE];
+        push_value(&mut buf, Some(key), LONG_LEN_SIZE);
+        return buf;
+    };
+    let long = key.len() > LEN_MASK as usize || value.len() > LEN_MASK as usize;
+    let mut buf = Vec::new();
+    let ls = if long {
+        buf.push(REC_LONG);
+        LONG_LEN_SIZE
+    } else {
+        SHORT_LEN_SIZE
+    };
+    push_value(&mut buf, Some(key), ls);
+    push_value(&mut buf, Some(value), ls);
+    buf
+}
+
+// key, value, 下一条记录的位置
+type Record<'a> = (&'a [u8], Option<Vec<u8>>, usize);
+
+// 普通记录、长记录或删除记录，格式见 encode_record
+// 返回 key, value 和下一条记录的位置，数据不完整时返回 None
+fn decode_record(buf: &[u8], index: usize) -> Option<Record<'_>> {
+    let len = buf.len();
+    if index >= len {
+        return None;
+    }
+    if buf[index] == REC_DELETE {
+        let index = index + 1;
+        let key_len = buf.get(index..index + LONG_LEN_SIZE).and_then(|_| read_len(buf, index, LONG_LEN_SIZE))?;
+        let key = buf.get(index + LONG_LEN_SIZE..index + LONG_LEN_SIZE + key_len)?;
+        return Some((key, None, index + LONG_LEN_SIZE + key_len));
+    }
+    let (index, ls) = if buf[index] == REC_LONG {
+        (index + 1, LONG_LEN_SIZE)
+    } else {
+        (index, SHORT_LEN_SIZE)
+    };
+    if index + ls > len {
+        return None;
+    }
+    let key_len = read_len(buf, index, ls).unwrap_or(0);
+    if index + ls + key_len + ls > len {
+        return None;
+    }
+    let key = &buf[index + ls..index + ls + key_len];
+    let value_len = match read_len(buf, index + ls + key_len, ls) {
+        Some(value_len) => value_len,
+        None => return Some((key, None, index + ls + key_len + ls)),
+    };
+    if index + ls + key_len + ls + value_len > len {
+        return None;
+    }
+    let value = Vec::from(&buf[index + ls + key_len + ls..index + ls + key_len + ls + value_len]);
+    Some((key, Some(value), index + ls + key_len + ls + value_len))
+}
+
+// 校验 WAL 文件每一帧的 crc，末尾写了一半的帧是崩溃留下的，不算损坏；旧格式没有校验和，直接通过
+fn verify_wal(buf: &[u8], path: &str) -> bool {
+    if !buf.starts_with(&WAL_MAGIC) {
+        return true;
+    }
+    let mut index = WAL_MAGIC.len();
+    while let Some(head) = buf.get(index..index + WAL_FRAME_HEAD) {
+        let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        let crc = u32::from_be_bytes([head[4], head[5], head[6], head[7]]);
+        let Some(payload) = buf.get(index + WAL_FRAME_HEAD..index + WAL_FRAME_HEAD + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            warn!("Wal file {} frame crc mismatch at {}", path, index);
+            return false;
+        }
+        index += WAL_FRAME_HEAD + len;
+    }
+    true
+}
+
+// [start, end) 中未删除的 key 的数量和它们的过期时间，end 为 None 表示没有上界
+fn count_keys(tables: &[Arc<SsTable>], start: &[u8], end: Option<&[u8]>) -> (usize, Vec<(u64, Vec<u8>)>) {
+    let mut keys = 0;
+    let mut expirations = Vec::new();
+    let entries = MergeIter::new(tables.iter().map(|table| table.scan_from(start)))
+        .take_while(|entry| end.is_none_or(|end| entry.key.as_slice() < end));
+    for entry in entries.filter(|entry| entry.value.is_some()) {
+        keys += 1;
+        if let Some(expire_at) = entry.expire_at {
+            expirations.push((expire_at, entry.key));
+        }
+    }
+    (keys, expirations)
+}
+
+fn file_path(data_path: &str, prefix: &str, number: u64) -> String {
+    format!("{}/{}{}", data_path, prefix, number)
+}
+
+// 文件不存在时忽略
+fn remove_if_exists(path: &str) {
+    if let Err(e) = remove_file(path) {
+        if e.kind() != ErrorKind::NotFound {
+            panic!("Remove file {} fail, err = {:?}", path, e);
+        }
+    }
+}
+
+// 截掉 WAL 文件回放不到的尾部，之后的检查和复制不会再读到它；失败时只记录，下次启动仍会跳过
+fn truncate_wal(path: &str, len: usize) {
+    warn!("Truncate wal file {} to {} bytes", path, len);
+    let truncated = std::fs::OpenOptions::new().write(true).open(path)
+        .and_then(|file| file.set_len(len as u64).and_then(|_| file.sync_all()));
+    if let Err(e) = truncated {
+        warn!("Truncate wal file {} fail, err = {:?}", path, e);
+    }
+}
+
+// 新建编号为 number 的 WAL 文件并写入 WAL_MAGIC，崩溃前留下的同名文件清空
+async fn create_wal(data_path: &str, number: u64) -> File {
+    let path = file_path(data_path, WAL_FILE_PREFIX, number);
+    info!("LSM create wal file {}", &path);
+    let mut file = File::create(&path).await.unwrap_or_else(|e| panic!("Create wal file {} fail, err = {:?}", &path, e));
+    file.write_all(&WAL_MAGIC).await.expect("Write wal file fail");
+    file
+}
+
+// 未过期的 value
+pub fn live(value: Option<&[u8]>, expire_at: Option<u64>, now: u64) -> Option<&[u8]> {
+    match expire_at {
+        Some(expire_at) if expire_at <= now => None,
+        _ => value,
+    }
+}
+
+// 按 key 合并多个有序的来源，后面的来源覆盖前面的，来源中的范围删除遮住前面的来源；value 为 None 表示已删除或已过期
+fn merge_records<'a, V>(sources: impl IntoIterator<Item = (Vec<(Vec<u8>, Option<V>)>, &'a [RangeTombstone])>) -> Vec<(Vec<u8>, V)> {
+    let mut merged = BTreeMap::new();
+    for (records, ranges) in sources {
+        for range in ranges {
+            merged.retain(|key: &Vec<u8>, _| !range.contains(key));
+        }
+        merged.extend(records);
+    }
+    merged.into_iter().filter_map(|(key, value)| Some((key, value?))).collect()
+}
+
+// 检查点文件格式
+// 8 bit first wal: 写入时 MANIFEST 中最早的 WAL 段
+// 8 bit next wal: 编号更小的 WAL 段都已包含在检查点中
+// 4 bit memtable count，之后从旧到新每个内存表：4 bit len, Memtable::serialize 的结果
+// 4 bit crc32 of all above
+// 先写临时文件再改名，失败时保留旧的检查点，启动时仍然可以回放全部 WAL 段
+fn write_checkpoint(path: &str, first_wal: u64, next_wal: u64, memtables: &[Box<dyn Memtable>]) {
+    let mut buf = Vec::new();
+    buf.extend(first_wal.to_be_bytes());
+    buf.extend(next_wal.to_be_bytes());
+    buf.extend((memtables.len() as u32).to_be_bytes());
+    for memtable in memtables {
+        let snapshot = memtable.serialize();
+        buf.extend((snapshot.len() as u32).to_be_bytes());
+        buf.extend(snapshot);
+    }
+    buf.extend(crc32fast::hash(&buf).to_be_bytes());
+    let tmp_path = format!("{}.tmp", path);
+    let res = std::fs::File::create(&tmp_path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, &buf).and_then(|_| file.sync_data()))
+        .and_then(|_| rename(&tmp_path, path));
+    match res {
+        Ok(()) => info!("Write checkpoint {} done, {} bytes, replay from wal {}", path, buf.len(), next_wal),
+        Err(e) => warn!("Write checkpoint {} fail, err = {:?}", path, e),
+    }
+}
+
+// 读入的检查点，字段见 write_checkpoint
+struct Checkpoint {
+    first_wal: u64,
+    next_wal: u64,
+    // 从旧到新
+    memtables: Vec<Box<dyn Memtable>>,
+}
+
+// 检查点格式见 write_checkpoint，内存表恢复为 kind 的实现；数据不完整或校验失败时返回 None
+fn decode_checkpoint(buf: &[u8], kind: MemtableKind) -> Option<Checkpoint> {
+    let (body, crc) = buf.split_at(buf.len().checked_sub(4)?);
+    if crc32fast::hash(body).to_be_bytes() != crc {
+        return None;
+    }
+    let first_wal = u64::from_be_bytes(body.get(0..8)?.try_into().ok()?);
+    let next_wal = u64::from_be_bytes(body.get(8..16)?.try_into().ok()?);
+    let count = u32::from_be_bytes(body.get(16..20)?.try_into().ok()?);
+    let mut index = 20;
+    let mut memtables = Vec::new();
+    for _ in 0..count {
+        let len = u32::from_be_bytes(body.get(index..index + 4)?.try_into().ok()?) as usize;
+        memtables.push(kind.deserialize(body.get(index + 4..index + 4 + len)?)?);
+        index += 4 + len;
+    }
+    (index == body.len()).then_some(Checkpoint { first_wal, next_wal, memtables })
+}
+
+// 把 fill 写入的记录保存为编号为 number 的 SST 文件
+// 先写临时文件再改名，重启时不会读到写了一半的文件；没有记录时不写文件
+fn write_table(data_path: &str, number: u64, level: u8, last_version: u64, options: &StorageOptions, fill: impl FnOnce(&mut SsTableWriter)) -> Option<LiveTable> {
+    let path = file_path(options.sst_dir(data_path, level), SST_FILE_PREFIX, number);
+    let tmp_path = format!("{}.tmp", &path);
+    let err_message = "Write sst file fail";
+    let mut writer = SsTableWriter::create(&tmp_path, options.compression(level), options.io_throttle.clone()).expect(err_message);
+    fill(&mut writer);
+    let Some((smallest, largest)) = writer.key_range() else {
+        drop(writer);
+        remove_if_exists(&tmp_path);
+        return None;
+    };
+    writer.finish(last_version).expect(err_message);
+    rename(&tmp_path, &path).expect("Rename sst file fail");
+    Some(LiveTable {
+        meta: TableFile { number, level, smallest, largest },
+        table: Arc::new(SsTable::open(&path, options.block_cache.clone(), options.mmap).expect("Open sst file fail")),
+        path,
+    })
+}
+
+// 内存表原样写入第 0 层，删除记录和范围删除也写入，遮住更旧文件中的 key
+fn flush_table(data_path: &str, number: u64, memtable: &dyn Memtable, options: &StorageOptions) -> Option<LiveTable> {
+    write_table(data_path, number, L0, memtable.last_version(), options, |writer| {
+        for range in memtable.ranges() {
+            writer.add_range(range);
+        }
+        for (key, (value, expire_at, version)) in memtable.iter() {
+            writer.add(&key, value, expire_at, version).expect("Write sst file fail");
+        }
+    })
+}
+
+// 按 key 有序的 entries 直接写入第 0 层，所有记录使用同一个版本号
+// tables 为已有的 SST 文件，返回新文件和其中之前不存在的 key 的数量
+fn ingest_table(data_path: &str, number: u64, entries: &[(Vec<u8>, Vec<u8>)], version: u64, tables: &[Arc<SsTable>], options: &StorageOptions) -> (Option<LiveTable>, usize) {
+    let mut added = 0;
+    let table = write_table(data_path, number, L0, version, options, |writer| {
+        for (key, value) in entries {
+            let existed = tables.iter().find_map(|table| table.get(key).expect("Read sst file fail")).is_some_and(|entry| entry.value.is_some());
+            if !existed {
+                added += 1;
+            }
+            writer.add(key, Some(value), None, version).expect("Write sst file fail");
+        }
+    });
+    (table, added)
+}
+
+// 合并所有 SST 文件写入第 1 层，tables 从新到旧排列
+// 已过期的 value 保留到删除记录写入为止，key 的数量不受影响
+// 输出在最底层，删除记录、范围删除和 now 之前过期的记录都不再需要；返回新文件和丢弃的已过期的 key
+fn compact_tables(data_path: &str, number: u64, tables: &[Arc<SsTable>], options: &StorageOptions, now: u64) -> (Option<LiveTable>, Vec<Vec<u8>>) {
+    let last_version = tables.iter().map(|table| table.last_version()).max().unwrap_or(0);
+    let mut expired = Vec::new();
+    let table = write_table(data_path, number, BOTTOM_LEVEL, last_version, options, |writer| {
+        for entry in MergeIter::new(tables.iter().map(|table| table.iter())).filter(|entry| entry.value.is_some()) {
+            if entry.expire_at.is_some_and(|expire_at| expire_at <= now) {
+                expired.push(entry.key);
+                continue;
+            }
+            writer.add(&entry.key, entry.value.as_deref(), entry.expire_at, entry.version).expect("Write sst file fail");
+        }
+    });
+    (table, expired)
+}
+
+// 旧版本由 INDEX 文件选择当前的一组文件，转换为 MANIFEST 描述的文件集合，回放顺序不变
+// 旧文件硬链接到新的文件名，MANIFEST 写入之前重启仍然按旧版本读取
+async fn upgrade_legacy(data_path: &str, cache: Arc<BlockCache>) -> FileSet {
+    let mut files = FileSet::default();
+    let index_path = format!("{}/{}", data_path, LEGACY_INDEX_FILE);
+    let index = match tokio::fs::read(&index_path).await {
+        Ok(buf) => buf.first().copied().filter(|i| (*i as u64) < LEGACY_FILE_BATCH).unwrap_or(0) as u64,
+        Err(_) => return files,
+    };
+    info!("Upgrade legacy files, file index is {}", index);
+    let last = LEGACY_FILE_BATCH - 1 - index;
+    let legacy = |prefix, i| file_path(data_path, prefix, i);
+    // this sst 包含 last wal 之前的所有数据；不存在时上次落盘没有完成，从 last sst 开始回放
+    // 没有 SST 文件时读取更旧版本的 LOG 文件
+    let (table, wals) = if try_exists(legacy(LEGACY_SST_FILE_PREFIX, index)).await.unwrap_or(false) {
+        (Some(legacy(LEGACY_SST_FILE_PREFIX, index)), vec![legacy(LEGACY_WAL_FILE_PREFIX, index)])
+    } else if try_exists(legacy(LEGACY_SST_FILE_PREFIX, last)).await.unwrap_or(false) {
+        let wals = vec![legacy(LEGACY_WAL_FILE_PREFIX, last), legacy(LEGACY_LOG_FILE_PREFIX, index), legacy(LEGACY_WAL_FILE_PREFIX, index)];
+        (Some(legacy(LEGACY_SST_FILE_PREFIX, last)), wals)
+    } else {
+        let wals = vec![legacy(LEGACY_LOG_FILE_PREFIX, last), legacy(LEGACY_WAL_FILE_PREFIX, last), legacy(LEGACY_LOG_FILE_PREFIX, index), legacy(LEGACY_WAL_FILE_PREFIX, index)];
+        (None, wals)
+    };
+    let link = |from: &str, to: &str| {
+        remove_if_exists(to);
+        hard_link(from, to).unwrap_or_else(|e| panic!("Link {} to {} fail, err = {:?}", from, to, e));
+        info!("Upgrade legacy file {} to {}", from, to);
+    };
+    if let Some(path) = table {
+        // 只用来读出 key 的范围，不需要映射
+        match SsTable::open(&path, cache, false) {
+            Ok(table) => match table.key_range() {
+                Ok(Some((smallest, largest))) => {
+                    let number = files.new_file_number();
+                    link(&path, &file_path(data_path, SST_FILE_PREFIX, number));
+                    files.apply(Edit::AddTable(TableFile { number, level: BOTTOM_LEVEL, smallest, largest }));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Read sst file {} fail, err = {:?}", &path, e),
+            },
+            Err(e) => warn!("Open sst file {} fail, err = {:?}", &path, e),
+        }
+    }
+    for path in wals {
+        if try_exists(&path).await.unwrap_or(false) {
+            let number = files.new_file_number();
+            link(&path, &file_path(data_path, WAL_FILE_PREFIX, number));
+            files.apply(Edit::AddWal(number));
+        }
+    }
+    files
+}
+
+// MANIFEST 写入之后删除旧版本的文件，INDEX 最后删除
+fn remove_legacy(data_path: &str) {
+    for i in 0..LEGACY_FILE_BATCH {
+        for prefix in [LEGACY_WAL_FILE_PREFIX, LEGACY_SST_FILE_PREFIX, LEGACY_LOG_FILE_PREFIX] {
+            remove_if_exists(&file_path(data_path, prefix, i));
+        }
+    }
+    remove_if_exists(&format!("{}/{}", data_path, LEGACY_INDEX_FILE));
+}
+
+impl LsmStorage {
+    // 记录的版本号，旧记录没有版本号时按回放顺序分配
+    fn record_version(&self, version: &mut Option<u64>) -> u64 {
+        version.take().unwrap_or(self.memtable.last_version() + 1)
+    }
+
+    fn view(&self) -> View<'_> {
+        View { memtable: Some(self.memtable.as_ref()), imms: &self.imms, tables: &self.tables }
+    }
+
+    fn lookup<T>(&self, key: &[u8], f: impl FnOnce(Option<&[u8]>, Option<u64>, u64) -> T) -> Option<T> {
+        self.view().lookup(key, f)
+    }
+
+    // 有 value 时返回过期时间，不论是否已过期
+    fn expire_at(&self, key: &[u8]) -> Option<u64> {
+        self.lookup(key, |value, expire_at, _| value.and(expire_at)).flatten()
+    }
+
+    // 写入内存表，同时维护 key 的数量和过期时间
+    // key 已有版本号更大的记录时忽略，回放时不依赖文件的顺序
+    fn apply(&mut self, key: &[u8], value: Option<Vec<u8>>, expire_at: Option<u64>, version: u64) {
+        let (existed, current) = self.lookup(key, |value, _, version| (value.is_some(), version)).unwrap_or((false, 0));
+        if current > version {
+            return;
+        }
+        match (existed, value.is_some()) {
+            (false, true) => self.keys += 1,
+            (true, false) => self.keys -= 1,
+            _ => {}
+        }
+        if let Some(expire_at) = expire_at {
+            self.expirations.push(Reverse((expire_at, key.to_vec())));
+        }
+        self.memtable.set_expire(key, value, expire_at, version);
+    }
+
+    // 删除 [start, end) 并维护 key 的数量，已过期但未删除的 key 也减去；返回删除的 key 的数量
+    fn apply_delete_range(&mut self, start: &[u8], end: &[u8], version: u64) -> usize {
+        let mut deleted = 0;
+        self.view().for_each_record(start, end, &mut |_, _, _| deleted += 1);
+        self.keys -= deleted;
+        self.memtable.delete_range(start, end, version);
+        deleted
+    }
+
+    // 追加到未过期的 value 后，不存在时创建；返回新长度
+    // 内存表中没有记录时以之前的 value 为基础写入完整的 value
+    fn apply_append(&mut self, key: &[u8], suffix: &[u8], version: u64) -> usize {
+        match self.memtable.record(key).map(|(value, _, current)| (value.map(|value| value.len()), current)) {
+            Some((len, current)) if current > version => len.unwrap_or(0),
+            Some((len, _)) => {
+                if len.is_none() {
+                    self.keys += 1;
+                }
+                self.memtable.append(key, suffix, version)
+            }
+            None => {
+                let mut value = self.get(key).unwrap_or_default();
+                value.extend_from_slice(suffix);
+                let len = value.len();
+                self.apply(key, Some(value), None, version);
+                len
+            }
+        }
+    }
+
+    // 回放一个 WAL 文件，只有最后一帧可能因为崩溃写了一半；返回回放到的位置，之后的字节是坏的尾部
+    fn load_wal(&mut self, buf: &[u8], path: &str) -> usize {
+        if !buf.starts_with(&WAL_MAGIC) {
+            self.load(buf);
+            return buf.len();
+        }
+        let mut index = WAL_MAGIC.len();
+        while index < buf.len() {
+            let Some(head) = buf.get(index..index + WAL_FRAME_HEAD) else {
+                warn!("Wal file {} frame head truncated at {}, skip the rest", path, index);
+                return index;
+            };
+            let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+            let crc = u32::from_be_bytes([head[4], head[5], head[6], head[7]]);
+            let Some(payload) = buf.get(index + WAL_FRAME_HEAD..index + WAL_FRAME_HEAD + len) else {
+                warn!("Wal file {} frame truncated at {}, skip the rest", path, index);
+                return index;
+            };
+            if crc32fast::hash(payload) != crc {
+                warn!("Wal file {} frame crc mismatch at {}, skip the rest", path, index);
+                return index;
+            }
+            self.load(payload);
+            index += WAL_FRAME_HEAD + len;
+        }
+        index
+    }
+
+    // 4 bit payload len
+    // 4 bit crc32 of payload
+    // n bit payload
+    async fn write_wal(&mut self, payload: &[u8]) {
+        let mut buf = Vec::with_capacity(WAL_FRAME_HEAD + payload.len());
+        buf.extend((payload.len() as u32).to_be_bytes());
+        buf.extend(crc32fast::hash(payload).to_be_bytes());
+        buf.extend_from_slice(payload);
+        self.wal.write_all(&buf).await.expect("Write wal file fail");
+        self.wal_dirty = true;
+        self.memtable_since.get_or_insert_with(Instant::now);
+        if self.options.durability == Durability::Always {
+            self.sync_wal().await;
+        }
+        self.wal_bytes += buf.len() as u64;
+        self.memtable_wal_bytes += buf.len() as u64;
+        if self.wal_bytes >= self.options.wal_segment_bytes {
+            self.rotate_wal().await;
+        }
+    }
+
+    // 以 REC_VERSION 开头的一次写入，写入 WAL 后发到复制日志
+    async fn write_record(&mut self, payload: &[u8]) {
+        self.write_wal(payload).await;
+        if let Some(sink) = self.options.replication.as_ref() {
+            sink.wal(payload);
+        }
+    }
+
+    // 之后的写入进入新的 WAL 段，返回它的编号；调用者负责写入 MANIFEST
+    async fn new_wal(&mut self) -> u64 {
+        // 换段之后定时 sync 只处理新的段，旧的段先 sync
+        if self.options.durability != Durability::Never {
+            self.sync_wal().await;
+        }
+        let number = self.files.new_file_number();
+        self.wal = create_wal(&self.data_path, number).await;
+        self.wal_bytes = WAL_MAGIC.len() as u64;
+        number
+    }
+
+    async fn rotate_wal(&mut self) -> u64 {
+        let number = self.new_wal().await;
+        self.manifest.log(&mut self.files, vec![Edit::AddWal(number)]).await;
+        self.checkpoint_due = self.options.checkpoint;
+        number
+    }
+
+    // WAL 换段后、新的段还没有写入时，在后台把所有内存表写入检查点
+    // 换段时最后一次写入还没有进入内存表，所以等写入完成后再检查；新的段已有写入或上一个检查点还在写入时跳过，等下次换段
+    fn maybe_checkpoint(&mut self) {
+        if !std::mem::take(&mut self.checkpoint_due) || self.wal_bytes > WAL_MAGIC.len() as u64
+            || self.checkpointing.as_ref().is_some_and(|checkpointing| !checkpointing.is_finished()) {
+            return;
+        }
+        let (Some(first_wal), Some(next_wal)) = (self.files.wals.first().copied(), self.files.wals.last().copied()) else {
+            return;
+        };
+        let memtables: Vec<Box<dyn Memtable>> = self.view().memtables().map(|memtable| memtable.snapshot()).collect();
+        let path = format!("{}/{}", self.data_path, CHECKPOINT_FILE);
+        self.checkpointing = Some(tokio::task::spawn_blocking(move || write_checkpoint(&path, first_wal, next_wal, &memtables)));
+    }
+
+    fn load(&mut self, buf: &[u8]) {
+        let mut index = 0;
+        let len = buf.len();
+        // REC_VERSION 给出的下一条记录的版本号
+        let mut version = None;
+        while index < len {
+            if buf[index] & REC_TAG == 0 || buf[index] == REC_LONG || buf[index] == REC_DELETE {
+                match decode_record(buf, index) {
+                    Some((key, value, next)) => {
+                        let version = self.record_version(&mut version);
+                        self.apply(key, value, None, version);
+                        index = next;
+                    }
+                    None => break,
+                }
+                continue;
+            }
+            match buf[index] {
+                REC_BATCH | REC_BATCH_LONG => {
+                    // 1 bit tag
+                    // 2 bit record count, 4 bit if REC_BATCH_LONG
+                    // n records
+                    let cs = if buf[index] == REC_BATCH_LONG { 4 } else { 2 };
+                    if index + 1 + cs > len {
+                        break;
+                    }
+                    let count = buf[index + 1..index + 1 + cs].iter().fold(0, |count, b| count * 0x100 + *b as usize);
+                    let mut next = index + 1 + cs;
+                    let mut entries = Vec::with_capacity(count);
+                    while entries.len() < count {
+                        match decode_record(buf, next) {
+                            Some((key, value, n)) => {
+                                entries.push((key, value));
+                                next = n;
+                            }
+                            None => break,
+                        }
+                    }
+                    // 不完整的批量记录整体丢弃
+                    if entries.len() < count {
+                        break;
+                    }
+                    let version = self.record_version(&mut version);
+                    for (key, value) in entries {
+                        self.apply(key, value, None, version);
+                    }
+                    index = next;
+                }
+                REC_EXPIRE => {
+                    // 1 bit tag
+                    // 8 bit expire at
+                    // 1 record
+                    if index + 1 + 8 > len {
+                        break;
+                    }
+                    let mut expire_at = [0; 8];
+                    expire_at.copy_from_slice(&buf[index + 1..index + 1 + 8]);
+                    match decode_record(buf, index + 1 + 8) {
+                        Some((key, value, next)) => {
+                            let expire_at = u64::from_be_bytes(expire_at);
+                            let version = self.record_version(&mut version);
+                            self.apply(key, value, Some(expire_at), version);
+                            index = next;
+                        }
+                        None => break,
+                    }
+                }
+                REC_APPEND => {
+                    // 1 bit tag
+                    // 1 record, value is the suffix
+                    match decode_record(buf, index + 1) {
+                        Some((key, Some(suffix), next)) => {
+                            let version = self.record_version(&mut version);
+                            self.apply_append(key, &suffix, version);
+                            index = next;
+                        }
+                        Some((_, None, next)) => {
+                            warn!("Append record without suffix at {}", index);
+                            version = None;
+                            index = next;
+                        }
+                        None => break,
+                    }
+                }
+                REC_DELETE_RANGE => {
+                    // 1 bit tag
+                    // 4 bit start len
+                    // n bit start
+                    // 4 bit end len
+                    // n bit end
+                    let field = |i: usize| {
+                        let len = buf.get(i..i + LONG_LEN_SIZE).and_then(|_| read_len(buf, i, LONG_LEN_SIZE))?;
+                        Some((buf.get(i + LONG_LEN_SIZE..i + LONG_LEN_SIZE + len)?, i + LONG_LEN_SIZE + len))
+                    };
+                    match field(index + 1).and_then(|(start, next)| Some((start, field(next)?))) {
+                        Some((start, (end, next))) => {
+                            let version = self.record_version(&mut version);
+                            self.apply_delete_range(start, end, version);
+                            index = next;
+                        }
+                        None => break,
+                    }
+                }
+                REC_VERSION | REC_LAST_VERSION => {
+                    // 1 bit tag
+                    // 8 bit version
+                    // 1 record, if REC_VERSION
+                    if index + 1 + 8 > len {
+                        break;
+                    }
+                    let mut v = [0; 8];
+                    v.copy_from_slice(&buf[index + 1..index + 1 + 8]);
+                    let v = u64::from_be_bytes(v);
+                    if buf[index] == REC_VERSION {
+                        version = Some(v);
+                    } else {
+                        self.memtable.raise_version(v);
+                    }
+                    index += 1 + 8;
+                }
+                tag => {
+                    warn!("Unknown record tag {} at {}", tag, index);
+                    break;
+                }
+            }
+        }
+    }
+
+    // 先在所在层的目录中找，没有时依次在数据目录和其他层的目录中找，修改 level_paths 之前写入的文件仍然可以打开
+    fn find_table(&self, meta: &TableFile) -> String {
+        let dir = self.options.sst_dir(&self.data_path, meta.level);
+        once(dir).chain(once(self.data_path.as_str())).chain(self.options.level_paths.iter().map(String::as_str))
+            .map(|dir| file_path(dir, SST_FILE_PREFIX, meta.number))
+            .find(|path| Path::new(path).exists())
+            .unwrap_or_else(|| file_path(dir, SST_FILE_PREFIX, meta.number))
+    }
+
+    fn add_table(&mut self, meta: TableFile, path: String, table: std::io::Result<SsTable>) {
+        match table {
+            Ok(table) => {
+                info!("Open sst file {}, level = {}", &path, meta.level);
+                self.memtable.raise_version(table.last_version());
+                self.tables.push(LiveTable { meta, path, table: Arc::new(table) });
+            }
+            Err(e) => warn!("Open sst file {} fail, err = {:?}", &path, e),
+        }
+    }
+
+    // 等待后台的落盘和合并完成，合并完第 0 层仍然较多时继续合并；离线工具退出前调用
+    pub async fn wait_background(&mut self) {
+        while self.flushing.is_some() {
+            self.wait_flush().await;
+        }
+        while self.compacting.is_some() {
+            self.wait_compaction().await;
+            self.maybe_compact();
+        }
+    }
+
+    // 按 key 顺序遍历所有未删除、未过期的 key，离线导出使用
+    pub fn for_each_live(&self, now: u64, f: &mut impl FnMut(&[u8], &[u8], Option<u64>)) {
+        self.view().for_each_record(&[], &[], &mut |key, value, expire_at| {
+            if let Some(value) = live(Some(value), expire_at, now) {
+                f(key, value, expire_at);
+            }
+        });
+    }
+
+    // 文件已从 MANIFEST 中移除，过一段时间后由 collect_garbage 删除
+    fn retire(&mut self, path: String, table: Option<Arc<SsTable>>) {
+        self.obsolete.push(ObsoleteFile { path, table, since: Instant::now() });
+    }
+
+    // 启动时删除 MANIFEST 中没有的 WAL 和 SST 文件，以及写了一半的临时文件
+    // 它们是上次运行中等待删除或崩溃前没来得及写入 MANIFEST 的文件；启动时没有后台任务，不会误删正在写的文件
+    fn remove_orphans(&self) {
+        let mut dirs: Vec<&str> = once(self.data_path.as_str()).chain(self.options.level_paths.iter().map(String::as_str)).collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let tmp = name.ends_with(".tmp");
+                let base = name.strip_suffix(".tmp").unwrap_or(&name);
+                let number = |prefix: &str| base.strip_prefix(prefix).and_then(|number| number.parse::<u64>().ok());
+                // 旧版本的文件名编号前还有其他字符，不会匹配
+                let orphan = if let Some(number) = number(WAL_FILE_PREFIX) {
+                    tmp || !self.files.wals.contains(&number)
+                } else if let Some(number) = number(SST_FILE_PREFIX) {
+                    tmp || !self.files.tables.iter().any(|meta| meta.number == number)
+                } else {
+                    false
+                };
+                if orphan {
+                    let path = format!("{}/{}", dir, &name);
+                    info!("Remove orphan file {}", &path);
+                    remove_if_exists(&path);
+                }
+            }
+        }
+    }
+
+    fn sort_tables(&mut self) {
+        self.tables.sort_by_key(|live| (live.meta.level, Reverse(live.meta.number)));
+    }
+
+    // 内存表转为只读，之后的写入进入新的内存表，版本号接着分配
+    fn take_memtable(&mut self) -> Box<dyn Memtable> {
+        let memtable = std::mem::replace(&mut self.memtable, self.options.memtable.create());
+        self.memtable.raise_version(memtable.last_version());
+        memtable
+    }
+
+    // 打开 MANIFEST 中的 SST 文件，再按编号顺序回放 WAL 文件
+    // 读取 WAL 文件、打开 SST 文件和统计 key 都并行进行，只有回放按顺序写入内存表
+    async fn recover(&mut self) {
+        // 有效的检查点包含编号小于 next_wal 的所有 WAL 段，这些段不再读取
+        let checkpoint = self.read_checkpoint().await;
+        let next_wal = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.next_wal);
+        for number in self.files.wals.iter().filter(|number| **number < next_wal) {
+            let path = file_path(&self.data_path, WAL_FILE_PREFIX, *number);
+            self.memtable_wal_bytes += std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+        }
+        let wals: Vec<(String, JoinHandle<std::io::Result<Vec<u8>>>)> = self.files.wals.iter().filter(|number| **number >= next_wal).map(|number| {
+            let path = file_path(&self.data_path, WAL_FILE_PREFIX, *number);
+            (path.clone(), tokio::spawn(tokio::fs::read(path)))
+        }).collect();
+        let opening: Vec<(TableFile, String, JoinHandle<std::io::Result<SsTable>>)> = self.files.tables.iter().map(|meta| {
+            let path = self.find_table(meta);
+            let cache = self.options.block_cache.clone();
+            let mmap = self.options.mmap;
+            let open_path = path.clone();
+            (meta.clone(), path, tokio::task::spawn_blocking(move || SsTable::open(&open_path, cache, mmap)))
+        }).collect();
+        for (meta, path, handle) in opening {
+            let table = handle.await.expect("Open sst file fail");
+            self.add_table(meta, path, table);
+        }
+        self.sort_tables();
+        // 按最旧的文件（通常是第 1 层）的 block 把 key 空间分段，每段合并扫描一遍统计 key 的数量和过期时间
+        let parallelism = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let bounds = self.tables.last().map(|live| live.table.split_keys(parallelism)).unwrap_or_default();
+        let starts: Vec<Vec<u8>> = once(Vec::new()).chain(bounds.iter().cloned()).collect();
+        let ends = bounds.into_iter().map(Some).chain(once(None));
+        let tables: Vec<Arc<SsTable>> = self.tables.iter().map(|live| live.table.clone()).collect();
+        let counting: Vec<_> = starts.into_iter().zip(ends).map(|(start, end)| {
+            let tables = tables.clone();
+            tokio::task::spawn_blocking(move || count_keys(&tables, &start, end.as_deref()))
+        }).collect();
+        for handle in counting {
+            let (keys, expirations) = handle.await.expect("Count keys fail");
+            self.keys += keys;
+            self.expirations.extend(expirations.into_iter().map(Reverse));
+        }
+        if let Some(checkpoint) = checkpoint {
+            for memtable in checkpoint.memtables {
+                self.restore(memtable.as_ref());
+            }
+        }
+        for (path, handle) in wals {
+            info!("Load wal file {}", &path);
+            match handle.await.expect("Read wal file fail") {
+                Ok(buf) => {
+                    let valid = self.load_wal(&buf, &path);
+                    if valid < buf.len() {
+                        truncate_wal(&path, valid);
+                    }
+                    self.memtable_wal_bytes += valid as u64;
+                }
+                Err(e) => warn!("Read wal file {} fail, err = {:?}", &path, e),
+            }
+        }
+    }
+
+    // 检查点写入之后有 WAL 段被删除时，其中的数据可能已经落盘并被更新的数据覆盖，不再有效
+    async fn read_checkpoint(&self) -> Option<Checkpoint> {
+        if !self.options.checkpoint {
+            return None;
+        }
+        let path = format!("{}/{}", self.data_path, CHECKPOINT_FILE);
+        let buf = tokio::fs::read(&path).await.ok()?;
+        let Some(checkpoint) = decode_checkpoint(&buf, self.options.memtable) else {
+            warn!("Checkpoint {} is corrupted, ignore it", &path);
+            return None;
+        };
+        if self.files.wals.first() != Some(&checkpoint.first_wal) || !self.files.wals.contains(&checkpoint.next_wal) {
+            info!("Checkpoint {} is stale, ignore it", &path);
+            return None;
+        }
+        info!("Load checkpoint {}, replay wal files from {}", &path, checkpoint.next_wal);
+        Some(checkpoint)
+    }
+
+    // 按版本号顺序写入检查点中一个内存表的记录和范围删除，与回放 WAL 的结果相同
+    fn restore(&mut self, memtable: &dyn Memtable) {
+        let mut records: Vec<_> = memtable.iter()
+            .map(|(key, (value, expire_at, version))| (key, value.map(<[u8]>::to_vec), expire_at, version))
+            .collect();
+        records.sort_by_key(|(_, _, _, version)| *version);
+        let mut ranges = memtable.ranges().to_vec();
+        ranges.sort_by_key(|range| range.version);
+        let mut ranges = ranges.into_iter().peekable();
+        for (key, value, expire_at, version) in records {
+            while let Some(range) = ranges.next_if(|range| range.version < version) {
+                self.apply_delete_range(&range.start, &range.end, range.version);
+            }
+            self.apply(&key, value, expire_at, version);
+        }
+        for range in ranges {
+            self.apply_delete_range(&range.start, &range.end, range.version);
+        }
+        self.memtable.raise_version(memtable.last_version());
+    }
+
+    // 1 bit REC_VERSION
+    // 8 bit version
+    fn version_head(version: u64) -> Vec<u8> {
+        let mut buf = vec![REC_VERSION];
+        buf.extend(version.to_be_bytes());
+        buf
+    }
+
+    async fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, expire_at: Option<u64>) {
+        // WAL
+        let version = self.memtable.last_version() + 1;
+        let mut buf = Self::version_head(version);
+        if let Some(expire_at) = expire_at {
+            // 1 bit tag
+            // 8 bit expire at
+            buf.push(REC_EXPIRE);
+            buf.extend(expire_at.to_be_bytes());
+        }
+        buf.extend(encode_record(&key, value.as_deref()));
+        self.write_record(&buf).await;
+        self.apply(&key, value, expire_at, version);
+        self.check_flush().await;
+    }
+
+    // 每次写入之后调用
+    async fn check_flush(&mut self) {
+        self.finish_flush().await;
+        if self.memtable.memory() > self.options.memtable_bytes || self.memtable_wal_bytes > MEMTABLE_WAL_LIMIT {
+            self.flush().await;
+        }
+        self.maybe_checkpoint();
+        self.publish();
+    }
+
+    // 内存表 O(1) 复制，不落盘
+    fn read_snapshot(&self) -> LsmSnapshot {
+        LsmSnapshot {
+            memtable: self.memtable.snapshot(),
+            imms: self.imms.clone(),
+            tables: self.tables.clone(),
+        }
+    }
+
+    // 数据改变后发布快照，在事件循环响应之前，连接收到响应后直接读取能读到自己的写入
+    fn publish(&self) {
+        if let Some(view) = self.options.shared_view.as_ref() {
+            view.store(self.read_snapshot());
+        }
+    }
+
+    // 后台任务完成后切换到新的 SST 文件
+    async fn finish_flush(&mut self) {
+        if self.flushing.as_ref().is_some_and(|flushing| flushing.is_finished()) {
+            self.wait_flush().await;
+        }
+        if self.compacting.as_ref().is_some_and(|compaction| compaction.handle.is_finished()) {
+            self.wait_compaction().await;
+        }
+    }
+
+    async fn wait_flush(&mut self) {
+        if let Some(flushing) = self.flushing.take() {
+            let table = flushing.await.expect("Save sst file fail");
+            let imm = self.imms.pop_front().expect("No immutable memtable");
+            // 新的 SST 文件包含 imm.wal 之前所有 WAL 的数据
+            let wals: Vec<u64> = self.files.wals.iter().copied().filter(|number| *number < imm.wal).collect();
+            let mut edits: Vec<Edit> = wals.iter().map(|number| Edit::RemoveWal(*number)).collect();
+            edits.extend(table.as_ref().map(|table| Edit::AddTable(table.meta.clone())));
+            self.manifest.log(&mut self.files, edits).await;
+            // MANIFEST 写入之后旧文件不再需要
+            for number in wals {
+                self.retire(file_path(&self.data_path, WAL_FILE_PREFIX, number), None);
+            }
+            self.tables.extend(table);
+            self.sort_tables();
+            info!("Save to sst file done");
+            self.start_flush();
+            self.maybe_compact();
+        }
+    }
+
+    // 只读的内存表依次落盘，较早的内存表得到较小的编号
+    fn start_flush(&mut self) {
+        if self.flushing.is_some() {
+            return;
+        }
+        let Some(imm) = self.imms.front() else {
+            return;
+        };
+        let number = self.files.new_file_number();
+        let memtable = imm.memtable.clone();
+        let data_path = self.data_path.clone();
+        let options = self.options.clone();
+        info!("Save to sst file {}", file_path(options.sst_dir(&data_path, L0), SST_FILE_PREFIX, number));
+        self.flushing = Some(tokio::task::spawn_blocking(move || flush_table(&data_path, number, memtable.as_ref(), &options)));
+    }
+
+    // 第 0 层的文件足够多时在后台合并所有 SST 文件
+    fn maybe_compact(&mut self) {
+        if self.compacting.is_some() || self.tables.iter().filter(|live| live.meta.level == L0).count() < L0_COMPACTION_TRIGGER {
+            return;
+        }
+        let inputs = self.tables.iter().map(|live| live.meta.number).collect();
+        self.start_compaction(inputs);
+    }
+
+    // inputs 必须包含第 1 层，以及第 0 层中不比其中任何一个新的文件，剩下的第 0 层文件都比新的第 1 层文件新
+    fn start_compaction(&mut self, inputs: Vec<u64>) {
+        let number = self.files.new_file_number();
+        let tables: Vec<Arc<SsTable>> = self.tables.iter().filter(|live| inputs.contains(&live.meta.number)).map(|live| live.table.clone()).collect();
+        let data_path = self.data_path.clone();
+        let options = self.options.clone();
+        info!("Compact sst files {:?} to {}", &inputs, file_path(options.sst_dir(&data_path, BOTTOM_LEVEL), SST_FILE_PREFIX, number));
+        let handle = tokio::task::spawn_blocking(move || compact_tables(&data_path, number, &tables, &options, now_millis()));
+        self.compacting = Some(Compaction { inputs, handle });
+    }
+
+    async fn wait_compaction(&mut self) {
+        if let Some(Compaction { inputs, handle }) = self.compacting.take() {
+            let (table, expired) = handle.await.expect("Compact sst files fail");
+            let mut edits: Vec<Edit> = inputs.iter().map(|number| Edit::RemoveTable(*number)).collect();
+            edits.extend(table.as_ref().map(|table| Edit::AddTable(table.meta.clone())));
+            self.manifest.log(&mut self.files, edits).await;
+            // 合并期间新落盘的第 0 层文件不受影响
+            let (removed, tables): (Vec<LiveTable>, Vec<LiveTable>) = self.tables.drain(..).partition(|live| inputs.contains(&live.meta.number));
+            self.tables = tables;
+            self.tables.extend(table);
+            self.sort_tables();
+            for live in removed {
+                self.retire(live.path, Some(live.table));
+            }
+            // 过期的 key 在删除前仍然计入数量，没有更新的记录时随记录一起丢弃，不再写删除记录
+            let dropped = expired.iter().filter(|key| self.lookup(key, |_, _, _| ()).is_none()).count();
+            self.keys -= dropped;
+            info!("Compact sst files done, drop {} expired keys", dropped);
+        }
+    }
+}
+
+// 读取由 View 实现，存储引擎和快照共用
+impl ReadView for LsmStorage {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.view().get(key)
+    }
+
+    fn version(&self, key: &[u8]) -> u64 {
+        self.view().version(key)
+    }
+
+    fn meta(&self, key: &[u8]) -> Option<KeyMeta> {
+        self.view().meta(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.view().contains(key)
+    }
+
+    fn longest_prefix(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.view().longest_prefix(key)
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.view().scan(start, end)
+    }
+
+    fn prefix(&self, prefix: &[u8], with_values: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.view().prefix(prefix, with_values)
+    }
+
+    fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        self.view().keys(pattern)
+    }
+
+    fn last_version(&self) -> u64 {
+        self.memtable.last_version()
+    }
+}
+
+impl ReadView for LsmSnapshot {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.view().get(key)
+    }
+
+    fn version(&self, key: &[u8]) -> u64 {
+        self.view().version(key)
+    }
+
+    fn meta(&self, key: &[u8]) -> Option<KeyMeta> {
+        self.view().meta(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.view().contains(key)
+    }
+
+    fn longest_prefix(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.view().longest_prefix(key)
+    }
+
+    fn scan(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.view().scan(start, end)
+    }
+
+    fn prefix(&self, prefix: &[u8], with_values: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.view().prefix(prefix, with_values)
+    }
+
+    fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        self.view().keys(pattern)
+    }
+
+    fn last_version(&self) -> u64 {
+        self.memtable.last_version()
+    }
+}
+
+impl StorageEngine for LsmStorage {
+    type Snapshot = LsmSnapshot;
+
+    async fn open(data_path: String, options: StorageOptions) -> Self {
+        // dir
+        if !try_exists(&data_path).await.unwrap_or_else(|e| { panic!("Try exists fail, err = {:?}", e); }) {
+            create_dir_all(&data_path).unwrap_or_else(|e| { panic!("Create data dir fail, err = {:?}", e) });
+        }
+        for dir in options.level_paths.iter() {
+            create_dir_all(dir).unwrap_or_else(|e| { panic!("Create sst dir {} fail, err = {:?}", dir, e) });
+        }
+        let legacy = !Manifest::exists(&data_path).await;
+        let mut files = if legacy {
+            upgrade_legacy(&data_path, options.block_cache.clone()).await
+        } else {
+            Manifest::load(&data_path).await
+        };
+        // 重写 MANIFEST，只保留当前的文件集合
+        let mut manifest = Manifest::create(&data_path, &files).await;
+        if legacy {
+            remove_legacy(&data_path);
+        }
+        // 每次启动写入新的 WAL 文件，之前的 WAL 文件尾部可能有写了一半的帧，在它之后追加的记录回放不到
+        let number = files.new_file_number();
+        let wal = create_wal(&data_path, number).await;
+        manifest.log(&mut files, vec![Edit::AddWal(number)]).await;
+        let memtable = options.memtable.create();
+        let mut storage = Self {
+            data_path,
+            options,
+            memtable,
+            imms: VecDeque::new(),
+            flushing: None,
+            compacting: None,
+            tables: Vec::new(),
+            keys: 0,
+            wal,
+            wal_bytes: WAL_MAGIC.len() as u64,
+            wal_dirty: false,
+            memtable_wal_bytes: 0,
+            manifest,
+            files,
+            expirations: BinaryHeap::new(),
+            memtable_since: None,
+            stalled: false,
+            obsolete: Vec::new(),
+            checkpoint_due: false,
+            checkpointing: None,
+        };
+        storage.recover().await;
+        storage.remove_orphans();
+        // 回放的数据从启动时开始计时
+        if !storage.memtable.is_empty() {
+            storage.memtable_since = Some(Instant::now());
+        }
+        storage.publish();
+        storage
+    }
+
+    fn options(&self) -> &StorageOptions {
+        &self.options
+    }
+
+    async fn snapshot(&mut self) -> LsmSnapshot {
+        self.read_snapshot()
+    }
+
+    fn size(&self) -> usize {
+        self.keys
+    }
+
+    async fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.write(key, Some(value), None).await
+    }
+
+    async fn put_expire(&mut self, key: Vec<u8>, value: Vec<u8>, expire_at: u64) {
+        self.write(key, Some(value), Some(expire_at)).await
+    }
+
+    async fn delete(&mut self, key: Vec<u8>) {
+        self.write(key, None, None).await
+    }
+
+    async fn delete_range(&mut self, start: Vec<u8>, end: Vec<u8>) -> usize {
+        if !end.is_empty() && start >= end {
+            return 0;
+        }
+        // WAL 中一条范围删除记录
+        // 1 bit tag
+        // 4 bit start len
+        // n bit start
+        // 4 bit end len; 0 表示没有上界
+        // n bit end
+        let version = self.memtable.last_version() + 1;
+        let mut buf = Self::version_head(version);
+        buf.push(REC_DELETE_RANGE);
+        push_value(&mut buf, Some(&start), LONG_LEN_SIZE);
+        push_value(&mut buf, Some(&end), LONG_LEN_SIZE);
+        self.write_record(&buf).await;
+        let deleted = self.apply_delete_range(&start, &end, version);
+        info!("Delete range {:?} to {:?}, {} keys", &start, &end, deleted);
+        self.check_flush().await;
+        deleted
+    }
+
+    async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> usize {
+        if let Some(expire_at) = self.expire_at(&key) {
+            // 带过期时间的 key 写完整记录，回放结果不受回放时是否已过期影响
+            return match self.get(&key) {
+                Some(mut value) => {
+                    value.extend(suffix);
+                    let len = value.len();
+                    self.write(key, Some(value), Some(expire_at)).await;
+                    len
+                }
+                None => {
+                    let len = suffix.len();
+                    self.write(key, Some(suffix), None).await;
+                    len
+                }
+            };
+        }
+        // WAL 中只记录追加的部分
+        // 1 bit tag
+        // 1 record
+        let version = self.memtable.last_version() + 1;
+        let mut buf = Self::version_head(version);
+        buf.push(REC_APPEND);
+        buf.extend(encode_record(&key, Some(&suffix)));
+        self.write_record(&buf).await;
+        let len = self.apply_append(&key, &suffix, version);
+        self.check_flush().await;
+        len
+    }
+
+    async fn write_batch(&mut self, entries: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+        // WAL 中一条批量记录
+        // 1 bit tag
+        // 2 bit record count, 4 bit if REC_BATCH_LONG
+        // n records
+        // 整批使用同一个版本号
+        let version = self.memtable.last_version() + 1;
+        let mut buf = Self::version_head(version);
+        if entries.len() > u16::MAX as usize {
+            buf.push(REC_BATCH_LONG);
+            buf.extend((entries.len() as u32).to_be_bytes());
+        } else {
+            buf.extend([REC_BATCH, (entries.len() >> 8) as u8, entries.len() as u8]);
+        }
+        for (key, value) in entries.iter() {
+            buf.extend(encode_record(key, value.as_deref()));
+        }
+        self.write_record(&buf).await;
+        for (key, value) in entries {
+            self.apply(&key, value, None, version);
+        }
+        self.check_flush().await;
+    }
+
+    async fn clear(&mut self) {
+        // 等后台落盘结束，否则删除后还会写入旧数据
+        while self.flushing.is_some() {
+            self.wait_flush().await;
+        }
+        self.wait_compaction().await;
+        // 版本号不重置，清空前读到的版本号不会和之后写入的相同
+        let last_version = self.memtable.last_version();
+        self.take_memtable();
+        let removed: Vec<LiveTable> = self.tables.drain(..).collect();
+        // 打开失败的 SST 文件也一起删除
+        let unopened: Vec<String> = self.files.tables.iter()
+            .filter(|meta| !removed.iter().any(|live| live.meta.number == meta.number))
+            .map(|meta| self.find_table(meta))
+            .collect();
+        self.keys = 0;
+        self.expirations.clear();
+        // 新的 WAL 文件以已分配的最大版本号开头
+        // 1 bit tag
+        // 8 bit last version
+        let wal_number = self.new_wal().await;
+        self.memtable_wal_bytes = 0;
+        let mut buf = vec![REC_LAST_VERSION];
+        buf.extend(last_version.to_be_bytes());
+        self.write_wal(&buf).await;
+        self.sync_wal().await;
+        if let Some(sink) = self.options.replication.as_ref() {
+            sink.clear(last_version);
+        }
+        let wals = self.files.wals.clone();
+        let tables: Vec<u64> = self.files.tables.iter().map(|meta| meta.number).collect();
+        let mut edits = vec![Edit::AddWal(wal_number)];
+        edits.extend(wals.iter().map(|number| Edit::RemoveWal(*number)));
+        edits.extend(tables.iter().map(|number| Edit::RemoveTable(*number)));
+        self.manifest.log(&mut self.files, edits).await;
+        for number in wals {
+            self.retire(file_path(&self.data_path, WAL_FILE_PREFIX, number), None);
+        }
+        for live in removed {
+            self.retire(live.path, Some(live.table));
+        }
+        for path in unopened {
+            self.retire(path, None);
+        }
+        self.publish();
+        info!("LSM clear all data");
+    }
+
+    async fn stats(&self) -> StorageStats {
+        let mut wal_bytes = 0;
+        for number in self.files.wals.iter() {
+            let path = file_path(&self.data_path, WAL_FILE_PREFIX, *number);
+            wal_bytes += tokio::fs::metadata(&path).await.map(|meta| meta.len()).unwrap_or(0);
+        }
+        StorageStats {
+            keys: self.keys as u64,
+            memtable_bytes: self.memtable.bytes() as u64,
+            memtable_memory: self.view().memtables().map(|memtable| memtable.memory()).sum::<usize>() as u64,
+            wal_bytes,
+            block_cache_hits: self.options.block_cache.hits(),
+            block_cache_misses: self.options.block_cache.misses(),
+        }
+    }
+
+    async fn remove_expired(&mut self, now: u64, limit: usize) -> Vec<Vec<u8>> {
+        self.finish_flush().await;
+        let mut keys = Vec::new();
+        while keys.len() < limit && self.expirations.peek().is_some_and(|Reverse((expire_at, _))| *expire_at <= now) {
+            let Some(Reverse((expire_at, key))) = self.expirations.pop() else {
+                break;
+            };
+            // 过期时间不同说明之后重新写入过
+            if self.expire_at(&key) == Some(expire_at) {
+                self.write(key.clone(), None, None).await;
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
+
+impl LsmExt for LsmStorage {
+    fn tune(&mut self, tunables: &Tunables) {
+        self.options.tune(tunables);
+    }
+
+    async fn sync_wal(&mut self) {
+        if self.wal_dirty {
+            self.wal.sync_data().await.expect("Sync wal file fail");
+            self.wal_dirty = false;
+        }
+    }
+
+    async fn flush_aged(&mut self) {
+        let Some(max_age) = self.options.memtable_max_age else {
+            return;
+        };
+        if self.memtable_since.is_some_and(|since| since.elapsed() >= max_age) {
+            info!("Memtable older than {:?}, flush", max_age);
+            self.flush().await;
+        }
+    }
+
+    async fn collect_garbage(&mut self) {
+        let now = Instant::now();
+        self.obsolete.retain(|file| {
+            // 快照或后台任务还在读取的 SST 文件继续保留
+            let in_use = file.table.as_ref().is_some_and(|table| Arc::strong_count(table) > 1);
+            if in_use || now.duration_since(file.since) < OBSOLETE_FILE_GRACE {
+                return true;
+            }
+            info!("Remove obsolete file {}", &file.path);
+            remove_if_exists(&file.path);
+            false
+        });
+    }
+
+    async fn write_stalled(&mut self) -> bool {
+        self.finish_flush().await;
+        // 暂停写入后不会再有落盘触发合并，这里补上
+        self.maybe_compact();
+        let l0 = self.tables.iter().filter(|live| live.meta.level == L0).count();
+        // 低于合并的触发数量时第 0 层文件不会减少，会一直暂停
+        let stalled = self.imms.len() >= self.options.max_immutable_memtables.max(1) || l0 >= self.options.l0_stop_trigger.max(L0_COMPACTION_TRIGGER);
+        if stalled != self.stalled {
+            if stalled {
+                warn!("Write stall, {} immutable memtables, {} l0 files", self.imms.len(), l0);
+            } else {
+                info!("Write stall end");
+            }
+            self.stalled = stalled;
+        }
+        stalled
+    }
+
+    async fn flush(&mut self) {
+        self.finish_flush().await;
+        self.memtable_since = None;
+        // 内存表为空时没有需要落盘的数据
+        if self.memtable.is_empty() {
+            return;
+        }
+        // 之后的写入进入新的 WAL 段
+        let wal = self.rotate_wal().await;
+        self.memtable_wal_bytes = 0;
+
+        // 内存表转为只读，上一个还在落盘时排队
+        // 落盘完成之前重启时 MANIFEST 中仍然是所有 WAL 文件，重新回放即可
+        let memtable = Arc::from(self.take_memtable());
+        self.imms.push_back(Immutable { memtable, wal });
+        self.start_flush();
+    }
+
+    async fn ingest(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> usize {
+        // 先把内存表全部落盘，新文件的编号最大，比已有的数据都新，之后的写入又比它新
+        self.flush().await;
+        while self.flushing.is_some() {
+            self.wait_flush().await;
+        }
+        let version = self.memtable.last_version() + 1;
+        self.memtable.raise_version(version);
+        if let Some(sink) = self.options.replication.as_ref() {
+            sink.ingest(version, &entries);
+        }
+        let number = self.files.new_file_number();
+        let tables: Vec<Arc<SsTable>> = self.tables.iter().map(|live| live.table.clone()).collect();
+        let data_path = self.data_path.clone();
+        let options = self.options.clone();
+        info!("Ingest {} entries to sst file {}", entries.len(), file_path(options.sst_dir(&data_path, L0), SST_FILE_PREFIX, number));
+        let (table, added) = tokio::task::spawn_blocking(move || ingest_table(&data_path, number, &entries, version, &tables, &options))
+            .await.expect("Ingest sst file fail");
+        // 没有记录时不写文件，版本号已经分配，不影响
+        let Some(table) = table else {
+            return 0;
+        };
+        self.manifest.log(&mut self.files, vec![Edit::AddTable(table.meta.clone())]).await;
+        self.tables.push(table);
+        self.sort_tables();
+        self.keys += added;
+        info!("Ingest done, {} new keys", added);
+        self.maybe_compact();
+        self.publish();
+        added
+    }
+
+    async fn compact(&mut self, start: &[u8], end: &[u8]) -> usize {
+        self.flush().await;
+        while self.flushing.is_some() {
+            self.wait_flush().await;
+        }
+        self.wait_compaction().await;
+        // 与范围重叠的第 0 层文件中最新的一个，它和更旧的第 0 层文件一起合并
+        // 只有第 1 层与范围重叠时只重写第 1 层，丢弃其中已过期的记录
+        let overlap = |meta: &TableFile| (end.is_empty() || meta.smallest.as_slice() < end) && meta.largest.as_slice() >= start;
+        if !self.tables.iter().any(|live| overlap(&live.meta)) {
+            info!("No sst file to compact in range");
+            return 0;
+        }
+        let newest = self.tables.iter().filter(|live| live.meta.level == L0 && overlap(&live.meta)).map(|live| live.meta.number).max().unwrap_or(0);
+        let inputs: Vec<u64> = self.tables.iter()
+            .filter(|live| live.meta.level == BOTTOM_LEVEL || live.meta.number <= newest)
+            .map(|live| live.meta.number)
+            .collect();
+        let count = inputs.len();
+        self.start_compaction(inputs);
+        self.wait_compaction().await;
+        count
+    }
+
+    async fn scrub(&mut self) -> ScrubReport {
+        // 正在写入的 WAL 段不校验
+        let wals: Vec<String> = self.files.wals.iter().rev().skip(1)
+            .map(|number| file_path(&self.data_path, WAL_FILE_PREFIX, *number))
+            .collect();
+        let tables: Vec<(String, Arc<SsTable>)> = self.tables.iter()
+            .map(|live| (live.path.clone(), live.table.clone()))
+            .collect();
+        let files = (wals.len() + tables.len()) as u32;
+        let corrupt = tokio::task::spawn_blocking(move || {
+            let mut corrupt = Vec::new();
+            for path in wals {
+                match std::fs::read(&path) {
+                    Ok(buf) if verify_wal(&buf, &path) => {}
+                    Ok(_) => corrupt.push(path),
+                    Err(e) => {
+                        warn!("Read wal file {} fail, err = {:?}", &path, e);
+                        corrupt.push(path);
+                    }
+                }
+            }
+            for (path, table) in tables {
+                let blocks = table.verify();
+                if blocks > 0 {
+                    warn!("Sst file {} has {} corrupted blocks", &path, blocks);
+                    corrupt.push(path);
+                }
+            }
+            corrupt
+        }).await.expect("Scrub fail");
+        info!("Scrub {} files, {} corrupted", files, corrupt.len());
+        ScrubReport { files, corrupt }
+    }
+
+    async fn replay(&mut self, record: Replicated) {
+        // 版本号与主节点相同，不大于已分配的最大版本号说明已经回放过，重连后可能重复收到
+        match record {
+            Replicated::Wal { payload, .. } => {
+                // 1 bit REC_VERSION
+                // 8 bit version
+                let version = payload.get(1..9).filter(|_| payload.first() == Some(&REC_VERSION))
+                    .map(|bytes| u64::from_be_bytes(bytes.try_into().expect("Version bytes")));
+                let Some(version) = version else {
+                    warn!("Replicated wal record without version, skip");
+                    return;
+                };
+                if version <= self.memtable.last_version() {
+                    return;
+                }
+                self.write_record(&payload).await;
+                self.load(&payload);
+                self.check_flush().await;
+            }
+            Replicated::Load { payload, .. } => {
+                // 快照的记录按 key 排序，版本号不是递增的，不跳过
+                self.write_record(&payload).await;
+                self.load(&payload);
+                self.check_flush().await;
+            }
+            Replicated::Clear { version, .. } => {
+                // 清空不分配版本号，相等时再清空一次也没有影响
+                if self.memtable.last_version() <= version {
+                    self.memtable.raise_version(version);
+                    self.clear().await;
+                }
+            }
+            Replicated::Ingest { version, entries, .. } => {
+                if self.memtable.last_version() < version {
+                    self.memtable.raise_version(version - 1);
+                    self.ingest(entries).await;
+                }
+            }
+            Replicated::Version { version, .. } => {
+                if self.memtable.last_version() < version {
+                    // 1 bit tag
+                    // 8 bit last version
+                    let mut buf = vec![REC_LAST_VERSION];
+                    buf.extend(version.to_be_bytes());
+                    self.write_wal(&buf).await;
+                    self.memtable.raise_version(version);
+                }
+            }
+        }
+    }
+
+    fn export(&self) -> Vec<Vec<u8>> {
+        // scan 之后才过期的 key 没有 meta，跳过
+        self.scan(&[], &[]).into_iter()
+            .filter_map(|(key, value)| {
+                let meta = self.meta(&key)?;
+                let mut buf = Self::version_head(meta.version);
+                if let Some(expire_at) = meta.expire_at {
+                    // 1 bit tag
+                    // 8 bit expire at
+                    buf.push(REC_EXPIRE);
+                    buf.extend(expire_at.to_be_bytes());
+                }
+                buf.extend(encode_record(&key, Some(&value)));
+                Some(buf)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{options, temp_path};
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("k{:03}", i).into_bytes()
+    }
+
+    // 正在写入的 WAL 段的路径
+    fn wal_path(storage: &LsmStorage) -> String {
+        file_path(&storage.data_path, WAL_FILE_PREFIX, *storage.files.wals.last().unwrap())
+    }
+
+    // 依次为 keys 中各 key 是否存在
+    fn present(storage: &LsmStorage, keys: &[&[u8]]) -> Vec<bool> {
+        keys.iter().map(|key| storage.get(key).is_some()).collect()
+    }
+
+    // 范围删除遮住已落盘的更旧数据，落盘、合并和重启之后仍然有效；end 不包含在内，空范围不删除
+    #[tokio::test]
+    async fn delete_range_hides_older_tables() {
+        const KEYS: [&[u8]; 7] = [b"a", b"b", b"c", b"d", b"e", b"x", b"y"];
+        let path = temp_path("delete-range");
+        let mut storage = LsmStorage::open(path.clone(), options()).await;
+        for key in KEYS {
+            storage.put(key.to_vec(), b"old".to_vec()).await;
+        }
+        storage.flush().await;
+        storage.wait_background().await;
+        assert!(storage.memtable.is_empty() && !storage.tables.is_empty());
+
+        assert_eq!(storage.delete_range(b"b".to_vec(), b"d".to_vec()).await, 2);
+        assert_eq!(storage.delete_range(b"c".to_vec(), b"c".to_vec()).await, 0);
+        assert_eq!(storage.delete_range(b"e".to_vec(), b"a".to_vec()).await, 0);
+        // end 为空表示没有上界
+        assert_eq!(storage.delete_range(b"x".to_vec(), Vec::new()).await, 2);
+        // 范围删除之后的写入不受影响
+        storage.put(b"c".to_vec(), b"new".to_vec()).await;
+        let expected = [true, false, true, true, true, false, false];
+        let check = |storage: &LsmStorage, stage: &str| {
+            assert_eq!(present(storage, &KEYS), expected, "{}", stage);
+            assert_eq!(storage.get(b"c"), Some(b"new".to_vec()), "{}", stage);
+            assert_eq!(storage.size(), 4, "{}", stage);
+            let keys: Vec<Vec<u8>> = storage.scan(b"", b"").into_iter().map(|(key, _)| key).collect();
+            assert_eq!(keys, [b"a".to_vec(), b"c".to_vec(), b"d".to_vec(), b"e".to_vec()], "{}", stage);
+        };
+        check(&storage, "memtable");
+
+        storage.flush().await;
+        storage.wait_background().await;
+        check(&storage, "flush");
+
+        assert!(storage.compact(b"", b"").await > 0);
+        check(&storage, "compaction");
+        // 合并到最底层后被遮住的记录和范围删除本身都不再保留
+        assert!(storage.tables.iter().all(|live| live.meta.level == BOTTOM_LEVEL));
+        assert_eq!(storage.tables.iter().map(|live| live.table.iter().count()).sum::<usize>(), 4);
+
+        // 没有落盘的范围删除在重启时从 WAL 回放
+        storage.put(b"b".to_vec(), b"again".to_vec()).await;
+        assert_eq!(storage.delete_range(b"b".to_vec(), b"c".to_vec()).await, 1);
+        storage.sync_wal().await;
+        drop(storage);
+        let storage = LsmStorage::open(path, options()).await;
+        check(&storage, "restart");
+    }
+
+    // 更旧的一层仍然有这个 key 时删除记录保留在第 0 层，只在合并到最底层时和旧值一起丢弃
+    #[tokio::test]
+    async fn tombstone_dropped_at_bottom() {
+        const KEYS: [&[u8]; 3] = [b"a", b"k", b"z"];
+        let path = temp_path("tombstone");
+        let mut storage = LsmStorage::open(path.clone(), options()).await;
+        for key in KEYS {
+            storage.put(key.to_vec(), b"v".to_vec()).await;
+        }
+        assert!(storage.compact(b"", b"").await > 0);
+        assert!(storage.tables.iter().all(|live| live.meta.level == BOTTOM_LEVEL));
+
+        storage.delete(b"k".to_vec()).await;
+        storage.flush().await;
+        storage.wait_background().await;
+        // 第 0 层和最底层中 key 的记录，删除记录为 None
+        let records = |storage: &LsmStorage, level: u8| -> Vec<Option<Vec<u8>>> {
+            storage.tables.iter().filter(|live| live.meta.level == level)
+                .flat_map(|live| live.table.iter())
+                .filter(|entry| entry.key == b"k")
+                .map(|entry| entry.value)
+                .collect()
+        };
+        let check = |storage: &LsmStorage, stage: &str| {
+            assert_eq!(records(storage, L0), [None], "{}", stage);
+            assert_eq!(records(storage, BOTTOM_LEVEL), [Some(b"v".to_vec())], "{}", stage);
+            assert_eq!(present(storage, &KEYS), [true, false, true], "{}", stage);
+            assert_eq!(storage.size(), 2, "{}", stage);
+        };
+        check(&storage, "flush");
+
+        // 只和最底层重叠的范围不合并第 0 层，删除记录仍然需要
+        assert_eq!(storage.compact(b"x", b"zz").await, 1);
+        check(&storage, "bottom only");
+
+        drop(storage);
+        let mut storage = LsmStorage::open(path.clone(), options()).await;
+        check(&storage, "restart");
+
+        assert!(storage.compact(b"", b"").await > 0);
+        assert!(storage.tables.iter().all(|live| live.meta.level == BOTTOM_LEVEL));
+        assert!(records(&storage, BOTTOM_LEVEL).is_empty());
+        assert_eq!(present(&storage, &KEYS), [true, false, true]);
+        assert_eq!(storage.size(), 2);
+
+        drop(storage);
+        let storage = LsmStorage::open(path, options()).await;
+        assert!(storage.tables.iter().flat_map(|live| live.table.iter()).all(|entry| entry.value.is_some()));
+        assert_eq!(present(&storage, &KEYS), [true, false, true]);
+    }
+
+    // SST 文件写完、MANIFEST 还没记录时崩溃：数据从 WAL 回放，没有记录的文件在启动时删除
+    #[tokio::test]
+    async fn crash_before_manifest() {
+        const N: usize = 10;
+        let path = temp_path("crash-manifest");
+        let mut storage = LsmStorage::open(path.clone(), options()).await;
+        for i in 0..N / 2 {
+            storage.put(key(i), b"v".to_vec()).await;
+        }
+        storage.flush().await;
+        storage.wait_background().await;
+        for i in N / 2..N {
+            storage.put(key(i), b"v".to_vec()).await;
+        }
+        storage.sync_wal().await;
+        // 落盘和合并的输出都已写入，MANIFEST 没有记录
+        let number = storage.files.new_file_number();
+        let flushed = flush_table(&storage.data_path, number, storage.memtable.as_ref(), &storage.options).unwrap();
+        let number = storage.files.new_file_number();
+        let tables: Vec<Arc<SsTable>> = storage.tables.iter().map(|live| live.table.clone()).collect();
+        let (compacted, _) = compact_tables(&storage.data_path, number, &tables, &storage.options, now_millis());
+        let orphans = [flushed.path, compacted.unwrap().path];
+        assert!(orphans.iter().all(|path| std::fs::metadata(path).is_ok()));
+        drop(tables);
+        drop(storage);
+
+        let mut storage = LsmStorage::open(path.clone(), options()).await;
+        assert!(orphans.iter().all(|path| std::fs::metadata(path).is_err()));
+        assert_eq!(storage.tables.len(), 1);
+        assert_eq!(storage.size(), N);
+        assert!((0..N).all(|i| storage.get(&key(i)).is_some()));
+
+        // 之后的落盘和合并可以复用这些编号
+        assert!(storage.compact(b"", b"").await > 0);
+        drop(storage);
+        let storage = LsmStorage::open(path, options()).await;
+        assert_eq!(storage.size(), N);
+        assert!((0..N).all(|i| storage.get(&key(i)).is_some()));
+    }
+
+    // 旧版本的 INDEX、WAL_FILE_、SST_FILE_、LOG_FILE_ 转换为 MANIFEST 管理的文件，回放结果不变
+    // 每种布局先只做一次转换不写 MANIFEST，模拟升级中途崩溃
+    #[tokio::test]
+    async fn upgrade_legacy_layout() {
+        type Records<'a> = &'a [(&'a [u8], Option<&'a [u8]>)];
+        fn wal(records: Records) -> Vec<u8> {
+            records.iter().flat_map(|(key, value)| encode_record(key, *value)).collect()
+        }
+        fn sst(path: &str, records: Records) {
+            let mut writer = SsTableWriter::create(path, Compression::None, None).unwrap();
+            for (key, value) in records {
+                writer.add(key, *value, None, 0).unwrap();
+            }
+            writer.finish(0).unwrap();
+        }
+        // 前缀、编号和文件中的记录
+        type Files<'a> = Vec<(&'a str, u64, Records<'a>)>;
+        const OLD: Records = &[(b"a", Some(b"old")), (b"b", Some(b"old"))];
+        // 每种布局：INDEX 的值，SST_FILE_ 文件，WAL_FILE_ 和 LOG_FILE_ 文件
+        let layouts: [(&str, u8, Files, Files); 3] = [
+            // 当前一组的 SST 文件存在，另一组的 WAL 已经不再需要
+            ("legacy-current", 0, vec![(LEGACY_SST_FILE_PREFIX, 0, OLD)], vec![
+                (LEGACY_WAL_FILE_PREFIX, 0, &[(b"a", Some(b"new")), (b"b", None), (b"c", Some(b"v"))]),
+                (LEGACY_WAL_FILE_PREFIX, 1, &[(b"z", Some(b"stale"))]),
+            ]),
+            // 当前一组的 SST 文件没有写完，从另一组的 SST 文件开始回放
+            ("legacy-last", 1, vec![(LEGACY_SST_FILE_PREFIX, 0, OLD)], vec![
+                (LEGACY_WAL_FILE_PREFIX, 0, &[(b"a", Some(b"new"))]),
+                (LEGACY_LOG_FILE_PREFIX, 1, &[(b"b", None)]),
+                (LEGACY_WAL_FILE_PREFIX, 1, &[(b"c", Some(b"v"))]),
+            ]),
+            // 更旧的版本没有 SST 文件，只有 LOG 文件
+            ("legacy-log", 1, vec![], vec![
+                (LEGACY_LOG_FILE_PREFIX, 0, OLD),
+                (LEGACY_WAL_FILE_PREFIX, 0, &[(b"a", Some(b"new"))]),
+                (LEGACY_LOG_FILE_PREFIX, 1, &[(b"b", None)]),
+                (LEGACY_WAL_FILE_PREFIX, 1, &[(b"c", Some(b"v"))]),
+            ]),
+        ];
+        for (name, index, tables, wals) in layouts {
+            let path = temp_path(name);
+            std::fs::write(format!("{}/{}", path, LEGACY_INDEX_FILE), [index]).unwrap();
+            for (prefix, i, records) in tables {
+                sst(&file_path(&path, prefix, i), records);
+            }
+            for (prefix, i, records) in wals {
+                std::fs::write(file_path(&path, prefix, i), wal(records)).unwrap();
+            }
+            upgrade_legacy(&path, options().block_cache.clone()).await;
+            assert!(!Manifest::exists(&path).await, "{}", name);
+
+            let check = |storage: &LsmStorage, stage: &str| {
+                assert_eq!(storage.get(b"a"), Some(b"new".to_vec()), "{} {}", name, stage);
+                assert_eq!(present(storage, &[b"b", b"c", b"z"]), [false, true, false], "{} {}", name, stage);
+                assert_eq!(storage.size(), 2, "{} {}", name, stage);
+            };
+            let storage = LsmStorage::open(path.clone(), options()).await;
+            check(&storage, "upgrade");
+            assert!(Manifest::exists(&path).await, "{}", name);
+            let legacy: Vec<String> = std::fs::read_dir(&path).unwrap().flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name == LEGACY_INDEX_FILE || [LEGACY_WAL_FILE_PREFIX, LEGACY_SST_FILE_PREFIX, LEGACY_LOG_FILE_PREFIX].iter().any(|prefix| name.starts_with(prefix)))
+                .collect();
+            assert!(legacy.is_empty(), "{} {:?}", name, legacy);
+            drop(storage);
+            let storage = LsmStorage::open(path, options()).await;
+            check(&storage, "restart");
+        }
+    }
+
+    // WAL 最后一帧写了一半或校验失败时回放到前一帧为止，坏的尾部被截掉
+    #[tokio::test]
+    async fn torn_wal_tail() {
+        const N: usize = 10;
+        for (name, torn) in [("wal-truncated", true), ("wal-flipped", false)] {
+            let path = temp_path(name);
+            let mut storage = LsmStorage::open(path.clone(), options()).await;
+            for i in 0..N - 1 {
+                storage.put(key(i), b"v".to_vec()).await;
+            }
+            storage.sync_wal().await;
+            let wal = wal_path(&storage);
+            let valid = std::fs::metadata(&wal).unwrap().len();
+            storage.put(key(N - 1), b"v".to_vec()).await;
+            storage.sync_wal().await;
+            drop(storage);
+
+            let mut buf = std::fs::read(&wal).unwrap();
+            assert!(buf.len() as u64 > valid);
+            if torn {
+                buf.pop();
+            } else {
+                *buf.last_mut().unwrap() ^= 1;
+            }
+            std::fs::write(&wal, &buf).unwrap();
+
+            let storage = LsmStorage::open(path, options()).await;
+            assert_eq!(storage.size(), N - 1);
+            assert!((0..N - 1).all(|i| storage.get(&key(i)).is_some()));
+            assert_eq!(storage.get(&key(N - 1)), None);
+            assert_eq!(std::fs::metadata(&wal).unwrap().len(), valid);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use dashmap::DashMap;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
+use crate::audit::AuditLog;
+use crate::cache::BlockCache;
+use crate::client::{Cancelled, Client};
+use crate::event::{Event, EventHandler, EventRes, Settings, SizeLimits, DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_NAMESPACES, DEFAULT_MAX_REQUEST_SIZE, DEFAULT_MAX_VALUE_SIZE};
+use crate::memtable::MemtableKind;
+use crate::memory::MemStorage;
+use crate::storage::{Durability, LsmStorage, StorageEngine, StorageOptions, Tunables, DEFAULT_L0_STOP_TRIGGER, DEFAULT_MAX_IMMUTABLE_MEMTABLES, DEFAULT_MEMTABLE_BYTES, DEFAULT_WAL_SEGMENT_BYTES};
+
+// 测试共用：临时数据目录、默认配置和事件循环
+
+// 临时目录下的空目录，同名测试的旧数据先删除；名字在各测试中唯一
+pub fn temp_path(name: &str) -> String {
+    let path = format!("{}/lsm-test-{}-{}", std::env::temp_dir().display(), std::process::id(), name);
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+pub fn settings() -> Settings {
+    Settings {
+        limits: SizeLimits { max_key: DEFAULT_MAX_KEY_SIZE, max_value: DEFAULT_MAX_VALUE_SIZE, max_request: DEFAULT_MAX_REQUEST_SIZE },
+        tunables: Tunables {
+            memtable_bytes: DEFAULT_MEMTABLE_BYTES,
+            wal_segment_bytes: DEFAULT_WAL_SEGMENT_BYTES,
+            durability: Durability::Never,
+            memtable_max_age: None,
+            max_immutable_memtables: DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+            l0_stop_trigger: DEFAULT_L0_STOP_TRIGGER,
+        },
+    }
+}
+
+pub fn options() -> StorageOptions {
+    let tunables = settings().tunables;
+    StorageOptions {
+        block_cache: Arc::new(BlockCache::new(1 << 20)),
+        memtable: MemtableKind::Trie,
+        memtable_bytes: tunables.memtable_bytes,
+        wal_segment_bytes: tunables.wal_segment_bytes,
+        durability: tunables.durability,
+        compression: Vec::new(),
+        mmap: false,
+        io_throttle: None,
+        level_paths: Vec::new(),
+        memtable_max_age: None,
+        max_immutable_memtables: tunables.max_immutable_memtables,
+        l0_stop_trigger: tunables.l0_stop_trigger,
+        checkpoint: false,
+        shared_view: None,
+        replication: None,
+    }
+}
+
+// 临时目录中新打开的存储引擎
+pub async fn open(name: &str) -> LsmStorage {
+    LsmStorage::open(temp_path(name), options()).await
+}
+
+// 测试中连接的客户端 id
+pub const CLIENT: &str = "c";
+
+// 在临时目录中打开存储引擎，启动从 receiver 接收事件的事件循环
+pub async fn spawn_loop(name: &str, receiver: Receiver<Event>, client_map: Arc<DashMap<String, Client>>, audit: Option<AuditLog>, setup: impl FnOnce(&mut EventHandler<LsmStorage>)) {
+    let path = temp_path(name);
+    let storage = LsmStorage::open(path.clone(), options()).await;
+    let (settings_tx, settings) = watch::channel(settings());
+    let mut handler = EventHandler::new(receiver, storage, path, DEFAULT_MAX_NAMESPACES, settings, client_map, audit, None);
+    setup(&mut handler);
+    tokio::spawn(async move {
+        // 配置通道一直打开，和运行时一样
+        let _settings_tx = settings_tx;
+        handler.start_event_loop().await
+    });
+}
+
+pub struct TestLoop {
+    events: Sender<Event>,
+    responses: Receiver<EventRes>,
+    // CLIENT 已超时放弃的请求
+    pub cancelled: Cancelled,
+}
+
+impl TestLoop {
+    pub async fn start(name: &str, setup: impl FnOnce(&mut EventHandler<LsmStorage>)) -> Self {
+        Self::with_audit(name, None, setup).await
+    }
+
+    pub async fn with_audit(name: &str, audit: Option<AuditLog>, setup: impl FnOnce(&mut EventHandler<LsmStorage>)) -> Self {
+        let (test_loop, receiver, client_map) = Self::connect();
+        spawn_loop(name, receiver, client_map, audit, setup).await;
+        test_loop
+    }
+
+    // 使用内存存储引擎的事件循环
+    pub async fn memory(name: &str) -> Self {
+        let (test_loop, receiver, client_map) = Self::connect();
+        let storage = MemStorage::open(name.to_string(), options()).await;
+        let (settings_tx, settings) = watch::channel(settings());
+        let mut handler = EventHandler::new(receiver, storage, temp_path(name), DEFAULT_MAX_NAMESPACES, settings, client_map, None, None);
+        tokio::spawn(async move {
+            let _settings_tx = settings_tx;
+            handler.start_event_loop().await
+        });
+        test_loop
+    }
+
+    // 连接了 CLIENT 的一端，返回事件循环接收事件的一端和客户端表
+    fn connect() -> (Self, Receiver<Event>, Arc<DashMap<String, Client>>) {
+        let (events, receiver) = mpsc::channel(128);
+        let (res_tx, responses) = mpsc::channel(128);
+        let client_map = Arc::new(DashMap::new());
+        let cancelled = Cancelled::default();
+        client_map.insert(CLIENT.to_string(), Client::new(CLIENT.to_string(), res_tx, cancelled.clone()));
+        (Self { events, responses, cancelled }, receiver, client_map)
+    }
+
+    pub async fn send(&self, event: Event) {
+        self.events.send(event).await.unwrap();
+    }
+
+    pub async fn recv(&mut self) -> EventRes {
+        self.responses.recv().await.unwrap()
+    }
+
+    // 发送事件并等待一条响应
+    pub async fn call(&mut self, event: Event) -> EventRes {
+        self.send(event).await;
+        self.recv().await
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+// 令牌桶，容量为一秒的令牌数
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    // 取 n 个令牌，令牌不足时允许透支，返回需要等待的时间
+    fn take(&mut self, n: f64, now: Instant) -> Duration {
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens -= n;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+struct Buckets {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+// 全局写限流，所有连接共享
+pub struct WriteThrottle {
+    buckets: Mutex<Buckets>,
+    // 被延迟的写入次数和累计等待的毫秒数，计入 STATS
+    delayed: AtomicU64,
+    delayed_ms: AtomicU64,
+}
+
+impl WriteThrottle {
+    pub fn new(ops_per_sec: Option<u64>, bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                ops: ops_per_sec.filter(|r| *r > 0).map(TokenBucket::new),
+                bytes: bytes_per_sec.filter(|r| *r > 0).map(TokenBucket::new),
+            }),
+            delayed: AtomicU64::new(0),
+            delayed_ms: AtomicU64::new(0),
+        }
+    }
+
+    // 重新读取配置文件或管理端口修改速率，桶重新装满
+    pub fn set_rates(&self, ops_per_sec: Option<u64>, bytes_per_sec: Option<u64>) {
+        let mut buckets = self.buckets.lock().expect("Lock write throttle fail");
+        buckets.ops = ops_per_sec.filter(|r| *r > 0).map(TokenBucket::new);
+        buckets.bytes = bytes_per_sec.filter(|r| *r > 0).map(TokenBucket::new);
+    }
+
+    // 当前的每秒 op 数和字节数，不限制时为 None
+    pub fn rates(&self) -> (Option<u64>, Option<u64>) {
+        let buckets = self.buckets.lock().expect("Lock write throttle fail");
+        (buckets.ops.as_ref().map(|bucket| bucket.rate as u64), buckets.bytes.as_ref().map(|bucket| bucket.rate as u64))
+    }
+
+    // 被延迟的写入次数和累计等待的毫秒数
+    pub fn delayed(&self) -> (u64, u64) {
+        (self.delayed.load(Ordering::Relaxed), self.delayed_ms.load(Ordering::Relaxed))
+    }
+
+    // 一次写入消耗一个 op 令牌和 bytes 个字节令牌
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut buckets = self.buckets.lock().expect("Lock write throttle fail");
+            let now = Instant::now();
+            let ops_wait = match buckets.ops.as_mut() {
+                Some(bucket) => bucket.take(1.0, now),
+                None => Duration::ZERO,
+            };
+            let bytes_wait = match buckets.bytes.as_mut() {
+                Some(bucket) => bucket.take(bytes as f64, now),
+                None => Duration::ZERO,
+            };
+            ops_wait.max(bytes_wait)
+        };
+        if !wait.is_zero() {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+            self.delayed_ms.fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+            sleep(wait).await;
+        }
+    }
+}
+
+// 落盘和合并写 SST 文件的限速，所有命名空间共享，避免后台写盘占满磁盘影响前台请求
+// 在后台线程中调用，令牌不足时阻塞当前线程
+pub struct IoThrottle {
+    bytes: Mutex<TokenBucket>,
+}
+
+impl IoThrottle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes: Mutex::new(TokenBucket::new(bytes_per_sec)),
+        }
+    }
+
+    pub fn acquire_blocking(&self, bytes: usize) {
+        let wait = self.bytes.lock().expect("Lock io throttle fail").take(bytes as f64, Instant::now());
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 超过速率的突发写入被延迟，延迟的次数和时间计入计数
+    #[tokio::test]
+    async fn burst_delayed() {
+        let throttle = WriteThrottle::new(Some(50), None);
+        let start = Instant::now();
+        for _ in 0..50 {
+            throttle.acquire(0).await;
+        }
+        assert_eq!(throttle.delayed(), (0, 0));
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // 桶空后每次写入约等待 20ms，睡眠超时时可能有几次拿到令牌不等待
+        let start = Instant::now();
+        for _ in 0..10 {
+            throttle.acquire(0).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        let (delayed, delayed_ms) = throttle.delayed();
+        assert!(delayed > 0 && delayed <= 10);
+        assert!(delayed_ms > 0);
+
+        // 不限制时不延迟
+        throttle.set_rates(None, None);
+        throttle.acquire(1 << 20).await;
+        assert_eq!(throttle.delayed().0, delayed);
+    }
+}
@@ -1,115 +1,639 @@
-use tokio::fs::File;
-use std::mem::MaybeUninit;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
-use crate::event::LEN_MASK;
+use crate::glob::Pattern;
+use crate::memtable::{Memtable, Record, Records};
+use crate::sstable::RangeTombstone;
+use crate::utils::now_millis;
 
-const NODE_SIZE: usize = 1 << 8;
+// 每个节点单独分配，加上 Arc 的引用计数；label、value 和它自己的子节点数组另算
+const NODE_BYTES: usize = std::mem::size_of::<Node>() + 2 * std::mem::size_of::<usize>();
+// 子节点数组中每一项占用的字节数
+const CHILD_BYTES: usize = std::mem::size_of::<Arc<Node>>();
+// value 单独分配，加上 Arc 的引用计数，内容另算
+const VALUE_BYTES: usize = std::mem::size_of::<Vec<u8>>() + 2 * std::mem::size_of::<usize>();
+const RANGE_BYTES: usize = std::mem::size_of::<RangeTombstone>();
 
+// 默认的内存表，按路径压缩的基数树存储，只有一个子节点且没有记录的节点合并到子节点中，节点数不超过记录数的两倍
+// 范围删除时被遮住的记录直接删除，空出的节点随之移除或合并
+// 所有操作都用循环或显式的栈遍历，不随 key 的长度递归
+// 节点和 value 由 Arc 共享，clone 是 O(1) 的快照；之后的写入只复制被修改的路径，快照看不到
+#[derive(Clone)]
 pub struct Trie {
-    nodes: Box<[Option<Trie>; NODE_SIZE]>,
-    value: Option<Vec<u8>>,
+    root: Arc<Node>,
+    // key 和 value 的总字节数，已过期但未删除的 key 也计入，范围删除计入边界的字节数
+    bytes: usize,
+    // 引用的堆内存：节点、子节点数组、label 和 value 按容量计算，加上范围删除；写入和删除时增量维护
+    // 与快照共享的部分也计入
+    heap_bytes: usize,
+    // 已分配的最大版本号，包括删除
+    last_version: u64,
+    // 范围删除，按写入顺序排列
+    ranges: Vec<RangeTombstone>,
 }
 
-impl Clone for Trie {
-    fn clone(&self) -> Self {
-        let mut res = Trie::new();
-        res.clone_from(self);
-        res
-    }
+// version 不为 0 的节点有记录
+#[derive(Clone)]
+struct Node {
+    // 父节点到这个节点之间的一段 key，根节点为空，其他节点不为空
+    label: Vec<u8>,
+    // 按 label 的第一个字节排序
+    children: Vec<Arc<Node>>,
+    value: Option<Arc<Vec<u8>>>,
+    // 过期时间，unix 毫秒
+    expire_at: Option<u64>,
+    // 最后一次写入的版本号，0 表示没有记录
+    version: u64,
+}
 
-    fn clone_from(&mut self, source: &Self) {
-        for i in 0..NODE_SIZE {
-            self.nodes[i] = source.nodes[i].clone();
-        }
-        self.value = source.value.clone();
+// a 和 b 公共前缀的长度
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn value_heap(value: &Option<Arc<Vec<u8>>>) -> usize {
+    value.as_ref().map_or(0, |value| VALUE_BYTES + value.capacity())
+}
+
+// 独占 node 后返回可变引用；与快照共享时只复制这一个节点，子节点和 value 仍然共享，heap 换成副本的内存
+fn make_mut<'a>(node: &'a mut Arc<Node>, heap: &mut usize) -> &'a mut Node {
+    if Arc::get_mut(node).is_none() {
+        let copy = Node::clone(node);
+        *heap = *heap - node.heap() + copy.heap();
+        *node = Arc::new(copy);
     }
+    Arc::get_mut(node).expect("Trie node is shared")
 }
 
 impl Trie {
     pub fn new() -> Self {
-        let mut node: [MaybeUninit<Option<Trie>>; NODE_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
-        for i in 0..NODE_SIZE {
-            node[i] = MaybeUninit::new(None);
-        }
-
         Trie {
-            nodes: Box::new(unsafe { core::mem::transmute(node) }),
-            value: None,
+            root: Arc::new(Node::new(Vec::new())),
+            bytes: 0,
+            heap_bytes: 0,
+            last_version: 0,
+            ranges: Vec::new(),
         }
     }
 
-    pub fn set(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
-        self.do_set(key, value, 0)
+    // key 对应的节点，不存在时创建，路径上与快照共享的节点复制一份；新分配的内存计入 heap_bytes
+    // 与子节点的 label 只有部分相同时在分叉处拆开子节点
+    fn entry(&mut self, key: &[u8]) -> &mut Node {
+        let heap = &mut self.heap_bytes;
+        let mut node = make_mut(&mut self.root, heap);
+        let mut key = key;
+        while let Some(c) = key.first() {
+            let i = match node.child_index(*c) {
+                Ok(i) => i,
+                Err(i) => {
+                    let capacity = node.children.capacity();
+                    node.children.insert(i, Arc::new(Node::new(key.to_vec())));
+                    *heap += (node.children.capacity() - capacity) * CHILD_BYTES + NODE_BYTES + node.children[i].heap();
+                    return Arc::get_mut(&mut node.children[i]).expect("Trie node is shared");
+                }
+            };
+            let common = common_prefix(&node.children[i].label, key);
+            if common < node.children[i].label.len() {
+                // 原来的子节点移到新节点的子节点数组中，只有 label 变短
+                let mut child = node.children.remove(i);
+                make_mut(&mut child, heap).label.drain(..common);
+                let mut split = Node::new(key[..common].to_vec());
+                split.children.push(child);
+                *heap += NODE_BYTES + split.heap();
+                node.children.insert(i, Arc::new(split));
+            }
+            key = &key[common..];
+            node = make_mut(&mut node.children[i], heap);
+        }
+        node
     }
 
-    fn do_set(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, index: usize) {
-        if index > key.len() {
-            return;
-        } else if index == key.len() {
-            self.value = value;
-        } else {
-            let i = key[index] as usize;
-            match self.nodes[i].as_mut() {
-                Some(node) => {
-                    node.do_set(key, value, index + 1)
+    // 删除 key 的记录，不留删除记录；返回是否有记录
+    // 没有子节点的节点从父节点中移除，没有记录且只剩一个子节点的节点与子节点合并，父节点因此只剩一个子节点时同样合并
+    // 路径上与快照共享的节点复制一份，没有记录时不复制
+    fn remove(&mut self, key: &[u8]) -> bool {
+        // 路径上每个节点在父节点中的位置
+        let mut path = Vec::new();
+        let mut node = self.root.as_ref();
+        let mut rest = key;
+        while let Some(c) = rest.first() {
+            let Ok(i) = node.child_index(*c) else {
+                return false;
+            };
+            node = node.children[i].as_ref();
+            let Some(next) = rest.strip_prefix(node.label.as_slice()) else {
+                return false;
+            };
+            rest = next;
+            path.push(i);
+        }
+        if node.version == 0 {
+            return false;
+        }
+        self.bytes -= node.value.as_ref().map_or(0, |value| key.len() + value.len());
+        let mut heap = self.heap_bytes;
+        match path.split_last() {
+            None => make_mut(&mut self.root, &mut heap).clear(&mut heap),
+            Some((&i, init)) => {
+                let mut slot = &mut self.root;
+                for &j in init {
+                    slot = &mut make_mut(slot, &mut heap).children[j];
                 }
-                None => {
-                    let mut node = Trie::new();
-                    node.do_set(key, value, index + 1);
-                    self.nodes[i] = Some(node);
+                prune(make_mut(slot, &mut heap), i, &mut heap);
+                // 根节点不合并
+                if !init.is_empty() && slot.version == 0 && slot.children.len() == 1 {
+                    merge(slot, &mut heap);
                 }
             }
         }
+        self.heap_bytes = heap;
+        true
+    }
+}
+
+// 删除 parent 的第 i 个子节点的记录：没有子节点时移除，只有一个子节点时与它合并
+fn prune(parent: &mut Node, i: usize, heap: &mut usize) {
+    match parent.children[i].children.len() {
+        0 => {
+            let node = parent.children.remove(i);
+            *heap -= NODE_BYTES + node.heap();
+        }
+        1 => merge(&mut parent.children[i], heap),
+        _ => make_mut(&mut parent.children[i], heap).clear(heap),
+    }
+}
+
+// 只有一个子节点的节点换成它的子节点，label 拼在子节点的 label 前面；节点自己的记录丢弃
+fn merge(slot: &mut Arc<Node>, heap: &mut usize) {
+    let mut child = Arc::clone(&slot.children[0]);
+    let label = [slot.label.as_slice(), child.label.as_slice()].concat();
+    *heap -= NODE_BYTES + slot.heap();
+    let child_node = make_mut(&mut child, heap);
+    *heap = *heap - child_node.label.capacity() + label.capacity();
+    child_node.label = label;
+    *slot = child;
+}
+
+impl Memtable for Trie {
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // 引用的堆内存，不含 Trie 本身
+    fn memory(&self) -> usize {
+        self.heap_bytes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.root.version == 0 && self.root.children.is_empty() && self.ranges.is_empty()
+    }
+
+    fn record(&self, key: &[u8]) -> Option<Record<'_>> {
+        self.root.node(key).filter(|node| node.version != 0).map(|node| (node.value(), node.expire_at, node.version))
+    }
+
+    // key 的前缀中未删除、未过期的最长的一个：前缀的长度和 value，key 本身也算前缀；只沿 key 的路径走一次
+    fn longest_prefix(&self, key: &[u8], now: u64) -> Option<(usize, &[u8])> {
+        let mut node = self.root.as_ref();
+        let mut len = 0;
+        let mut longest = node.live_value(now).map(|value| (0, value.as_slice()));
+        while let Some(c) = key.get(len) {
+            let Ok(i) = node.child_index(*c) else {
+                break;
+            };
+            node = node.children[i].as_ref();
+            if !key[len..].starts_with(&node.label) {
+                break;
+            }
+            len += node.label.len();
+            if let Some(value) = node.live_value(now) {
+                longest = Some((len, value.as_slice()));
+            }
+        }
+        longest
+    }
+
+    fn ranges(&self) -> &[RangeTombstone] {
+        &self.ranges
+    }
+
+    fn last_version(&self) -> u64 {
+        self.last_version
+    }
+
+    fn raise_version(&mut self, version: u64) {
+        self.last_version = self.last_version.max(version);
+    }
+
+    fn set_expire(&mut self, key: &[u8], value: Option<Vec<u8>>, expire_at: Option<u64>, version: u64) {
+        self.raise_version(version);
+        let new_bytes = value.as_ref().map(|v| key.len() + v.len());
+        let value = value.map(Arc::new);
+        let new_heap = value_heap(&value);
+        let node = self.entry(key);
+        let old_bytes = node.value.as_ref().map(|v| key.len() + v.len());
+        let old_heap = value_heap(&node.value);
+        node.value = value;
+        node.expire_at = expire_at;
+        node.version = version;
+        self.bytes = self.bytes - old_bytes.unwrap_or(0) + new_bytes.unwrap_or(0);
+        self.heap_bytes = self.heap_bytes - old_heap + new_heap;
     }
 
-    pub fn get(&self, key: Vec<u8>) -> Option<Vec<u8>> {
-        self.do_get(key, 0)
+    fn delete_range(&mut self, start: &[u8], end: &[u8], version: u64) {
+        self.raise_version(version);
+        // 范围删除遮住范围内版本号更小的记录，不再需要它们的删除记录，直接删除并修剪节点
+        let keys: Vec<Vec<u8>> = self.range(start, end)
+            .filter(|(_, (_, _, current))| *current < version)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+        self.bytes += start.len() + end.len();
+        let capacity = self.ranges.capacity();
+        self.ranges.push(RangeTombstone { start: start.to_vec(), end: end.to_vec(), version });
+        self.heap_bytes += start.len() + end.len() + (self.ranges.capacity() - capacity) * RANGE_BYTES;
     }
 
-    fn do_get(&self, key: Vec<u8>, index: usize) -> Option<Vec<u8>> {
-        if index > key.len() {
-            None
-        } else if index == key.len() {
-            self.value.clone()
-        } else {
-            let i = key[index] as usize;
-            match self.nodes[i].as_ref() {
-                Some(node) => {
-                    node.do_get(key, index + 1)
+    fn append(&mut self, key: &[u8], suffix: &[u8], version: u64) -> usize {
+        self.raise_version(version);
+        let now = now_millis();
+        let node = self.entry(key);
+        let old = node.value.as_ref().map(|v| v.len());
+        let old_heap = value_heap(&node.value);
+        if node.live_value(now).is_none() {
+            node.value = None;
+            node.expire_at = None;
+        }
+        node.version = version;
+        // 与快照共享时复制一份再追加
+        let value = Arc::make_mut(node.value.get_or_insert_with(Default::default));
+        value.extend_from_slice(suffix);
+        let len = value.len();
+        let new_heap = value_heap(&node.value);
+        self.heap_bytes = self.heap_bytes - old_heap + new_heap;
+        match old {
+            Some(old) => self.bytes = self.bytes - old + len,
+            None => self.bytes += key.len() + len,
+        }
+        len
+    }
+
+    // 匹配 glob 模式的记录，按 key 排序，同时返回是否未删除且在 now 时未过期
+    // 先直接定位到模式字面量前缀对应的子树，之后只进入还可能匹配的子节点
+    fn keys(&self, pattern: &Pattern, now: u64) -> Vec<(Vec<u8>, bool)> {
+        let mut res = Vec::new();
+        let literal = pattern.literal_prefix();
+        let Some((node, mut key)) = self.root.subtree(&literal) else {
+            return res;
+        };
+        // 子树的 key 可能比字面量前缀长，多出的部分也要读入
+        let mut states = pattern.start();
+        for c in &key[literal.len()..] {
+            states = pattern.step(&states, *c);
+            if states.is_empty() {
+                return res;
+            }
+        }
+        // 栈中是子树的根、父节点对应的 key 的长度和读入子树的 label 之后的状态，较大的子节点先入栈
+        let mut stack = vec![(node, key.len() - node.label.len(), states)];
+        while let Some((node, depth, states)) = stack.pop() {
+            key.truncate(depth);
+            key.extend_from_slice(&node.label);
+            if node.version != 0 && pattern.is_match(&states) {
+                res.push((key.clone(), node.live_value(now).is_some()));
+            }
+            'children: for n in node.children.iter().rev() {
+                let mut next = states.clone();
+                for c in &n.label {
+                    next = pattern.step(&next, *c);
+                    if next.is_empty() {
+                        continue 'children;
+                    }
                 }
-                None => None
+                stack.push((n.as_ref(), key.len(), next));
             }
         }
+        res
     }
 
-    pub fn save(&self, file: &Arc<Mutex<File>>) {
+    // 按顺序遍历 [start, end) 中的记录，end 为空表示没有上界
+    // 只沿 start 的路径下降一次，跳过更小的子树；遇到不小于 end 的节点时结束，不再访问之后的子树
+    fn range(&self, start: &[u8], end: &[u8]) -> Records<'_> {
+        let mut stack = Vec::new();
         let mut key = Vec::new();
-        self.do_save(file, &mut key)
-    }
-
-    fn do_save(&self, file: &Arc<Mutex<File>>, key: &mut Vec<u8>) {
-        async fn do_write(file: &Arc<Mutex<File>>, key: &Vec<u8>, value: &Vec<u8>) {
-            let err_message = "Write log file fail";
-            file.lock().await.write_u8(((key.len() as u16 & LEN_MASK) >> 8) as u8).await.expect(err_message);
-            file.lock().await.write_u8(key.len() as u8).await.expect(err_message);
-            file.lock().await.write_all(key.as_slice()).await.expect(err_message);
-            file.lock().await.write_u8(((value.len() as u16 & LEN_MASK) >> 8) as u8).await.expect(err_message);
-            file.lock().await.write_u8(value.len() as u8).await.expect(err_message);
-            file.lock().await.write_all(value.as_slice()).await.expect(err_message);
-        }
-        futures::executor::block_on(async {
-            if self.value.is_some() {
-                do_write(file, key, self.value.as_ref().unwrap()).await;
+        let mut node = self.root.as_ref();
+        // node 对应的 key 是 start 的前缀
+        loop {
+            let Some(c) = start.get(key.len()) else {
+                stack.push((node, key.len() - node.label.len()));
+                break;
+            };
+            // 栈顶先访问，较大的子节点先入栈
+            let i = node.child_index(*c).unwrap_or_else(|i| i);
+            for n in node.children[i..].iter().rev().take_while(|n| n.label[0] > *c) {
+                stack.push((n.as_ref(), key.len()));
+            }
+            let Some(n) = node.children.get(i).filter(|n| n.label[0] == *c) else {
+                break;
+            };
+            let rest = &start[key.len()..];
+            let common = common_prefix(&n.label, rest);
+            if common < n.label.len() {
+                // 分叉处 label 更大，或者 start 在 label 中间结束，整个子树都不小于 start
+                if common == rest.len() || n.label[common] > rest[common] {
+                    stack.push((n.as_ref(), key.len()));
+                }
+                break;
+            }
+            key.extend_from_slice(&n.label);
+            node = n.as_ref();
+        }
+        Box::new(Iter { stack, key, end: end.to_vec() })
+    }
+
+    fn snapshot(&self) -> Box<dyn Memtable> {
+        Box::new(self.clone())
+    }
+}
+
+// 按 key 顺序遍历内存表中的记录：key 和 value（不论是否过期）、过期时间、版本号
+// 只借用内存表，用显式的栈代替递归，key 逐个拼出
+pub struct Iter<'a> {
+    // 待访问的子树的根和父节点对应的 key 的长度，栈顶先访问
+    stack: Vec<(&'a Node, usize)>,
+    key: Vec<u8>,
+    // 上界，为空表示没有上界
+    end: Vec<u8>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Vec<u8>, Record<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, depth)) = self.stack.pop() {
+            self.key.truncate(depth);
+            self.key.extend_from_slice(&node.label);
+            // 先序遍历，之后的 key 都比这个节点的大
+            if !self.end.is_empty() && self.key >= self.end {
+                self.stack.clear();
+                return None;
+            }
+            for n in node.children.iter().rev() {
+                self.stack.push((n.as_ref(), self.key.len()));
             }
-        });
-        for i in 0..NODE_SIZE {
-            if let Some(n) = self.nodes[i].as_ref() {
-                key.push(i as u8);
-                n.do_save(file, key);
-                key.pop();
+            if node.version != 0 {
+                return Some((self.key.clone(), (node.value(), node.expire_at, node.version)));
             }
         }
+        None
+    }
+}
+
+impl Node {
+    fn new(label: Vec<u8>) -> Self {
+        Node {
+            label,
+            children: Vec::new(),
+            value: None,
+            expire_at: None,
+            version: 0,
+        }
+    }
+
+    fn clear(&mut self, heap: &mut usize) {
+        *heap -= value_heap(&self.value);
+        self.value = None;
+        self.expire_at = None;
+        self.version = 0;
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref().map(Vec::as_slice)
+    }
+
+    // 未过期的 value
+    fn live_value(&self, now: u64) -> Option<&Vec<u8>> {
+        match self.expire_at {
+            Some(expire_at) if expire_at <= now => None,
+            _ => self.value.as_deref(),
+        }
+    }
+
+    // 节点的 label、子节点数组和 value 占用的堆内存，不含节点本身和子节点
+    fn heap(&self) -> usize {
+        self.label.capacity() + self.children.capacity() * CHILD_BYTES + value_heap(&self.value)
+    }
+
+    // label 以 c 开头的子节点的位置
+    fn child_index(&self, c: u8) -> Result<usize, usize> {
+        self.children.binary_search_by_key(&c, |child| child.label[0])
+    }
+
+    fn node(&self, key: &[u8]) -> Option<&Node> {
+        let mut node = self;
+        let mut key = key;
+        while let Some(c) = key.first() {
+            let child = node.children[node.child_index(*c).ok()?].as_ref();
+            key = key.strip_prefix(child.label.as_slice())?;
+            node = child;
+        }
+        Some(node)
+    }
+
+    // 所有 key 以 prefix 开头的最小子树，返回子树的根和它对应的完整 key
+    fn subtree(&self, prefix: &[u8]) -> Option<(&Node, Vec<u8>)> {
+        let mut node = self;
+        let mut rest = prefix;
+        let mut key = Vec::new();
+        while let Some(c) = rest.first() {
+            let child = node.children[node.child_index(*c).ok()?].as_ref();
+            let common = common_prefix(&child.label, rest);
+            if common < child.label.len() && common < rest.len() {
+                return None;
+            }
+            key.extend_from_slice(&child.label);
+            rest = &rest[common..];
+            node = child;
+        }
+        Some((node, key))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // 检查树的结构并重新计算引用的堆内存：非根节点的 label 不为空，没有记录的非根节点至少有两个子节点，子节点按首字节严格递增
+    // 返回非根节点的数量
+    fn check(trie: &Trie) -> usize {
+        let mut nodes = 0;
+        let mut heap = trie.root.heap();
+        let mut stack: Vec<&Node> = trie.root.children.iter().map(Arc::as_ref).collect();
+        assert!(trie.root.children.windows(2).all(|pair| pair[0].label[0] < pair[1].label[0]));
+        while let Some(node) = stack.pop() {
+            nodes += 1;
+            heap += NODE_BYTES + node.heap();
+            assert!(!node.label.is_empty());
+            assert!(node.version != 0 || node.children.len() >= 2, "Empty node {:?}", node.label);
+            assert!(node.children.windows(2).all(|pair| pair[0].label[0] < pair[1].label[0]));
+            stack.extend(node.children.iter().map(Arc::as_ref));
+        }
+        heap += trie.ranges.iter().map(|range| range.start.len() + range.end.len()).sum::<usize>() + trie.ranges.capacity() * RANGE_BYTES;
+        assert_eq!(trie.memory(), heap);
+        nodes
+    }
+
+    // 根节点下的 label
+    fn labels(trie: &Trie) -> Vec<&[u8]> {
+        trie.root.children.iter().map(|node| node.label.as_slice()).collect()
+    }
+
+    fn put(trie: &mut Trie, key: &[u8]) {
+        let version = trie.last_version() + 1;
+        trie.set_expire(key, Some(key.to_vec()), None, version);
+    }
+
+    fn keys(records: Records) -> Vec<Vec<u8>> {
+        records.map(|(key, _)| key).collect()
+    }
+
+    // 新 key 在 label 中间分叉或在 label 中间结束时拆开节点，原来的记录不变
+    #[test]
+    fn split_mid_edge() {
+        let mut trie = Trie::new();
+        put(&mut trie, b"abcdef");
+        assert_eq!(labels(&trie), [b"abcdef".as_slice()]);
+        put(&mut trie, b"abcxyz");
+        assert_eq!(labels(&trie), [b"abc".as_slice()]);
+        assert_eq!(trie.root.children[0].version, 0);
+        put(&mut trie, b"ab");
+        assert_eq!(labels(&trie), [b"ab".as_slice()]);
+        let ab = trie.root.children[0].as_ref();
+        assert_eq!(ab.children.iter().map(|node| node.label.as_slice()).collect::<Vec<_>>(), [b"c".as_slice()]);
+        assert_eq!(check(&trie), 4);
+        assert_eq!(keys(trie.iter()), [b"ab".to_vec(), b"abcdef".to_vec(), b"abcxyz".to_vec()]);
+        for key in [b"ab".as_slice(), b"abcdef", b"abcxyz"] {
+            assert_eq!(trie.record(key).map(|(value, _, _)| value), Some(Some(key)));
+        }
+        assert_eq!(trie.record(b"abc"), None);
+        assert_eq!(trie.record(b"abcd"), None);
+        assert_eq!(trie.bytes(), 4 + 12 + 12);
+    }
+
+    // 删除后没有子节点的节点移除，没有记录且只剩一个子节点的节点与子节点合并，父节点随之合并
+    #[test]
+    fn remove_and_merge() {
+        let mut trie = Trie::new();
+        for key in [b"a1x".as_slice(), b"a1y", b"a2"] {
+            put(&mut trie, key);
+        }
+        assert_eq!(check(&trie), 5);
+        assert!(trie.remove(b"a2"));
+        assert_eq!(labels(&trie), [b"a1".as_slice()]);
+        assert_eq!(check(&trie), 3);
+        assert!(!trie.remove(b"a1"));
+        assert!(!trie.remove(b"a2"));
+        assert!(trie.remove(b"a1x"));
+        assert_eq!(labels(&trie), [b"a1y".as_slice()]);
+        assert_eq!(check(&trie), 1);
+        assert_eq!(trie.record(b"a1y").map(|(value, _, _)| value), Some(Some(b"a1y".as_slice())));
+
+        // 有记录的节点只剩一个子节点时保留，记录删除后与子节点合并
+        put(&mut trie, b"a");
+        put(&mut trie, b"a1z");
+        assert!(trie.remove(b"a1y"));
+        assert_eq!(check(&trie), 2);
+        assert!(trie.remove(b"a"));
+        assert_eq!(labels(&trie), [b"a1z".as_slice()]);
+        assert!(trie.remove(b"a1z"));
+        assert_eq!(check(&trie), 0);
+        assert!(trie.is_empty());
+        assert_eq!(trie.bytes(), 0);
+        assert_eq!(trie.memory(), trie.root.heap());
     }
-}
\ No newline at end of file
+
+    // 范围删除修剪被遮住的记录，不留下空节点；节点数不超过记录数的两倍
+    #[test]
+    fn delete_range_prunes() {
+        let mut trie = Trie::new();
+        let all: Vec<Vec<u8>> = (0..200).map(|i| format!("{:x}", i * 7919 % 1000).into_bytes()).collect();
+        for key in &all {
+            put(&mut trie, key);
+        }
+        let version = trie.last_version() + 1;
+        trie.delete_range(b"1", b"3", version);
+        let version = trie.last_version() + 1;
+        trie.delete_range(b"3e", b"3e8", version);
+        let deleted = |key: &[u8]| (b"1".as_slice()..b"3".as_slice()).contains(&key) || (b"3e".as_slice()..b"3e8".as_slice()).contains(&key);
+        let left: Vec<&Vec<u8>> = all.iter().filter(|key| !deleted(key)).collect();
+        assert_eq!(trie.iter().count(), left.len());
+        let nodes = check(&trie);
+        assert!(nodes <= 2 * left.len(), "{} nodes for {} keys", nodes, left.len());
+        assert_eq!(trie.ranges().len(), 2);
+
+        let version = trie.last_version() + 1;
+        trie.delete_range(b"", b"", version);
+        assert_eq!(check(&trie), 0);
+        assert!(trie.iter().next().is_none());
+    }
+
+    // range 和 keys 按 key 顺序返回，起点可以在 label 中间或分叉处
+    #[test]
+    fn range_and_prefix_order() {
+        let mut trie = Trie::new();
+        let mut expected = BTreeMap::new();
+        for i in 0..300 {
+            let key = format!("{}", i * 7 % 1000).into_bytes();
+            put(&mut trie, &key);
+            expected.insert(key, ());
+        }
+        check(&trie);
+        let bounds: [(&[u8], &[u8]); 8] = [
+            (b"", b""), (b"1", b"2"), (b"10", b""), (b"105", b"5"),
+            (b"0", b"1"), (b"99", b""), (b"4", b"4"), (b"6", b"3"),
+        ];
+        for (start, end) in bounds {
+            let want: Vec<Vec<u8>> = expected.keys()
+                .filter(|key| key.as_slice() >= start && (end.is_empty() || key.as_slice() < end))
+                .cloned()
+                .collect();
+            assert_eq!(keys(trie.range(start, end)), want, "{:?} {:?}", start, end);
+        }
+        for pattern in [b"1*".as_slice(), b"10*", b"*5", b"2?1", b"[13]4*", b"9999*"] {
+            let glob = Pattern::parse(pattern);
+            let want: Vec<(Vec<u8>, bool)> = expected.keys().filter(|key| glob.matches(key)).map(|key| (key.clone(), true)).collect();
+            assert_eq!(trie.keys(&glob, 0), want, "{:?}", pattern);
+        }
+    }
+
+    // 快照之后的写入、删除、追加和范围删除都复制路径，快照的内容和占用的内存不变
+    #[test]
+    fn snapshot_unchanged() {
+        let mut trie = Trie::new();
+        for i in 0..100 {
+            put(&mut trie, format!("k{}", i).as_bytes());
+        }
+        let snapshot = trie.clone();
+        let before: Vec<(Vec<u8>, Option<Vec<u8>>, u64)> = snapshot.iter().map(|(key, (value, _, version))| (key, value.map(<[u8]>::to_vec), version)).collect();
+        let memory = snapshot.memory();
+
+        put(&mut trie, b"k1");
+        put(&mut trie, b"k");
+        put(&mut trie, b"k100");
+        trie.append(b"k2", b"+", trie.last_version() + 1);
+        assert!(trie.remove(b"k3"));
+        trie.delete_range(b"k5", b"k6", trie.last_version() + 1);
+        check(&trie);
+
+        let after: Vec<(Vec<u8>, Option<Vec<u8>>, u64)> = snapshot.iter().map(|(key, (value, _, version))| (key, value.map(<[u8]>::to_vec), version)).collect();
+        assert_eq!(after, before);
+        assert_eq!(snapshot.memory(), memory);
+        check(&snapshot);
+        assert_eq!(trie.record(b"k2").map(|(value, _, _)| value), Some(Some(b"k2+".as_slice())));
+        assert_eq!(snapshot.record(b"k2").map(|(value, _, _)| value), Some(Some(b"k2".as_slice())));
+        assert_eq!(trie.record(b"k3"), None);
+        assert!(snapshot.record(b"k3").is_some());
+    }
+}
@@ -1,4 +1,51 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 // 根据 ip 和 port 获取 client id
 pub fn get_id(ip : &String, port :u16) -> String {
     format!("{}:{}", ip, port)
-}
\ No newline at end of file
+}
+
+// 当前 unix 毫秒
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("System time before unix epoch").as_millis() as u64
+}
+
+// 按秒统计的次数
+pub struct OpsCounter {
+    // 当前秒，unix 秒
+    second: u64,
+    current: u64,
+    // 上一秒的次数
+    last: u64,
+}
+
+impl OpsCounter {
+    pub fn new() -> Self {
+        Self {
+            second: now_millis() / 1000,
+            current: 0,
+            last: 0,
+        }
+    }
+
+    fn roll(&mut self) {
+        let second = now_millis() / 1000;
+        if second != self.second {
+            // 中间有空闲的秒时上一秒为 0
+            self.last = if second == self.second + 1 { self.current } else { 0 };
+            self.current = 0;
+            self.second = second;
+        }
+    }
+
+    pub fn incr(&mut self) {
+        self.roll();
+        self.current += 1;
+    }
+
+    // 上一个完整秒的次数
+    pub fn per_sec(&mut self) -> u64 {
+        self.roll();
+        self.last
+    }
+}